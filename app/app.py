@@ -3,10 +3,9 @@ from app.rate_limiting import RateLimiting
 import dash
 from dash import dcc, html, Input, Output, State
 import dash_bootstrap_components as dbc
-import subprocess
 import json
 import os
-from trading_engine import run_backtest
+from trading_engine import Backtest, BacktestConfig, EngineError, StrategyConfig
 
 
 app = dash.Dash(__name__, external_stylesheets=[dbc.themes.LITERA])
@@ -68,10 +67,13 @@ def run_backtest_callback(
     short_qty,
 ):
     try:
-        backtest_result = run_backtest(strategy, ticker, window, capital, long_qty, short_qty)
-
-        # Extract trades from the portfolio
-        trades = backtest_result.get('portfolio', {}).get('trades', [])
+        config = BacktestConfig(
+            universe=[ticker],
+            strategy=StrategyConfig(strategy, int(window), int(long_qty), int(short_qty)),
+            capital=int(capital),
+        )
+        backtest_result = Backtest(config).run()
+        trades = backtest_result.trades
 
         trades_table = html.Table([
             html.Thead(
@@ -86,27 +88,31 @@ def run_backtest_callback(
             ),
             html.Tbody([
                 html.Tr([
-                    html.Td(trade.get('date', '')),
-                    html.Td(trade.get('type', '')),
-                    html.Td(trade.get('symbol', '')),
-                    html.Td(trade.get('quantity', '')),
-                    html.Td(f"${trade.get('price', 0):.2f}"),
-                    html.Td(f"${trade.get('value', 0):.2f}")
+                    html.Td(trade.timestamp.strftime("%Y-%m-%d")),
+                    html.Td("Buy" if trade.quantity > 0 else "Sell"),
+                    html.Td(trade.ticker),
+                    html.Td(abs(trade.quantity)),
+                    html.Td(f"${trade.price:.2f}"),
+                    html.Td(f"${abs(trade.value):.2f}")
                 ]) for trade in trades
             ]),
         ], style={'width': '100%', 'border-collapse': 'collapse'})
 
         # Display the results
+        metrics = backtest_result.metrics
         return html.Div([
             html.H3("Backtest Results"),
-            html.P(f"Number of Trades: {backtest_result['n_trades']}"),
+            html.P(f"Number of Trades: {backtest_result.n_trades}"),
+            html.P(f"Total Return: {metrics.total_return:.2%}"),
+            html.P(f"Sharpe Ratio: {metrics.sharpe_ratio:.2f}"),
+            html.P(f"Max Drawdown: {metrics.max_drawdown:.2%}"),
             html.H4("Trades"),
             trades_table if trades else html.P("No trades were executed in this backtest.")
         ])
-    except subprocess.CalledProcessError as e:
+    except (EngineError, ValueError) as e:
         return html.Div([
             html.H3("Error"),
-            html.P(f"Backtest failed: {e.stderr}"),
+            html.P(f"Backtest failed: {e}"),
         ])
 
 
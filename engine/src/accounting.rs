@@ -0,0 +1,385 @@
+//!
+//! Tax-lot accounting.
+//!
+//! Every fill either opens a lot or relieves existing lots of the opposite
+//! sign, using the selected relief method. Closed lots become realized
+//! lots carrying their holding period and short/long-term character.
+//! Trading costs are folded into the cost basis on open and netted from
+//! proceeds on close.
+//!
+
+use crate::error::EngineError;
+use crate::portfolio::Trade;
+use chrono::{DateTime, Duration, Utc};
+use derive_new::new;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+
+/// Holding period beyond which a gain is long-term.
+const LONG_TERM_DAYS: i64 = 365;
+/// Window either side of a loss sale in which a repurchase is a wash sale.
+const WASH_SALE_DAYS: i64 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LotMethod {
+    #[default]
+    Fifo,
+    Lifo,
+    HighestCost,
+    AverageCost,
+}
+
+impl FromStr for LotMethod {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fifo" => Ok(LotMethod::Fifo),
+            "lifo" => Ok(LotMethod::Lifo),
+            "highest_cost" => Ok(LotMethod::HighestCost),
+            "average_cost" => Ok(LotMethod::AverageCost),
+            _ => Err(EngineError::Config(format!("Unknown lot relief method: {}", s))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GainTerm {
+    ShortTerm,
+    LongTerm,
+}
+
+/// An open position lot. Short lots carry a negative quantity and their
+/// basis is the net proceeds per share of the opening sale.
+#[derive(Debug, Clone, new)]
+pub struct TaxLot {
+    pub ticker: String,
+    pub opened: DateTime<Utc>,
+    pub quantity: i64,
+    pub basis: f64,
+    /// Index of the purchase that opened a long lot.
+    #[new(default)]
+    pub purchase: Option<usize>,
+}
+impl TaxLot {
+    pub fn unrealized_pnl(&self, price: f64) -> f64 {
+        self.quantity as f64 * (price - self.basis)
+    }
+
+    pub fn holding_days(&self, as_of: DateTime<Utc>) -> i64 {
+        (as_of - self.opened).num_days()
+    }
+}
+
+/// A lot, or part of one, that has been closed out.
+#[derive(Debug, Clone, new)]
+pub struct RealizedLot {
+    pub ticker: String,
+    pub opened: DateTime<Utc>,
+    pub closed: DateTime<Utc>,
+    /// Signed quantity of the lot that was closed.
+    pub quantity: i64,
+    pub basis: f64,
+    pub exit_price: f64,
+    pub realized_pnl: f64,
+    /// Index of the purchase that opened the lot, for long lots.
+    #[new(default)]
+    pub purchase: Option<usize>,
+}
+impl RealizedLot {
+    pub fn holding_days(&self) -> i64 {
+        (self.closed - self.opened).num_days()
+    }
+
+    pub fn term(&self) -> GainTerm {
+        if self.holding_days() > LONG_TERM_DAYS {
+            GainTerm::LongTerm
+        } else {
+            GainTerm::ShortTerm
+        }
+    }
+}
+
+/// A realized loss disallowed because the same ticker was repurchased
+/// within 30 days either side of the sale.
+#[derive(Debug, Clone, new)]
+pub struct WashSale {
+    pub ticker: String,
+    pub sale_date: DateTime<Utc>,
+    pub replacement_date: DateTime<Utc>,
+    pub disallowed_loss: f64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TaxReport {
+    pub short_term_gain: f64,
+    pub long_term_gain: f64,
+    pub wash_sale_disallowed: f64,
+    pub wash_sales: Vec<WashSale>,
+}
+impl TaxReport {
+    pub fn net_gain(&self) -> f64 {
+        self.short_term_gain + self.long_term_gain + self.wash_sale_disallowed
+    }
+}
+
+
+#[derive(Debug, Clone, Default)]
+pub struct LotBook {
+    method: LotMethod,
+    lots: HashMap<String, Vec<TaxLot>>,
+    realized: Vec<RealizedLot>,
+    /// Opening purchases, kept for wash-sale matching.
+    purchases: Vec<(String, DateTime<Utc>, i64)>,
+}
+impl LotBook {
+    pub fn new(method: LotMethod) -> Self {
+        LotBook { method, ..Default::default() }
+    }
+
+    /// Builds a lot book from an external trade history, e.g. imported
+    /// brokerage executions.
+    pub fn from_trades(trades: &[Trade], method: LotMethod) -> Self {
+        let mut book = LotBook::new(method);
+        let mut sorted: Vec<&Trade> = trades.iter().collect();
+        sorted.sort_by_key(|t| t.timestamp);
+        for trade in sorted {
+            book.apply(&trade.ticker, trade.timestamp, trade.quantity, trade.price, 0.0);
+        }
+        book
+    }
+
+    pub fn method(&self) -> LotMethod {
+        self.method
+    }
+
+    /// Books an execution of `quantity` shares (signed) at `price`,
+    /// returning the realized P&L it generated.
+    pub fn apply(
+        &mut self,
+        ticker: &str,
+        timestamp: DateTime<Utc>,
+        quantity: i64,
+        price: f64,
+        trading_costs: f64,
+    ) -> f64 {
+        if quantity == 0 {
+            return 0.0;
+        }
+        let fee_per_share = trading_costs / quantity.unsigned_abs() as f64;
+        let purchase = (quantity > 0).then(|| {
+            self.purchases.push((ticker.to_string(), timestamp, quantity));
+            self.purchases.len() - 1
+        });
+
+        let method = self.method;
+        let lots = self.lots.entry(ticker.to_string()).or_default();
+        let average_basis = Self::average_basis(lots);
+        let mut remaining = quantity;
+        let mut realized_pnl = 0.0;
+
+        while remaining != 0 {
+            let idx = match Self::relief_index(lots, method, remaining.signum()) {
+                Some(idx) => idx,
+                None => break,
+            };
+            let lot = &mut lots[idx];
+            let side = lot.quantity.signum();
+            let close_qty = remaining.abs().min(lot.quantity.abs());
+            let basis = match method {
+                LotMethod::AverageCost => average_basis,
+                _ => lot.basis,
+            };
+            let exit_price = price - side as f64 * fee_per_share;
+            let pnl = side as f64 * close_qty as f64 * (exit_price - basis);
+
+            let mut realized = RealizedLot::new(
+                ticker.to_string(),
+                lot.opened,
+                timestamp,
+                side * close_qty,
+                basis,
+                exit_price,
+                pnl,
+            );
+            realized.purchase = lot.purchase;
+            self.realized.push(realized);
+            realized_pnl += pnl;
+
+            lot.quantity -= side * close_qty;
+            remaining += side * close_qty;
+            if lot.quantity == 0 {
+                lots.remove(idx);
+            }
+        }
+
+        // Average cost pools the basis: the shares left carry the average
+        // the sale was realized against.
+        if method == LotMethod::AverageCost && remaining != quantity {
+            for lot in lots.iter_mut() {
+                lot.basis = average_basis;
+            }
+        }
+        if remaining != 0 {
+            let basis = price + remaining.signum() as f64 * fee_per_share;
+            let mut lot = TaxLot::new(ticker.to_string(), timestamp, remaining, basis);
+            lot.purchase = purchase;
+            lots.push(lot);
+        }
+
+        realized_pnl
+    }
+
+    fn average_basis(lots: &[TaxLot]) -> f64 {
+        let quantity: i64 = lots.iter().map(|l| l.quantity).sum();
+        if quantity == 0 {
+            return 0.0;
+        }
+        lots.iter().map(|l| l.basis * l.quantity as f64).sum::<f64>() / quantity as f64
+    }
+
+    /// Picks the lot relieved next by an execution of sign `side`.
+    fn relief_index(lots: &[TaxLot], method: LotMethod, side: i64) -> Option<usize> {
+        let mut candidates = lots.iter().enumerate().filter(|(_, l)| l.quantity.signum() == -side);
+        match method {
+            LotMethod::Fifo | LotMethod::AverageCost => candidates.next().map(|(i, _)| i),
+            LotMethod::Lifo => candidates.next_back().map(|(i, _)| i),
+            // Highest cost for long lots, lowest proceeds for short lots:
+            // both minimise the realized gain.
+            LotMethod::HighestCost => candidates
+                .max_by(|(_, a), (_, b)| {
+                    let (a, b) = (a.basis * a.quantity.signum() as f64, b.basis * b.quantity.signum() as f64);
+                    a.total_cmp(&b)
+                })
+                .map(|(i, _)| i),
+        }
+    }
+
+    pub fn open_lots(&self, ticker: &str) -> &[TaxLot] {
+        self.lots.get(ticker).map(|l| l.as_slice()).unwrap_or(&[])
+    }
+
+    pub fn realized_lots(&self) -> &[RealizedLot] {
+        &self.realized
+    }
+
+    pub fn realized_pnl(&self) -> f64 {
+        self.realized.iter().map(|l| l.realized_pnl).sum()
+    }
+
+    pub fn unrealized_pnl(&self, ticker: &str, price: f64) -> f64 {
+        self.open_lots(ticker).iter().map(|l| l.unrealized_pnl(price)).sum()
+    }
+
+    /// Realized losses with a repurchase of the same ticker within 30
+    /// days either side of the sale, excluding the purchase that opened
+    /// the lot being sold. Each purchased share replaces at most one sold
+    /// share, and a loss is disallowed in proportion to the shares
+    /// replaced.
+    pub fn wash_sales(&self) -> Vec<WashSale> {
+        let window = Duration::days(WASH_SALE_DAYS);
+        let mut unused: Vec<i64> = self.purchases.iter().map(|(_, _, quantity)| *quantity).collect();
+        let mut wash_sales = vec![];
+        for lot in self.realized.iter().filter(|lot| lot.quantity > 0) {
+            // Shares sold can no longer replace anything.
+            if let Some(own) = lot.purchase {
+                unused[own] = (unused[own] - lot.quantity).max(0);
+            }
+            if lot.realized_pnl >= 0.0 {
+                continue;
+            }
+            let mut unmatched = lot.quantity;
+            for (i, (ticker, date, _)) in self.purchases.iter().enumerate() {
+                if unmatched == 0 {
+                    break;
+                }
+                let replaces = *ticker == lot.ticker
+                    && Some(i) != lot.purchase
+                    && unused[i] > 0
+                    && (*date - lot.closed).num_seconds().abs() <= window.num_seconds();
+                if !replaces {
+                    continue;
+                }
+                let shares = unmatched.min(unused[i]);
+                unused[i] -= shares;
+                unmatched -= shares;
+                wash_sales.push(WashSale::new(
+                    lot.ticker.clone(),
+                    lot.closed,
+                    *date,
+                    -lot.realized_pnl * shares as f64 / lot.quantity as f64,
+                ));
+            }
+        }
+        wash_sales
+    }
+
+    pub fn tax_report(&self, detect_wash_sales: bool) -> TaxReport {
+        let mut report = TaxReport::default();
+        for lot in &self.realized {
+            match lot.term() {
+                GainTerm::ShortTerm => report.short_term_gain += lot.realized_pnl,
+                GainTerm::LongTerm => report.long_term_gain += lot.realized_pnl,
+            }
+        }
+        if detect_wash_sales {
+            report.wash_sales = self.wash_sales();
+            report.wash_sale_disallowed = report.wash_sales.iter().map(|w| w.disallowed_loss).sum();
+        }
+        report
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn day(d: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 3, d, 0, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn average_cost_restates_remaining_basis() {
+        let mut book = LotBook::new(LotMethod::AverageCost);
+        book.apply("AAPL", day(1), 100, 10.0, 0.0);
+        book.apply("AAPL", day(2), 100, 20.0, 0.0);
+
+        assert_eq!(book.apply("AAPL", day(3), -100, 15.0, 0.0), 0.0);
+        assert!(book.open_lots("AAPL").iter().all(|l| l.basis == 15.0));
+        assert_eq!(book.unrealized_pnl("AAPL", 15.0), 0.0);
+        assert_eq!(book.apply("AAPL", day(4), -100, 15.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn wash_sale_disallows_only_replaced_shares() {
+        let mut book = LotBook::new(LotMethod::Fifo);
+        book.apply("AAPL", day(1), 100, 20.0, 0.0);
+        book.apply("AAPL", day(2), -100, 10.0, 0.0);
+        book.apply("AAPL", day(5), 40, 10.0, 0.0);
+
+        let report = book.tax_report(true);
+        assert_eq!(report.wash_sales.len(), 1);
+        assert_eq!(report.wash_sale_disallowed, 400.0);
+    }
+
+    #[test]
+    fn one_replacement_covers_one_loss() {
+        let mut book = LotBook::new(LotMethod::Fifo);
+        book.apply("AAPL", day(1), 50, 20.0, 0.0);
+        book.apply("AAPL", day(1), 50, 20.0, 0.0);
+        book.apply("AAPL", day(2), -50, 10.0, 0.0);
+        book.apply("AAPL", day(3), -50, 10.0, 0.0);
+        book.apply("AAPL", day(5), 50, 10.0, 0.0);
+
+        // The second purchase on day 1 replaces the first loss sale, and
+        // the day 5 purchase the second.
+        let wash_sales = book.wash_sales();
+        assert_eq!(wash_sales.len(), 2);
+        assert_eq!(wash_sales[0].replacement_date, day(1));
+        assert_eq!(wash_sales[1].replacement_date, day(5));
+        assert_eq!(book.tax_report(true).wash_sale_disallowed, 1000.0);
+    }
+}
@@ -0,0 +1,500 @@
+//!
+//! Alpaca order API.
+//!
+//! `AlpacaBroker` implements `Broker` over Alpaca's v2 REST API. Orders go
+//! out as market orders whose `client_order_id` is `te-<order id>`, and
+//! fills are found by polling each working order's filled quantity and
+//! average price. Keys are read from `APCA_API_KEY_ID` and
+//! `APCA_API_SECRET_KEY` unless configured otherwise, and the paper
+//! trading endpoint is the default.
+//!
+//! `MockAlpaca` serves the same endpoints from memory for running
+//! offline. Market orders fill at the price set for their symbol with
+//! `set_price`, optionally a few shares per request to exercise partial
+//! fills.
+//!
+//! ```yaml
+//! paper:
+//!   venue: alpaca
+//!   alpaca:
+//!     base_url: http://127.0.0.1:8766
+//! ```
+//!
+
+use crate::broker::{Account, Broker};
+use crate::clock;
+use crate::config::Config;
+use crate::error::{EngineError, Result};
+use crate::fixtures::{LocalServer, Request};
+use crate::order::{Fill, Order, OrderId, TimeInForce};
+use chrono::{DateTime, Utc};
+use reqwest::blocking::Client;
+use reqwest::{Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AlpacaConfig {
+    pub base_url: String,
+    /// Environment variables holding the key pair.
+    pub key_id_env: String,
+    pub secret_key_env: String,
+    pub timeout_secs: f64,
+}
+impl Default for AlpacaConfig {
+    fn default() -> Self {
+        AlpacaConfig {
+            base_url: AlpacaBroker::PAPER_URL.to_string(),
+            key_id_env: "APCA_API_KEY_ID".to_string(),
+            secret_key_env: "APCA_API_SECRET_KEY".to_string(),
+            timeout_secs: 30.0,
+        }
+    }
+}
+impl AlpacaConfig {
+    pub fn broker(&self) -> Result<AlpacaBroker> {
+        let key = |name: &String| Config::get(name.clone()).map_err(|_| EngineError::MissingApiKey(name.clone()));
+        Ok(AlpacaBroker::new(&self.base_url, &key(&self.key_id_env)?, &key(&self.secret_key_env)?)
+            .with_timeout(Duration::from_secs_f64(self.timeout_secs)))
+    }
+
+    /// Problems with the settings, for config validation.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if !self.base_url.starts_with("http://") && !self.base_url.starts_with("https://") {
+            problems.push(format!("base_url: expected an http(s) URL, got {:?}", self.base_url));
+        }
+        if !(self.timeout_secs > 0.0 && self.timeout_secs.is_finite()) {
+            problems.push(format!("timeout_secs: must be positive, got {}", self.timeout_secs));
+        }
+        problems
+    }
+}
+
+
+/// What is known of an order sent to Alpaca.
+#[derive(Debug, Clone)]
+struct Tracked {
+    filled_quantity: i64,
+    filled_notional: f64,
+    done: bool,
+}
+
+#[derive(Clone)]
+pub struct AlpacaBroker {
+    client: Client,
+    base_url: String,
+    key_id: String,
+    secret_key: String,
+    timeout: Duration,
+    orders: BTreeMap<OrderId, Tracked>,
+}
+impl fmt::Debug for AlpacaBroker {
+    // Keeps the keys out of logs.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AlpacaBroker")
+            .field("base_url", &self.base_url)
+            .field("orders", &self.orders.len())
+            .finish()
+    }
+}
+impl AlpacaBroker {
+    pub const PAPER_URL: &str = "https://paper-api.alpaca.markets";
+
+    pub fn new(base_url: &str, key_id: &str, secret_key: &str) -> Self {
+        AlpacaBroker {
+            client: Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            key_id: key_id.to_string(),
+            secret_key: secret_key.to_string(),
+            timeout: Duration::from_secs(30),
+            orders: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Tracks orders sent before a restart, so fills already booked
+    /// against them are not reported again.
+    pub fn resume(mut self, orders: &[Order]) -> Self {
+        for order in orders {
+            self.orders.insert(order.id, Tracked {
+                filled_quantity: order.filled_quantity,
+                filled_notional: order.fills.iter().map(|f| f.price * f.quantity as f64).sum(),
+                done: !order.is_open(),
+            });
+        }
+        self
+    }
+
+    pub fn client_order_id(id: OrderId) -> String {
+        format!("te-{}", id)
+    }
+
+    fn request(&self, method: Method, path: &str, query: &[(&str, &str)], body: Option<Value>) -> Result<Value> {
+        let mut request = self.client.request(method.clone(), format!("{}{}", self.base_url, path))
+            .header("APCA-API-KEY-ID", &self.key_id)
+            .header("APCA-API-SECRET-KEY", &self.secret_key)
+            .query(query)
+            .timeout(self.timeout);
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+        let response = request.send()
+            .map_err(|e| EngineError::Broker(format!("{} {}: {}", method, path, e)))?;
+
+        let status = response.status();
+        let text = response.text().map_err(|e| EngineError::Broker(e.to_string()))?;
+        if status.is_success() {
+            return match text.trim().is_empty() {
+                true => Ok(Value::Null),
+                false => Ok(serde_json::from_str(&text)?),
+            };
+        }
+        let message = serde_json::from_str::<Value>(&text).ok()
+            .and_then(|v| v.get("message").and_then(Value::as_str).map(str::to_string))
+            .unwrap_or(text);
+        Err(match status {
+            StatusCode::TOO_MANY_REQUESTS => EngineError::RateLimit(message),
+            StatusCode::FORBIDDEN | StatusCode::UNPROCESSABLE_ENTITY if path.starts_with("/v2/orders") => {
+                EngineError::OrderRejected(message)
+            },
+            _ => EngineError::Broker(format!("{} {}: HTTP {}: {}", method, path, status.as_u16(), message)),
+        })
+    }
+
+    fn get_order(&self, id: OrderId) -> Result<Value> {
+        let client_order_id = Self::client_order_id(id);
+        self.request(Method::GET, "/v2/orders:by_client_order_id", &[("client_order_id", &client_order_id)], None)
+    }
+}
+
+impl Broker for AlpacaBroker {
+    fn submit(&mut self, order: &Order) -> Result<()> {
+        let time_in_force = match order.time_in_force {
+            TimeInForce::ImmediateOrCancel => "ioc",
+            // The engine cancels orders good for a number of bars.
+            TimeInForce::GoodTilCancelled | TimeInForce::GoodForBars(_) => "gtc",
+        };
+        let body = json!({
+            "symbol": order.ticker,
+            "qty": order.remaining_quantity().abs().to_string(),
+            "side": if order.quantity > 0 { "buy" } else { "sell" },
+            "type": "market",
+            "time_in_force": time_in_force,
+            "client_order_id": Self::client_order_id(order.id),
+        });
+        self.request(Method::POST, "/v2/orders", &[], Some(body))?;
+        self.orders.insert(order.id, Tracked { filled_quantity: 0, filled_notional: 0.0, done: false });
+        Ok(())
+    }
+
+    fn cancel(&mut self, id: OrderId) -> Result<()> {
+        let order = self.get_order(id)?;
+        let alpaca_id = order["id"].as_str()
+            .ok_or_else(|| EngineError::Broker(format!("No Alpaca id for order {}", id)))?;
+        self.request(Method::DELETE, &format!("/v2/orders/{}", alpaca_id), &[], None)?;
+        Ok(())
+    }
+
+    fn positions(&self) -> Result<BTreeMap<String, i64>> {
+        let positions = self.request(Method::GET, "/v2/positions", &[], None)?;
+        positions.as_array().into_iter().flatten()
+            .map(|p| Ok((field_str(p, "symbol")?.to_string(), number(&p["qty"])? as i64)))
+            .collect()
+    }
+
+    fn account(&self) -> Result<Account> {
+        let account = self.request(Method::GET, "/v2/account", &[], None)?;
+        Ok(Account { cash: number(&account["cash"])?, equity: number(&account["equity"])? })
+    }
+
+    /// Polls each working order and reports the quantity filled since
+    /// the last poll at the price implied by the new average.
+    fn fills(&mut self) -> Result<Vec<Fill>> {
+        let working: Vec<OrderId> = self.orders.iter().filter(|(_, t)| !t.done).map(|(id, _)| *id).collect();
+        let mut fills = vec![];
+        for id in working {
+            let order = self.get_order(id)?;
+            let sign = if field_str(&order, "side")? == "sell" { -1 } else { 1 };
+            let filled_quantity = sign * number(&order["filled_qty"])? as i64;
+            let average_price = match &order["filled_avg_price"] {
+                Value::Null => 0.0,
+                price => number(price)?,
+            };
+            let status = field_str(&order, "status")?;
+            let tracked = self.orders.get_mut(&id).ok_or_else(|| EngineError::Broker(format!("Unknown order {}", id)))?;
+
+            let quantity = filled_quantity - tracked.filled_quantity;
+            if quantity != 0 {
+                let notional = average_price * filled_quantity as f64;
+                let timestamp = order["filled_at"].as_str().or(order["updated_at"].as_str())
+                    .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+                    .map(|t| t.with_timezone(&Utc))
+                    .unwrap_or_else(clock::wall_time);
+                fills.push(Fill::new(
+                    id,
+                    field_str(&order, "symbol")?.to_string(),
+                    timestamp,
+                    quantity,
+                    (notional - tracked.filled_notional) / quantity as f64,
+                    0.0,
+                ));
+                tracked.filled_quantity = filled_quantity;
+                tracked.filled_notional = notional;
+            }
+            tracked.done = matches!(status, "filled" | "canceled" | "expired" | "rejected" | "replaced");
+        }
+        fills.sort_by_key(|f| f.timestamp);
+        Ok(fills)
+    }
+}
+
+fn field_str<'a>(value: &'a Value, name: &str) -> Result<&'a str> {
+    value[name].as_str().ok_or_else(|| EngineError::Broker(format!("Missing {:?} in {}", name, value)))
+}
+
+/// Alpaca sends numbers as strings.
+fn number(value: &Value) -> Result<f64> {
+    match value {
+        Value::String(text) => Ok(text.parse()?),
+        Value::Number(n) => n.as_f64().ok_or_else(|| EngineError::Broker(format!("Invalid number {}", n))),
+        _ => Err(EngineError::Broker(format!("Expected a number, got {}", value))),
+    }
+}
+
+
+#[derive(Debug, Clone)]
+struct MockOrder {
+    id: String,
+    client_order_id: String,
+    symbol: String,
+    /// Signed shares.
+    quantity: i64,
+    filled: i64,
+    filled_notional: f64,
+    time_in_force: String,
+    status: &'static str,
+    submitted_at: String,
+    filled_at: Option<String>,
+}
+impl MockOrder {
+    fn is_open(&self) -> bool {
+        matches!(self.status, "new" | "accepted" | "partially_filled")
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "client_order_id": self.client_order_id,
+            "symbol": self.symbol,
+            "qty": self.quantity.abs().to_string(),
+            "filled_qty": self.filled.abs().to_string(),
+            "filled_avg_price": (self.filled != 0).then(|| (self.filled_notional / self.filled as f64).to_string()),
+            "side": if self.quantity > 0 { "buy" } else { "sell" },
+            "type": "market",
+            "time_in_force": self.time_in_force,
+            "status": self.status,
+            "submitted_at": self.submitted_at,
+            "filled_at": self.filled_at,
+            "updated_at": self.filled_at.as_ref().unwrap_or(&self.submitted_at),
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    cash: f64,
+    prices: HashMap<String, f64>,
+    positions: BTreeMap<String, i64>,
+    orders: Vec<MockOrder>,
+    max_fill: Option<i64>,
+}
+impl MockState {
+    /// Fills open orders that have a price, up to `max_fill` shares each.
+    fn work(&mut self) {
+        let now = clock::wall_time().to_rfc3339();
+        for order in self.orders.iter_mut().filter(|o| o.is_open()) {
+            let price = match self.prices.get(&order.symbol) {
+                Some(price) => *price,
+                None if order.time_in_force == "ioc" => {
+                    order.status = "canceled";
+                    continue;
+                },
+                None => continue,
+            };
+            let remaining = order.quantity - order.filled;
+            let quantity = remaining.signum() * remaining.abs().min(self.max_fill.unwrap_or(i64::MAX));
+            order.filled += quantity;
+            order.filled_notional += quantity as f64 * price;
+            order.filled_at = Some(now.clone());
+            order.status = if order.filled == order.quantity { "filled" } else { "partially_filled" };
+            self.cash -= quantity as f64 * price;
+            let position = self.positions.entry(order.symbol.clone()).or_insert(0);
+            *position += quantity;
+            if *position == 0 {
+                self.positions.remove(&order.symbol);
+            }
+        }
+    }
+
+    fn respond(&mut self, request: &Request) -> (u16, Value) {
+        let authorized = ["apca-api-key-id", "apca-api-secret-key"].iter()
+            .all(|h| request.headers.get(*h).is_some_and(|v| !v.is_empty()));
+        if !authorized {
+            return (403, json!({ "message": "forbidden." }));
+        }
+        self.work();
+        let order_path = request.path.strip_prefix("/v2/orders/");
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/v2/account") => {
+                let value: f64 = self.positions.iter()
+                    .map(|(s, q)| *q as f64 * self.prices.get(s).copied().unwrap_or(0.0))
+                    .sum();
+                (200, json!({
+                    "id": "mock",
+                    "status": "ACTIVE",
+                    "currency": "USD",
+                    "cash": self.cash.to_string(),
+                    "equity": (self.cash + value).to_string(),
+                    "buying_power": self.cash.max(0.0).to_string(),
+                }))
+            },
+            ("GET", "/v2/positions") => (200, self.positions.iter()
+                .map(|(symbol, qty)| json!({
+                    "symbol": symbol,
+                    "qty": qty.to_string(),
+                    "side": if *qty > 0 { "long" } else { "short" },
+                    "market_value": (*qty as f64 * self.prices.get(symbol).copied().unwrap_or(0.0)).to_string(),
+                }))
+                .collect()),
+            ("POST", "/v2/orders") => self.submit(&request.body),
+            ("GET", "/v2/orders") => {
+                let status = request.query.get("status").map(String::as_str).unwrap_or("open");
+                (200, self.orders.iter()
+                    .filter(|o| status == "all" || (status == "open") == o.is_open())
+                    .map(MockOrder::to_json)
+                    .collect())
+            },
+            ("GET", "/v2/orders:by_client_order_id") => {
+                let client_order_id = request.query.get("client_order_id").cloned().unwrap_or_default();
+                match self.orders.iter().find(|o| o.client_order_id == client_order_id) {
+                    Some(order) => (200, order.to_json()),
+                    None => (404, json!({ "message": "order not found" })),
+                }
+            },
+            ("GET", _) if order_path.is_some() => match self.orders.iter().find(|o| Some(o.id.as_str()) == order_path) {
+                Some(order) => (200, order.to_json()),
+                None => (404, json!({ "message": "order not found" })),
+            },
+            ("DELETE", _) if order_path.is_some() => match self.orders.iter_mut().find(|o| Some(o.id.as_str()) == order_path) {
+                Some(order) if order.is_open() => {
+                    order.status = "canceled";
+                    (204, Value::Null)
+                },
+                Some(order) => (422, json!({ "message": format!("order is already in \"{}\" state", order.status) })),
+                None => (404, json!({ "message": "order not found" })),
+            },
+            _ => (404, json!({ "message": "endpoint not found" })),
+        }
+    }
+
+    fn submit(&mut self, body: &str) -> (u16, Value) {
+        let invalid = |message: &str| (422, json!({ "code": 40010001, "message": message }));
+        let order: Value = match serde_json::from_str(body) {
+            Ok(order) => order,
+            Err(_) => return invalid("request body format is invalid"),
+        };
+        let quantity = match order["qty"].as_str().and_then(|q| q.parse::<i64>().ok()) {
+            Some(quantity) if quantity > 0 => quantity,
+            _ => return invalid("qty must be a positive integer"),
+        };
+        let sign = match order["side"].as_str() {
+            Some("buy") => 1,
+            Some("sell") => -1,
+            _ => return invalid("side must be buy or sell"),
+        };
+        if order["type"].as_str() != Some("market") {
+            return invalid("only market orders are supported");
+        }
+        let symbol = order["symbol"].as_str().unwrap_or_default().to_uppercase();
+        let client_order_id = order["client_order_id"].as_str().unwrap_or_default().to_string();
+        if !client_order_id.is_empty() && self.orders.iter().any(|o| o.client_order_id == client_order_id) {
+            return invalid("client_order_id must be unique");
+        }
+        if let Some(price) = self.prices.get(&symbol).filter(|_| sign > 0) {
+            if quantity as f64 * price > self.cash {
+                return (403, json!({ "code": 40310000, "message": "insufficient buying power" }));
+            }
+        }
+
+        let order = MockOrder {
+            id: format!("mock-{:08}", self.orders.len() + 1),
+            client_order_id,
+            symbol,
+            quantity: sign * quantity,
+            filled: 0,
+            filled_notional: 0.0,
+            time_in_force: order["time_in_force"].as_str().unwrap_or("day").to_string(),
+            status: "accepted",
+            submitted_at: clock::wall_time().to_rfc3339(),
+            filled_at: None,
+        };
+        self.orders.push(order);
+        self.work();
+        (200, self.orders[self.orders.len() - 1].to_json())
+    }
+}
+
+/// An in-memory Alpaca account served on a local port until dropped.
+#[derive(Debug)]
+pub struct MockAlpaca {
+    server: LocalServer,
+    state: Arc<Mutex<MockState>>,
+}
+impl MockAlpaca {
+    /// Port 0 picks a free port.
+    pub fn start(port: u16, cash: f64) -> Result<Self> {
+        let state = Arc::new(Mutex::new(MockState { cash, ..Default::default() }));
+        let shared = state.clone();
+        let server = LocalServer::start(port, move |request| {
+            let mut state = shared.lock().unwrap_or_else(|e| e.into_inner());
+            let (status, body) = state.respond(request);
+            (status, if body.is_null() { String::new() } else { body.to_string() })
+        })?;
+        Ok(MockAlpaca { server, state })
+    }
+
+    /// The base URL to configure as `alpaca.base_url`.
+    pub fn url(&self) -> String {
+        format!("http://{}", self.server.addr())
+    }
+
+    /// Market orders for `symbol` fill at `price` from now on.
+    pub fn set_price(&self, symbol: &str, price: f64) {
+        self.lock().prices.insert(symbol.to_uppercase(), price);
+    }
+
+    /// Caps each fill at `shares`, so orders fill over several requests.
+    pub fn set_max_fill(&self, shares: Option<i64>) {
+        self.lock().max_fill = shares;
+    }
+
+    /// Blocks until the server thread exits.
+    pub fn wait(self) {
+        self.server.wait()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MockState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
@@ -1,61 +1,322 @@
-/// Backtesting
+//! Backtesting
 
 use crate::strategy::Strategy;
-use crate::portfolio::{Portfolio, Trade};
-use crate::order::Order;
-use crate::broker::Broker;
+use crate::portfolio::Portfolio;
+use crate::order::{Fill, Order, OrderId, OrderStatus};
+use crate::broker::{Broker, SimulatedBroker};
+use crate::clock::{self, parse_bar_time, ReplayClock};
+use crate::metrics::{EquityPoint, Metrics};
+use crate::slippage::BarContext;
 use crate::data_loading::{DatedStockData, Metadata};
-use std::error::Error;
+use crate::risk::{RiskDecision, RiskEvent, RiskLimits, RiskManager};
+use crate::rebalance::{AllocationStrategy, RebalanceConfig, RebalanceRecord, Rebalancer};
+use crate::execution::Executor;
+use crate::error::{EngineError, Result};
+use std::collections::{BTreeSet, HashMap};
 use derive_new::new;
 use log::{info, warn};
 
 
+/// Number of daily returns used to estimate volatility for slippage.
+pub const VOLATILITY_LOOKBACK: usize = 20;
+
 #[allow(dead_code)]
 #[derive(Debug, new)]
 pub struct BacktestResult<'a> {
     pub n_trades: isize,
     pub portfolio: &'a Portfolio,
+    pub risk_events: &'a [RiskEvent],
+    pub rebalances: &'a [RebalanceRecord],
+    pub equity_curve: &'a [EquityPoint],
+}
+impl BacktestResult<'_> {
+    pub fn metrics(&self) -> Metrics {
+        Metrics::from_equity_curve(self.equity_curve, self.portfolio.trades.len())
+    }
 }
 
 #[derive(Debug, new)]
 pub struct Backtest {
     warm_up_periods: u32,
     portfolio: Portfolio,
-    #[new(value = "Broker::new(0.50)")]
-    broker: Broker,
-    #[new(value = "0.0")]
-    pnl: f64,
+    #[new(value = "Box::new(SimulatedBroker::default())")]
+    broker: Box<dyn Broker>,
+    #[new(value = "OrderProcessor::new()")]
+    processor: OrderProcessor,
+    #[new(value = "RiskManager::new(RiskLimits::default())")]
+    risk_manager: RiskManager,
+    #[new(value = "Rebalancer::new(RebalanceConfig::default())")]
+    rebalancer: Rebalancer,
+    #[new(default)]
+    executor: Option<Executor>,
+    #[new(default)]
+    equity_curve: Vec<EquityPoint>,
     #[new(value = "0")]
     n_trades: isize,
 }
 impl Backtest {
+    /// Routes orders to `broker` instead of the default simulator.
+    pub fn with_broker(mut self, broker: Box<dyn Broker>) -> Self {
+        self.broker = broker;
+        self
+    }
+
+    pub fn with_risk_limits(mut self, limits: RiskLimits) -> Self {
+        self.risk_manager = RiskManager::new(limits);
+        self
+    }
+
+    pub fn with_rebalance_config(mut self, config: RebalanceConfig) -> Self {
+        self.rebalancer = Rebalancer::new(config);
+        self
+    }
+
+    /// Works approved orders through `executor`'s algo as parent orders
+    /// rather than sending them whole.
+    pub fn with_executor(mut self, executor: Option<Executor>) -> Self {
+        self.executor = executor;
+        self
+    }
+
     pub fn run(
         &mut self,
         strategy: &dyn Strategy,
-        data: &Vec<DatedStockData>,
+        data: &[DatedStockData],
         metadata: &Metadata,
-    ) -> Result<BacktestResult, Box<dyn Error>> {
-        let n: u32 = data.len().try_into().unwrap();
+    ) -> Result<BacktestResult<'_>> {
+        let clock = ReplayClock::start();
 
-        for i in self.warm_up_periods..n {
-            let data_slice = data.get(0..i as usize).ok_or("Data slice out of bounds")?;
+        for i in self.warm_up_periods as usize..data.len() {
+            let data_slice = &data[..i];
+            if let Some(bar) = data_slice.last() {
+                clock.set(parse_bar_time(&bar.date)?);
+            }
+            self.step(strategy, data_slice, metadata)?;
+        }
 
-            match strategy.on_data(data_slice.to_vec(), &metadata, &self.portfolio) {
-                Some(order) => {
-                    let mut processor = OrderProcessor::new();
-                    processor.process(order, &mut self.broker, &mut self.portfolio)?;
-                    self.n_trades += 1;
-                },
-                None => (),
+        Ok(self.result())
+    }
+
+    /// Trades the last bar of `history`: marks the portfolio, works open
+    /// orders and parent orders, then passes the strategy's new order, if
+    /// any, through risk and on to the broker. Paper trading calls this as
+    /// bars arrive.
+    pub fn step(&mut self, strategy: &dyn Strategy, history: &[DatedStockData], metadata: &Metadata) -> Result<()> {
+        let (bar, date) = match (BarContext::from_history(history, VOLATILITY_LOOKBACK), history.last()) {
+            (Some(bar), Some(last)) => (bar, &last.date),
+            _ => return Ok(()),
+        };
+
+        self.portfolio.mark_to_market(&metadata.symbol, bar.close);
+        self.risk_manager.begin_bar(date, &self.portfolio);
+
+        let on_fill = |fill: &Fill, order: &Order| strategy.on_fill(fill, order);
+        self.processor.work_open_orders(&metadata.symbol, &bar, &on_fill, &mut *self.broker, &mut self.portfolio)?;
+        self.work_parents(&metadata.symbol, history, &bar, &on_fill)?;
+
+        self.release_held(|ticker| ticker == metadata.symbol, &on_fill)?;
+        if let Some(order) = strategy.on_data(history.to_vec(), metadata, &self.portfolio) {
+            self.submit(order, &on_fill)?;
+        }
+        self.record_equity();
+        Ok(())
+    }
+
+    pub fn portfolio(&self) -> &Portfolio {
+        &self.portfolio
+    }
+
+    pub fn broker(&self) -> &dyn Broker {
+        &*self.broker
+    }
+
+    /// Books fills the broker reported between bars, as a live venue
+    /// does, and returns how many there were.
+    pub fn sync(&mut self, strategy: &dyn Strategy) -> Result<usize> {
+        let on_fill = |fill: &Fill, order: &Order| strategy.on_fill(fill, order);
+        let n_fills = self.processor.book_fills(&on_fill, &mut *self.broker, &mut self.portfolio)?;
+        self.portfolio.blotter.refresh_parents();
+        Ok(n_fills)
+    }
+
+    /// Tells the broker the fills booked so far are saved.
+    pub fn acknowledge_fills(&mut self) -> Result<()> {
+        self.broker.acknowledge()
+    }
+
+    /// Runs an allocation strategy across several symbols, rebalancing to
+    /// its target weights whenever the rebalancer is due. Bars are aligned
+    /// by date; a symbol without a bar on a date is not traded that day.
+    pub fn run_allocation(
+        &mut self,
+        strategy: &dyn AllocationStrategy,
+        data: &HashMap<String, Vec<DatedStockData>>,
+    ) -> Result<BacktestResult<'_>> {
+        let dates: BTreeSet<&str> = data.values()
+            .flat_map(|bars| bars.iter().map(|bar| bar.date.as_str()))
+            .collect();
+        let mut cursors: HashMap<&str, usize> = data.keys().map(|t| (t.as_str(), 0)).collect();
+        let on_fill = |fill: &Fill, order: &Order| strategy.on_fill(fill, order);
+        let clock = ReplayClock::start();
+
+        for (i, date) in dates.into_iter().enumerate() {
+            clock.set(parse_bar_time(date)?);
+            let mut history: HashMap<String, &[DatedStockData]> = HashMap::new();
+            let mut bars: HashMap<String, BarContext> = HashMap::new();
+            for (ticker, series) in data {
+                let cursor = cursors.entry(ticker.as_str()).or_insert(0);
+                let traded_today = series.get(*cursor).map(|bar| bar.date == date).unwrap_or(false);
+                if traded_today {
+                    *cursor += 1;
+                }
+                let seen = &series[..*cursor];
+                history.insert(ticker.clone(), seen);
+                if traded_today {
+                    if let Some(bar) = BarContext::from_history(seen, VOLATILITY_LOOKBACK) {
+                        bars.insert(ticker.clone(), bar);
+                    }
+                }
+            }
+
+            for (ticker, bar) in &bars {
+                self.portfolio.mark_to_market(ticker, bar.close);
+            }
+            self.risk_manager.begin_bar(date, &self.portfolio);
+            for (ticker, bar) in &bars {
+                self.processor.work_open_orders(ticker, bar, &on_fill, &mut *self.broker, &mut self.portfolio)?;
+                self.work_parents(ticker, history[ticker], bar, &on_fill)?;
             }
+
+            self.release_held(|ticker| bars.contains_key(ticker), &on_fill)?;
+
+            if (i as u32) < self.warm_up_periods {
+                continue;
+            }
+            let weights = strategy.target_weights(date, &history, &self.portfolio);
+            if self.rebalancer.is_due(date, &weights, &self.portfolio)? {
+                for order in self.rebalancer.rebalance(date, &weights, &self.portfolio)? {
+                    if !bars.contains_key(&order.ticker) {
+                        self.rebalancer.record_dropped(&order, "no bar");
+                        continue;
+                    }
+                    let price = self.portfolio.last_price(&order.ticker).unwrap_or(0.0);
+                    match self.submit(order.clone(), &on_fill)? {
+                        Some(quantity) => self.rebalancer.record_sent(quantity, price),
+                        None => self.rebalancer.record_dropped(&order, "held or rejected by risk"),
+                    }
+                }
+            }
+            self.record_equity();
         }
 
-        Ok(BacktestResult::new(self.n_trades, &self.portfolio))
+        Ok(self.result())
+    }
+
+    fn record_equity(&mut self) {
+        self.equity_curve.push(EquityPoint::new(
+            clock::now(),
+            self.portfolio.equity(),
+            self.portfolio.cash,
+        ));
+    }
+
+    fn result(&mut self) -> BacktestResult<'_> {
+        self.portfolio.blotter.refresh_parents();
+        self.n_trades = self.portfolio.trades.len() as isize;
+        BacktestResult::new(
+            self.n_trades,
+            &self.portfolio,
+            self.risk_manager.events(),
+            self.rebalancer.records(),
+            &self.equity_curve,
+        )
+    }
+
+    /// Sends the children parent orders for `ticker` have due on `bar`,
+    /// the last bar of `history`.
+    fn work_parents(
+        &mut self,
+        ticker: &str,
+        history: &[DatedStockData],
+        bar: &BarContext,
+        on_fill: &dyn Fn(&Fill, &Order),
+    ) -> Result<()> {
+        let Some(executor) = &self.executor else {
+            return Ok(());
+        };
+        for child in executor.slice(ticker, history, bar, &mut self.portfolio.blotter) {
+            let child_id = child.id;
+            if let Err(e) = self.processor.process(child, on_fill, &mut *self.broker, &mut self.portfolio) {
+                warn!("Child order {} failed: {}", child_id, e);
+            }
+        }
+        self.portfolio.blotter.refresh_parents();
+        Ok(())
+    }
+
+    /// Checks the orders held on earlier bars again for the tickers
+    /// `traded` accepts.
+    fn release_held(&mut self, traded: impl Fn(&str) -> bool, on_fill: &dyn Fn(&Fill, &Order)) -> Result<()> {
+        let held: Vec<Order> = self.portfolio.blotter.with_status(OrderStatus::Held)
+            .filter(|o| traded(&o.ticker))
+            .cloned()
+            .collect();
+        for order in held {
+            self.submit(order, on_fill)?;
+        }
+        Ok(())
+    }
+
+    /// Passes an order, new or held, through the risk manager and on to
+    /// execution. Held orders stay in the blotter until risk approves or
+    /// rejects them. Returns the quantity sent on, or `None` if risk held
+    /// or rejected the order.
+    fn submit(&mut self, order: Order, on_fill: &dyn Fn(&Fill, &Order)) -> Result<Option<i64>> {
+        let order_id = order.id;
+        let held = self.portfolio.blotter.get(order_id).is_some();
+        match self.risk_manager.check(order, &self.portfolio) {
+            RiskDecision::Approve(order) | RiskDecision::Resize(order) => {
+                let quantity = order.quantity;
+                let arrival_price = self.portfolio.last_price(&order.ticker);
+                match (&self.executor, arrival_price) {
+                    (Some(executor), Some(price)) if order.quantity != 0 => {
+                        if held {
+                            info!("Held order {} handed to the execution algo", order_id);
+                            self.portfolio.blotter.cancel(order_id)?;
+                        }
+                        executor.start(order, price, &mut self.portfolio.blotter);
+                    },
+                    _ if held => {
+                        self.portfolio.blotter.resize(order_id, quantity)?;
+                        self.processor.send(order_id, on_fill, &mut *self.broker, &mut self.portfolio)?;
+                    },
+                    _ => self.processor.process(order, on_fill, &mut *self.broker, &mut self.portfolio)?,
+                }
+                Ok(Some(quantity))
+            },
+            RiskDecision::Hold(order) => {
+                if !held {
+                    self.portfolio.blotter.submit(order);
+                    self.portfolio.blotter.transition(order_id, OrderStatus::Held)?;
+                }
+                Ok(None)
+            },
+            RiskDecision::Reject(order) => {
+                if !held {
+                    self.portfolio.blotter.submit(order);
+                }
+                self.portfolio.blotter.transition(order_id, OrderStatus::Rejected)?;
+                Ok(None)
+            },
+        }
     }
 }
 
+fn missing_order(order_id: OrderId) -> EngineError {
+    EngineError::Accounting(format!("Order {} missing from blotter", order_id))
+}
 
-#[derive(new)]
+#[derive(Debug, new)]
 pub struct OrderProcessor {
     #[new(value = "0")]
     total_orders_processed: u32,
@@ -64,38 +325,188 @@ pub struct OrderProcessor {
 }
 
 impl OrderProcessor {
-    pub fn process(&mut self, order: Order, broker: &mut Broker, portfolio: &mut Portfolio) -> Result<(), Box<dyn Error>> {
-        info!("Processing order: {:?}", order);
+    /// Accepts a new order and sends it to the broker, which may fill
+    /// it on the current bar.
+    pub fn process(
+        &mut self,
+        order: Order,
+        on_fill: &dyn Fn(&Fill, &Order),
+        broker: &mut dyn Broker,
+        portfolio: &mut Portfolio,
+    ) -> Result<()> {
+        info!("Processing order {}: {:?}", order.id, order);
 
-        let quote = broker.quote(order.ticker.clone(), order.quantity)?;
-        info!("Received quote at price: ${:.2}", quote.quote);
+        let order_id = portfolio.blotter.submit(order);
+        self.send(order_id, on_fill, broker, portfolio)
+    }
 
-        let confirm = broker.execute(order)?;
-        info!("Order executed: {} shares at ${:.2}", confirm.quantity_filled, confirm.executed_price);
+    /// Accepts an order already in the blotter, new or held, and sends
+    /// it to the broker.
+    pub fn send(
+        &mut self,
+        order_id: OrderId,
+        on_fill: &dyn Fn(&Fill, &Order),
+        broker: &mut dyn Broker,
+        portfolio: &mut Portfolio,
+    ) -> Result<()> {
+        portfolio.blotter.transition(order_id, OrderStatus::Accepted)?;
+        self.total_orders_processed += 1;
 
-        if confirm.quantity_filled != quote.quantity {
-            warn!("Partial fill: requested {} but filled {}",
-                  quote.quantity, confirm.quantity_filled);
+        let order = portfolio.blotter.get(order_id).ok_or_else(|| missing_order(order_id))?;
+        if let Err(e) = broker.submit(order) {
+            warn!("Order {} rejected: {}", order_id, e);
+            portfolio.blotter.transition(order_id, OrderStatus::Rejected)?;
+            return Err(e);
         }
+        self.settle(&[order_id], on_fill, broker, portfolio)
+    }
 
-        let trade = Trade::new(
-            confirm.ticker,
-            confirm.executed_timestamp,
-            confirm.executed_price,
-            confirm.quantity_filled,
-    );
+    /// Passes the bar to the broker, which works every order for
+    /// `ticker` still open from earlier bars against it.
+    pub fn work_open_orders(
+        &mut self,
+        ticker: &str,
+        bar: &BarContext,
+        on_fill: &dyn Fn(&Fill, &Order),
+        broker: &mut dyn Broker,
+        portfolio: &mut Portfolio,
+    ) -> Result<()> {
+        let open: Vec<OrderId> = portfolio.blotter.open_orders()
+            .filter(|o| o.ticker == ticker && o.is_sent())
+            .map(|o| o.id)
+            .collect();
+        broker.on_bar(ticker, bar)?;
+        self.settle(&open, on_fill, broker, portfolio)
+    }
 
-        portfolio.trades.push(trade);
-        portfolio.position += confirm.quantity_filled;
-        portfolio.pnl += (portfolio.position as f64) * quote.change;
-        portfolio.pnl -= confirm.trading_costs;
+    /// Books the broker's fills, then ends the bar for the orders worked
+    /// on it, cancelling any whose time in force ran out.
+    fn settle(
+        &mut self,
+        worked: &[OrderId],
+        on_fill: &dyn Fn(&Fill, &Order),
+        broker: &mut dyn Broker,
+        portfolio: &mut Portfolio,
+    ) -> Result<()> {
+        self.book_fills(on_fill, broker, portfolio)?;
+        for &order_id in worked {
+            portfolio.blotter.end_bar(order_id)?;
+            let order = portfolio.blotter.get(order_id).ok_or_else(|| missing_order(order_id))?;
+            if order.status == OrderStatus::Expired {
+                warn!("Order {} expired with {} unfilled", order_id, order.remaining_quantity());
+                if let Err(e) = broker.cancel(order_id) {
+                    warn!("Cancelling order {}: {}", order_id, e);
+                }
+            }
+        }
+        Ok(())
+    }
 
-        self.total_orders_processed += 1;
-        self.total_value_processed += confirm.executed_price * confirm.quantity_filled as f64;
+    /// Books every fill the broker reported since the last call and
+    /// returns how many there were.
+    pub fn book_fills(
+        &mut self,
+        on_fill: &dyn Fn(&Fill, &Order),
+        broker: &mut dyn Broker,
+        portfolio: &mut Portfolio,
+    ) -> Result<usize> {
+        let fills = broker.fills()?;
+        for fill in &fills {
+            let order_id = fill.order_id;
+            let status = match portfolio.blotter.record_fill(fill.clone()) {
+                Ok(status) => status,
+                // A live venue can fill an order after it was cancelled
+                // here; the shares are still held.
+                Err(e) if portfolio.blotter.get(order_id).is_some_and(|o| !o.is_open()) => {
+                    warn!("Late fill for order {}: {}", order_id, e);
+                    portfolio.apply_fill(fill);
+                    continue;
+                },
+                Err(e) => return Err(e),
+            };
+            let order = portfolio.blotter.get(order_id).ok_or_else(|| missing_order(order_id))?;
+            info!("Order {} {}: {} shares at ${:.2}, {} remaining",
+                  order_id, status, fill.quantity, fill.price, order.remaining_quantity());
+            on_fill(fill, order);
 
-        info!("Updated portfolio position: {}", portfolio.position);
-        info!("Current P&L: ${:.2}", portfolio.pnl);
+            portfolio.apply_fill(fill);
 
-        Ok(())
+            self.total_value_processed += fill.price * fill.quantity as f64;
+
+            info!("Updated portfolio position: {}", portfolio.position);
+            info!("Current P&L: ${:.2}", portfolio.pnl);
+        }
+        Ok(fills.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rebalance::FixedWeights;
+
+    struct BuyWhenIdle;
+    impl Strategy for BuyWhenIdle {
+        fn on_data(&self, _data: Vec<DatedStockData>, metadata: &Metadata, portfolio: &Portfolio) -> Option<Order> {
+            if portfolio.blotter.is_working(&metadata.symbol) {
+                return None;
+            }
+            Some(Order::new(metadata.symbol.clone(), 10))
+        }
+    }
+
+    fn bars(n: usize) -> Vec<DatedStockData> {
+        (1..=n)
+            .map(|day| DatedStockData::new(format!("2024-01-{:02}", day), 100.0, 101.0, 99.0, 100.0, 1_000))
+            .collect()
+    }
+
+    fn allocation(limits: RiskLimits) -> RebalanceRecord {
+        let data: HashMap<String, Vec<DatedStockData>> = [("A".to_string(), bars(3)), ("B".to_string(), bars(3))].into();
+        let weights = FixedWeights::new([("A".to_string(), 0.5), ("B".to_string(), 0.5)].into());
+        let mut backtest = Backtest::new(0, Portfolio::new(100_000)).with_risk_limits(limits);
+        let result = backtest.run_allocation(&weights, &data).unwrap();
+        result.rebalances[0].clone()
+    }
+
+    #[test]
+    fn rebalance_turnover_counts_only_sent_orders() {
+        let resized = allocation(RiskLimits { max_order_notional: Some(1_000.0), ..RiskLimits::default() });
+        assert_eq!((resized.n_orders, resized.n_dropped), (2, 0));
+        assert!((resized.traded_notional - 2_000.0).abs() < 1e-9);
+        assert!((resized.turnover - 0.02).abs() < 1e-9);
+
+        let held = allocation(RiskLimits { daily_loss_limit: Some(0.0), ..RiskLimits::default() });
+        assert_eq!((held.n_orders, held.n_dropped), (0, 2));
+        assert_eq!(held.traded_notional, 0.0);
+    }
+
+    #[test]
+    fn held_orders_stay_working_in_the_blotter() {
+        let limits = RiskLimits { daily_loss_limit: Some(0.0), ..RiskLimits::default() };
+        let mut backtest = Backtest::new(1, Portfolio::new(100_000)).with_risk_limits(limits);
+        let metadata = Metadata::new("TEST".to_string());
+        let result = backtest.run(&BuyWhenIdle, &bars(6), &metadata).unwrap();
+
+        let orders = result.portfolio.blotter.orders();
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].status, OrderStatus::Held);
+        assert_eq!(result.portfolio.blotter.pending_quantity("TEST"), 10);
+        assert_eq!(result.risk_events.len(), 1);
+    }
+
+    #[test]
+    fn only_runs_replay_bar_time() {
+        let metadata = Metadata::new("TEST".to_string());
+        let mut backtest = Backtest::new(1, Portfolio::new(100_000));
+        let result = backtest.run(&BuyWhenIdle, &bars(3), &metadata).unwrap();
+        let replayed = result.portfolio.blotter.orders()[0].timestamp;
+        assert_eq!(replayed, parse_bar_time("2024-01-01").unwrap());
+
+        // Paper trading steps bars as they arrive, in real time.
+        let before = clock::wall_time();
+        let mut backtest = Backtest::new(0, Portfolio::new(100_000));
+        backtest.step(&BuyWhenIdle, &bars(3), &metadata).unwrap();
+        assert!(backtest.portfolio().blotter.orders()[0].timestamp >= before);
     }
 }
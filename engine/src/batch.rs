@@ -0,0 +1,94 @@
+//!
+//! Batch runs.
+//!
+//! A fixed set of worker threads pulls jobs from a shared list and sends
+//! each result back, tagged with the job's index, as soon as it is done.
+//! The caller owns the receiving end, so it can report progress while the
+//! batch runs and restore submission order at the end. Setting the cancel
+//! flag stops workers from starting new jobs; jobs already running finish.
+//! A job that panics reports an `EngineError::Panic` and its worker moves
+//! on to the next job.
+//!
+
+use crate::error::{EngineError, Result};
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread;
+
+
+/// Worker count used when none is given: one per available core.
+pub fn default_workers() -> usize {
+    thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+/// Runs `run` over `jobs` on up to `workers` threads. Results arrive as
+/// `(index, result)` in completion order.
+pub fn run_parallel<J, R, F>(
+    jobs: Vec<J>,
+    workers: usize,
+    cancel: Arc<AtomicBool>,
+    run: F,
+) -> Receiver<(usize, Result<R>)>
+where
+    J: Send + Sync + 'static,
+    R: Send + 'static,
+    F: Fn(&J) -> R + Send + Sync + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+    let jobs = Arc::new(jobs);
+    let next = Arc::new(AtomicUsize::new(0));
+    let run = Arc::new(run);
+
+    for _ in 0..workers.clamp(1, jobs.len().max(1)) {
+        let (jobs, next, run, cancel, sender) =
+            (jobs.clone(), next.clone(), run.clone(), cancel.clone(), sender.clone());
+        thread::spawn(move || loop {
+            if cancel.load(Ordering::Relaxed) {
+                break;
+            }
+            let i = next.fetch_add(1, Ordering::Relaxed);
+            if i >= jobs.len() {
+                break;
+            }
+            // A closed channel means the caller has given up on the batch.
+            let result = panic::catch_unwind(AssertUnwindSafe(|| run(&jobs[i])))
+                .map_err(|payload| EngineError::Panic(panic_message(payload)));
+            if sender.send((i, result)).is_err() {
+                break;
+            }
+        });
+    }
+    receiver
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => payload.downcast_ref::<&str>().map_or("unknown cause".to_string(), |m| m.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn panicking_jobs_report_an_error() {
+        let receiver = run_parallel(vec![1, 2, 3], 2, Arc::new(AtomicBool::new(false)), |&job: &i32| {
+            if job == 2 {
+                panic!("job {} failed", job);
+            }
+            job * 10
+        });
+        let mut results: Vec<(usize, Result<i32>)> = receiver.iter().collect();
+        results.sort_by_key(|(i, _)| *i);
+
+        assert_eq!(results.len(), 3);
+        assert!(matches!(results[0].1, Ok(10)));
+        assert!(matches!(&results[1].1, Err(EngineError::Panic(m)) if m == "job 2 failed"));
+        assert!(matches!(results[2].1, Ok(30)));
+    }
+}
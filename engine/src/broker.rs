@@ -1,91 +1,170 @@
-///
-/// Brokerage objects.
-/// May 2023
-/// Jack Tobin
-///
-/// These objects are designed to receive orders from the strategy/user
-/// and return back trade confirmations that detail the amount of the
-/// order filled, at what price, the timestamp, etc.
-///
-
-use rand_distr::{Normal, Uniform, Distribution};
-use rand;
-use crate::order::{Order, Confirm, OrderResult};
-use crate::data_loading::{AlphaVantage, Quote};
-use std::error::Error;
+//!
+//! Brokerage objects.
+//! May 2023
+//! Jack Tobin
+//!
+//! A `Broker` is the venue orders are sent to. It accepts and cancels
+//! orders and reports back the fills, positions and account balances it
+//! holds. The engine keeps its own book from the fills it is sent, so a
+//! strategy runs unchanged against `SimulatedBroker` in a backtest, the
+//! persisted `paper::PaperBroker`, or a live API such as
+//! `alpaca::AlpacaBroker`.
+//!
+
+use crate::order::{Order, Confirm, Fill, OrderId, OrderResult};
+use crate::commission::{CommissionModel, PerShare};
+use crate::slippage::{BarContext, FixedBasisPoints, SlippageModel};
+use crate::error::{EngineError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
 use derive_new::new;
 
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Account {
+    pub cash: f64,
+    /// Cash plus positions at their latest prices.
+    pub equity: f64,
+}
+
+pub trait Broker: Debug + Send {
+    /// Sends an accepted order to the venue.
+    fn submit(&mut self, order: &Order) -> Result<()>;
+
+    /// Withdraws the unfilled remainder of an order.
+    fn cancel(&mut self, id: OrderId) -> Result<()>;
+
+    /// Net shares held per symbol.
+    fn positions(&self) -> Result<BTreeMap<String, i64>>;
+
+    fn account(&self) -> Result<Account>;
+
+    /// Fills since the last call, oldest first.
+    fn fills(&mut self) -> Result<Vec<Fill>>;
+
+    /// Confirms that every fill returned by `fills` so far is booked and
+    /// saved. Venues that keep delivered fills to replay after a crash
+    /// can let them go.
+    fn acknowledge(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Market data for the latest bar of `ticker`. Simulated venues work
+    /// their open orders against it; live venues ignore it.
+    fn on_bar(&mut self, _ticker: &str, _bar: &BarContext) -> Result<()> {
+        Ok(())
+    }
+}
+
+
+/// What a `SimulatedBroker` holds for its client.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Ledger {
+    pub cash: f64,
+    pub positions: BTreeMap<String, i64>,
+    pub marks: BTreeMap<String, f64>,
+    /// Orders still working, in submission order.
+    pub orders: Vec<Order>,
+    /// Fills not yet collected through `fills`.
+    pub unreported: Vec<Fill>,
+}
+
+/// Fills orders against historical or live bars, limited by each bar's
+/// volume and priced off its close with slippage and commission.
 #[derive(Debug, new)]
-pub struct Broker {
-    trading_costs: f64,
+pub struct SimulatedBroker {
+    commission: Box<dyn CommissionModel>,
+    slippage: Box<dyn SlippageModel>,
+    /// Maximum fraction of a bar's volume that can be filled.
+    max_participation: f64,
+    #[new(default)]
+    volume_capacity: HashMap<String, i64>,
+    #[new(default)]
+    bars: HashMap<String, BarContext>,
+    #[new(default)]
+    ledger: Ledger,
+}
+impl Default for SimulatedBroker {
+    fn default() -> Self {
+        SimulatedBroker::new(Box::new(PerShare::new(0.50)), Box::new(FixedBasisPoints::new(5.0)), 0.10)
+    }
 }
-impl Broker {
-    pub fn quote(&self, ticker: String, quantity: i64) -> Result<Quote, Box<dyn Error>> {
-        let av = AlphaVantage;
-        let quote = av.get_quote(ticker.clone(), quantity)?;
-        Ok(quote)
+impl SimulatedBroker {
+    pub fn set_commission(&mut self, commission: Box<dyn CommissionModel>) {
+        self.commission = commission;
     }
 
-    fn market_noise(&self, mean: f64, variance: f64) -> Result<f64, Box<dyn Error>> {
-        let std_dev: f64 = variance.powf(1.0/2.0);
-        let normal = Normal::new(mean, std_dev)?;
-        let noise = normal.sample(&mut rand::thread_rng());
-        Ok(noise)
+    pub fn set_slippage(&mut self, slippage: Box<dyn SlippageModel>) {
+        self.slippage = slippage;
     }
 
-    fn market_slippage(&self, max_slippage: i64) -> Result<i64, Box<dyn Error>> {
-        let abs_max_slippage = max_slippage.abs();
-        let uniform = Uniform::new(0, abs_max_slippage);
-        let slippage = uniform.sample(&mut rand::thread_rng());
+    pub fn set_max_participation(&mut self, max_participation: f64) {
+        self.max_participation = max_participation;
+    }
 
-        Ok(slippage)
+    /// Opening cash balance; without it the account reports net cash
+    /// flow.
+    pub fn with_cash(mut self, cash: f64) -> Self {
+        self.ledger.cash = cash;
+        self
     }
 
-    fn executed_price(&self, quote: &Quote) -> Result<f64, Box<dyn Error>> {
-        let random_noise = self.market_noise(0.0, 1.0)?;
-        let executed_price = quote.quote + random_noise;
+    /// Resumes from a ledger saved earlier.
+    pub fn with_ledger(mut self, ledger: Ledger) -> Self {
+        self.ledger = ledger;
+        self
+    }
 
-        Ok(executed_price)
+    pub fn ledger(&self) -> &Ledger {
+        &self.ledger
     }
 
-    fn executed_quantity(&self, quantity_desired: i64) -> Result<i64, Box<dyn Error>> {
-        let max_slippage = (0.25 * (quantity_desired as f64)).abs() as i64;
-        let slippage = self.market_slippage(max_slippage)?;
+    /// Resets the fill capacity for `ticker` to `max_participation` of
+    /// the bar's traded volume, shared by every order worked on that bar.
+    pub fn begin_bar(&mut self, ticker: &str, bar: &BarContext) {
+        let capacity = (self.max_participation * bar.volume as f64).floor() as i64;
+        self.volume_capacity.insert(ticker.to_string(), capacity);
+    }
 
-        // If quantity desired is negative, need to add the slippage to the order.
-        // Otherwise, subtract it.
-        let executed_qty: i64;
-        if quantity_desired < 0 {
-            executed_qty = quantity_desired + slippage
-        } else if quantity_desired > 0 {
-            executed_qty = quantity_desired - slippage
-        } else {
-            executed_qty = 0
-        }
+    fn executed_quantity(&mut self, ticker: &str, quantity_desired: i64) -> i64 {
+        let capacity = self.volume_capacity.entry(ticker.to_string()).or_insert(0);
+        let executed_qty = quantity_desired.abs().min((*capacity).max(0));
+        *capacity -= executed_qty;
 
-        Ok(executed_qty)
+        executed_qty * quantity_desired.signum()
     }
 
-    fn send_order(&self, quote: Quote) -> Result<OrderResult, Box<dyn std::error::Error>> {
-        let amount_filled = self.executed_quantity(quote.quantity)?;
-        let price_filled = self.executed_price(&quote)?;
+    fn send_order(&mut self, order: &Order, bar: &BarContext) -> OrderResult {
+        let amount_filled = self.executed_quantity(&order.ticker, order.remaining_quantity());
+        let price_filled = self.slippage.execution_price(amount_filled, bar.close, bar);
 
-        let result = OrderResult::new(
-            quote.ticker.clone(),
+        OrderResult::new(
+            order.id,
+            order.ticker.clone(),
             amount_filled,
             price_filled,
-        );
-
-        Ok(result)
+        )
     }
 
-    pub fn execute(&self, order: Order) -> Result<Confirm, Box<dyn std::error::Error>> {
-        let trading_costs = self.trading_costs * (order.quantity as f64);
-        let quote = self.quote(order.ticker.clone(), order.quantity)?;
-        let result = self.send_order(quote)?;
+    /// Fills as much of the order's remaining quantity as the bar's
+    /// volume capacity allows, priced off the bar close.
+    pub fn execute(&mut self, order: &Order, bar: &BarContext) -> Result<Confirm> {
+        if !order.is_open() {
+            return Err(EngineError::OrderRejected(format!(
+                "Order {} is {} and cannot be executed", order.id, order.status,
+            )));
+        }
+        let result = self.send_order(order, bar);
+        let trading_costs = self.commission.commission(
+            result.filled_quantity,
+            result.filled_price,
+            result.timestamp,
+            order.filled_quantity,
+        );
 
         let confirm = Confirm::new(
+            order.id,
             order.ticker.clone(),
             result.timestamp,
             result.filled_quantity,
@@ -95,4 +174,173 @@ impl Broker {
 
         Ok(confirm)
     }
+
+    /// Works the order at `index` against its symbol's current bar and
+    /// books any fill.
+    fn work(&mut self, index: usize) -> Result<()> {
+        let order = self.ledger.orders[index].clone();
+        let bar = match self.bars.get(&order.ticker) {
+            Some(bar) => bar.clone(),
+            None => return Ok(()),
+        };
+        let confirm = self.execute(&order, &bar)?;
+        if confirm.quantity_filled == 0 {
+            return Ok(());
+        }
+
+        let fill = confirm.to_fill();
+        self.ledger.cash -= fill.price * fill.quantity as f64 + fill.trading_costs;
+        let position = self.ledger.positions.entry(fill.ticker.clone()).or_insert(0);
+        *position += fill.quantity;
+        if *position == 0 {
+            self.ledger.positions.remove(&fill.ticker);
+        }
+        self.ledger.orders[index].apply_fill(fill.clone())?;
+        self.ledger.unreported.push(fill);
+        Ok(())
+    }
+}
+
+impl Broker for SimulatedBroker {
+    /// Works the order straight away if its symbol has a current bar.
+    fn submit(&mut self, order: &Order) -> Result<()> {
+        if !order.is_open() || order.remaining_quantity() == 0 {
+            return Err(EngineError::OrderRejected(format!(
+                "Order {} is {} with {} to fill", order.id, order.status, order.remaining_quantity(),
+            )));
+        }
+        self.ledger.orders.push(order.clone());
+        self.work(self.ledger.orders.len() - 1)?;
+        self.ledger.orders.retain(Order::is_open);
+        Ok(())
+    }
+
+    fn cancel(&mut self, id: OrderId) -> Result<()> {
+        let index = self.ledger.orders.iter().position(|o| o.id == id)
+            .ok_or_else(|| EngineError::OrderRejected(format!("Order {} is not working", id)))?;
+        self.ledger.orders.remove(index);
+        Ok(())
+    }
+
+    fn positions(&self) -> Result<BTreeMap<String, i64>> {
+        Ok(self.ledger.positions.clone())
+    }
+
+    fn account(&self) -> Result<Account> {
+        let value: f64 = self.ledger.positions.iter()
+            .map(|(ticker, quantity)| *quantity as f64 * self.ledger.marks.get(ticker).copied().unwrap_or(0.0))
+            .sum();
+        Ok(Account { cash: self.ledger.cash, equity: self.ledger.cash + value })
+    }
+
+    fn fills(&mut self) -> Result<Vec<Fill>> {
+        Ok(std::mem::take(&mut self.ledger.unreported))
+    }
+
+    fn on_bar(&mut self, ticker: &str, bar: &BarContext) -> Result<()> {
+        self.begin_bar(ticker, bar);
+        self.bars.insert(ticker.to_string(), bar.clone());
+        self.ledger.marks.insert(ticker.to_string(), bar.close);
+        for index in 0..self.ledger.orders.len() {
+            if self.ledger.orders[index].ticker == ticker {
+                self.work(index)?;
+            }
+        }
+        self.ledger.orders.retain(Order::is_open);
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commission::CommissionPreset;
+    use crate::order::OrderStatus;
+
+    /// Fills up to a tenth of each bar's volume at the close, free.
+    fn broker() -> SimulatedBroker {
+        SimulatedBroker::new(Box::new(PerShare::new(0.0)), Box::new(FixedBasisPoints::new(0.0)), 0.1)
+    }
+
+    fn bar(close: f64, volume: u64) -> BarContext {
+        BarContext::new(close, close, close, volume, 0.01)
+    }
+
+    fn order(quantity: i64) -> Order {
+        let mut order = Order::new("TEST".to_string(), quantity);
+        order.transition(OrderStatus::Accepted).unwrap();
+        order
+    }
+
+    fn filled(fills: &[Fill]) -> Vec<(OrderId, i64)> {
+        fills.iter().map(|f| (f.order_id, f.quantity)).collect()
+    }
+
+    #[test]
+    fn fills_are_capped_at_the_bar_participation() {
+        let mut broker = broker();
+        broker.on_bar("TEST", &bar(100.0, 1_000)).unwrap();
+        let order = order(250);
+        broker.submit(&order).unwrap();
+
+        let fills = broker.fills().unwrap();
+        assert_eq!(filled(&fills), vec![(order.id, 100)]);
+        assert_eq!(fills[0].price, 100.0);
+        assert_eq!(broker.positions().unwrap().get("TEST"), Some(&100));
+        assert_eq!(broker.ledger().orders.len(), 1);
+    }
+
+    #[test]
+    fn orders_on_one_bar_share_its_capacity() {
+        let mut broker = broker();
+        broker.on_bar("TEST", &bar(100.0, 1_000)).unwrap();
+        let (buy, sell) = (order(60), order(-60));
+        broker.submit(&buy).unwrap();
+        broker.submit(&sell).unwrap();
+        assert_eq!(filled(&broker.fills().unwrap()), vec![(buy.id, 60), (sell.id, -40)]);
+
+        let third = order(10);
+        broker.submit(&third).unwrap();
+        assert!(broker.fills().unwrap().is_empty());
+    }
+
+    #[test]
+    fn remainders_work_on_later_bars() {
+        let mut broker = broker();
+        let order = order(250);
+        broker.submit(&order).unwrap();
+        assert!(broker.fills().unwrap().is_empty());
+
+        let mut reported = vec![];
+        for (close, volume) in [(100.0, 1_000), (110.0, 1_000), (120.0, 1_000)] {
+            broker.on_bar("TEST", &bar(close, volume)).unwrap();
+            reported.push(broker.fills().unwrap());
+        }
+        let quantities: Vec<Vec<(OrderId, i64)>> = reported.iter().map(|f| filled(f)).collect();
+        assert_eq!(quantities, vec![vec![(order.id, 100)], vec![(order.id, 100)], vec![(order.id, 50)]]);
+        assert!(broker.ledger().orders.is_empty());
+        let account = broker.account().unwrap();
+        assert_eq!(account.cash, -(100.0 * 100.0 + 100.0 * 110.0 + 50.0 * 120.0));
+        assert_eq!(account.equity, account.cash + 250.0 * 120.0);
+    }
+
+    #[test]
+    fn per_order_fees_are_charged_once_across_bars() {
+        // 150 shares fill as 100 and then 50 over two bars at $10.
+        let costs = |preset: CommissionPreset| {
+            let mut broker = broker();
+            broker.set_commission(preset.model());
+            broker.submit(&order(150)).unwrap();
+            let mut costs = vec![];
+            for _ in 0..2 {
+                broker.on_bar("TEST", &bar(10.0, 1_000)).unwrap();
+                costs.extend(broker.fills().unwrap().iter().map(|f| f.trading_costs));
+            }
+            costs
+        };
+        // The $1 minimum covers the first 100 shares; the rest pay $0.005 a share.
+        assert_eq!(costs(CommissionPreset::IbkrFixed), vec![1.0, 0.25]);
+        assert_eq!(costs(CommissionPreset::RetailFlat), vec![4.95, 0.0]);
+    }
 }
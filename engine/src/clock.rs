@@ -0,0 +1,105 @@
+//!
+//! Clocks.
+//!
+//! Records are stamped through `clock::now()` rather than the wall clock.
+//! During a backtest a `ReplayClock` publishes the current bar's time on
+//! the running thread, so orders, fills and trades carry simulated time
+//! and strategies can ask for the current bar time. Outside a replay,
+//! `now()` falls back to real time.
+//!
+//! Only `Backtest::run` and `Backtest::run_allocation` start a replay.
+//! Paper trading calls `Backtest::step` directly, so its orders and
+//! market-hours checks read real time through `now()`. Live venues (FIX,
+//! Alpaca) stamp fills with the venue's own time and fall back to
+//! `wall_time()`, never to a replayed bar.
+//!
+
+use crate::error::{EngineError, Result};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use std::cell::Cell;
+
+
+thread_local! {
+    static SIMULATED_TIME: Cell<Option<DateTime<Utc>>> = const { Cell::new(None) };
+}
+
+/// Current time: the replayed bar time if a `ReplayClock` is active on
+/// this thread, otherwise the wall clock.
+pub fn now() -> DateTime<Utc> {
+    SIMULATED_TIME.with(|t| t.get()).unwrap_or_else(Utc::now)
+}
+
+/// Real time, even on a thread that is replaying bars.
+pub fn wall_time() -> DateTime<Utc> {
+    Utc::now()
+}
+
+/// Parses a bar date, either `YYYY-MM-DD` (stamped at midnight UTC) or
+/// `YYYY-MM-DD HH:MM:SS` for intraday bars.
+pub fn parse_bar_time(date: &str) -> Result<DateTime<Utc>> {
+    if let Ok(time) = NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S") {
+        return Ok(time.and_utc());
+    }
+    let day = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map_err(|e| EngineError::Parse(format!("Invalid bar date {:?}: {}", date, e)))?;
+    Ok(day.and_time(NaiveTime::MIN).and_utc())
+}
+
+/// The inverse of `parse_bar_time`: midnight is written as a plain date.
+pub fn format_bar_time(time: NaiveDateTime) -> String {
+    if time.time() == NaiveTime::MIN {
+        time.format("%Y-%m-%d").to_string()
+    } else {
+        time.format("%Y-%m-%d %H:%M:%S").to_string()
+    }
+}
+
+
+/// Historical replay clock. Time only moves when `set` is called, and
+/// the simulated time is cleared from the thread when the clock is
+/// dropped.
+#[derive(Debug)]
+pub struct ReplayClock {
+    // Tied to the thread whose simulated time it controls.
+    _not_send: std::marker::PhantomData<*const ()>,
+}
+impl ReplayClock {
+    pub fn start() -> Self {
+        ReplayClock { _not_send: std::marker::PhantomData }
+    }
+
+    pub fn set(&self, time: DateTime<Utc>) {
+        SIMULATED_TIME.with(|t| t.set(Some(time)));
+    }
+}
+
+impl Drop for ReplayClock {
+    fn drop(&mut self) {
+        SIMULATED_TIME.with(|t| t.set(None));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_time_is_cleared_on_drop() {
+        let time = parse_bar_time("2024-03-01 10:30:00").unwrap();
+        {
+            let clock = ReplayClock::start();
+            clock.set(time);
+            assert_eq!(now(), time);
+        }
+        assert_ne!(now(), time);
+    }
+
+    #[test]
+    fn wall_time_ignores_the_replay() {
+        let time = parse_bar_time("2024-03-01").unwrap();
+        let clock = ReplayClock::start();
+        clock.set(time);
+        assert_eq!(now(), time);
+        assert!(wall_time() > time);
+    }
+}
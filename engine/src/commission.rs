@@ -0,0 +1,219 @@
+//!
+//! Commission models.
+//!
+//! A `CommissionModel` prices the brokerage fee for a single execution.
+//! Fees are always non-negative regardless of the side of the trade.
+//! Fees charged per order, such as a flat ticket or a minimum, are paid
+//! on the order's first fill only, so an order filled over several bars
+//! pays them once. Presets mimic common retail and institutional fee
+//! schedules.
+//!
+
+use crate::error::EngineError;
+use chrono::{DateTime, Datelike, Utc};
+use derive_new::new;
+use std::fmt::Debug;
+use std::str::FromStr;
+
+
+pub trait CommissionModel: Debug + Send + Sync {
+    /// Fee charged for executing `quantity` shares (signed) at `price`
+    /// at `time`, after `filled_before` shares of the same order.
+    fn commission(&mut self, quantity: i64, price: f64, time: DateTime<Utc>, filled_before: i64) -> f64;
+}
+
+
+/// Fixed fee per share traded.
+#[derive(Debug, Clone, new)]
+pub struct PerShare {
+    rate: f64,
+}
+impl CommissionModel for PerShare {
+    fn commission(&mut self, quantity: i64, _price: f64, _time: DateTime<Utc>, _filled_before: i64) -> f64 {
+        self.rate * quantity.unsigned_abs() as f64
+    }
+}
+
+
+/// Flat fee per order, independent of size.
+#[derive(Debug, Clone, new)]
+pub struct PerTrade {
+    fee: f64,
+}
+impl CommissionModel for PerTrade {
+    fn commission(&mut self, quantity: i64, _price: f64, _time: DateTime<Utc>, filled_before: i64) -> f64 {
+        if quantity == 0 || filled_before != 0 { 0.0 } else { self.fee }
+    }
+}
+
+
+/// Fee as a fraction of traded notional, e.g. 0.0002 for 2bps.
+#[derive(Debug, Clone, new)]
+pub struct PercentOfNotional {
+    rate: f64,
+}
+impl CommissionModel for PercentOfNotional {
+    fn commission(&mut self, quantity: i64, price: f64, _time: DateTime<Utc>, _filled_before: i64) -> f64 {
+        self.rate * (quantity as f64 * price).abs()
+    }
+}
+
+
+/// Per-share rate that steps down as volume traded in the calendar month
+/// grows.
+///
+/// Tiers are `(volume_threshold, rate)` pairs; the rate of the highest
+/// threshold not exceeding the volume traded so far this month applies.
+#[derive(Debug, Clone)]
+pub struct TieredByVolume {
+    tiers: Vec<(u64, f64)>,
+    traded_volume: u64,
+    month: Option<(i32, u32)>,
+}
+impl TieredByVolume {
+    pub fn new(mut tiers: Vec<(u64, f64)>) -> Self {
+        tiers.sort_by_key(|(threshold, _)| *threshold);
+        TieredByVolume { tiers, traded_volume: 0, month: None }
+    }
+
+    fn current_rate(&self) -> f64 {
+        self.tiers.iter()
+            .take_while(|(threshold, _)| *threshold <= self.traded_volume)
+            .last()
+            .or(self.tiers.first())
+            .map(|(_, rate)| *rate)
+            .unwrap_or(0.0)
+    }
+}
+impl CommissionModel for TieredByVolume {
+    fn commission(&mut self, quantity: i64, _price: f64, time: DateTime<Utc>, _filled_before: i64) -> f64 {
+        let month = (time.year(), time.month());
+        if self.month != Some(month) {
+            self.month = Some(month);
+            self.traded_volume = 0;
+        }
+        let fee = self.current_rate() * quantity.unsigned_abs() as f64;
+        self.traded_volume += quantity.unsigned_abs();
+        fee
+    }
+}
+
+
+/// Clamps another model's fee to a minimum and optional maximums.
+///
+/// The minimum applies to an order's first fill. `max_pct_notional`
+/// caps the fee as a fraction of the trade value.
+#[derive(Debug, new)]
+pub struct MinMaxFee {
+    inner: Box<dyn CommissionModel>,
+    min_fee: f64,
+    max_fee: Option<f64>,
+    max_pct_notional: Option<f64>,
+}
+impl CommissionModel for MinMaxFee {
+    fn commission(&mut self, quantity: i64, price: f64, time: DateTime<Utc>, filled_before: i64) -> f64 {
+        if quantity == 0 {
+            return 0.0;
+        }
+        let mut fee = self.inner.commission(quantity, price, time, filled_before);
+        if filled_before == 0 {
+            fee = fee.max(self.min_fee);
+        }
+        if let Some(max_fee) = self.max_fee {
+            fee = fee.min(max_fee);
+        }
+        if let Some(pct) = self.max_pct_notional {
+            fee = fee.min(pct * (quantity as f64 * price).abs());
+        }
+        fee
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommissionPreset {
+    /// Commission-free retail brokers.
+    Zero,
+    /// Legacy discount retail: flat $4.95 per trade.
+    RetailFlat,
+    /// Interactive Brokers fixed: $0.005/share, $1 minimum, 1% of value maximum.
+    IbkrFixed,
+    /// Interactive Brokers tiered: $0.0035 to $0.0005/share by monthly volume.
+    IbkrTiered,
+    /// Institutional agency execution: 2bps of notional.
+    Institutional,
+}
+impl CommissionPreset {
+    pub fn model(&self) -> Box<dyn CommissionModel> {
+        match self {
+            CommissionPreset::Zero => Box::new(PerTrade::new(0.0)),
+            CommissionPreset::RetailFlat => Box::new(PerTrade::new(4.95)),
+            CommissionPreset::IbkrFixed => Box::new(MinMaxFee::new(
+                Box::new(PerShare::new(0.005)),
+                1.0,
+                None,
+                Some(0.01),
+            )),
+            CommissionPreset::IbkrTiered => Box::new(MinMaxFee::new(
+                Box::new(TieredByVolume::new(vec![
+                    (0, 0.0035),
+                    (300_000, 0.002),
+                    (3_000_000, 0.0015),
+                    (20_000_000, 0.001),
+                    (100_000_000, 0.0005),
+                ])),
+                0.35,
+                None,
+                Some(0.01),
+            )),
+            CommissionPreset::Institutional => Box::new(PercentOfNotional::new(0.0002)),
+        }
+    }
+}
+
+impl FromStr for CommissionPreset {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zero" => Ok(CommissionPreset::Zero),
+            "retail_flat" => Ok(CommissionPreset::RetailFlat),
+            "ibkr_fixed" => Ok(CommissionPreset::IbkrFixed),
+            "ibkr_tiered" => Ok(CommissionPreset::IbkrTiered),
+            "institutional" => Ok(CommissionPreset::Institutional),
+            _ => Err(EngineError::Config(format!("Unknown commission preset: {}", s))),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn tiered_volume_resets_each_month() {
+        let mut model = TieredByVolume::new(vec![(0, 0.01), (1_000, 0.005)]);
+        let jan = Utc.with_ymd_and_hms(2024, 1, 10, 0, 0, 0).unwrap();
+        let late_jan = Utc.with_ymd_and_hms(2024, 1, 31, 0, 0, 0).unwrap();
+        let feb = Utc.with_ymd_and_hms(2024, 2, 1, 0, 0, 0).unwrap();
+
+        assert_eq!(model.commission(1_000, 10.0, jan, 0), 10.0);
+        assert_eq!(model.commission(-100, 10.0, late_jan, 0), 0.5);
+        assert_eq!(model.commission(100, 10.0, feb, 0), 1.0);
+    }
+
+    #[test]
+    fn per_order_fees_are_charged_on_the_first_fill() {
+        let now = Utc::now();
+        let mut flat = PerTrade::new(4.95);
+        assert_eq!((flat.commission(100, 10.0, now, 0), flat.commission(50, 10.0, now, 100)), (4.95, 0.0));
+
+        let mut ibkr = CommissionPreset::IbkrFixed.model();
+        assert_eq!(ibkr.commission(100, 10.0, now, 0), 1.0);
+        assert_eq!(ibkr.commission(-100, 10.0, now, -100), 0.5);
+        // 1% of a $5 trade is below the first fill's minimum.
+        assert_eq!(ibkr.commission(1, 5.0, now, 0), 0.05);
+    }
+}
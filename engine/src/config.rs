@@ -1,14 +1,637 @@
+//!
+//! Configuration.
+//!
+//! `Config::get` reads single settings such as API keys from the
+//! environment. `BacktestConfig` describes a whole run: data source,
+//! universe, date range, strategy, broker cost models, risk limits,
+//! outputs, execution algos and paper trading. It loads from YAML
+//! or TOML, then environment variables of the form
+//! `TRADING__<SECTION>__<KEY>` override individual fields, e.g.
+//! `TRADING__STRATEGY__WINDOW=50` or `TRADING__UNIVERSE="[AAPL, MSFT]"`.
+//! The result is validated before anything runs so that mistakes surface
+//! as one readable list.
+//!
+//! ```yaml
+//! data:
+//!   rate_limit:
+//!     per_minute: 75
+//!     per_day: null
+//! universe: [AAPL]
+//! start_date: 2018-01-01
+//! capital: 1000000
+//! strategy:
+//!   name: ma_crossover
+//!   window: 90
+//!   long_quantity: 100
+//!   short_quantity: -100
+//! broker:
+//!   commission: ibkr_fixed
+//!   slippage: spread
+//! risk:
+//!   max_position: 1000
+//! output:
+//!   dir: runs
+//!   format: parquet
+//!   report: true
+//!   store: runs/history.db
+//! ```
+//!
 
-
-use std::env;
-use std::error::Error;
+use crate::accounting::LotMethod;
+use crate::backtest::{Backtest, BacktestResult};
+use crate::broker::SimulatedBroker;
+use crate::commission::CommissionPreset;
+use crate::data_loading::{AlphaVantage, CsvCache, DatedStockData, Interval};
+use crate::error::{EngineError, Result};
+use crate::export::{export_run, ExportFormat, RunManifest};
+use crate::paper::PaperConfig;
+use crate::execution::ExecutionConfig;
+use crate::portfolio::Portfolio;
+use crate::ratelimit::{ApiUsage, RateLimits};
+use crate::report::{write_tearsheet, ReportData};
+use crate::risk::RiskLimits;
+use crate::sizing::sizer_from_spec;
+use crate::slippage::SlippagePreset;
+use crate::store::RunStore;
+use crate::strategy::{get_strategy_factory, Strategy, DEFAULT_LONG_QUANTITY, DEFAULT_SHORT_QUANTITY};
+use chrono::NaiveDate;
 use derive_new::new;
+use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+
+/// Prefix of environment variables that override configuration fields.
+pub const ENV_PREFIX: &str = "TRADING__";
 
 #[derive(Debug, new)]
 pub struct Config {}
 impl Config {
-    pub fn get(key: String) -> Result<String, Box<dyn Error>> {
+    pub fn get(key: String) -> Result<String> {
         // Assumes that the config key is stored as an environment variable.
-        Ok(env::var(key)?)
+        env::var(&key).map_err(|e| EngineError::Config(format!("{}: {}", key, e)))
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DataSource {
+    #[default]
+    AlphaVantage,
+    /// Bars previously downloaded into `cache_dir` by `fetch`.
+    Cache,
+}
+
+impl DataSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DataSource::AlphaVantage => "alpha_vantage",
+            DataSource::Cache => "cache",
+        }
+    }
+}
+
+impl FromStr for DataSource {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "alpha_vantage" => Ok(DataSource::AlphaVantage),
+            "cache" => Ok(DataSource::Cache),
+            _ => Err(EngineError::Config(format!("Unknown data source: {}", s))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DataConfig {
+    pub source: DataSource,
+    pub interval: String,
+    pub cache_dir: Option<PathBuf>,
+    /// Alpha Vantage endpoint, e.g. a local `mock-server`.
+    pub base_url: Option<String>,
+    /// Environment variable holding the API key, `AV_KEY` by default.
+    pub api_key_env: Option<String>,
+    /// Call budget and retries for the Alpha Vantage plan.
+    pub rate_limit: RateLimits,
+}
+impl Default for DataConfig {
+    fn default() -> Self {
+        DataConfig {
+            source: DataSource::AlphaVantage,
+            interval: "day".to_string(),
+            cache_dir: None,
+            base_url: None,
+            api_key_env: None,
+            rate_limit: RateLimits::default(),
+        }
+    }
+}
+impl DataConfig {
+    pub fn alpha_vantage(&self) -> AlphaVantage {
+        let mut client = AlphaVantage::new(&self.rate_limit);
+        if let Some(base_url) = &self.base_url {
+            client = client.with_base_url(base_url);
+        }
+        if let Some(name) = &self.api_key_env {
+            client = client.with_api_key_env(name);
+        }
+        client
+    }
+
+    /// Usage of the Alpha Vantage key, if that is where data comes from.
+    pub fn api_usage(&self) -> Option<ApiUsage> {
+        (self.source == DataSource::AlphaVantage).then(|| self.alpha_vantage().usage())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StrategyConfig {
+    pub name: String,
+    pub window: u32,
+    #[serde(default = "default_long_quantity")]
+    pub long_quantity: i64,
+    #[serde(default = "default_short_quantity")]
+    pub short_quantity: i64,
+    /// Position sizer spec such as `percent_equity:0.1`. Overrides the
+    /// fixed quantities when set.
+    #[serde(default)]
+    pub sizing: Option<String>,
+    /// Largest position a sizer may take, as a fraction of equity.
+    #[serde(default = "default_max_position_fraction")]
+    pub max_position_fraction: f64,
+}
+
+fn default_long_quantity() -> i64 {
+    DEFAULT_LONG_QUANTITY
+}
+
+fn default_max_position_fraction() -> f64 {
+    1.0
+}
+
+fn default_short_quantity() -> i64 {
+    DEFAULT_SHORT_QUANTITY
+}
+
+fn default_capital() -> i64 {
+    1_000_000
+}
+
+/// Broker cost models by preset name; unset fields keep the backtest
+/// defaults.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BrokerConfig {
+    pub commission: Option<String>,
+    pub slippage: Option<String>,
+    pub max_participation: Option<f64>,
+    pub lot_method: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+    /// Each run is exported to `<dir>/<run_id>/`.
+    pub dir: Option<PathBuf>,
+    pub format: String,
+    /// Also write `report.html` into the run directory.
+    pub report: bool,
+    /// SQLite run store to record runs in.
+    pub store: Option<PathBuf>,
+}
+impl Default for OutputConfig {
+    fn default() -> Self {
+        OutputConfig { dir: None, format: "json".to_string(), report: false, store: None }
+    }
+}
+
+impl BrokerConfig {
+    /// The fill simulator with these cost models.
+    pub fn simulator(&self) -> Result<SimulatedBroker> {
+        let mut simulator = SimulatedBroker::default();
+        if let Some(preset) = &self.commission {
+            simulator.set_commission(CommissionPreset::from_str(preset)?.model());
+        }
+        if let Some(preset) = &self.slippage {
+            simulator.set_slippage(SlippagePreset::from_str(preset)?.model());
+        }
+        if let Some(max_participation) = self.max_participation {
+            simulator.set_max_participation(max_participation);
+        }
+        Ok(simulator)
+    }
+}
+
+/// Where a run's outputs ended up.
+#[derive(Debug, Clone, Default)]
+pub struct RunOutputs {
+    pub exported_files: Vec<PathBuf>,
+    pub report: Option<PathBuf>,
+    pub run_id: Option<String>,
+}
+
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BacktestConfig {
+    #[serde(default)]
+    pub data: DataConfig,
+    pub universe: Vec<String>,
+    #[serde(default)]
+    pub start_date: Option<NaiveDate>,
+    #[serde(default)]
+    pub end_date: Option<NaiveDate>,
+    #[serde(default = "default_capital")]
+    pub capital: i64,
+    pub strategy: StrategyConfig,
+    #[serde(default)]
+    pub broker: BrokerConfig,
+    #[serde(default)]
+    pub risk: RiskLimits,
+    #[serde(default)]
+    pub output: OutputConfig,
+    #[serde(default)]
+    pub execution: ExecutionConfig,
+    #[serde(default)]
+    pub paper: PaperConfig,
+}
+impl BacktestConfig {
+    /// Loads a `.yaml`, `.yml` or `.toml` file, applies environment
+    /// overrides and validates the result.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| EngineError::Config(format!("Unable to read config {}: {}", path.display(), e)))?;
+        let value = match path.extension().and_then(|e| e.to_str()) {
+            Some("yaml") | Some("yml") => parse_yaml(&text)?,
+            Some("toml") => parse_toml(&text)?,
+            _ => return Err(EngineError::Config(format!("Unsupported config format: {}", path.display()))),
+        };
+        Self::from_value(value, env::vars())
+    }
+
+    pub fn from_yaml_str(text: &str) -> Result<Self> {
+        Self::from_value(parse_yaml(text)?, env::vars())
+    }
+
+    pub fn from_toml_str(text: &str) -> Result<Self> {
+        Self::from_value(parse_toml(text)?, env::vars())
+    }
+
+    fn from_value(mut value: Value, vars: impl Iterator<Item = (String, String)>) -> Result<Self> {
+        for (key, raw) in vars {
+            if let Some(path) = key.strip_prefix(ENV_PREFIX) {
+                let path: Vec<String> = path.split("__").map(|p| p.to_lowercase()).collect();
+                let parsed: Value = serde_yaml::from_str(&raw).unwrap_or(Value::String(raw.clone()));
+                set_path(&mut value, &path, parsed)
+                    .map_err(|e| EngineError::Config(format!("Invalid override {}: {}", key, e)))?;
+            }
+        }
+        let config: BacktestConfig = serde_yaml::from_value(value)
+            .map_err(|e| EngineError::Config(format!("Invalid configuration: {}", e)))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// A copy with `overrides` applied, each a dotted path such as
+    /// `strategy.window` and a YAML value, then validated.
+    pub fn with_overrides(&self, overrides: &[(String, String)]) -> Result<Self> {
+        let vars = overrides.iter()
+            .map(|(path, value)| (format!("{}{}", ENV_PREFIX, path.replace('.', "__")), value.clone()));
+        let value = serde_yaml::to_value(self).map_err(|e| EngineError::Config(e.to_string()))?;
+        Self::from_value(value, vars)
+    }
+
+    /// Checks every field and reports all problems at once.
+    pub fn validate(&self) -> Result<()> {
+        let mut problems: Vec<String> = vec![];
+
+        if self.data.interval != "day" {
+            problems.push(format!(
+                "data.interval: only daily bars are supported by {:?}, got {:?}",
+                self.data.source, self.data.interval,
+            ));
+        }
+        if self.data.source == DataSource::Cache && self.data.cache_dir.is_none() {
+            problems.push("data.cache_dir: required when data.source is cache".to_string());
+        }
+        if let Some(url) = self.data.base_url.as_deref().filter(|u| !u.starts_with("http://") && !u.starts_with("https://")) {
+            problems.push(format!("data.base_url: expected an http(s) URL, got {:?}", url));
+        }
+        problems.extend(self.data.rate_limit.problems().into_iter().map(|p| format!("data.rate_limit.{}", p)));
+        if self.universe.is_empty() {
+            problems.push("universe: at least one symbol is required".to_string());
+        }
+        for symbol in &self.universe {
+            if symbol.trim().is_empty() || symbol.contains(',') {
+                problems.push(format!("universe: invalid symbol {:?}", symbol));
+            }
+        }
+        if let (Some(start), Some(end)) = (self.start_date, self.end_date) {
+            if start > end {
+                problems.push(format!("start_date {} is after end_date {}", start, end));
+            }
+        }
+        if self.capital <= 0 {
+            problems.push(format!("capital: must be positive, got {}", self.capital));
+        }
+
+        let factory = get_strategy_factory();
+        if !factory.names().contains(&self.strategy.name.as_str()) {
+            problems.push(format!(
+                "strategy.name: unknown strategy {:?}, expected one of {:?}",
+                self.strategy.name, factory.names(),
+            ));
+        }
+        if self.strategy.window == 0 {
+            problems.push("strategy.window: must be at least 1".to_string());
+        }
+        if let Some(Err(e)) = self.strategy.sizing.as_deref().map(|s| sizer_from_spec(s, self.strategy.max_position_fraction)) {
+            problems.push(format!("strategy.sizing: {}", e));
+        }
+        if !(self.strategy.max_position_fraction > 0.0 && self.strategy.max_position_fraction.is_finite()) {
+            problems.push(format!("strategy.max_position_fraction: must be positive, got {}", self.strategy.max_position_fraction));
+        }
+
+        if let Some(Err(e)) = self.broker.commission.as_deref().map(CommissionPreset::from_str) {
+            problems.push(format!("broker.commission: {}", e));
+        }
+        if let Some(Err(e)) = self.broker.slippage.as_deref().map(SlippagePreset::from_str) {
+            problems.push(format!("broker.slippage: {}", e));
+        }
+        if let Some(Err(e)) = self.broker.lot_method.as_deref().map(LotMethod::from_str) {
+            problems.push(format!("broker.lot_method: {}", e));
+        }
+        if let Some(participation) = self.broker.max_participation {
+            if !(participation > 0.0 && participation <= 1.0) {
+                problems.push(format!("broker.max_participation: must be in (0, 1], got {}", participation));
+            }
+        }
+
+        let limits = [
+            ("max_order_notional", self.risk.max_order_notional),
+            ("max_gross_exposure", self.risk.max_gross_exposure),
+            ("max_net_exposure", self.risk.max_net_exposure),
+            ("max_leverage", self.risk.max_leverage),
+            ("max_concentration", self.risk.max_concentration),
+            ("daily_loss_limit", self.risk.daily_loss_limit),
+            ("max_position", self.risk.max_position.map(|p| p as f64)),
+        ];
+        for (name, limit) in limits {
+            if let Some(limit) = limit.filter(|l| *l <= 0.0) {
+                problems.push(format!("risk.{}: must be positive, got {}", name, limit));
+            }
+        }
+
+        if let Err(e) = ExportFormat::from_str(&self.output.format) {
+            problems.push(format!("output.format: {}", e));
+        }
+        if self.output.report && self.output.dir.is_none() {
+            problems.push("output.report: requires output.dir".to_string());
+        }
+        problems.extend(self.execution.problems().into_iter().map(|p| format!("execution.{}", p)));
+        problems.extend(self.paper.problems().into_iter().map(|p| format!("paper.{}", p)));
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(EngineError::Config(format!("Invalid configuration:\n  - {}", problems.join("\n  - "))))
+        }
+    }
+
+    /// The strategy and cost parameters recorded with each run.
+    pub fn parameters(&self) -> BTreeMap<String, String> {
+        let strategy = &self.strategy;
+        let mut parameters: BTreeMap<String, String> = [
+            ("window", strategy.window.to_string()),
+            ("capital", self.capital.to_string()),
+            ("long_qty", strategy.long_quantity.to_string()),
+            ("short_qty", strategy.short_quantity.to_string()),
+            ("commission", self.broker.commission.clone().unwrap_or("default".to_string())),
+            ("slippage", self.broker.slippage.clone().unwrap_or("default".to_string())),
+            ("sizing", strategy.sizing.clone().unwrap_or("fixed".to_string())),
+        ].into_iter().map(|(k, v)| (k.to_string(), v)).collect();
+        if let Some(start) = self.start_date {
+            parameters.insert("start_date".to_string(), start.to_string());
+        }
+        if let Some(end) = self.end_date {
+            parameters.insert("end_date".to_string(), end.to_string());
+        }
+        if strategy.sizing.is_some() {
+            parameters.insert("max_position_fraction".to_string(), strategy.max_position_fraction.to_string());
+        }
+        if let Some(algo) = &self.execution.algo {
+            parameters.insert("execution".to_string(), algo.clone());
+        }
+        parameters
+    }
+
+    /// Bars for `symbol` from the configured source, restricted to the
+    /// date range.
+    pub fn load_data(&self, symbol: &str) -> Result<Vec<DatedStockData>> {
+        let data = match self.data.source {
+            DataSource::AlphaVantage => self.data.alpha_vantage()
+                .get_timeseries(symbol, &Interval::from_str(&self.data.interval)?)?,
+            DataSource::Cache => {
+                let dir = self.data.cache_dir.clone()
+                    .ok_or_else(|| EngineError::Config("data.cache_dir is not set".to_string()))?;
+                CsvCache::new(dir).load(symbol)?
+            },
+        };
+        Ok(self.select_range(data))
+    }
+
+    pub fn select_range(&self, data: Vec<DatedStockData>) -> Vec<DatedStockData> {
+        data.into_iter()
+            .filter(|bar| {
+                let day = NaiveDate::parse_from_str(bar.date.get(..10).unwrap_or(&bar.date), "%Y-%m-%d").ok();
+                let after_start = match (self.start_date, day) {
+                    (Some(start), Some(day)) => day >= start,
+                    _ => true,
+                };
+                let before_end = match (self.end_date, day) {
+                    (Some(end), Some(day)) => day <= end,
+                    _ => true,
+                };
+                after_start && before_end
+            })
+            .collect()
+    }
+
+    pub fn strategy(&self) -> Result<Box<dyn Strategy>> {
+        let factory = get_strategy_factory();
+        let strategy = match &self.strategy.sizing {
+            Some(spec) => {
+                let sizer = sizer_from_spec(spec, self.strategy.max_position_fraction)?;
+                factory.create_sized(&self.strategy.name, self.strategy.window, sizer)
+            },
+            None => factory.create(
+                &self.strategy.name,
+                self.strategy.window,
+                self.strategy.long_quantity,
+                self.strategy.short_quantity,
+            ),
+        };
+        strategy.ok_or_else(|| EngineError::UnknownStrategy(self.strategy.name.clone()))
+    }
+
+    /// A backtest with this configuration's capital, cost models and risk
+    /// limits.
+    pub fn backtest(&self) -> Result<Backtest> {
+        self.backtest_from(self.portfolio()?)
+    }
+
+    /// An empty portfolio with the configured capital and lot method.
+    pub fn portfolio(&self) -> Result<Portfolio> {
+        let mut portfolio = Portfolio::new(self.capital as isize);
+        if let Some(method) = &self.broker.lot_method {
+            portfolio = portfolio.with_lot_method(LotMethod::from_str(method)?);
+        }
+        Ok(portfolio)
+    }
+
+    /// A backtest with the configured broker, risk limits and execution
+    /// algo that trades `portfolio`, such as one restored from a paper
+    /// account.
+    pub fn backtest_from(&self, portfolio: Portfolio) -> Result<Backtest> {
+        let simulator = self.broker.simulator()?.with_cash(portfolio.cash);
+        Ok(Backtest::new(self.strategy.window, portfolio)
+            .with_risk_limits(self.risk.clone())
+            .with_broker(Box::new(simulator))
+            .with_executor(self.execution.executor()?))
+    }
+
+    /// Manifest for a run over `symbol` trading the bars in `data`.
+    pub fn manifest(&self, symbol: &str, data: &[DatedStockData]) -> RunManifest {
+        let symbols = vec![symbol.to_string()];
+        let mut manifest = RunManifest::for_run(&self.strategy.name, self.parameters(), symbols, data)
+            .with_api_usage(self.data.api_usage());
+        manifest.run_id = format!("{}-{}", symbol, manifest.run_id);
+        manifest
+    }
+
+    /// Exports, reports and records a finished run as configured.
+    pub fn write_outputs(&self, result: &BacktestResult, manifest: &RunManifest) -> Result<RunOutputs> {
+        let mut outputs = RunOutputs::default();
+        if let Some(root) = &self.output.dir {
+            let dir = root.join(&manifest.run_id);
+            let format = ExportFormat::from_str(&self.output.format)?;
+            outputs.exported_files = export_run(result, manifest, &dir, format)?;
+            if self.output.report {
+                let path = dir.join("report.html");
+                write_tearsheet(&ReportData::from_result(result, manifest), &path)?;
+                outputs.report = Some(path);
+            }
+        }
+        if let Some(path) = &self.output.store {
+            outputs.run_id = Some(RunStore::open(path)?.record(result, manifest)?);
+        }
+        Ok(outputs)
+    }
+}
+
+fn parse_yaml(text: &str) -> Result<Value> {
+    serde_yaml::from_str(text).map_err(|e| EngineError::Parse(format!("Invalid YAML: {}", e)))
+}
+
+fn parse_toml(text: &str) -> Result<Value> {
+    let value = toml::from_str(text).map_err(|e| EngineError::Parse(format!("Invalid TOML: {}", e)))?;
+    Ok(from_toml(value))
+}
+
+/// Converts parsed TOML into the YAML value model, with dates as strings.
+fn from_toml(value: toml::Value) -> Value {
+    match value {
+        toml::Value::String(s) => Value::String(s),
+        toml::Value::Integer(i) => Value::Number(i.into()),
+        toml::Value::Float(f) => Value::Number(f.into()),
+        toml::Value::Boolean(b) => Value::Bool(b),
+        toml::Value::Datetime(d) => Value::String(d.to_string()),
+        toml::Value::Array(items) => Value::Sequence(items.into_iter().map(from_toml).collect()),
+        toml::Value::Table(table) => Value::Mapping(
+            table.into_iter().map(|(k, v)| (Value::String(k), from_toml(v))).collect(),
+        ),
+    }
+}
+
+/// Sets `value` at the nested mapping `path`, creating mappings as needed.
+fn set_path(target: &mut Value, path: &[String], value: Value) -> Result<()> {
+    let (key, rest) = path.split_first().ok_or_else(|| EngineError::Config("empty key".to_string()))?;
+    if target.is_null() {
+        *target = Value::Mapping(Mapping::new());
+    }
+    let mapping = target.as_mapping_mut()
+        .ok_or_else(|| EngineError::Config(format!("{} is not a section", key)))?;
+    let key = Value::String(key.clone());
+    if rest.is_empty() {
+        mapping.insert(key, value);
+        return Ok(());
+    }
+    let child = mapping.entry(key).or_insert(Value::Null);
+    set_path(child, rest, value)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "
+universe: [AAPL]
+strategy:
+  name: ma_crossover
+  window: 90
+";
+
+    fn load(text: &str, vars: &[(&str, &str)]) -> Result<BacktestConfig> {
+        let vars = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<Vec<_>>();
+        BacktestConfig::from_value(parse_yaml(text)?, vars.into_iter())
+    }
+
+    #[test]
+    fn nested_env_overrides_win_over_the_file() {
+        let config = load(CONFIG, &[
+            ("TRADING__STRATEGY__WINDOW", "50"),
+            ("TRADING__DATA__RATE_LIMIT__PER_MINUTE", "30"),
+            ("TRADING__UNIVERSE", "[AAPL, MSFT]"),
+            ("OTHER__STRATEGY__WINDOW", "10"),
+        ]).unwrap();
+        assert_eq!(config.strategy.window, 50);
+        assert_eq!(config.data.rate_limit.per_minute, 30);
+        assert_eq!(config.universe, ["AAPL", "MSFT"]);
+    }
+
+    #[test]
+    fn mistyped_overrides_are_config_errors() {
+        let error = load(CONFIG, &[("TRADING__STRATEGY__WINDOW", "fifty")]).unwrap_err();
+        assert!(matches!(&error, EngineError::Config(m) if m.contains("\"fifty\"")), "{:?}", error);
+        let error = load(CONFIG, &[("TRADING__STRATEGY__WINDOW__DAYS", "5")]).unwrap_err();
+        assert!(matches!(&error, EngineError::Config(m) if m.contains("TRADING__STRATEGY__WINDOW__DAYS")), "{:?}", error);
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        for text in [format!("{}colour: blue\n", CONFIG), CONFIG.replace("window: 90", "window: 90\n  windw: 20")] {
+            let error = load(&text, &[]).unwrap_err();
+            assert!(matches!(&error, EngineError::Config(m) if m.contains("unknown field")), "{:?}", error);
+        }
+        let error = load(CONFIG, &[("TRADING__BROKER__COMISSION", "ibkr_fixed")]).unwrap_err();
+        assert!(matches!(&error, EngineError::Config(m) if m.contains("comission")), "{:?}", error);
+    }
+
+    #[test]
+    fn validation_lists_every_problem() {
+        let error = load(CONFIG, &[("TRADING__CAPITAL", "0"), ("TRADING__STRATEGY__WINDOW", "0"), ("TRADING__BROKER__SLIPPAGE", "huge")]).unwrap_err();
+        let message = error.to_string();
+        for field in ["capital", "strategy.window", "broker.slippage"] {
+            assert!(message.contains(field), "{} missing from {}", field, message);
+        }
     }
 }
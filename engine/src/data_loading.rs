@@ -1,15 +1,27 @@
-///
-/// Data loading functions.
-///
+//!
+//! Data loading functions.
+//!
 
 use crate::config::Config;
-use reqwest::blocking::{Response, get};
-use std::error::Error;
+use crate::ratelimit::{ApiUsage, Failure, RateLimiter, RateLimits};
+use reqwest::blocking::Client;
+use reqwest::StatusCode;
 use serde_json::Value;
-use chrono::{DateTime, Utc};
-use serde::Deserialize;
+use chrono::{DateTime, NaiveDate, Utc};
+use crate::clock;
+use crate::error::{EngineError, Result};
+use serde::{Deserialize, Serialize};
 use derive_new::new;
 use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
+use polars::export::arrow::temporal_conversions::{
+    timestamp_ms_to_datetime, timestamp_ns_to_datetime, timestamp_us_to_datetime,
+};
+use polars::prelude::*;
 
 
 #[allow(dead_code)]
@@ -19,7 +31,7 @@ pub struct Quote {
     pub quote: f64,
     pub change: f64,
     pub quantity: i64,
-    #[new(value = "Utc::now()")]
+    #[new(value = "clock::now()")]
     pub timestamp: DateTime<Utc>,
 }
 
@@ -38,6 +50,21 @@ pub enum Interval {
     Month,
 }
 
+impl FromStr for Interval {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "minute" => Ok(Interval::Minute),
+            "hour" => Ok(Interval::Hour),
+            "day" => Ok(Interval::Day),
+            "week" => Ok(Interval::Week),
+            "month" => Ok(Interval::Month),
+            _ => Err(EngineError::Config(format!("Unknown interval: {}", s))),
+        }
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Deserialize)]
 struct TimeSeriesResponse {
@@ -64,7 +91,7 @@ struct MetaData {
 }
 
 #[allow(dead_code)]
-#[derive(Debug, new, Clone)]
+#[derive(Debug, new, Clone, Serialize, Deserialize)]
 pub struct DatedStockData {
     pub date: String,
     pub open: f64,
@@ -88,21 +115,94 @@ struct StockData {
     volume: String,
 }
 
-#[derive(Debug)]
-pub struct AlphaVantage;
+/// Alpha Vantage client. Calls go through one HTTP client and the
+/// process-wide `RateLimiter` of the API key, so every client using a key
+/// draws on the same budget.
+/// The endpoint and key can be swapped, e.g. for a `FixtureServer`.
+#[derive(Clone)]
+pub struct AlphaVantage {
+    client: Client,
+    limits: RateLimits,
+    base_url: String,
+    api_key_env: String,
+    api_key: Option<String>,
+}
+impl Default for AlphaVantage {
+    fn default() -> Self {
+        AlphaVantage::new(&RateLimits::default())
+    }
+}
+impl fmt::Debug for AlphaVantage {
+    // Keeps the key out of logs.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AlphaVantage")
+            .field("base_url", &self.base_url)
+            .field("api_key_env", &self.api_key_env)
+            .field("api_key", &self.api_key.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
 impl AlphaVantage {
-    const BASE_URL: &str = "https://www.alphavantage.co/query";
-    const CONFIG_KEY: &str = "AV_KEY";
+    pub const BASE_URL: &str = "https://www.alphavantage.co/query";
+    pub const CONFIG_KEY: &str = "AV_KEY";
+
+    pub fn new(limits: &RateLimits) -> Self {
+        static CLIENT: OnceLock<Client> = OnceLock::new();
+        AlphaVantage {
+            client: CLIENT.get_or_init(Client::new).clone(),
+            limits: *limits,
+            base_url: Self::BASE_URL.to_string(),
+            api_key_env: Self::CONFIG_KEY.to_string(),
+            api_key: None,
+        }
+    }
+
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        self.base_url = base_url.to_string();
+        self
+    }
+
+    /// Reads the key from `name` instead of `AV_KEY`.
+    pub fn with_api_key_env(mut self, name: &str) -> Self {
+        self.api_key_env = name.to_string();
+        self
+    }
+
+    /// Uses `api_key` rather than reading one from the environment.
+    pub fn with_api_key(mut self, api_key: &str) -> Self {
+        self.api_key = Some(api_key.to_string());
+        self
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Usage of the key's budget; empty if no key is set.
+    pub fn usage(&self) -> ApiUsage {
+        self.limiter().map(|limiter| limiter.usage()).unwrap_or_default()
+    }
+
+    fn api_key(&self) -> Result<String> {
+        match &self.api_key {
+            Some(api_key) => Ok(api_key.clone()),
+            None => Config::get(self.api_key_env.clone())
+                .map_err(|_| EngineError::MissingApiKey(self.api_key_env.clone())),
+        }
+    }
+
+    fn limiter(&self) -> Result<Arc<RateLimiter>> {
+        Ok(RateLimiter::for_key(&self.api_key()?, &self.limits))
+    }
 
     pub fn get_quote(
         &self,
         ticker: String,
         quantity: i64,
-    ) -> Result<Quote, Box<dyn Error>> {
+    ) -> Result<Quote> {
         let function = "GLOBAL_QUOTE".to_string();
         let url = self.get_url(function, ticker.clone())?;
-        let response = get(url)?;
-        let json = self._unpack_response_to_json(response)?;
+        let json = self.get_json(&url)?;
         let quote = self._unpack_json_quote_data(&json["Global Quote"]["05. price"])?;
         let change = self._unpack_json_quote_data(&json["Global Quote"]["09. change"])?;
 
@@ -116,63 +216,91 @@ impl AlphaVantage {
         )
     }
 
+    /// The latest trading day so far as a daily bar, from the same
+    /// quote. The bar is still forming while the market is open.
+    pub fn get_quote_bar(&self, ticker: &str) -> Result<DatedStockData> {
+        let url = self.get_url("GLOBAL_QUOTE".to_string(), ticker.to_string())?;
+        let json = self.get_json(&url)?;
+        let quote = &json["Global Quote"];
+        let date = quote["07. latest trading day"].as_str()
+            .ok_or_else(|| EngineError::DataFetch(format!("No quote for {}", ticker)))?;
+        Ok(DatedStockData::new(
+            date.to_string(),
+            self._unpack_json_quote_data(&quote["02. open"])?,
+            self._unpack_json_quote_data(&quote["03. high"])?,
+            self._unpack_json_quote_data(&quote["04. low"])?,
+            self._unpack_json_quote_data(&quote["05. price"])?,
+            self._unpack_json_quote_data(&quote["06. volume"])? as u64,
+        ))
+    }
+
     fn get_url(
         &self,
         function: String,
         symbol: String,
-    ) -> Result<String, Box<dyn Error>> {
-        let api_key = Config::get(Self::CONFIG_KEY.to_string())?;
+    ) -> Result<String> {
+        let api_key = self.api_key()?;
         let url_suffix = format!(
             "?function={}&symbol={}&apikey={}",
             function,
             symbol,
             api_key,
         );
-        let url = [Self::BASE_URL.to_string(), url_suffix].join("");
+        let url = [self.base_url.clone(), url_suffix].join("");
 
         Ok(url)
     }
 
-    fn _unpack_json_quote_data(&self, value: &Value) -> Result<f64, Box<dyn Error>> {
+    fn _unpack_json_quote_data(&self, value: &Value) -> Result<f64> {
         let price_string = value.as_str()
-            .ok_or("Failed to convert value to string")?
+            .ok_or_else(|| EngineError::DataFetch(format!("Unexpected quote value: {}", value)))?
             .replace("\"", "");
         Ok(price_string.parse::<f64>()?)
     }
 
-    fn _unpack_response_to_json(&self, response: Response) -> Result<Value, Box<dyn Error>> {
-        Ok(response.json::<Value>()?)
+    /// Fetches `url` within the call budget. Timeouts, server errors and
+    /// per-minute rate limit responses are retried.
+    fn get_json(&self, url: &str) -> Result<Value> {
+        let limiter = self.limiter()?;
+        let timeout = self.limits.timeout();
+        limiter.call(|| {
+            let response = self.client.get(url).timeout(timeout).send()
+                .map_err(|e| match e.is_timeout() || e.is_connect() || e.is_request() {
+                    true => Failure::Transient(e.into()),
+                    false => Failure::Fatal(e.into()),
+                })?;
+            let status = response.status();
+            if status == StatusCode::TOO_MANY_REQUESTS {
+                return Err(Failure::RateLimited(EngineError::RateLimit(status.to_string())));
+            }
+            if !status.is_success() {
+                let error = EngineError::DataFetch(format!("HTTP {}", status));
+                return Err(match status.is_server_error() {
+                    true => Failure::Transient(error),
+                    false => Failure::Fatal(error),
+                });
+            }
+            let json: Value = response.json().map_err(|e| Failure::Transient(e.into()))?;
+            check_response(&json, &limiter)?;
+            Ok(json)
+        })
     }
 
     pub fn get_timeseries(
         &self,
-        ticker: &String,
+        ticker: &str,
         interval: &Interval,
-    ) -> Result<Vec<DatedStockData>, Box<dyn Error>> {
+    ) -> Result<Vec<DatedStockData>> {
         let function = self._api_function_from_interval(interval)?;
-        let url = self.get_url(function, ticker.clone())?;
-        let response = get(&url)?;
-        if !response.status().is_success() {
-            return Err(format!("Failed to get timeseries data: {}", response.status()).into());
-        }
-
-        let text = response.text()?;
-        let json: Value = serde_json::from_str(&text)?;
-
-        if let Some(info) = json.get("Information") {
-            if info.as_str().unwrap_or("").contains("API rate limit") {
-                return Err("Alpha Vantage API rate limit exceeded".into());
-            }
-        }
-
-        let timeseries = serde_json::from_str(&text)?;
+        let url = self.get_url(function, ticker.to_string())?;
+        let timeseries = serde_json::from_value(self.get_json(&url)?)?;
         self._unpack_ts_data(timeseries)
     }
 
     fn _api_function_from_interval(
         &self,
         interval: &Interval,
-    ) -> Result<String, Box<dyn Error>> {
+    ) -> Result<String> {
         let function = match interval {
             Interval::Minute => "TIME_SERIES_INTRADAY&interval=1min",
             Interval::Hour => "TIME_SERIES_INTRADAY&interval=60min",
@@ -186,7 +314,7 @@ impl AlphaVantage {
     fn _unpack_ts_data(
         &self,
         ts: TimeSeriesResponse,
-    ) -> Result<Vec<DatedStockData>, Box<dyn Error>> {
+    ) -> Result<Vec<DatedStockData>> {
         let ts_map = ts.ts_data;
         let mut rows: Vec<DatedStockData> = vec![];
         for (date, stock_data) in ts_map.iter() {
@@ -200,13 +328,14 @@ impl AlphaVantage {
             );
             rows.push(dated_stockdata);
         }
+        rows.sort_by(|a, b| a.date.cmp(&b.date));
         Ok(rows)
     }
 
     fn _ts_key_from_interval(
         &self,
         interval: &Interval,
-    ) -> Result<String, Box<dyn Error>> {
+    ) -> Result<String> {
         let key = match interval {
             Interval::Minute => "Time Series (1min)",
             Interval::Hour => "Time Series (60min)",
@@ -217,3 +346,217 @@ impl AlphaVantage {
         Ok(key.to_string())
     }
 }
+
+/// Alpha Vantage reports rate limits and bad requests with HTTP 200
+/// and a message in place of the data.
+fn check_response(json: &Value, limiter: &RateLimiter) -> std::result::Result<(), Failure> {
+    let notice = json.get("Information").or_else(|| json.get("Note")).and_then(Value::as_str);
+    if let Some(notice) = notice {
+        if notice.contains("rate limit") || notice.contains("call frequency") {
+            let error = EngineError::RateLimit(notice.to_string());
+            // The per-minute notice quotes the daily quota as well.
+            if notice.contains("per day") && !notice.contains("per minute") && !notice.contains("per second") {
+                limiter.exhaust(true);
+                return Err(Failure::Fatal(error));
+            }
+            return Err(Failure::RateLimited(error));
+        }
+    }
+    if let Some(message) = json.get("Error Message").and_then(Value::as_str) {
+        return Err(Failure::Fatal(EngineError::DataFetch(message.to_string())));
+    }
+    Ok(())
+}
+
+/// Local cache of daily bars, one `<SYMBOL>.csv` per symbol with columns
+/// `date,open,high,low,close,volume`.
+#[derive(Debug, Clone, new)]
+pub struct CsvCache {
+    pub dir: PathBuf,
+}
+impl CsvCache {
+    const HEADER: &str = "date,open,high,low,close,volume";
+
+    pub fn path(&self, symbol: &str) -> PathBuf {
+        self.dir.join(format!("{}.csv", symbol))
+    }
+
+    pub fn store(&self, symbol: &str, rows: &[DatedStockData]) -> Result<PathBuf> {
+        fs::create_dir_all(&self.dir)?;
+        let mut text = format!("{}\n", Self::HEADER);
+        for row in rows {
+            text.push_str(&format!(
+                "{},{},{},{},{},{}\n",
+                row.date, row.open, row.high, row.low, row.close, row.volume,
+            ));
+        }
+        let path = self.path(symbol);
+        fs::write(&path, text)?;
+        Ok(path)
+    }
+
+    pub fn load(&self, symbol: &str) -> Result<Vec<DatedStockData>> {
+        let path = self.path(symbol);
+        let text = fs::read_to_string(&path)
+            .map_err(|e| EngineError::DataFetch(format!("Unable to read {}: {}", path.display(), e)))?;
+        let mut rows: Vec<DatedStockData> = vec![];
+        for (number, line) in text.lines().enumerate().skip(1) {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let row = Self::parse_row(line)
+                .map_err(|e| EngineError::Parse(format!("{}:{}: {}", path.display(), number + 1, e)))?;
+            rows.push(row);
+        }
+        rows.sort_by(|a, b| a.date.cmp(&b.date));
+        Ok(rows)
+    }
+
+    pub fn is_header(line: &str) -> bool {
+        line.trim() == Self::HEADER
+    }
+
+    /// Parses one `date,open,high,low,close,volume` line.
+    pub fn parse_row(line: &str) -> Result<DatedStockData> {
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        match fields.as_slice() {
+            [date, open, high, low, close, volume] => Ok(DatedStockData::new(
+                date.to_string(),
+                open.parse()?,
+                high.parse()?,
+                low.parse()?,
+                close.parse()?,
+                volume.parse()?,
+            )),
+            _ => Err(EngineError::Parse("expected 6 fields".to_string())),
+        }
+    }
+}
+
+
+/// Names accepted for the bar time column, matched case-insensitively.
+/// `__index_level_0__` is an unnamed pandas index converted to Arrow.
+pub const DATE_COLUMNS: [&str; 5] = ["date", "timestamp", "datetime", "time", "__index_level_0__"];
+
+/// Index of the first of `names` matching one of `wanted`, ignoring case.
+pub fn find_column<S: AsRef<str>>(names: &[S], wanted: &[&str]) -> Option<usize> {
+    wanted.iter().find_map(|w| names.iter().position(|n| n.as_ref().eq_ignore_ascii_case(w)))
+}
+
+/// Reads bars from a frame with a date column (see `DATE_COLUMNS`) and
+/// `open`, `high`, `low`, `close` and `volume` columns. Dates may be
+/// strings, dates or datetimes (read as UTC); prices and volumes may be
+/// any numeric type. Row order is kept.
+pub fn bars_from_frame(frame: &DataFrame) -> Result<Vec<DatedStockData>> {
+    let names = frame.get_column_names();
+    let column = |wanted: &[&str]| -> Result<&Series> {
+        let i = find_column(&names, wanted)
+            .ok_or_else(|| EngineError::Parse(format!("Missing column: {}", wanted[0])))?;
+        Ok(&frame.get_columns()[i])
+    };
+
+    let dates = bar_dates(column(&DATE_COLUMNS)?)?;
+    let open = float_values(column(&["open"])?)?;
+    let high = float_values(column(&["high"])?)?;
+    let low = float_values(column(&["low"])?)?;
+    let close = float_values(column(&["close"])?)?;
+    let volume = float_values(column(&["volume"])?)?;
+
+    dates.into_iter().enumerate()
+        .map(|(i, date)| {
+            if !(volume[i] >= 0.0 && volume[i].is_finite()) {
+                return Err(EngineError::Parse(format!("Invalid volume {} at row {}", volume[i], i)));
+            }
+            Ok(DatedStockData::new(date, open[i], high[i], low[i], close[i], volume[i].round() as u64))
+        })
+        .collect()
+}
+
+fn float_values(series: &Series) -> Result<Vec<f64>> {
+    let values = series.cast(&DataType::Float64)
+        .map_err(|e| EngineError::Parse(format!("Column {} is not numeric: {}", series.name(), e)))?;
+    values.f64()?.into_iter().enumerate()
+        .map(|(i, v)| v.ok_or_else(|| EngineError::Parse(format!("Missing {} at row {}", series.name(), i))))
+        .collect()
+}
+
+fn bar_dates(series: &Series) -> Result<Vec<String>> {
+    let missing = |i: usize| EngineError::Parse(format!("Missing {} at row {}", series.name(), i));
+    let times = match series.dtype() {
+        DataType::Utf8 => {
+            return series.utf8()?.into_iter().enumerate()
+                .map(|(i, d)| d.map(str::to_string).ok_or_else(|| missing(i)))
+                .collect();
+        },
+        DataType::Date => series.cast(&DataType::Datetime(TimeUnit::Milliseconds, None))?,
+        DataType::Datetime(_, _) => series.clone(),
+        other => return Err(EngineError::Parse(format!("Column {} has unsupported type {}", series.name(), other))),
+    };
+    let times = times.datetime()?;
+    let to_time = match times.time_unit() {
+        TimeUnit::Nanoseconds => timestamp_ns_to_datetime,
+        TimeUnit::Microseconds => timestamp_us_to_datetime,
+        TimeUnit::Milliseconds => timestamp_ms_to_datetime,
+    };
+    times.into_iter().enumerate()
+        .map(|(i, t)| t.map(|t| clock::format_bar_time(to_time(t))).ok_or_else(|| missing(i)))
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueSeverity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, new, Serialize)]
+pub struct DataIssue {
+    pub date: String,
+    pub severity: IssueSeverity,
+    pub message: String,
+}
+
+/// Calendar days between bars beyond which a gap is reported.
+const MAX_GAP_DAYS: i64 = 5;
+
+/// Checks bars for problems that would distort a backtest: bad or
+/// out-of-order dates, duplicates, inconsistent OHLC values, non-positive
+/// prices, zero volume and gaps.
+pub fn validate_bars(rows: &[DatedStockData]) -> Vec<DataIssue> {
+    let mut issues: Vec<DataIssue> = vec![];
+    let mut previous: Option<(&str, NaiveDate)> = None;
+    for row in rows {
+        let error = |message: String| DataIssue::new(row.date.clone(), IssueSeverity::Error, message);
+        let warning = |message: String| DataIssue::new(row.date.clone(), IssueSeverity::Warning, message);
+
+        match NaiveDate::parse_from_str(row.date.get(..10).unwrap_or(&row.date), "%Y-%m-%d") {
+            Ok(day) => {
+                if let Some((previous_date, previous_day)) = previous {
+                    if row.date.as_str() == previous_date {
+                        issues.push(error("duplicate bar".to_string()));
+                    } else if row.date.as_str() < previous_date {
+                        issues.push(error(format!("out of order after {}", previous_date)));
+                    } else if (day - previous_day).num_days() > MAX_GAP_DAYS {
+                        issues.push(warning(format!("{} day gap since {}", (day - previous_day).num_days(), previous_date)));
+                    }
+                }
+                previous = Some((&row.date, day));
+            },
+            Err(e) => issues.push(error(format!("invalid date: {}", e))),
+        }
+
+        if [row.open, row.high, row.low, row.close].iter().any(|p| !p.is_finite() || *p <= 0.0) {
+            issues.push(error("non-positive or non-finite price".to_string()));
+        }
+        if row.high < row.low {
+            issues.push(error(format!("high {} below low {}", row.high, row.low)));
+        } else if row.open > row.high || row.open < row.low || row.close > row.high || row.close < row.low {
+            issues.push(error("open or close outside the high-low range".to_string()));
+        }
+        if row.volume == 0 {
+            issues.push(warning("zero volume".to_string()));
+        }
+    }
+    issues
+}
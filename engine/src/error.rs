@@ -0,0 +1,94 @@
+//!
+//! Engine errors.
+//!
+//! Every fallible engine call returns an `EngineError`. The Python
+//! bindings raise a distinct exception class per variant, all derived
+//! from `trading_engine.EngineError`, so callers can tell a rate limit
+//! from a bad configuration without parsing messages.
+//!
+
+use polars::prelude::PolarsError;
+use thiserror::Error;
+
+
+#[derive(Debug, Error)]
+pub enum EngineError {
+    /// A market data request failed or its response was unusable.
+    #[error("Data fetch failed: {0}")]
+    DataFetch(String),
+    #[error("API rate limit exceeded: {0}")]
+    RateLimit(String),
+    #[error("Missing API key: {0} is not set")]
+    MissingApiKey(String),
+    /// Input that could not be read: bar files, frames, dates, numbers.
+    #[error("{0}")]
+    Parse(String),
+    #[error("{0}")]
+    Config(String),
+    #[error("Unknown strategy: {0}")]
+    UnknownStrategy(String),
+    #[error("Order rejected: {0}")]
+    OrderRejected(String),
+    /// A broker could not be reached or answered with an error.
+    #[error("Broker error: {0}")]
+    Broker(String),
+    /// A change to orders or positions that would leave the books
+    /// inconsistent, such as an overfill or an invalid status change.
+    #[error("Accounting error: {0}")]
+    Accounting(String),
+    #[error("Run store error: {0}")]
+    Store(String),
+    #[error("Export error: {0}")]
+    Export(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// A job on a worker thread panicked.
+    #[error("Panicked: {0}")]
+    Panic(String),
+}
+
+pub type Result<T> = std::result::Result<T, EngineError>;
+
+impl From<rusqlite::Error> for EngineError {
+    fn from(e: rusqlite::Error) -> Self {
+        EngineError::Store(e.to_string())
+    }
+}
+
+/// Polars errors are mostly from reading frames; writers map to `Export`.
+impl From<PolarsError> for EngineError {
+    fn from(e: PolarsError) -> Self {
+        EngineError::Parse(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for EngineError {
+    fn from(e: serde_json::Error) -> Self {
+        EngineError::Parse(e.to_string())
+    }
+}
+
+impl From<std::num::ParseFloatError> for EngineError {
+    fn from(e: std::num::ParseFloatError) -> Self {
+        EngineError::Parse(e.to_string())
+    }
+}
+
+impl From<std::num::ParseIntError> for EngineError {
+    fn from(e: std::num::ParseIntError) -> Self {
+        EngineError::Parse(e.to_string())
+    }
+}
+
+impl From<reqwest::Error> for EngineError {
+    fn from(e: reqwest::Error) -> Self {
+        // The URL can carry an API key.
+        EngineError::DataFetch(e.without_url().to_string())
+    }
+}
+
+impl From<chrono::ParseError> for EngineError {
+    fn from(e: chrono::ParseError) -> Self {
+        EngineError::Parse(e.to_string())
+    }
+}
@@ -0,0 +1,489 @@
+//!
+//! Execution algorithms.
+//!
+//! With an `execution.algo` configured, an order that passes risk becomes
+//! a parent order and is worked in child orders over the following bars
+//! instead of going to the broker whole. Each bar the `Executor` sends
+//! one immediate-or-cancel child sized by the algo, so whatever the bar
+//! cannot fill is carried back into the parent and rescheduled:
+//!
+//! - `twap` trades evenly over `horizon` bars.
+//! - `vwap` follows the volume profile of recent bars: average volume by
+//!   time of day for intraday bars, by weekday for daily ones.
+//! - `pov` trades `participation` of each bar's volume until done.
+//! - `is` (implementation shortfall) front-loads an Almgren-Chriss
+//!   trajectory over `horizon` bars, more so the higher the `urgency`.
+//!
+//! Scheduled algos that fall behind catch up after the horizon. Limits
+//! cap any child at `max_participation` of its bar's volume, skip bars
+//! whose close is more than `limit_bps` worse than the arrival price, and
+//! expire what is left after `max_bars`. Children go straight to the
+//! broker; the parent was checked against risk limits already. Each
+//! `ParentOrder` reports its average fill price against the arrival
+//! price, the close when the order was placed.
+//!
+//! ```yaml
+//! execution:
+//!   algo: vwap
+//!   horizon: 5
+//!   max_participation: 0.05
+//!   limit_bps: 200
+//! ```
+//!
+
+use crate::data_loading::DatedStockData;
+use crate::error::{EngineError, Result};
+use crate::order::{Order, OrderBlotter, OrderId, OrderStatus, TimeInForce};
+use crate::slippage::BarContext;
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use derive_new::new;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExecutionAlgo {
+    Twap,
+    Vwap,
+    Pov,
+    #[serde(rename = "is")]
+    ImplementationShortfall,
+}
+
+impl fmt::Display for ExecutionAlgo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ExecutionAlgo::Twap => "twap",
+            ExecutionAlgo::Vwap => "vwap",
+            ExecutionAlgo::Pov => "pov",
+            ExecutionAlgo::ImplementationShortfall => "is",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for ExecutionAlgo {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "twap" => Ok(ExecutionAlgo::Twap),
+            "vwap" => Ok(ExecutionAlgo::Vwap),
+            "pov" => Ok(ExecutionAlgo::Pov),
+            "is" | "implementation_shortfall" => Ok(ExecutionAlgo::ImplementationShortfall),
+            _ => Err(EngineError::Config(format!("Unknown execution algo: {}", s))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExecutionConfig {
+    /// `twap`, `vwap`, `pov` or `is`. Unset, orders go out whole.
+    pub algo: Option<String>,
+    /// Bars the scheduled algos spread a parent over.
+    pub horizon: u32,
+    /// From 0, trading evenly, to 1, trading mostly up front. Used by `is`.
+    pub urgency: f64,
+    /// Share of each bar's volume `pov` trades.
+    pub participation: f64,
+    /// Largest child as a share of its bar's volume.
+    pub max_participation: Option<f64>,
+    /// Skip bars whose close is this many basis points worse than the
+    /// arrival price.
+    pub limit_bps: Option<f64>,
+    /// Expire what is left of a parent after this many bars worked.
+    pub max_bars: Option<u32>,
+    /// Bars of history behind the `vwap` volume profile.
+    pub volume_lookback: usize,
+}
+impl Default for ExecutionConfig {
+    fn default() -> Self {
+        ExecutionConfig {
+            algo: None,
+            horizon: 5,
+            urgency: 0.5,
+            participation: 0.1,
+            max_participation: None,
+            limit_bps: None,
+            max_bars: None,
+            volume_lookback: 20,
+        }
+    }
+}
+impl ExecutionConfig {
+    /// The executor for the configured algo, if any.
+    pub fn executor(&self) -> Result<Option<Executor>> {
+        match &self.algo {
+            Some(algo) => Ok(Some(Executor::new(ExecutionAlgo::from_str(algo)?, self.clone()))),
+            None => Ok(None),
+        }
+    }
+
+    /// Problems with the settings, for config validation.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if let Some(Err(e)) = self.algo.as_deref().map(ExecutionAlgo::from_str) {
+            problems.push(format!("algo: {}", e));
+        }
+        if self.horizon == 0 {
+            problems.push("horizon: must be at least 1".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.urgency) {
+            problems.push(format!("urgency: must be in [0, 1], got {}", self.urgency));
+        }
+        for (name, share) in [("participation", Some(self.participation)), ("max_participation", self.max_participation)] {
+            if let Some(share) = share.filter(|s| !(*s > 0.0 && *s <= 1.0)) {
+                problems.push(format!("{}: must be in (0, 1], got {}", name, share));
+            }
+        }
+        if let Some(limit) = self.limit_bps.filter(|l| *l <= 0.0) {
+            problems.push(format!("limit_bps: must be positive, got {}", limit));
+        }
+        if self.max_bars == Some(0) {
+            problems.push("max_bars: must be at least 1".to_string());
+        }
+        if self.volume_lookback == 0 {
+            problems.push("volume_lookback: must be at least 1".to_string());
+        }
+        problems
+    }
+}
+
+
+/// Average volume by slot of the trading cycle: time of day for intraday
+/// bars, weekday for daily ones.
+#[derive(Debug, Clone, Default)]
+pub struct VolumeProfile {
+    slots: BTreeMap<String, f64>,
+}
+impl VolumeProfile {
+    /// Builds the profile from the last `lookback` bars of `history`.
+    pub fn from_history(history: &[DatedStockData], lookback: usize) -> Self {
+        let mut totals: BTreeMap<String, (f64, usize)> = BTreeMap::new();
+        for bar in &history[history.len().saturating_sub(lookback)..] {
+            if let Some(slot) = Self::slot(&bar.date) {
+                let total = totals.entry(slot).or_insert((0.0, 0));
+                total.0 += bar.volume as f64;
+                total.1 += 1;
+            }
+        }
+        VolumeProfile { slots: totals.into_iter().map(|(slot, (volume, n))| (slot, volume / n as f64)).collect() }
+    }
+
+    /// The slot of a bar dated `YYYY-MM-DD` or `YYYY-MM-DD HH:MM:SS`.
+    pub fn slot(date: &str) -> Option<String> {
+        match date.split_once(' ') {
+            Some((_, time)) => Some(time.to_string()),
+            None => NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()
+                .map(|d| d.weekday().num_days_from_monday().to_string()),
+        }
+    }
+
+    /// Expected shares of volume over the `n` bars starting at `slot`,
+    /// cycling through the slots seen. Even when nothing is known.
+    pub fn weights(&self, slot: &str, n: usize) -> Vec<f64> {
+        let slots: Vec<f64> = self.slots.values().copied().collect();
+        let start = self.slots.keys().position(|s| s.as_str() >= slot).unwrap_or(0);
+        let volumes: Vec<f64> = (0..n).map(|i| slots.get((start + i) % slots.len().max(1)).copied().unwrap_or(0.0)).collect();
+        let total: f64 = volumes.iter().sum();
+        if total > 0.0 {
+            volumes.iter().map(|v| v / total).collect()
+        } else {
+            vec![1.0 / n as f64; n]
+        }
+    }
+}
+
+
+/// An order worked by an execution algo through child orders. Parents
+/// are kept in the `OrderBlotter` alongside their children.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ParentOrder {
+    /// The id of the order the strategy placed.
+    pub id: OrderId,
+    pub ticker: String,
+    pub algo: ExecutionAlgo,
+    pub quantity: i64,
+    pub arrival_time: DateTime<Utc>,
+    /// Close of the bar the order was placed on.
+    pub arrival_price: f64,
+    pub status: OrderStatus,
+    pub filled_quantity: i64,
+    pub average_price: Option<f64>,
+    /// Average price against arrival in basis points, positive when
+    /// execution cost money.
+    pub shortfall_bps: Option<f64>,
+    pub trading_costs: f64,
+    /// Bars worked, not counting those skipped for `limit_bps`.
+    pub bars: u32,
+    pub children: Vec<OrderId>,
+    /// Cumulative share of the parent due by each bar of the horizon.
+    schedule: Vec<f64>,
+    /// Unfilled quantity of children still open at the broker.
+    #[serde(skip)]
+    in_flight: i64,
+}
+impl ParentOrder {
+    pub fn new(order: Order, algo: ExecutionAlgo, arrival_price: f64) -> Self {
+        ParentOrder {
+            id: order.id,
+            ticker: order.ticker,
+            algo,
+            quantity: order.quantity,
+            arrival_time: order.timestamp,
+            arrival_price,
+            status: OrderStatus::Accepted,
+            filled_quantity: 0,
+            average_price: None,
+            shortfall_bps: None,
+            trading_costs: 0.0,
+            bars: 0,
+            children: vec![],
+            schedule: vec![],
+            in_flight: 0,
+        }
+    }
+
+    pub fn remaining_quantity(&self) -> i64 {
+        self.quantity - self.filled_quantity
+    }
+
+    pub fn is_open(&self) -> bool {
+        !self.status.is_terminal()
+    }
+
+    /// Recomputes fills and prices from the parent's `children`.
+    pub(crate) fn refresh<'a>(&mut self, children: impl Iterator<Item = &'a Order>) {
+        let (mut quantity, mut notional, mut costs, mut in_flight) = (0, 0.0, 0.0, 0);
+        for child in children {
+            for fill in &child.fills {
+                quantity += fill.quantity;
+                notional += fill.price * fill.quantity as f64;
+                costs += fill.trading_costs;
+            }
+            if child.is_open() {
+                in_flight += child.remaining_quantity();
+            }
+        }
+        self.filled_quantity = quantity;
+        self.trading_costs = costs;
+        self.in_flight = in_flight;
+        self.average_price = (quantity != 0).then(|| notional / quantity as f64);
+        self.shortfall_bps = self.average_price
+            .filter(|_| self.arrival_price > 0.0)
+            .map(|price| self.quantity.signum() as f64 * (price / self.arrival_price - 1.0) * 10_000.0);
+        if self.is_open() && self.filled_quantity != 0 {
+            self.status = if self.remaining_quantity() == 0 { OrderStatus::Filled } else { OrderStatus::PartiallyFilled };
+        }
+    }
+}
+
+
+/// Shortfall across `parents` in basis points, weighting each by the
+/// arrival value of what it filled.
+pub fn average_shortfall_bps(parents: &[ParentOrder]) -> Option<f64> {
+    let (weighted, total) = parents.iter()
+        .filter_map(|p| p.shortfall_bps.map(|bps| (bps, (p.filled_quantity as f64 * p.arrival_price).abs())))
+        .fold((0.0, 0.0), |(weighted, total), (bps, value)| (weighted + bps * value, total + value));
+    (total > 0.0).then(|| weighted / total)
+}
+
+
+/// Turns orders into parent orders and releases their children bar by
+/// bar.
+#[derive(Debug, Clone, new)]
+pub struct Executor {
+    algo: ExecutionAlgo,
+    config: ExecutionConfig,
+}
+impl Executor {
+    pub fn algo(&self) -> ExecutionAlgo {
+        self.algo
+    }
+
+    /// Takes `order` on as a parent in `blotter`; its first child goes
+    /// out with the next `slice` for its ticker.
+    pub fn start(&self, order: Order, arrival_price: f64, blotter: &mut OrderBlotter) {
+        info!("Working order {} for {} {} by {}", order.id, order.quantity, order.ticker, self.algo);
+        blotter.work(ParentOrder::new(order, self.algo, arrival_price));
+    }
+
+    /// The children due on the current bar of `ticker`, the last bar of
+    /// `history`, recorded against their parents in `blotter`.
+    pub fn slice(&self, ticker: &str, history: &[DatedStockData], bar: &BarContext, blotter: &mut OrderBlotter) -> Vec<Order> {
+        blotter.refresh_parents();
+        let mut children = vec![];
+        for parent in blotter.parents_mut().iter_mut().filter(|p| p.ticker == ticker && p.is_open()) {
+            if let Some(child) = self.child(parent, history, bar) {
+                parent.children.push(child.id);
+                children.push(child);
+            }
+        }
+        children
+    }
+
+    fn child(&self, parent: &mut ParentOrder, history: &[DatedStockData], bar: &BarContext) -> Option<Order> {
+        let config = &self.config;
+        if config.max_bars.is_some_and(|max| parent.bars >= max) {
+            warn!("Parent order {} expired after {} bars with {} unfilled", parent.id, parent.bars, parent.remaining_quantity());
+            parent.status = OrderStatus::Expired;
+            return None;
+        }
+        let side = parent.quantity.signum();
+        if let Some(limit) = config.limit_bps.filter(|_| parent.arrival_price > 0.0) {
+            let drift_bps = side as f64 * (bar.close / parent.arrival_price - 1.0) * 10_000.0;
+            if drift_bps > limit {
+                info!("Parent order {}: close {:.2} is {:.0}bps through arrival; waiting", parent.id, bar.close, drift_bps);
+                return None;
+            }
+        }
+        parent.bars += 1;
+        if parent.schedule.is_empty() {
+            parent.schedule = schedule(parent.algo, config, history);
+        }
+
+        let sent = (parent.filled_quantity + parent.in_flight).abs();
+        let mut quantity = match parent.algo {
+            ExecutionAlgo::Pov => (config.participation * bar.volume as f64).floor() as i64,
+            _ => {
+                let due = parent.schedule.get(parent.bars as usize - 1).copied().unwrap_or(1.0);
+                (due * parent.quantity.abs() as f64).round() as i64 - sent
+            },
+        };
+        if let Some(max_participation) = config.max_participation {
+            quantity = quantity.min((max_participation * bar.volume as f64).floor() as i64);
+        }
+        let quantity = quantity.min(parent.quantity.abs() - sent);
+        if quantity <= 0 {
+            return None;
+        }
+        Some(Order::new(parent.ticker.clone(), side * quantity).with_time_in_force(TimeInForce::ImmediateOrCancel))
+    }
+}
+
+/// Cumulative share of a parent due by each bar of the horizon; empty
+/// for `pov`, which follows the market instead.
+fn schedule(algo: ExecutionAlgo, config: &ExecutionConfig, history: &[DatedStockData]) -> Vec<f64> {
+    let n = config.horizon as usize;
+    let weights = match algo {
+        ExecutionAlgo::Pov => return vec![],
+        ExecutionAlgo::Twap => vec![1.0 / n as f64; n],
+        ExecutionAlgo::Vwap => {
+            let profile = VolumeProfile::from_history(history, config.volume_lookback);
+            let slot = history.last().and_then(|b| VolumeProfile::slot(&b.date)).unwrap_or_default();
+            profile.weights(&slot, n)
+        },
+        ExecutionAlgo::ImplementationShortfall => {
+            // Almgren-Chriss holdings decay as sinh(kappa (T - t)) / sinh(kappa T).
+            let kappa = 5.0 * config.urgency;
+            let held = |t: f64| if kappa > 1e-9 { (kappa * (1.0 - t)).sinh() / kappa.sinh() } else { 1.0 - t };
+            (1..=n).map(|k| held((k - 1) as f64 / n as f64) - held(k as f64 / n as f64)).collect()
+        },
+    };
+    weights.iter()
+        .scan(0.0, |due, w| {
+            *due += w;
+            Some(*due)
+        })
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::order::Fill;
+
+    fn assert_close(actual: &[f64], expected: &[f64]) {
+        assert_eq!(actual.len(), expected.len(), "{:?} != {:?}", actual, expected);
+        assert!(actual.iter().zip(expected).all(|(a, e)| (a - e).abs() < 1e-9), "{:?} != {:?}", actual, expected);
+    }
+
+    /// Two weeks of daily bars from Monday 2024-03-04, trading 100, 200,
+    /// 300, 200 and 200 shares Monday to Friday.
+    fn history() -> Vec<DatedStockData> {
+        let monday = NaiveDate::from_ymd_opt(2024, 3, 4).unwrap();
+        (0..14)
+            .map(|d| monday + chrono::Duration::days(d))
+            .filter(|d| d.weekday().num_days_from_monday() < 5)
+            .map(|d| {
+                let volume = [100, 200, 300, 200, 200][d.weekday().num_days_from_monday() as usize];
+                DatedStockData::new(d.format("%Y-%m-%d").to_string(), 100.0, 100.0, 100.0, 100.0, volume)
+            })
+            .collect()
+    }
+
+    fn bar(close: f64) -> BarContext {
+        BarContext::new(close, close, close, 1_000, 0.01)
+    }
+
+    fn filled(quantity: i64, fills: &[(i64, f64)]) -> Order {
+        let mut order = Order::new("TEST".to_string(), quantity);
+        order.transition(OrderStatus::Accepted).unwrap();
+        for (quantity, price) in fills {
+            order.apply_fill(Fill::new(order.id, "TEST".to_string(), Utc::now(), *quantity, *price, 1.0)).unwrap();
+        }
+        order
+    }
+
+    #[test]
+    fn weights_follow_the_profile_from_the_slot() {
+        let profile = VolumeProfile::from_history(&history(), 20);
+        assert_close(&profile.weights("1", 3), &[200.0 / 700.0, 300.0 / 700.0, 200.0 / 700.0]);
+        // Friday wraps round to the next week.
+        assert_close(&profile.weights("4", 3), &[0.4, 0.2, 0.4]);
+        assert_close(&VolumeProfile::default().weights("1", 4), &[0.25; 4]);
+    }
+
+    #[test]
+    fn schedules_end_with_the_whole_parent_due() {
+        let config = ExecutionConfig { horizon: 4, ..Default::default() };
+        let history = history();
+        assert_close(&schedule(ExecutionAlgo::Twap, &config, &history), &[0.25, 0.5, 0.75, 1.0]);
+        assert!(schedule(ExecutionAlgo::Pov, &config, &history).is_empty());
+
+        // The last bar is a Friday, so VWAP starts there and wraps.
+        let vwap = schedule(ExecutionAlgo::Vwap, &config, &history);
+        assert_close(&vwap, &[0.25, 0.375, 0.625, 1.0]);
+
+        let patient = ExecutionConfig { urgency: 0.0, ..config.clone() };
+        assert_close(&schedule(ExecutionAlgo::ImplementationShortfall, &patient, &history), &[0.25, 0.5, 0.75, 1.0]);
+        let urgent = schedule(ExecutionAlgo::ImplementationShortfall, &ExecutionConfig { urgency: 1.0, ..config }, &history);
+        assert!(urgent[0] > 0.5 && urgent.windows(2).all(|w| w[0] < w[1]), "{:?}", urgent);
+        assert!((urgent[3] - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn refresh_sums_the_children() {
+        let mut parent = ParentOrder::new(Order::new("TEST".to_string(), 10), ExecutionAlgo::Twap, 100.0);
+        let children = [filled(6, &[(4, 101.0)]), filled(4, &[(4, 103.0)])];
+        parent.refresh(children.iter());
+
+        assert_eq!((parent.filled_quantity, parent.in_flight, parent.status), (8, 2, OrderStatus::PartiallyFilled));
+        assert_close(&[parent.average_price.unwrap(), parent.shortfall_bps.unwrap(), parent.trading_costs], &[102.0, 200.0, 2.0]);
+
+        let mut sell = ParentOrder::new(Order::new("TEST".to_string(), -10), ExecutionAlgo::Twap, 100.0);
+        sell.refresh([filled(-10, &[(-10, 99.0)])].iter());
+        assert_eq!((sell.filled_quantity, sell.in_flight, sell.status), (-10, 0, OrderStatus::Filled));
+        assert_close(&[sell.shortfall_bps.unwrap()], &[100.0]);
+    }
+
+    #[test]
+    fn bars_through_the_limit_are_not_worked() {
+        let config = ExecutionConfig { horizon: 4, limit_bps: Some(100.0), ..Default::default() };
+        let executor = Executor::new(ExecutionAlgo::Twap, config);
+        let mut parent = ParentOrder::new(Order::new("TEST".to_string(), 100), ExecutionAlgo::Twap, 100.0);
+
+        assert!(executor.child(&mut parent, &history(), &bar(102.0)).is_none());
+        assert_eq!(parent.bars, 0);
+        assert!(parent.schedule.is_empty());
+
+        let child = executor.child(&mut parent, &history(), &bar(100.5)).unwrap();
+        assert_eq!((child.quantity, parent.bars), (25, 1));
+    }
+}
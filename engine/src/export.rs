@@ -0,0 +1,258 @@
+//!
+//! Backtest result export.
+//!
+//! Writes a run's trades, order blotter, parent orders, equity curve and
+//! metrics as CSV, JSON or Parquet tables alongside a `manifest.json`
+//! describing the run. Column names and order are part of the schema;
+//! bump `SCHEMA_VERSION` when they change.
+//!
+//! | table      | columns                                                               |
+//! |------------|-----------------------------------------------------------------------|
+//! | trades     | timestamp, ticker, quantity, price                                    |
+//! | orders     | order_id, timestamp, ticker, quantity, filled_quantity, average_price, status |
+//! | executions | order_id, arrival_time, ticker, algo, quantity, filled_quantity, arrival_price, average_price, shortfall_bps, trading_costs, bars, n_children, status |
+//! | equity     | timestamp, equity, cash, drawdown                                     |
+//! | metrics    | one row of `Metrics` fields                                           |
+//!
+
+use crate::backtest::BacktestResult;
+use crate::clock::parse_bar_time;
+use crate::data_loading::DatedStockData;
+use crate::error::{EngineError, Result};
+use crate::metrics::{drawdowns, Metrics};
+use crate::ratelimit::ApiUsage;
+use chrono::{DateTime, SecondsFormat, Utc};
+use polars::prelude::*;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+
+pub const SCHEMA_VERSION: u32 = 2;
+pub const ENGINE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+    Parquet,
+}
+impl ExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Json => "json",
+            ExportFormat::Parquet => "parquet",
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(ExportFormat::Csv),
+            "json" => Ok(ExportFormat::Json),
+            "parquet" => Ok(ExportFormat::Parquet),
+            _ => Err(EngineError::Config(format!("Unknown export format: {}", s))),
+        }
+    }
+}
+
+
+/// Everything needed to identify and reproduce a run.
+#[derive(Debug, Clone, Serialize)]
+pub struct RunManifest {
+    pub run_id: String,
+    pub schema_version: u32,
+    pub engine_version: String,
+    pub created_at: DateTime<Utc>,
+    pub strategy: String,
+    pub parameters: BTreeMap<String, String>,
+    pub symbols: Vec<String>,
+    pub data_start: Option<DateTime<Utc>>,
+    pub data_end: Option<DateTime<Utc>>,
+    /// Alpha Vantage usage of the run's API key when the manifest was
+    /// written, for runs that fetched their data from it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_usage: Option<ApiUsage>,
+    pub files: Vec<String>,
+}
+impl RunManifest {
+    /// Describes a run over `symbols`, the configured universe, and the
+    /// bars in `data`, the range actually loaded.
+    pub fn for_run(
+        strategy: &str,
+        parameters: BTreeMap<String, String>,
+        symbols: Vec<String>,
+        data: &[DatedStockData],
+    ) -> Self {
+        let created_at = Utc::now();
+        let bar_time = |bar: Option<&DatedStockData>| bar.and_then(|b| parse_bar_time(&b.date).ok());
+
+        RunManifest {
+            run_id: format!("{}-{}-{:08x}", strategy, created_at.format("%Y%m%dT%H%M%S"), rand::random::<u32>()),
+            schema_version: SCHEMA_VERSION,
+            engine_version: ENGINE_VERSION.to_string(),
+            created_at,
+            strategy: strategy.to_string(),
+            parameters,
+            symbols,
+            data_start: bar_time(data.first()),
+            data_end: bar_time(data.last()),
+            api_usage: None,
+            files: vec![],
+        }
+    }
+
+    pub fn with_api_usage(mut self, api_usage: Option<ApiUsage>) -> Self {
+        self.api_usage = api_usage;
+        self
+    }
+}
+
+fn timestamp(time: &DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+pub fn trades_frame(result: &BacktestResult) -> PolarsResult<DataFrame> {
+    let trades = &result.portfolio.trades;
+    df!(
+        "timestamp" => trades.iter().map(|t| timestamp(&t.timestamp)).collect::<Vec<_>>(),
+        "ticker" => trades.iter().map(|t| t.ticker.clone()).collect::<Vec<_>>(),
+        "quantity" => trades.iter().map(|t| t.quantity).collect::<Vec<_>>(),
+        "price" => trades.iter().map(|t| t.price).collect::<Vec<_>>(),
+    )
+}
+
+pub fn orders_frame(result: &BacktestResult) -> PolarsResult<DataFrame> {
+    let orders = result.portfolio.blotter.orders();
+    df!(
+        "order_id" => orders.iter().map(|o| o.id.0).collect::<Vec<_>>(),
+        "timestamp" => orders.iter().map(|o| timestamp(&o.timestamp)).collect::<Vec<_>>(),
+        "ticker" => orders.iter().map(|o| o.ticker.clone()).collect::<Vec<_>>(),
+        "quantity" => orders.iter().map(|o| o.quantity).collect::<Vec<_>>(),
+        "filled_quantity" => orders.iter().map(|o| o.filled_quantity).collect::<Vec<_>>(),
+        "average_price" => orders.iter().map(|o| o.average_fill_price()).collect::<Vec<_>>(),
+        "status" => orders.iter().map(|o| o.status.to_string()).collect::<Vec<_>>(),
+    )
+}
+
+pub fn executions_frame(result: &BacktestResult) -> PolarsResult<DataFrame> {
+    let parents = result.portfolio.blotter.parents();
+    df!(
+        "order_id" => parents.iter().map(|p| p.id.0).collect::<Vec<_>>(),
+        "arrival_time" => parents.iter().map(|p| timestamp(&p.arrival_time)).collect::<Vec<_>>(),
+        "ticker" => parents.iter().map(|p| p.ticker.clone()).collect::<Vec<_>>(),
+        "algo" => parents.iter().map(|p| p.algo.to_string()).collect::<Vec<_>>(),
+        "quantity" => parents.iter().map(|p| p.quantity).collect::<Vec<_>>(),
+        "filled_quantity" => parents.iter().map(|p| p.filled_quantity).collect::<Vec<_>>(),
+        "arrival_price" => parents.iter().map(|p| p.arrival_price).collect::<Vec<_>>(),
+        "average_price" => parents.iter().map(|p| p.average_price).collect::<Vec<_>>(),
+        "shortfall_bps" => parents.iter().map(|p| p.shortfall_bps).collect::<Vec<_>>(),
+        "trading_costs" => parents.iter().map(|p| p.trading_costs).collect::<Vec<_>>(),
+        "bars" => parents.iter().map(|p| p.bars).collect::<Vec<_>>(),
+        "n_children" => parents.iter().map(|p| p.children.len() as u32).collect::<Vec<_>>(),
+        "status" => parents.iter().map(|p| p.status.to_string()).collect::<Vec<_>>(),
+    )
+}
+
+pub fn equity_frame(result: &BacktestResult) -> PolarsResult<DataFrame> {
+    let curve = result.equity_curve;
+    df!(
+        "timestamp" => curve.iter().map(|p| timestamp(&p.timestamp)).collect::<Vec<_>>(),
+        "equity" => curve.iter().map(|p| p.equity).collect::<Vec<_>>(),
+        "cash" => curve.iter().map(|p| p.cash).collect::<Vec<_>>(),
+        "drawdown" => drawdowns(curve),
+    )
+}
+
+pub fn metrics_frame(metrics: &Metrics) -> PolarsResult<DataFrame> {
+    df!(
+        "initial_equity" => [metrics.initial_equity],
+        "final_equity" => [metrics.final_equity],
+        "total_return" => [metrics.total_return],
+        "annualized_return" => [metrics.annualized_return],
+        "annualized_volatility" => [metrics.annualized_volatility],
+        "sharpe_ratio" => [metrics.sharpe_ratio],
+        "max_drawdown" => [metrics.max_drawdown],
+        "n_trades" => [metrics.n_trades as u64],
+    )
+}
+
+fn export_error(e: PolarsError) -> EngineError {
+    EngineError::Export(e.to_string())
+}
+
+fn write_frame(frame: &mut DataFrame, path: &Path, format: ExportFormat) -> Result<()> {
+    let file = File::create(path)?;
+    match format {
+        ExportFormat::Csv => CsvWriter::new(file).finish(frame).map_err(export_error)?,
+        ExportFormat::Json => JsonWriter::new(file).with_json_format(JsonFormat::Json).finish(frame).map_err(export_error)?,
+        ExportFormat::Parquet => {
+            ParquetWriter::new(file).finish(frame).map_err(export_error)?;
+        },
+    }
+    Ok(())
+}
+
+/// Writes every table of `result` to `dir` in `format`, then the manifest.
+/// Returns the paths written.
+pub fn export_run(
+    result: &BacktestResult,
+    manifest: &RunManifest,
+    dir: &Path,
+    format: ExportFormat,
+) -> Result<Vec<PathBuf>> {
+    fs::create_dir_all(dir)?;
+
+    let tables = [
+        ("trades", trades_frame(result).map_err(export_error)?),
+        ("orders", orders_frame(result).map_err(export_error)?),
+        ("executions", executions_frame(result).map_err(export_error)?),
+        ("equity", equity_frame(result).map_err(export_error)?),
+        ("metrics", metrics_frame(&result.metrics()).map_err(export_error)?),
+    ];
+
+    let mut written: Vec<PathBuf> = vec![];
+    for (name, mut frame) in tables {
+        let path = dir.join(format!("{}.{}", name, format.extension()));
+        write_frame(&mut frame, &path, format)?;
+        written.push(path);
+    }
+
+    let mut manifest = manifest.clone();
+    manifest.files = written.iter()
+        .filter_map(|p| p.file_name().map(|n| n.to_string_lossy().to_string()))
+        .collect();
+    let manifest_path = dir.join("manifest.json");
+    serde_json::to_writer_pretty(File::create(&manifest_path)?, &manifest)?;
+    written.push(manifest_path);
+
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest_describes_the_loaded_universe() {
+        let data: Vec<DatedStockData> = ["2024-01-02", "2024-01-03", "2024-01-04"].iter()
+            .map(|date| DatedStockData::new(date.to_string(), 1.0, 1.0, 1.0, 1.0, 1))
+            .collect();
+        let manifest = |data: &[DatedStockData]| {
+            RunManifest::for_run("ma_crossover", BTreeMap::new(), vec!["AAPL".to_string()], data)
+        };
+
+        let first = manifest(&data);
+        assert_eq!(first.symbols, vec!["AAPL".to_string()]);
+        assert_eq!(first.data_start, parse_bar_time("2024-01-02").ok());
+        assert_eq!(first.data_end, parse_bar_time("2024-01-04").ok());
+        assert_ne!(first.run_id, manifest(&data).run_id);
+        assert_eq!(manifest(&[]).data_start, None);
+    }
+}
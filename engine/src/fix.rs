@@ -0,0 +1,1584 @@
+//!
+//! FIX 4.4 order entry.
+//!
+//! `FixBroker` implements `Broker` over a FIX 4.4 initiator session to an
+//! execution desk. Orders go out as NewOrderSingle market orders whose
+//! ClOrdID is `te-<order id>`, cancels as OrderCancelRequest, and each
+//! ExecutionReport that trades becomes a `Confirm` and from it a fill.
+//! Order entry has no account queries, so positions and cash are those
+//! of the executions reported.
+//!
+//! The session logs on when the broker is built and is kept alive by a
+//! background thread answering heartbeats and test requests. Sequence
+//! numbers and the orders sent are kept under `store_dir`, so a restart
+//! logs on where the last run left off: a gap in what arrives is asked
+//! for again with a ResendRequest, and the desk's resend requests are
+//! answered by sending orders again flagged PossDupFlag and gap filling
+//! the session messages. Reports seen twice are recognised by their
+//! cumulative quantity. Brokers built from the same settings share one
+//! session, and a broker whose session dropped reconnects on next use.
+//!
+//! `MockAcceptor` is a local stand-in for the desk. Market orders fill at
+//! the price set for their symbol, optionally a few shares at a time, and
+//! it can lose execution reports to exercise resends.
+//!
+//! ```yaml
+//! paper:
+//!   venue: fix
+//!   fix:
+//!     host: 127.0.0.1
+//!     port: 9878
+//!     sender_comp_id: ENGINE
+//!     target_comp_id: DESK
+//! ```
+//!
+
+use crate::broker::{Account, Broker};
+use crate::clock;
+use crate::error::{EngineError, Result};
+use crate::order::{Confirm, Fill, Order, OrderId, TimeInForce};
+use crate::paper::write_atomic;
+use crate::slippage::BarContext;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+
+pub const BEGIN_STRING: &str = "FIX.4.4";
+
+/// How often session threads wake to read, send heartbeats and check
+/// for a stop.
+const TICK: Duration = Duration::from_millis(100);
+
+pub mod tag {
+    pub const AVG_PX: u32 = 6;
+    pub const BEGIN_SEQ_NO: u32 = 7;
+    pub const BEGIN_STRING: u32 = 8;
+    pub const BODY_LENGTH: u32 = 9;
+    pub const CHECK_SUM: u32 = 10;
+    pub const CL_ORD_ID: u32 = 11;
+    pub const COMMISSION: u32 = 12;
+    pub const CUM_QTY: u32 = 14;
+    pub const END_SEQ_NO: u32 = 16;
+    pub const EXEC_ID: u32 = 17;
+    pub const HANDL_INST: u32 = 21;
+    pub const LAST_PX: u32 = 31;
+    pub const LAST_QTY: u32 = 32;
+    pub const MSG_SEQ_NUM: u32 = 34;
+    pub const MSG_TYPE: u32 = 35;
+    pub const NEW_SEQ_NO: u32 = 36;
+    pub const ORDER_ID: u32 = 37;
+    pub const ORDER_QTY: u32 = 38;
+    pub const ORD_STATUS: u32 = 39;
+    pub const ORD_TYPE: u32 = 40;
+    pub const ORIG_CL_ORD_ID: u32 = 41;
+    pub const POSS_DUP_FLAG: u32 = 43;
+    pub const REF_SEQ_NUM: u32 = 45;
+    pub const SENDER_COMP_ID: u32 = 49;
+    pub const SENDING_TIME: u32 = 52;
+    pub const SIDE: u32 = 54;
+    pub const SYMBOL: u32 = 55;
+    pub const TARGET_COMP_ID: u32 = 56;
+    pub const TEXT: u32 = 58;
+    pub const TIME_IN_FORCE: u32 = 59;
+    pub const TRANSACT_TIME: u32 = 60;
+    pub const ENCRYPT_METHOD: u32 = 98;
+    pub const CXL_REJ_REASON: u32 = 102;
+    pub const HEART_BT_INT: u32 = 108;
+    pub const TEST_REQ_ID: u32 = 112;
+    pub const ORIG_SENDING_TIME: u32 = 122;
+    pub const GAP_FILL_FLAG: u32 = 123;
+    pub const RESET_SEQ_NUM_FLAG: u32 = 141;
+    pub const EXEC_TYPE: u32 = 150;
+    pub const LEAVES_QTY: u32 = 151;
+    pub const CXL_REJ_RESPONSE_TO: u32 = 434;
+}
+
+pub mod msg_type {
+    pub const HEARTBEAT: &str = "0";
+    pub const TEST_REQUEST: &str = "1";
+    pub const RESEND_REQUEST: &str = "2";
+    pub const REJECT: &str = "3";
+    pub const SEQUENCE_RESET: &str = "4";
+    pub const LOGOUT: &str = "5";
+    pub const EXECUTION_REPORT: &str = "8";
+    pub const ORDER_CANCEL_REJECT: &str = "9";
+    pub const LOGON: &str = "A";
+    pub const NEW_ORDER_SINGLE: &str = "D";
+    pub const ORDER_CANCEL_REQUEST: &str = "F";
+}
+
+/// Standard header fields, written by `Session` rather than the caller.
+const HEADER: &[u32] = &[
+    tag::BEGIN_STRING, tag::BODY_LENGTH, tag::CHECK_SUM, tag::MSG_TYPE, tag::SENDER_COMP_ID,
+    tag::TARGET_COMP_ID, tag::MSG_SEQ_NUM, tag::SENDING_TIME, tag::POSS_DUP_FLAG, tag::ORIG_SENDING_TIME,
+];
+
+pub fn utc_timestamp(time: DateTime<Utc>) -> String {
+    time.format("%Y%m%d-%H:%M:%S%.3f").to_string()
+}
+
+pub fn parse_utc_timestamp(text: &str) -> Result<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(text, "%Y%m%d-%H:%M:%S%.f")
+        .or_else(|_| NaiveDateTime::parse_from_str(text, "%Y%m%d-%H:%M:%S"))
+        .map(|t| t.and_utc())
+        .map_err(|e| EngineError::Parse(format!("Invalid UTCTimestamp {:?}: {}", text, e)))
+}
+
+
+/// A FIX message as ordered tag=value fields, without the framing
+/// fields BeginString, BodyLength and CheckSum.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    fields: Vec<(u32, String)>,
+}
+impl Message {
+    pub fn new(msg_type: &str) -> Self {
+        Message { fields: vec![(tag::MSG_TYPE, msg_type.to_string())] }
+    }
+
+    pub fn with(mut self, tag: u32, value: impl ToString) -> Self {
+        self.set(tag, value);
+        self
+    }
+
+    /// Replaces the field if present, otherwise appends it.
+    pub fn set(&mut self, tag: u32, value: impl ToString) {
+        let value = value.to_string();
+        match self.fields.iter_mut().find(|(t, _)| *t == tag) {
+            Some(field) => field.1 = value,
+            None => self.fields.push((tag, value)),
+        }
+    }
+
+    pub fn get(&self, tag: u32) -> Option<&str> {
+        self.fields.iter().find(|(t, _)| *t == tag).map(|(_, v)| v.as_str())
+    }
+
+    pub fn require(&self, tag: u32) -> Result<&str> {
+        self.get(tag)
+            .ok_or_else(|| EngineError::Broker(format!("FIX {} message without tag {}: {}", self.msg_type(), tag, self)))
+    }
+
+    pub fn number<T: FromStr>(&self, tag: u32) -> Result<T> {
+        let value = self.require(tag)?;
+        value.parse()
+            .map_err(|_| EngineError::Broker(format!("FIX tag {} is not a number: {:?}", tag, value)))
+    }
+
+    pub fn msg_type(&self) -> &str {
+        self.get(tag::MSG_TYPE).unwrap_or_default()
+    }
+
+    pub fn seq_num(&self) -> Option<u64> {
+        self.get(tag::MSG_SEQ_NUM).and_then(|s| s.parse().ok())
+    }
+
+    /// Session-level rather than application messages.
+    pub fn is_admin(&self) -> bool {
+        use msg_type::*;
+        matches!(self.msg_type(), HEARTBEAT | TEST_REQUEST | RESEND_REQUEST | REJECT | SEQUENCE_RESET | LOGOUT | LOGON)
+    }
+
+    pub fn is_poss_dup(&self) -> bool {
+        self.get(tag::POSS_DUP_FLAG) == Some("Y")
+    }
+
+    /// The message with its standard header for sending as `seq`.
+    fn stamped(&self, sender: &str, target: &str, seq: u64, sending_time: &str) -> Message {
+        let mut fields = vec![
+            (tag::MSG_TYPE, self.msg_type().to_string()),
+            (tag::SENDER_COMP_ID, sender.to_string()),
+            (tag::TARGET_COMP_ID, target.to_string()),
+            (tag::MSG_SEQ_NUM, seq.to_string()),
+            (tag::SENDING_TIME, sending_time.to_string()),
+        ];
+        for tag in [tag::POSS_DUP_FLAG, tag::ORIG_SENDING_TIME] {
+            if let Some(value) = self.get(tag) {
+                fields.push((tag, value.to_string()));
+            }
+        }
+        fields.extend(self.fields.iter().filter(|(t, _)| !HEADER.contains(t)).cloned());
+        Message { fields }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = format!("{}={}\x01", tag::MSG_TYPE, self.msg_type());
+        for (tag, value) in self.fields.iter().filter(|(t, _)| !matches!(*t, tag::BEGIN_STRING | tag::BODY_LENGTH | tag::CHECK_SUM | tag::MSG_TYPE)) {
+            body.push_str(&format!("{}={}\x01", tag, value));
+        }
+        let mut text = format!("{}={}\x01{}={}\x01{}", tag::BEGIN_STRING, BEGIN_STRING, tag::BODY_LENGTH, body.len(), body);
+        text.push_str(&format!("{}={:03}\x01", tag::CHECK_SUM, checksum(text.as_bytes())));
+        text.into_bytes()
+    }
+
+    /// Parses one framed message, checking its BeginString, BodyLength
+    /// and CheckSum.
+    pub fn decode(bytes: &[u8]) -> Result<Message> {
+        let garbled = |why: &str| EngineError::Broker(format!("Garbled FIX message ({}): {}", why, String::from_utf8_lossy(bytes).replace('\x01', "|")));
+        let text = std::str::from_utf8(bytes).map_err(|_| garbled("not UTF-8"))?;
+        let trailer = text.strip_suffix('\x01')
+            .and_then(|t| t.rfind("\x0110=").map(|i| i + 1))
+            .ok_or_else(|| garbled("no CheckSum"))?;
+        let expected: u32 = text[trailer + 3..text.len() - 1].parse().map_err(|_| garbled("bad CheckSum"))?;
+        if checksum(&bytes[..trailer]) != expected {
+            return Err(garbled("CheckSum mismatch"));
+        }
+
+        let mut fields = vec![];
+        for field in text[..trailer].split('\x01').filter(|f| !f.is_empty()) {
+            let (tag, value) = field.split_once('=').ok_or_else(|| garbled("field without '='"))?;
+            fields.push((tag.parse::<u32>().map_err(|_| garbled("bad tag"))?, value.to_string()));
+        }
+        match fields.as_slice() {
+            [(tag::BEGIN_STRING, begin), (tag::BODY_LENGTH, length), (tag::MSG_TYPE, _), ..] => {
+                if begin != BEGIN_STRING {
+                    return Err(garbled("unsupported BeginString"));
+                }
+                let start = text.find("\x0135=").ok_or_else(|| garbled("no MsgType"))? + 1;
+                if length.parse::<usize>().ok() != Some(trailer - start) {
+                    return Err(garbled("BodyLength mismatch"));
+                }
+            },
+            _ => return Err(garbled("header out of order")),
+        }
+        fields.drain(..2);
+        Ok(Message { fields })
+    }
+
+    /// Splits the first complete message off the front of `buffer`.
+    fn take_frame(buffer: &mut Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if buffer.len() >= 2 && !buffer.starts_with(b"8=") {
+            return Err(EngineError::Broker("FIX stream out of step: expected BeginString".to_string()));
+        }
+        let mut separators = buffer.iter().enumerate().filter(|(_, b)| **b == 1).map(|(i, _)| i);
+        let (first, second) = match (separators.next(), separators.next()) {
+            (Some(first), Some(second)) => (first, second),
+            _ => return Ok(None),
+        };
+        let length = std::str::from_utf8(&buffer[first + 1..second]).ok()
+            .and_then(|f| f.strip_prefix("9="))
+            .and_then(|l| l.parse::<usize>().ok())
+            .ok_or_else(|| EngineError::Broker("FIX stream out of step: expected BodyLength".to_string()))?;
+        // The body, then "10=nnn" and its separator.
+        let total = second + 1 + length + 7;
+        if buffer.len() < total {
+            return Ok(None);
+        }
+        Ok(Some(buffer.drain(..total).collect()))
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fields: Vec<String> = self.fields.iter().map(|(t, v)| format!("{}={}", t, v)).collect();
+        write!(f, "{}", fields.join("|"))
+    }
+}
+
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().map(|b| *b as u32).sum::<u32>() % 256
+}
+
+/// Reads whatever arrives within the stream's read timeout. `None` once
+/// the other side has closed the connection. Garbled messages are
+/// dropped, as FIX requires.
+fn read_messages(stream: &mut TcpStream, buffer: &mut Vec<u8>) -> Result<Option<Vec<Message>>> {
+    let mut chunk = [0u8; 4096];
+    match stream.read(&mut chunk) {
+        Ok(0) => return Ok(None),
+        Ok(n) => buffer.extend_from_slice(&chunk[..n]),
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {},
+        Err(e) => return Err(e.into()),
+    }
+    let mut messages = vec![];
+    while let Some(frame) = Message::take_frame(buffer)? {
+        match Message::decode(&frame) {
+            Ok(message) => messages.push(message),
+            Err(e) => warn!("{}", e),
+        }
+    }
+    Ok(Some(messages))
+}
+
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SeqNums {
+    next_out: u64,
+    next_in: u64,
+}
+
+/// Sequence numbers of one end of a session and the application
+/// messages it sent, kept on disk when it has a path.
+#[derive(Debug)]
+struct SeqStore {
+    next_out: u64,
+    next_in: u64,
+    sent: BTreeMap<u64, Message>,
+    path: Option<PathBuf>,
+}
+impl SeqStore {
+    fn memory() -> Self {
+        SeqStore { next_out: 1, next_in: 1, sent: BTreeMap::new(), path: None }
+    }
+
+    /// Resumes `<dir>/<sender>-<target>.json` and the messages journalled
+    /// in `<sender>-<target>.messages`, or starts from 1.
+    fn open(dir: &Path, sender: &str, target: &str) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(format!("{}-{}", sender, target));
+        let mut store = SeqStore { path: Some(path.clone()), ..SeqStore::memory() };
+
+        let seqnums_path = path.with_extension("json");
+        match fs::read_to_string(&seqnums_path) {
+            Ok(text) => {
+                let seqnums: SeqNums = serde_json::from_str(&text)
+                    .map_err(|e| EngineError::Parse(format!("{}: {}", seqnums_path.display(), e)))?;
+                store.next_out = seqnums.next_out;
+                store.next_in = seqnums.next_in;
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => {},
+            Err(e) => return Err(e.into()),
+        }
+        match fs::read(path.with_extension("messages")) {
+            Ok(bytes) => {
+                for line in bytes.split(|b| *b == b'\n').filter(|l| !l.is_empty()) {
+                    let message = Message::decode(line)?;
+                    if let Some(seq) = message.seq_num() {
+                        store.sent.insert(seq, message);
+                    }
+                }
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => {},
+            Err(e) => return Err(e.into()),
+        }
+        Ok(store)
+    }
+
+    fn save(&self) -> Result<()> {
+        match &self.path {
+            Some(path) => write_atomic(
+                &path.with_extension("json"),
+                &serde_json::to_string(&SeqNums { next_out: self.next_out, next_in: self.next_in })?,
+            ),
+            None => Ok(()),
+        }
+    }
+
+    fn record_sent(&mut self, seq: u64, message: &Message) -> Result<()> {
+        self.next_out = seq + 1;
+        if !message.is_admin() {
+            if let Some(path) = &self.path {
+                let mut journal = OpenOptions::new().create(true).append(true).open(path.with_extension("messages"))?;
+                let mut line = message.encode();
+                line.push(b'\n');
+                journal.write_all(&line)?;
+            }
+            self.sent.insert(seq, message.clone());
+        }
+        self.save()
+    }
+
+    fn set_next_in(&mut self, next_in: u64) -> Result<()> {
+        self.next_in = next_in;
+        self.save()
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.next_out = 1;
+        self.next_in = 1;
+        self.sent.clear();
+        if let Some(path) = &self.path {
+            fs::File::create(path.with_extension("messages"))?;
+        }
+        self.save()
+    }
+}
+
+
+/// One end of a FIX session: sequencing, heartbeats, test requests and
+/// resends. Other messages are handed back to the caller.
+#[derive(Debug)]
+struct Session {
+    stream: TcpStream,
+    sender: String,
+    target: String,
+    heartbeat: Duration,
+    store: SeqStore,
+    last_sent: Instant,
+    last_received: Instant,
+    test_request: Option<Instant>,
+    /// The last sequence number known to be missing while a resend is
+    /// outstanding.
+    resend_until: Option<u64>,
+}
+impl Session {
+    fn new(stream: TcpStream, sender: &str, target: &str, heartbeat: Duration, store: SeqStore) -> Self {
+        Session {
+            stream,
+            sender: sender.to_string(),
+            target: target.to_string(),
+            heartbeat,
+            store,
+            last_sent: Instant::now(),
+            last_received: Instant::now(),
+            test_request: None,
+            resend_until: None,
+        }
+    }
+
+    fn send(&mut self, message: Message) -> Result<u64> {
+        let seq = self.store.next_out;
+        let stamped = message.stamped(&self.sender, &self.target, seq, &utc_timestamp(Utc::now()));
+        // Stored first, so a message that went out is never sequenced again.
+        self.store.record_sent(seq, &stamped)?;
+        self.write(&stamped)?;
+        Ok(seq)
+    }
+
+    /// Sequences and journals `message` without sending it, as if it
+    /// were lost on the way, so the other side has to ask for it again.
+    fn lose(&mut self, message: Message) -> Result<u64> {
+        let seq = self.store.next_out;
+        let stamped = message.stamped(&self.sender, &self.target, seq, &utc_timestamp(Utc::now()));
+        self.store.record_sent(seq, &stamped)?;
+        Ok(seq)
+    }
+
+    fn write(&mut self, message: &Message) -> Result<()> {
+        debug!("FIX out: {}", message);
+        self.stream.write_all(&message.encode())?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    /// Sequences an incoming message, handling the session-level ones.
+    /// Returns the message if the caller should act on it. Errs if the
+    /// session cannot continue, with the reason to log out with.
+    fn receive(&mut self, message: Message) -> Result<Option<Message>> {
+        debug!("FIX in: {}", message);
+        self.last_received = Instant::now();
+        self.test_request = None;
+        if message.get(tag::SENDER_COMP_ID) != Some(&self.target) || message.get(tag::TARGET_COMP_ID) != Some(&self.sender) {
+            return Err(EngineError::Broker(format!(
+                "CompID problem: expected {} to {}, got {}",
+                self.target, self.sender, message,
+            )));
+        }
+        let seq = message.seq_num()
+            .ok_or_else(|| EngineError::Broker(format!("No MsgSeqNum in {}", message)))?;
+        let kind = message.msg_type().to_string();
+
+        if kind == msg_type::SEQUENCE_RESET && message.get(tag::GAP_FILL_FLAG) != Some("Y") {
+            let next = message.number::<u64>(tag::NEW_SEQ_NO)?;
+            if next > self.store.next_in {
+                self.store.set_next_in(next)?;
+            }
+            return Ok(None);
+        }
+        // Resets above apply whatever their own number.
+        if kind == msg_type::LOGON && message.get(tag::RESET_SEQ_NUM_FLAG) == Some("Y") {
+            self.store.set_next_in(1)?;
+        }
+        if seq > self.store.next_in {
+            // Answered even out of order: if both sides wait for the other's
+            // resend first, neither comes.
+            if kind == msg_type::RESEND_REQUEST {
+                self.resend(message.number(tag::BEGIN_SEQ_NO)?, message.number(tag::END_SEQ_NO)?)?;
+            }
+            if self.resend_until.is_none() {
+                info!("FIX gap from {}: expected {}, received {}; asking for a resend", self.target, self.store.next_in, seq);
+                let begin = self.store.next_in;
+                self.send(Message::new(msg_type::RESEND_REQUEST).with(tag::BEGIN_SEQ_NO, begin).with(tag::END_SEQ_NO, 0))?;
+                self.resend_until = Some(seq);
+            }
+            // Logon and Logout count even out of order; the rest comes again.
+            return Ok(matches!(kind.as_str(), msg_type::LOGON | msg_type::LOGOUT).then_some(message));
+        }
+        if seq < self.store.next_in {
+            if message.is_poss_dup() {
+                return Ok(None);
+            }
+            return Err(EngineError::Broker(format!(
+                "MsgSeqNum too low, expecting {} but received {}", self.store.next_in, seq,
+            )));
+        }
+
+        let next = match kind.as_str() {
+            msg_type::SEQUENCE_RESET => message.number::<u64>(tag::NEW_SEQ_NO)?.max(seq + 1),
+            _ => seq + 1,
+        };
+        self.store.set_next_in(next)?;
+        if self.resend_until.is_some_and(|until| next > until) {
+            self.resend_until = None;
+        }
+        match kind.as_str() {
+            msg_type::HEARTBEAT | msg_type::SEQUENCE_RESET => Ok(None),
+            msg_type::TEST_REQUEST => {
+                let id = message.get(tag::TEST_REQ_ID).unwrap_or_default().to_string();
+                self.send(Message::new(msg_type::HEARTBEAT).with(tag::TEST_REQ_ID, id))?;
+                Ok(None)
+            },
+            msg_type::RESEND_REQUEST => {
+                self.resend(message.number(tag::BEGIN_SEQ_NO)?, message.number(tag::END_SEQ_NO)?)?;
+                Ok(None)
+            },
+            msg_type::REJECT => {
+                warn!("FIX reject from {} of message {}: {}", self.target,
+                    message.get(tag::REF_SEQ_NUM).unwrap_or("?"), message.get(tag::TEXT).unwrap_or_default());
+                Ok(None)
+            },
+            _ => Ok(Some(message)),
+        }
+    }
+
+    /// Answers a ResendRequest: application messages go again flagged
+    /// PossDupFlag, and runs of session messages are gap filled.
+    fn resend(&mut self, begin: u64, end: u64) -> Result<()> {
+        let last = self.store.next_out - 1;
+        let end = if end == 0 || end > last { last } else { end };
+        if begin == 0 || begin > end {
+            return Ok(());
+        }
+        info!("FIX resending {}..={} to {}", begin, end, self.target);
+        let now = utc_timestamp(Utc::now());
+        let originals: Vec<(u64, Message)> = self.store.sent.range(begin..=end).map(|(s, m)| (*s, m.clone())).collect();
+        let mut next = begin;
+        for (seq, original) in originals {
+            if seq > next {
+                self.gap_fill(next, seq)?;
+            }
+            let mut message = original.clone();
+            message.set(tag::POSS_DUP_FLAG, "Y");
+            message.set(tag::ORIG_SENDING_TIME, original.get(tag::SENDING_TIME).unwrap_or(&now));
+            self.write(&message.stamped(&self.sender, &self.target, seq, &now))?;
+            next = seq + 1;
+        }
+        if next <= end {
+            self.gap_fill(next, end + 1)?;
+        }
+        Ok(())
+    }
+
+    fn gap_fill(&mut self, seq: u64, next: u64) -> Result<()> {
+        let message = Message::new(msg_type::SEQUENCE_RESET)
+            .with(tag::POSS_DUP_FLAG, "Y")
+            .with(tag::GAP_FILL_FLAG, "Y")
+            .with(tag::NEW_SEQ_NO, next);
+        self.write(&message.stamped(&self.sender, &self.target, seq, &utc_timestamp(Utc::now())))
+    }
+
+    /// Sends a heartbeat when idle and a test request when the other side
+    /// has gone quiet. Errs once a test request goes unanswered.
+    fn tick(&mut self) -> Result<()> {
+        match self.test_request {
+            Some(sent) if sent.elapsed() > self.heartbeat => {
+                return Err(EngineError::Broker(format!("{} did not answer a test request", self.target)));
+            },
+            Some(_) => {},
+            None if self.last_received.elapsed() > self.heartbeat + self.heartbeat / 5 => {
+                self.send(Message::new(msg_type::TEST_REQUEST).with(tag::TEST_REQ_ID, utc_timestamp(Utc::now())))?;
+                self.test_request = Some(Instant::now());
+            },
+            None => {},
+        }
+        if self.last_sent.elapsed() >= self.heartbeat {
+            self.send(Message::new(msg_type::HEARTBEAT))?;
+        }
+        Ok(())
+    }
+
+    fn close(&self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FixConfig {
+    pub host: String,
+    pub port: u16,
+    pub sender_comp_id: String,
+    pub target_comp_id: String,
+    pub heartbeat_secs: u32,
+    /// Directory for sequence numbers and the orders sent.
+    pub store_dir: PathBuf,
+    /// Start both sides' sequence numbers again from 1 at logon.
+    pub reset_on_logon: bool,
+    /// How long to wait for logon and for orders to be acknowledged.
+    pub timeout_secs: f64,
+}
+impl Default for FixConfig {
+    fn default() -> Self {
+        FixConfig {
+            host: "127.0.0.1".to_string(),
+            port: 9878,
+            sender_comp_id: "ENGINE".to_string(),
+            target_comp_id: "DESK".to_string(),
+            heartbeat_secs: 30,
+            store_dir: PathBuf::from("fix"),
+            reset_on_logon: false,
+            timeout_secs: 10.0,
+        }
+    }
+}
+
+static SESSIONS: Mutex<BTreeMap<String, Weak<FixSession>>> = Mutex::new(BTreeMap::new());
+
+impl FixConfig {
+    /// The logged-on session for these settings, connecting unless one
+    /// is already open.
+    pub fn session(&self) -> Result<Arc<FixSession>> {
+        let key = format!("{}:{}/{}/{}", self.host, self.port, self.sender_comp_id, self.target_comp_id);
+        let mut sessions = SESSIONS.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(session) = sessions.get(&key).and_then(Weak::upgrade).filter(|s| !s.is_closed()) {
+            return Ok(session);
+        }
+        let session = Arc::new(FixSession::connect(self)?);
+        sessions.insert(key, Arc::downgrade(&session));
+        Ok(session)
+    }
+
+    pub fn broker(&self) -> Result<FixBroker> {
+        Ok(FixBroker::new(self.clone(), self.session()?))
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_secs_f64(self.timeout_secs)
+    }
+
+    /// Problems with the settings, for config validation.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.host.is_empty() {
+            problems.push("host: must not be empty".to_string());
+        }
+        if self.port == 0 {
+            problems.push("port: must not be 0".to_string());
+        }
+        for (name, id) in [("sender_comp_id", &self.sender_comp_id), ("target_comp_id", &self.target_comp_id)] {
+            if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                problems.push(format!("{}: expected letters, digits or '_', got {:?}", name, id));
+            }
+        }
+        if self.heartbeat_secs == 0 {
+            problems.push("heartbeat_secs: must be positive".to_string());
+        }
+        if !(self.timeout_secs > 0.0 && self.timeout_secs.is_finite()) {
+            problems.push(format!("timeout_secs: must be positive, got {}", self.timeout_secs));
+        }
+        problems
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+enum Status {
+    LoggingOn,
+    Active,
+    Closed(String),
+}
+
+#[derive(Debug)]
+struct Shared {
+    session: Session,
+    status: Status,
+    logout_sent: bool,
+    /// Execution reports and cancel rejects by the order they concern.
+    reports: HashMap<OrderId, Vec<Message>>,
+}
+impl Shared {
+    fn handle(&mut self, message: Message) {
+        let message = match self.session.receive(message) {
+            Ok(Some(message)) => message,
+            Ok(None) => return,
+            Err(e) => return self.logout(&e.to_string()),
+        };
+        match message.msg_type() {
+            msg_type::LOGON => {
+                info!("FIX session {} to {} logged on", self.session.sender, self.session.target);
+                self.status = Status::Active;
+            },
+            msg_type::LOGOUT => {
+                if !self.logout_sent {
+                    let _ = self.session.send(Message::new(msg_type::LOGOUT));
+                }
+                self.close(message.get(tag::TEXT).unwrap_or("logged out").to_string());
+            },
+            msg_type::EXECUTION_REPORT | msg_type::ORDER_CANCEL_REJECT => {
+                let id = message.get(tag::ORIG_CL_ORD_ID).or(message.get(tag::CL_ORD_ID)).and_then(FixBroker::order_id);
+                match id {
+                    Some(id) => self.reports.entry(id).or_default().push(message),
+                    None => warn!("FIX report for an order not sent by the engine: {}", message),
+                }
+            },
+            _ => warn!("Unexpected FIX message: {}", message),
+        }
+    }
+
+    fn logout(&mut self, reason: &str) {
+        warn!("FIX session to {}: {}", self.session.target, reason);
+        let _ = self.session.send(Message::new(msg_type::LOGOUT).with(tag::TEXT, reason));
+        self.close(reason.to_string());
+    }
+
+    fn close(&mut self, reason: String) {
+        if !matches!(self.status, Status::Closed(_)) {
+            info!("FIX session to {} closed: {}", self.session.target, reason);
+            self.status = Status::Closed(reason);
+        }
+        self.session.close();
+    }
+}
+
+/// A logged-on initiator session, serviced by a background thread until
+/// dropped.
+#[derive(Debug)]
+pub struct FixSession {
+    shared: Arc<(Mutex<Shared>, Condvar)>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+impl FixSession {
+    /// Connects and logs on, resuming the stored sequence numbers.
+    pub fn connect(config: &FixConfig) -> Result<Self> {
+        let mut store = SeqStore::open(&config.store_dir, &config.sender_comp_id, &config.target_comp_id)?;
+        if config.reset_on_logon {
+            store.reset()?;
+        }
+        let address = format!("{}:{}", config.host, config.port);
+        let unreachable = |e: std::io::Error| EngineError::Broker(format!("Connecting to {}: {}", address, e));
+        let addr = address.to_socket_addrs().map_err(unreachable)?.next()
+            .ok_or_else(|| EngineError::Broker(format!("No address for {}", address)))?;
+        let stream = TcpStream::connect_timeout(&addr, config.timeout()).map_err(unreachable)?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(TICK))?;
+        let mut reader = stream.try_clone()?;
+
+        let heartbeat = Duration::from_secs(config.heartbeat_secs as u64);
+        let mut session = Session::new(stream, &config.sender_comp_id, &config.target_comp_id, heartbeat, store);
+        let mut logon = Message::new(msg_type::LOGON)
+            .with(tag::ENCRYPT_METHOD, 0)
+            .with(tag::HEART_BT_INT, config.heartbeat_secs);
+        if config.reset_on_logon {
+            logon.set(tag::RESET_SEQ_NUM_FLAG, "Y");
+        }
+        session.send(logon)?;
+
+        let shared = Arc::new((
+            Mutex::new(Shared { session, status: Status::LoggingOn, logout_sent: false, reports: HashMap::new() }),
+            Condvar::new(),
+        ));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let (shared, stop) = (shared.clone(), stop.clone());
+            thread::spawn(move || service(&mut reader, &shared, &stop))
+        };
+        let fix = FixSession { shared, stop, thread: Some(thread) };
+
+        let deadline = Instant::now() + config.timeout();
+        loop {
+            match fix.status() {
+                Status::Active => return Ok(fix),
+                Status::Closed(reason) => return Err(EngineError::Broker(format!("Logon to {} failed: {}", address, reason))),
+                Status::LoggingOn if Instant::now() >= deadline => {
+                    return Err(EngineError::Broker(format!("No logon response from {} within {}s", address, config.timeout_secs)));
+                },
+                Status::LoggingOn => fix.wait(deadline - Instant::now()),
+            }
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        matches!(self.status(), Status::Closed(_))
+    }
+
+    fn status(&self) -> Status {
+        self.lock().status.clone()
+    }
+
+    pub fn send(&self, message: Message) -> Result<u64> {
+        let mut shared = self.lock();
+        match &shared.status {
+            Status::Active => shared.session.send(message),
+            Status::LoggingOn => Err(EngineError::Broker("FIX session is not logged on".to_string())),
+            Status::Closed(reason) => Err(EngineError::Broker(format!("FIX session is closed: {}", reason))),
+        }
+    }
+
+    /// Takes the reports received so far for the orders `ids`, oldest
+    /// first.
+    fn take_reports(&self, ids: &[OrderId]) -> Vec<(OrderId, Message)> {
+        let mut shared = self.lock();
+        ids.iter()
+            .filter_map(|id| shared.reports.remove(id).map(|reports| (*id, reports)))
+            .flat_map(|(id, reports)| reports.into_iter().map(move |r| (id, r)))
+            .collect()
+    }
+
+    /// Blocks until something arrives or `timeout` passes.
+    fn wait(&self, timeout: Duration) {
+        let (_, changed) = &*self.shared;
+        let _ = changed.wait_timeout(self.lock(), timeout.min(TICK));
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Shared> {
+        self.shared.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Drop for FixSession {
+    fn drop(&mut self) {
+        {
+            let (_, changed) = &*self.shared;
+            let mut shared = self.lock();
+            if shared.status == Status::Active {
+                shared.logout_sent = shared.session.send(Message::new(msg_type::LOGOUT)).is_ok();
+                // Gives the desk a moment to confirm the logout.
+                let deadline = Instant::now() + Duration::from_secs(2);
+                while shared.status == Status::Active && Instant::now() < deadline {
+                    shared = changed.wait_timeout(shared, TICK).map(|(s, _)| s).unwrap_or_else(|e| e.into_inner().0);
+                }
+            }
+            shared.close("logged out".to_string());
+        }
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Reads and handles incoming messages and keeps the session alive
+/// until it closes or `stop` is set.
+fn service(reader: &mut TcpStream, shared: &(Mutex<Shared>, Condvar), stop: &AtomicBool) {
+    let (lock, changed) = shared;
+    let mut buffer = vec![];
+    while !stop.load(Ordering::Relaxed) {
+        let read = read_messages(reader, &mut buffer);
+        let mut shared = lock.lock().unwrap_or_else(|e| e.into_inner());
+        match read {
+            Ok(Some(messages)) => messages.into_iter().for_each(|m| shared.handle(m)),
+            Ok(None) => shared.close("connection closed by the desk".to_string()),
+            Err(e) => shared.close(e.to_string()),
+        }
+        if shared.status == Status::Active {
+            if let Err(e) = shared.session.tick() {
+                shared.logout(&e.to_string());
+            }
+        }
+        let closed = matches!(shared.status, Status::Closed(_));
+        drop(shared);
+        changed.notify_all();
+        if closed {
+            break;
+        }
+    }
+}
+
+
+/// What is known of an order sent over FIX.
+#[derive(Debug, Clone)]
+struct Tracked {
+    ticker: String,
+    /// Signed quantity sent.
+    quantity: i64,
+    filled_quantity: i64,
+    filled_notional: f64,
+    acknowledged: bool,
+    done: bool,
+    rejection: Option<String>,
+    cancels: u32,
+    cancel_rejection: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct FixBroker {
+    config: FixConfig,
+    session: Arc<FixSession>,
+    orders: BTreeMap<OrderId, Tracked>,
+    cash: f64,
+    positions: BTreeMap<String, i64>,
+    marks: BTreeMap<String, f64>,
+    confirms: Vec<Confirm>,
+}
+impl FixBroker {
+    pub fn new(config: FixConfig, session: Arc<FixSession>) -> Self {
+        FixBroker {
+            config,
+            session,
+            orders: BTreeMap::new(),
+            cash: 0.0,
+            positions: BTreeMap::new(),
+            marks: BTreeMap::new(),
+            confirms: vec![],
+        }
+    }
+
+    /// Cash balance as of the orders passed to `resume`; without it the
+    /// account reports net cash flow.
+    pub fn with_cash(mut self, cash: f64) -> Self {
+        self.cash = cash;
+        self
+    }
+
+    /// Tracks orders sent before a restart and rebuilds positions from
+    /// their fills, so reports sent again for them are not counted twice.
+    /// Cash is already net of these fills.
+    pub fn resume(mut self, orders: &[Order]) -> Self {
+        for order in orders {
+            for fill in &order.fills {
+                let position = self.positions.entry(fill.ticker.clone()).or_insert(0);
+                *position += fill.quantity;
+                if *position == 0 {
+                    self.positions.remove(&fill.ticker);
+                }
+            }
+            self.orders.insert(order.id, Tracked {
+                ticker: order.ticker.clone(),
+                quantity: order.quantity,
+                filled_quantity: order.filled_quantity,
+                filled_notional: order.fills.iter().map(|f| f.price * f.quantity as f64).sum(),
+                acknowledged: true,
+                done: !order.is_open(),
+                rejection: None,
+                cancels: 0,
+                cancel_rejection: None,
+            });
+        }
+        self
+    }
+
+    pub fn client_order_id(id: OrderId) -> String {
+        format!("te-{}", id)
+    }
+
+    /// The engine order behind a ClOrdID, including those of its cancel
+    /// requests, `te-<id>-c<n>`.
+    pub fn order_id(client_order_id: &str) -> Option<OrderId> {
+        let id = client_order_id.strip_prefix("te-")?;
+        id.split('-').next()?.parse().ok().map(OrderId)
+    }
+
+    fn session(&mut self) -> Result<Arc<FixSession>> {
+        if self.session.is_closed() {
+            warn!("FIX session to {} is closed; reconnecting", self.config.target_comp_id);
+            self.session = self.config.session()?;
+        }
+        Ok(self.session.clone())
+    }
+
+    fn book(&mut self, ticker: &str, quantity: i64, price: f64, costs: f64) {
+        self.cash -= price * quantity as f64 + costs;
+        let position = self.positions.entry(ticker.to_string()).or_insert(0);
+        *position += quantity;
+        if *position == 0 {
+            self.positions.remove(ticker);
+        }
+    }
+
+    /// Applies the reports that have arrived for working orders.
+    fn collect(&mut self) -> Result<()> {
+        let session = self.session()?;
+        let working: Vec<OrderId> = self.orders.iter().filter(|(_, t)| !t.done).map(|(id, _)| *id).collect();
+        for (id, report) in session.take_reports(&working) {
+            self.on_report(id, &report)?;
+        }
+        Ok(())
+    }
+
+    fn on_report(&mut self, id: OrderId, report: &Message) -> Result<()> {
+        let tracked = match self.orders.get_mut(&id) {
+            Some(tracked) => tracked,
+            None => return Ok(()),
+        };
+        let text = report.get(tag::TEXT).map(str::to_string);
+        if report.msg_type() == msg_type::ORDER_CANCEL_REJECT {
+            tracked.cancel_rejection = Some(text.unwrap_or_else(|| "cancel rejected".to_string()));
+            return Ok(());
+        }
+        tracked.acknowledged = true;
+        match report.require(tag::EXEC_TYPE)? {
+            "8" => tracked.rejection = Some(text.unwrap_or_else(|| "rejected by the desk".to_string())),
+            "F" => {
+                let cumulative = tracked.quantity.signum() * report.number::<f64>(tag::CUM_QTY)? as i64;
+                let quantity = cumulative - tracked.filled_quantity;
+                // Reports already applied come again after a resend.
+                if quantity != 0 && quantity.signum() == tracked.quantity.signum() {
+                    let last_quantity = report.number::<f64>(tag::LAST_QTY)? as i64;
+                    let price = if last_quantity == quantity.abs() {
+                        report.number::<f64>(tag::LAST_PX)?
+                    } else {
+                        warn!("FIX order {}: missed {} shares of executions; pricing them at the average", id, quantity.abs() - last_quantity);
+                        let notional = report.number::<f64>(tag::AVG_PX)? * cumulative as f64;
+                        (notional - tracked.filled_notional) / quantity as f64
+                    };
+                    let commission = match report.get(tag::COMMISSION) {
+                        Some(_) => report.number::<f64>(tag::COMMISSION)?.abs(),
+                        None => 0.0,
+                    };
+                    let timestamp = match report.get(tag::TRANSACT_TIME) {
+                        Some(time) => parse_utc_timestamp(time)?,
+                        None => clock::wall_time(),
+                    };
+                    tracked.filled_quantity = cumulative;
+                    tracked.filled_notional += price * quantity as f64;
+                    let confirm = Confirm::new(id, tracked.ticker.clone(), timestamp, quantity, price, commission);
+                    self.book(&confirm.ticker, quantity, price, commission);
+                    self.confirms.push(confirm);
+                }
+            },
+            _ => {},
+        }
+        if let Some(tracked) = self.orders.get_mut(&id) {
+            if matches!(report.get(tag::ORD_STATUS), Some("2" | "4" | "8" | "C")) {
+                tracked.done = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies reports until `until` holds for order `id` or the timeout
+    /// passes; returns whether it held.
+    fn wait_for(&mut self, id: OrderId, until: impl Fn(&Tracked) -> bool) -> Result<bool> {
+        let deadline = Instant::now() + self.config.timeout();
+        loop {
+            self.collect()?;
+            if self.orders.get(&id).is_some_and(&until) {
+                return Ok(true);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(false);
+            }
+            self.session.wait(deadline - now);
+        }
+    }
+}
+
+impl Broker for FixBroker {
+    /// Sends a NewOrderSingle and waits for the desk to acknowledge it.
+    fn submit(&mut self, order: &Order) -> Result<()> {
+        if !order.is_open() || order.remaining_quantity() == 0 {
+            return Err(EngineError::OrderRejected(format!(
+                "Order {} is {} with {} to fill", order.id, order.status, order.remaining_quantity(),
+            )));
+        }
+        let quantity = order.remaining_quantity();
+        let time_in_force = match order.time_in_force {
+            TimeInForce::ImmediateOrCancel => "3",
+            // The engine cancels orders good for a number of bars.
+            TimeInForce::GoodTilCancelled | TimeInForce::GoodForBars(_) => "1",
+        };
+        let message = Message::new(msg_type::NEW_ORDER_SINGLE)
+            .with(tag::CL_ORD_ID, Self::client_order_id(order.id))
+            .with(tag::HANDL_INST, 1)
+            .with(tag::SYMBOL, &order.ticker)
+            .with(tag::SIDE, if quantity > 0 { 1 } else { 2 })
+            .with(tag::TRANSACT_TIME, utc_timestamp(Utc::now()))
+            .with(tag::ORDER_QTY, quantity.abs())
+            .with(tag::ORD_TYPE, 1)
+            .with(tag::TIME_IN_FORCE, time_in_force);
+        self.session()?.send(message)?;
+        self.orders.insert(order.id, Tracked {
+            ticker: order.ticker.clone(),
+            quantity,
+            filled_quantity: 0,
+            filled_notional: 0.0,
+            acknowledged: false,
+            done: false,
+            rejection: None,
+            cancels: 0,
+            cancel_rejection: None,
+        });
+
+        if !self.wait_for(order.id, |t| t.acknowledged)? {
+            warn!("FIX order {} not acknowledged within {}s", order.id, self.config.timeout_secs);
+        }
+        match self.orders.get(&order.id).and_then(|t| t.rejection.clone()) {
+            Some(reason) => Err(EngineError::OrderRejected(reason)),
+            None => Ok(()),
+        }
+    }
+
+    /// Sends an OrderCancelRequest and waits for the cancel to be
+    /// confirmed or rejected.
+    fn cancel(&mut self, id: OrderId) -> Result<()> {
+        let tracked = self.orders.get_mut(&id)
+            .filter(|t| !t.done)
+            .ok_or_else(|| EngineError::OrderRejected(format!("Order {} is not working", id)))?;
+        tracked.cancels += 1;
+        tracked.cancel_rejection = None;
+        let message = Message::new(msg_type::ORDER_CANCEL_REQUEST)
+            .with(tag::ORIG_CL_ORD_ID, Self::client_order_id(id))
+            .with(tag::CL_ORD_ID, format!("{}-c{}", Self::client_order_id(id), tracked.cancels))
+            .with(tag::SYMBOL, &tracked.ticker)
+            .with(tag::SIDE, if tracked.quantity > 0 { 1 } else { 2 })
+            .with(tag::TRANSACT_TIME, utc_timestamp(Utc::now()))
+            .with(tag::ORDER_QTY, tracked.quantity.abs());
+        self.session()?.send(message)?;
+
+        if !self.wait_for(id, |t| t.done || t.cancel_rejection.is_some())? {
+            return Err(EngineError::Broker(format!("No answer to the cancel of order {} within {}s", id, self.config.timeout_secs)));
+        }
+        match self.orders.get(&id).and_then(|t| t.cancel_rejection.clone()) {
+            Some(reason) => Err(EngineError::OrderRejected(reason)),
+            None => Ok(()),
+        }
+    }
+
+    fn positions(&self) -> Result<BTreeMap<String, i64>> {
+        Ok(self.positions.clone())
+    }
+
+    fn account(&self) -> Result<Account> {
+        let value: f64 = self.positions.iter()
+            .map(|(ticker, quantity)| *quantity as f64 * self.marks.get(ticker).copied().unwrap_or(0.0))
+            .sum();
+        Ok(Account { cash: self.cash, equity: self.cash + value })
+    }
+
+    fn fills(&mut self) -> Result<Vec<Fill>> {
+        self.collect()?;
+        let mut fills: Vec<Fill> = self.confirms.drain(..).map(|c| c.to_fill()).collect();
+        fills.sort_by_key(|f| f.timestamp);
+        Ok(fills)
+    }
+
+    /// Keeps the latest close to value positions with.
+    fn on_bar(&mut self, ticker: &str, bar: &BarContext) -> Result<()> {
+        self.marks.insert(ticker.to_string(), bar.close);
+        Ok(())
+    }
+}
+
+
+#[derive(Debug, Clone)]
+struct DeskOrder {
+    id: String,
+    client_order_id: String,
+    symbol: String,
+    /// Signed shares.
+    quantity: i64,
+    filled: i64,
+    filled_notional: f64,
+    immediate: bool,
+    /// OrdStatus.
+    status: &'static str,
+}
+impl DeskOrder {
+    fn is_open(&self) -> bool {
+        matches!(self.status, "0" | "1")
+    }
+}
+
+#[derive(Debug, Default)]
+struct Desk {
+    prices: HashMap<String, f64>,
+    max_fill: Option<i64>,
+    orders: Vec<DeskOrder>,
+    /// Sequence numbers by initiator, kept between its connections.
+    stores: HashMap<String, SeqStore>,
+    lose_reports: u32,
+    executions: u64,
+}
+impl Desk {
+    fn report(&mut self, index: usize, exec_type: &str, last: Option<(i64, f64)>, text: Option<&str>) -> Message {
+        self.executions += 1;
+        let order = &self.orders[index];
+        let (last_quantity, last_price) = last.unwrap_or((0, 0.0));
+        let average = if order.filled == 0 { 0.0 } else { order.filled_notional / order.filled as f64 };
+        let mut report = Message::new(msg_type::EXECUTION_REPORT)
+            .with(tag::ORDER_ID, &order.id)
+            .with(tag::CL_ORD_ID, &order.client_order_id)
+            .with(tag::EXEC_ID, format!("exec-{:08}", self.executions))
+            .with(tag::EXEC_TYPE, exec_type)
+            .with(tag::ORD_STATUS, order.status)
+            .with(tag::SYMBOL, &order.symbol)
+            .with(tag::SIDE, if order.quantity > 0 { 1 } else { 2 })
+            .with(tag::ORDER_QTY, order.quantity.abs())
+            .with(tag::ORD_TYPE, 1)
+            .with(tag::LAST_QTY, last_quantity.abs())
+            .with(tag::LAST_PX, last_price)
+            .with(tag::LEAVES_QTY, if order.is_open() { (order.quantity - order.filled).abs() } else { 0 })
+            .with(tag::CUM_QTY, order.filled.abs())
+            .with(tag::AVG_PX, average)
+            .with(tag::TRANSACT_TIME, utc_timestamp(Utc::now()));
+        if let Some(text) = text {
+            report.set(tag::TEXT, text);
+        }
+        report
+    }
+
+    fn reject(&mut self, message: &Message, text: &str) -> Message {
+        self.executions += 1;
+        Message::new(msg_type::EXECUTION_REPORT)
+            .with(tag::ORDER_ID, "NONE")
+            .with(tag::CL_ORD_ID, message.get(tag::CL_ORD_ID).unwrap_or_default())
+            .with(tag::EXEC_ID, format!("exec-{:08}", self.executions))
+            .with(tag::EXEC_TYPE, "8")
+            .with(tag::ORD_STATUS, "8")
+            .with(tag::SYMBOL, message.get(tag::SYMBOL).unwrap_or_default())
+            .with(tag::SIDE, message.get(tag::SIDE).unwrap_or("1"))
+            .with(tag::LEAVES_QTY, 0)
+            .with(tag::CUM_QTY, 0)
+            .with(tag::AVG_PX, 0)
+            .with(tag::TEXT, text)
+    }
+
+    fn new_order(&mut self, message: &Message) -> Option<Message> {
+        let client_order_id = message.get(tag::CL_ORD_ID).unwrap_or_default().to_string();
+        if self.orders.iter().any(|o| o.client_order_id == client_order_id) {
+            return match message.is_poss_dup() {
+                true => None,
+                false => Some(self.reject(message, "Duplicate ClOrdID")),
+            };
+        }
+        let quantity = match message.get(tag::ORDER_QTY).and_then(|q| q.parse::<i64>().ok()) {
+            Some(quantity) if quantity > 0 => quantity,
+            _ => return Some(self.reject(message, "OrderQty must be a positive integer")),
+        };
+        let sign = match message.get(tag::SIDE) {
+            Some("1") => 1,
+            Some("2") => -1,
+            _ => return Some(self.reject(message, "Side must be 1 (buy) or 2 (sell)")),
+        };
+        if message.get(tag::ORD_TYPE) != Some("1") {
+            return Some(self.reject(message, "Only market orders are supported"));
+        }
+        let symbol = message.get(tag::SYMBOL).unwrap_or_default().to_uppercase();
+        if client_order_id.is_empty() || symbol.is_empty() {
+            return Some(self.reject(message, "ClOrdID and Symbol are required"));
+        }
+
+        self.orders.push(DeskOrder {
+            id: format!("desk-{:08}", self.orders.len() + 1),
+            client_order_id,
+            symbol,
+            quantity: sign * quantity,
+            filled: 0,
+            filled_notional: 0.0,
+            immediate: message.get(tag::TIME_IN_FORCE) == Some("3"),
+            status: "0",
+        });
+        Some(self.report(self.orders.len() - 1, "0", None, None))
+    }
+
+    fn cancel(&mut self, message: &Message) -> Option<Message> {
+        let original = message.get(tag::ORIG_CL_ORD_ID).unwrap_or_default();
+        let index = self.orders.iter().position(|o| o.client_order_id == original);
+        let refuse = |status: &str, reason: u32, text: &str| Message::new(msg_type::ORDER_CANCEL_REJECT)
+            .with(tag::ORDER_ID, "NONE")
+            .with(tag::CL_ORD_ID, message.get(tag::CL_ORD_ID).unwrap_or_default())
+            .with(tag::ORIG_CL_ORD_ID, original)
+            .with(tag::ORD_STATUS, status)
+            .with(tag::CXL_REJ_RESPONSE_TO, 1)
+            .with(tag::CXL_REJ_REASON, reason)
+            .with(tag::TEXT, text);
+        match index {
+            None => Some(refuse("8", 1, "Unknown order")),
+            Some(index) if !self.orders[index].is_open() => match message.is_poss_dup() {
+                true => None,
+                false => Some(refuse(self.orders[index].status, 0, "Too late to cancel")),
+            },
+            Some(index) => {
+                self.orders[index].status = "4";
+                let mut report = self.report(index, "4", None, None);
+                report.set(tag::ORIG_CL_ORD_ID, original);
+                report.set(tag::CL_ORD_ID, message.get(tag::CL_ORD_ID).unwrap_or_default());
+                Some(report)
+            },
+        }
+    }
+
+    /// Fills open orders that have a price, up to `max_fill` shares each,
+    /// and cancels what is left of immediate-or-cancel orders.
+    fn work(&mut self) -> Vec<Message> {
+        let mut reports = vec![];
+        for index in 0..self.orders.len() {
+            if !self.orders[index].is_open() {
+                continue;
+            }
+            if let Some(price) = self.prices.get(&self.orders[index].symbol).copied() {
+                let order = &mut self.orders[index];
+                let remaining = order.quantity - order.filled;
+                let quantity = remaining.signum() * remaining.abs().min(self.max_fill.unwrap_or(i64::MAX));
+                order.filled += quantity;
+                order.filled_notional += quantity as f64 * price;
+                order.status = if order.filled == order.quantity { "2" } else { "1" };
+                reports.push(self.report(index, "F", Some((quantity, price)), None));
+            }
+            if self.orders[index].immediate && self.orders[index].is_open() {
+                self.orders[index].status = "4";
+                reports.push(self.report(index, "4", None, Some("Immediate or cancel")));
+            }
+        }
+        reports
+    }
+}
+
+/// A FIX 4.4 acceptor standing in for an execution desk on a local
+/// port, serving one initiator connection at a time until dropped.
+#[derive(Debug)]
+pub struct MockAcceptor {
+    addr: SocketAddr,
+    desk: Arc<Mutex<Desk>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+impl MockAcceptor {
+    /// Port 0 picks a free port. `comp_id` is the acceptor's CompID, the
+    /// initiator's `target_comp_id`.
+    pub fn start(port: u16, comp_id: &str) -> Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        let addr = listener.local_addr()?;
+        let desk = Arc::new(Mutex::new(Desk::default()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let (desk, stop, comp_id) = (desk.clone(), stop.clone(), comp_id.to_string());
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stop.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    if let Err(e) = stream.map_err(EngineError::from).and_then(|s| accept(s, &comp_id, &desk, &stop)) {
+                        warn!("Mock acceptor: {}", e);
+                    }
+                }
+            })
+        };
+        Ok(MockAcceptor { addr, desk, stop, thread: Some(thread) })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Market orders for `symbol` fill at `price` from now on.
+    pub fn set_price(&self, symbol: &str, price: f64) {
+        self.lock().prices.insert(symbol.to_uppercase(), price);
+    }
+
+    /// Caps each fill at `shares`, so orders fill over several reports.
+    pub fn set_max_fill(&self, shares: Option<i64>) {
+        self.lock().max_fill = shares;
+    }
+
+    /// Sequences the next `count` execution reports without sending them.
+    pub fn lose_reports(&self, count: u32) {
+        self.lock().lose_reports = count;
+    }
+
+    /// Blocks until the acceptor thread exits.
+    pub fn wait(mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Desk> {
+        self.desk.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Drop for MockAcceptor {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // Wakes the accept loop so it sees the flag.
+        let _ = TcpStream::connect(self.addr);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Serves one initiator connection, keeping its sequence numbers for the
+/// next one.
+fn accept(stream: TcpStream, comp_id: &str, desk: &Mutex<Desk>, stop: &AtomicBool) -> Result<()> {
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(TICK))?;
+    let mut session = None;
+    let served = serve(stream, comp_id, desk, stop, &mut session);
+    if let Some(session) = session {
+        session.close();
+        desk.lock().unwrap_or_else(|e| e.into_inner()).stores.insert(session.target, session.store);
+    }
+    served
+}
+
+fn serve(mut stream: TcpStream, comp_id: &str, desk: &Mutex<Desk>, stop: &AtomicBool, session: &mut Option<Session>) -> Result<()> {
+    let mut buffer = vec![];
+    while !stop.load(Ordering::Relaxed) {
+        let messages = match read_messages(&mut stream, &mut buffer)? {
+            Some(messages) => messages,
+            None => return Ok(()),
+        };
+        let mut desk = desk.lock().unwrap_or_else(|e| e.into_inner());
+        for message in messages {
+            let active = match session.as_mut() {
+                Some(active) => active,
+                None => {
+                    let opened = logon(&stream, comp_id, &mut desk, message)?;
+                    let logged_on = opened.1;
+                    *session = Some(opened.0);
+                    if !logged_on {
+                        return Ok(());
+                    }
+                    continue;
+                },
+            };
+            let message = match active.receive(message) {
+                Ok(Some(message)) => message,
+                Ok(None) => continue,
+                Err(e) => {
+                    active.send(Message::new(msg_type::LOGOUT).with(tag::TEXT, e.to_string()))?;
+                    return Ok(());
+                },
+            };
+            let report = match message.msg_type() {
+                msg_type::LOGON => None,
+                msg_type::LOGOUT => {
+                    active.send(Message::new(msg_type::LOGOUT))?;
+                    return Ok(());
+                },
+                msg_type::NEW_ORDER_SINGLE => desk.new_order(&message),
+                msg_type::ORDER_CANCEL_REQUEST => desk.cancel(&message),
+                _ => {
+                    let seq = message.seq_num().unwrap_or_default();
+                    active.send(Message::new(msg_type::REJECT).with(tag::REF_SEQ_NUM, seq).with(tag::TEXT, "Unsupported MsgType"))?;
+                    None
+                },
+            };
+            if let Some(report) = report {
+                send_report(active, &mut desk, report)?;
+            }
+        }
+        if let Some(active) = session.as_mut() {
+            for report in desk.work() {
+                send_report(active, &mut desk, report)?;
+            }
+            if let Err(e) = active.tick() {
+                active.send(Message::new(msg_type::LOGOUT).with(tag::TEXT, e.to_string()))?;
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Answers the initiator's Logon; the flag is false if it was refused.
+fn logon(stream: &TcpStream, comp_id: &str, desk: &mut Desk, message: Message) -> Result<(Session, bool)> {
+    if message.msg_type() != msg_type::LOGON {
+        return Err(EngineError::Broker(format!("Expected Logon, got {}", message)));
+    }
+    if message.get(tag::TARGET_COMP_ID) != Some(comp_id) {
+        return Err(EngineError::Broker(format!("Logon for {:?}, expected {}", message.get(tag::TARGET_COMP_ID), comp_id)));
+    }
+    let initiator = message.require(tag::SENDER_COMP_ID)?.to_string();
+    let heartbeat = message.number::<u64>(tag::HEART_BT_INT)?;
+    let reset = message.get(tag::RESET_SEQ_NUM_FLAG) == Some("Y");
+    let mut store = desk.stores.remove(&initiator).unwrap_or_else(SeqStore::memory);
+    if reset {
+        store.reset()?;
+    }
+    let mut session = Session::new(stream.try_clone()?, comp_id, &initiator, Duration::from_secs(heartbeat), store);
+
+    let seq = message.seq_num().unwrap_or_default();
+    let refusal = if heartbeat == 0 {
+        Some("HeartBtInt must be positive".to_string())
+    } else if seq < session.store.next_in {
+        Some(format!("MsgSeqNum too low, expecting {} but received {}", session.store.next_in, seq))
+    } else {
+        None
+    };
+    if let Some(text) = refusal {
+        session.send(Message::new(msg_type::LOGOUT).with(tag::TEXT, text))?;
+        return Ok((session, false));
+    }
+
+    let mut reply = Message::new(msg_type::LOGON).with(tag::ENCRYPT_METHOD, 0).with(tag::HEART_BT_INT, heartbeat);
+    if reset {
+        reply.set(tag::RESET_SEQ_NUM_FLAG, "Y");
+    }
+    session.send(reply)?;
+    info!("Mock acceptor: {} logged on", initiator);
+    // Asks for a resend if the initiator is ahead.
+    session.receive(message)?;
+    Ok((session, true))
+}
+
+fn send_report(session: &mut Session, desk: &mut Desk, report: Message) -> Result<()> {
+    if desk.lose_reports > 0 {
+        desk.lose_reports -= 1;
+        session.lose(report)?;
+    } else {
+        session.send(report)?;
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair() -> (Session, Session) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let initiator = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (acceptor, _) = listener.accept().unwrap();
+        let session = |stream: TcpStream, sender, target| {
+            stream.set_read_timeout(Some(TICK)).unwrap();
+            Session::new(stream, sender, target, Duration::from_secs(30), SeqStore::memory())
+        };
+        (session(initiator, "ENGINE", "DESK"), session(acceptor, "DESK", "ENGINE"))
+    }
+
+    /// Reads what has arrived for `session`, returning the messages handed
+    /// back to the caller.
+    fn deliver(session: &mut Session) -> Vec<Message> {
+        let mut reader = session.stream.try_clone().unwrap();
+        let (mut buffer, mut handed) = (vec![], vec![]);
+        while let Some(messages) = read_messages(&mut reader, &mut buffer).unwrap().filter(|m| !m.is_empty()) {
+            for message in messages {
+                handed.extend(session.receive(message).unwrap());
+            }
+        }
+        handed
+    }
+
+    #[test]
+    fn simultaneous_gaps_are_both_resent() {
+        let (mut engine, mut desk) = pair();
+        engine.lose(Message::new(msg_type::NEW_ORDER_SINGLE).with(tag::CL_ORD_ID, "te-1")).unwrap();
+        desk.lose(Message::new(msg_type::EXECUTION_REPORT).with(tag::CL_ORD_ID, "te-0")).unwrap();
+
+        // Each side sees a gap, and the desk's resend request is itself
+        // ahead of what the engine expects.
+        engine.send(Message::new(msg_type::HEARTBEAT)).unwrap();
+        assert!(deliver(&mut desk).is_empty());
+        assert!(deliver(&mut engine).is_empty());
+
+        let orders = deliver(&mut desk);
+        assert_eq!(orders.len(), 1);
+        assert!(orders[0].is_poss_dup());
+        assert_eq!(orders[0].get(tag::CL_ORD_ID), Some("te-1"));
+        let reports = deliver(&mut engine);
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].get(tag::CL_ORD_ID), Some("te-0"));
+
+        assert_eq!((engine.store.next_in, desk.store.next_in), (desk.store.next_out, engine.store.next_out));
+        assert!(engine.resend_until.is_none() && desk.resend_until.is_none());
+    }
+}
@@ -0,0 +1,268 @@
+//!
+//! Offline Alpha Vantage.
+//!
+//! `FixtureServer` answers Alpha Vantage queries on a local port with
+//! recorded JSON responses, so the data path can be exercised without
+//! network access or an API key. Point `data.base_url` (or
+//! `AlphaVantage::with_base_url`) at `FixtureServer::url`.
+//!
+//! A query for `function=F&symbol=S` is answered from the first fixture
+//! found of `S.F.<n>.json`, where `n` counts calls for that pair from 1,
+//! then `S.F.json`, then `S.json`. Numbered fixtures replay a sequence,
+//! such as a rate limit notice followed by data. Intraday queries append
+//! the interval to the function, e.g. `S.TIME_SERIES_INTRADAY_1min.json`.
+//! Anything else gets Alpha Vantage's invalid call error, as does a query
+//! without an `apikey`. Fixtures come from a directory, or from the set
+//! bundled with the engine: daily bars and quotes for `IBM` and `AAPL`, a
+//! per-minute limit notice for `RATELIMIT`, the daily one for
+//! `DAILYLIMIT`, and `FLAKY`, which is rate limited once and then served.
+//!
+//! Underneath is `LocalServer`, a small HTTP server that other offline
+//! stand-ins such as `alpaca::MockAlpaca` share.
+//!
+
+use crate::error::Result;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+
+macro_rules! bundled {
+    ($($name:literal),* $(,)?) => {
+        &[$(($name, include_str!(concat!("../fixtures/alpha_vantage/", $name)))),*]
+    };
+}
+
+const BUNDLED: &[(&str, &str)] = bundled![
+    "AAPL.GLOBAL_QUOTE.json",
+    "AAPL.TIME_SERIES_DAILY.json",
+    "DAILYLIMIT.json",
+    "FLAKY.TIME_SERIES_DAILY.1.json",
+    "FLAKY.TIME_SERIES_DAILY.json",
+    "IBM.GLOBAL_QUOTE.json",
+    "IBM.TIME_SERIES_DAILY.json",
+    "INVALID.GLOBAL_QUOTE.json",
+    "RATELIMIT.json",
+];
+
+const MISSING_KEY: &str = r#"{"Error Message": "the parameter apikey is invalid or missing. Please claim your free API key on (https://www.alphavantage.co/support/#api-key). It should take less than 20 seconds."}"#;
+
+
+/// Where fixtures are read from.
+#[derive(Debug, Clone, Default)]
+pub enum Fixtures {
+    #[default]
+    Bundled,
+    Dir(PathBuf),
+}
+impl Fixtures {
+    fn get(&self, name: &str) -> Option<String> {
+        match self {
+            Fixtures::Bundled => BUNDLED.iter().find(|(n, _)| *n == name).map(|(_, body)| body.to_string()),
+            Fixtures::Dir(dir) => fs::read_to_string(dir.join(name)).ok(),
+        }
+    }
+}
+
+
+/// A request to a `LocalServer`. Header names are lowercased.
+#[derive(Debug, Clone, Default)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: HashMap<String, String>,
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+/// A minimal HTTP/1.1 server on `127.0.0.1` for offline stand-ins of
+/// remote APIs. Requests are answered one at a time by the handler, on a
+/// background thread, until the server is dropped.
+#[derive(Debug)]
+pub struct LocalServer {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+impl LocalServer {
+    /// Port 0 picks a free port. The handler returns a status code and
+    /// a JSON body.
+    pub fn start<H>(port: u16, mut handler: H) -> Result<Self>
+    where
+        H: FnMut(&Request) -> (u16, String) + Send + 'static,
+    {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        let addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let thread = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stopped.load(Ordering::Relaxed) {
+                    break;
+                }
+                let served = stream.and_then(|stream| serve(stream, &mut handler));
+                if let Err(e) = served {
+                    warn!("Local server: {}", e);
+                }
+            }
+        });
+        Ok(LocalServer { addr, stop, thread: Some(thread) })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Blocks until the server thread exits.
+    pub fn wait(mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for LocalServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // Wakes the accept loop so it sees the flag.
+        let _ = TcpStream::connect(self.addr);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn serve(mut stream: TcpStream, handler: &mut dyn FnMut(&Request) -> (u16, String)) -> std::io::Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut request = Request::default();
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        if let Some((name, value)) = line.split_once(':') {
+            request.headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+        }
+        line.clear();
+    }
+    let length = request.headers.get("content-length").and_then(|n| n.parse().ok()).unwrap_or(0);
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    request.body = String::from_utf8_lossy(&body).into_owned();
+
+    let mut parts = request_line.split_whitespace();
+    request.method = parts.next().unwrap_or("").to_string();
+    let target = parts.next().unwrap_or("");
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    request.path = decode(path);
+    request.query = parse_query(query);
+
+    let (status, body) = handler(&request);
+    info!("{} {} -> {}", request.method, target.split("apikey=").next().unwrap_or(target), status);
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, reason(status), body.len(), body,
+    )?;
+    stream.flush()
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        422 => "Unprocessable Entity",
+        429 => "Too Many Requests",
+        _ => "Internal Server Error",
+    }
+}
+
+
+/// Serves fixtures until dropped.
+#[derive(Debug)]
+pub struct FixtureServer {
+    server: LocalServer,
+}
+impl FixtureServer {
+    /// Port 0 picks a free port.
+    pub fn start(fixtures: Fixtures, port: u16) -> Result<Self> {
+        let mut calls: HashMap<String, usize> = HashMap::new();
+        let server = LocalServer::start(port, move |request| match request.path.as_str() {
+            "/query" => (200, respond(&request.query, &fixtures, &mut calls)),
+            _ => (404, r#"{"Error Message": "Not found"}"#.to_string()),
+        })?;
+        Ok(FixtureServer { server })
+    }
+
+    /// The Alpha Vantage query endpoint.
+    pub fn url(&self) -> String {
+        format!("http://{}/query", self.server.addr())
+    }
+
+    /// Blocks until the server thread exits.
+    pub fn wait(self) {
+        self.server.wait()
+    }
+}
+
+
+fn respond(query: &HashMap<String, String>, fixtures: &Fixtures, calls: &mut HashMap<String, usize>) -> String {
+    let function = query.get("function").cloned().unwrap_or_default();
+    if query.get("apikey").is_none_or(String::is_empty) {
+        return MISSING_KEY.to_string();
+    }
+    let function = match query.get("interval") {
+        Some(interval) => format!("{}_{}", function, interval),
+        None => function,
+    };
+    let symbol = query.get("symbol").cloned().unwrap_or_default().to_uppercase();
+
+    let key = format!("{}.{}", symbol, function);
+    let call = calls.entry(key.clone()).or_insert(0);
+    *call += 1;
+    [format!("{}.{}.json", key, call), format!("{}.json", key), format!("{}.json", symbol)].iter()
+        .find_map(|name| fixtures.get(name))
+        .unwrap_or_else(|| format!(
+            r#"{{"Error Message": "Invalid API call. Please retry or visit the documentation (https://www.alphavantage.co/documentation/) for {}."}}"#,
+            function,
+        ))
+}
+
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(k, v)| (decode(k), decode(v)))
+        .collect()
+}
+
+fn decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+                match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => {
+                        out.push(byte);
+                        i += 2;
+                    },
+                    None => out.push(b'%'),
+                }
+            },
+            byte => out.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
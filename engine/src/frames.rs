@@ -0,0 +1,248 @@
+//!
+//! DataFrame interchange with Python.
+//!
+//! Bars come in and result tables go out through the Arrow PyCapsule
+//! interface (`__arrow_c_stream__`), so pyarrow tables and polars or
+//! pandas frames cross the boundary without copying their buffers.
+//! Objects without the interface, such as older pandas frames or a dict
+//! of lists, are read column by column instead.
+//!
+
+use crate::clock;
+use crate::data_loading::{bars_from_frame, find_column, DatedStockData, DATE_COLUMNS};
+use crate::python::value_error;
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime};
+use polars::export::arrow::array::{Array, StructArray};
+use polars::export::arrow::datatypes::{DataType as ArrowDataType, Field as ArrowField};
+use polars::export::arrow::ffi;
+use polars::prelude::*;
+use pyo3::prelude::*;
+use pyo3::types::{PyCapsule, PyDict, PyList};
+use std::ffi::CStr;
+
+
+const STREAM_CAPSULE: &CStr = c"arrow_array_stream";
+
+// Owns its batches, so handing it to whichever thread releases the
+// capsule is sound.
+#[repr(transparent)]
+struct ExportedStream(ffi::ArrowArrayStream);
+unsafe impl Send for ExportedStream {}
+
+
+/// Imports an object implementing `__arrow_c_stream__`.
+pub fn frame_from_arrow(data: &Bound<'_, PyAny>) -> PyResult<DataFrame> {
+    let capsule = data.call_method0("__arrow_c_stream__")?.downcast_into::<PyCapsule>()?;
+    if capsule.name()? != Some(STREAM_CAPSULE) {
+        return Err(value_error("__arrow_c_stream__ did not return an arrow_array_stream capsule"));
+    }
+    // Move the stream out, leaving a released one behind for the
+    // capsule's destructor.
+    let stream = unsafe {
+        std::ptr::replace(capsule.pointer() as *mut ffi::ArrowArrayStream, ffi::ArrowArrayStream::empty())
+    };
+    let mut reader = unsafe { ffi::ArrowArrayStreamReader::try_new(Box::new(stream)) }.map_err(value_error)?;
+    let fields = match reader.field().data_type() {
+        ArrowDataType::Struct(fields) => fields.clone(),
+        other => return Err(value_error(format!("Expected a stream of record batches, got {:?}", other))),
+    };
+
+    let mut chunks: Vec<Vec<Box<dyn Array>>> = vec![vec![]; fields.len()];
+    while let Some(batch) = unsafe { reader.next() } {
+        let batch = batch.map_err(value_error)?;
+        let batch = batch.as_any().downcast_ref::<StructArray>()
+            .ok_or_else(|| value_error("Expected a stream of record batches"))?;
+        for (column, values) in chunks.iter_mut().zip(batch.values()) {
+            column.push(values.clone());
+        }
+    }
+
+    let columns = fields.iter().zip(chunks)
+        .map(|(field, column)| match column.is_empty() {
+            true => Ok(Series::new_empty(&field.name, &DataType::from(field.data_type()))),
+            false => Series::try_from((field.name.as_str(), column)),
+        })
+        .collect::<PolarsResult<Vec<Series>>>()
+        .map_err(value_error)?;
+    DataFrame::new(columns).map_err(value_error)
+}
+
+fn stream_capsule<'py>(py: Python<'py>, frame: &DataFrame) -> PyResult<Bound<'py, PyCapsule>> {
+    let data_type = ArrowDataType::Struct(frame.schema().to_arrow().fields);
+    let batches: Vec<_> = frame.iter_chunks()
+        .map(|chunk| Ok(StructArray::new(data_type.clone(), chunk.into_arrays(), None).boxed()))
+        .collect();
+    let stream = ffi::export_iterator(Box::new(batches.into_iter()), ArrowField::new("", data_type, false));
+    PyCapsule::new(py, ExportedStream(stream), Some(STREAM_CAPSULE.to_owned()))
+}
+
+
+/// Reads bars from a frame or mapping with a date column and `open`,
+/// `high`, `low`, `close` and `volume` columns. A pandas frame indexed by
+/// date may keep the dates in its index.
+pub fn bars_from_python(data: &Bound<'_, PyAny>) -> PyResult<Vec<DatedStockData>> {
+    if data.hasattr("__arrow_c_stream__")? {
+        return Ok(bars_from_frame(&frame_from_arrow(data)?)?);
+    }
+
+    let names: Vec<String> = match data.downcast::<PyDict>() {
+        Ok(dict) => dict.keys().extract()?,
+        Err(_) => data.getattr("columns")
+            .map_err(|_| value_error("Expected a DataFrame, an Arrow table or a mapping of columns"))?
+            .try_iter()?
+            .map(|name| name?.str().map(|s| s.to_string()))
+            .collect::<PyResult<_>>()?,
+    };
+    let column = |wanted: &[&str]| -> PyResult<Vec<Bound<'_, PyAny>>> {
+        let i = find_column(&names, wanted)
+            .ok_or_else(|| value_error(format!("Missing column: {}", wanted[0])))?;
+        data.get_item(&names[i])?.try_iter()?.collect()
+    };
+
+    let dates = match find_column(&names, &DATE_COLUMNS) {
+        Some(_) => column(&DATE_COLUMNS)?,
+        None if data.hasattr("index")? && !data.is_instance_of::<PyDict>() => {
+            data.getattr("index")?.try_iter()?.collect::<PyResult<_>>()?
+        },
+        None => return Err(value_error("Missing column: date")),
+    };
+    let floats = |wanted: &str| -> PyResult<Vec<f64>> {
+        column(&[wanted])?.iter().map(|v| v.extract::<f64>()).collect()
+    };
+    let (open, high, low, close) = (floats("open")?, floats("high")?, floats("low")?, floats("close")?);
+    let volume = floats("volume")?;
+
+    if [open.len(), high.len(), low.len(), close.len(), volume.len()].iter().any(|&n| n != dates.len()) {
+        return Err(value_error("Columns have different lengths"));
+    }
+    dates.iter().enumerate()
+        .map(|(i, date)| {
+            if !(volume[i] >= 0.0 && volume[i].is_finite()) {
+                return Err(value_error(format!("Invalid volume {} at row {}", volume[i], i)));
+            }
+            Ok(DatedStockData::new(bar_date(date)?, open[i], high[i], low[i], close[i], volume[i].round() as u64))
+        })
+        .collect()
+}
+
+fn bar_date(value: &Bound<'_, PyAny>) -> PyResult<String> {
+    if let Ok(date) = value.extract::<String>() {
+        return Ok(date);
+    }
+    // datetime subclasses date, so try it first.
+    if let Ok(time) = value.extract::<DateTime<FixedOffset>>() {
+        return Ok(clock::format_bar_time(time.naive_utc()));
+    }
+    if let Ok(time) = value.extract::<NaiveDateTime>() {
+        return Ok(clock::format_bar_time(time));
+    }
+    if let Ok(day) = value.extract::<NaiveDate>() {
+        return Ok(day.format("%Y-%m-%d").to_string());
+    }
+    Err(value_error(format!("Unsupported date value: {}", value)))
+}
+
+
+/// Replaces the RFC 3339 `timestamp` column of an export table with a
+/// UTC datetime column.
+fn with_datetimes(mut frame: DataFrame) -> PolarsResult<DataFrame> {
+    if frame.get_column_names().contains(&"timestamp") {
+        let millis: Vec<Option<i64>> = frame.column("timestamp")?.utf8()?.into_iter()
+            .map(|t| t.and_then(|t| DateTime::parse_from_rfc3339(t).ok()).map(|t| t.timestamp_millis()))
+            .collect();
+        let timestamps = Series::new("timestamp", millis)
+            .cast(&DataType::Datetime(TimeUnit::Milliseconds, Some("UTC".to_string())))?;
+        frame.with_column(timestamps)?;
+    }
+    Ok(frame)
+}
+
+fn column_list<'py>(py: Python<'py>, series: &Series) -> PyResult<Bound<'py, PyList>> {
+    match series.dtype() {
+        DataType::Float64 => PyList::new(py, series.f64().map_err(value_error)?),
+        DataType::Int64 => PyList::new(py, series.i64().map_err(value_error)?),
+        DataType::UInt64 => PyList::new(py, series.u64().map_err(value_error)?),
+        DataType::Boolean => PyList::new(py, series.bool().map_err(value_error)?),
+        DataType::Utf8 => PyList::new(py, series.utf8().map_err(value_error)?),
+        DataType::Datetime(TimeUnit::Milliseconds, _) => PyList::new(
+            py,
+            series.datetime().map_err(value_error)?.into_iter()
+                .map(|t| t.and_then(NaiveDateTime::from_timestamp_millis).map(|t| t.and_utc())),
+        ),
+        _ => column_list(py, &series.cast(&DataType::Utf8).map_err(value_error)?),
+    }
+}
+
+
+/// A result table. Implements the Arrow PyCapsule interface, so it can be
+/// passed straight to `pyarrow.table`, `polars.DataFrame` or DuckDB.
+#[pyclass(name = "Table", module = "trading_engine", frozen)]
+#[derive(Debug, Clone)]
+pub struct PyTable(pub DataFrame);
+impl PyTable {
+    /// Wraps an export table, typing its `timestamp` column.
+    pub fn from_export(frame: PolarsResult<DataFrame>) -> PyResult<Self> {
+        frame.and_then(with_datetimes).map(PyTable).map_err(value_error)
+    }
+}
+
+#[pymethods]
+impl PyTable {
+    /// The requested schema is ignored, as the interface allows.
+    #[pyo3(signature = (requested_schema=None))]
+    fn __arrow_c_stream__<'py>(
+        &self,
+        py: Python<'py>,
+        requested_schema: Option<Bound<'py, PyAny>>,
+    ) -> PyResult<Bound<'py, PyCapsule>> {
+        let _ = requested_schema;
+        stream_capsule(py, &self.0)
+    }
+
+    #[getter]
+    fn columns(&self) -> Vec<String> {
+        self.0.get_column_names().into_iter().map(str::to_string).collect()
+    }
+
+    fn __len__(&self) -> usize {
+        self.0.height()
+    }
+
+    /// Column name to list of values.
+    fn to_dict<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let dict = PyDict::new(py);
+        for series in self.0.get_columns() {
+            dict.set_item(series.name(), column_list(py, series)?)?;
+        }
+        Ok(dict)
+    }
+
+    fn to_arrow<'py>(slf: &Bound<'py, Self>) -> PyResult<Bound<'py, PyAny>> {
+        slf.py().import("pyarrow")?.call_method1("table", (slf,))
+    }
+
+    /// Converts through pyarrow when it is installed, otherwise from
+    /// `to_dict`.
+    fn to_pandas<'py>(slf: &Bound<'py, Self>) -> PyResult<Bound<'py, PyAny>> {
+        let py = slf.py();
+        match py.import("pyarrow") {
+            Ok(_) => Self::to_arrow(slf)?.call_method0("to_pandas"),
+            Err(_) => py.import("pandas")?.call_method1("DataFrame", (slf.get().to_dict(py)?,)),
+        }
+    }
+
+    /// Converts through pyarrow when it is installed, otherwise from
+    /// `to_dict`.
+    fn to_polars<'py>(slf: &Bound<'py, Self>) -> PyResult<Bound<'py, PyAny>> {
+        let py = slf.py();
+        let polars = py.import("polars")?;
+        match py.import("pyarrow") {
+            Ok(_) => polars.call_method1("from_arrow", (Self::to_arrow(slf)?,)),
+            Err(_) => polars.call_method1("DataFrame", (slf.get().to_dict(py)?,)),
+        }
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Table(rows={}, columns={:?})", self.0.height(), self.columns())
+    }
+}
@@ -1,31 +1,62 @@
 use pyo3::prelude::*;
-use pyo3::types::{PyDict, PyList};
-use pyo3::exceptions::PyValueError;
-
-mod broker;
-mod order;
-mod config;
-mod data_loading;
-mod backtest;
-mod strategy;
-mod portfolio;
-
-use crate::data_loading::{AlphaVantage, Interval, Metadata};
-use crate::portfolio::*;
-use crate::strategy::*;
-use crate::backtest::*;
-
-#[pyclass]
-struct BacktestResult {
-    #[pyo3(get)]
-    final_capital: f64,
-    #[pyo3(get)]
-    total_return: f64,
-    #[pyo3(get)]
-    trades: Vec<PyObject>,
-}
+use pyo3::types::{PyDict, PyList, PyMapping, PyType};
+use pyo3::exceptions::PyRuntimeError;
+
+pub mod accounting;
+pub mod alpaca;
+pub mod broker;
+pub mod clock;
+pub mod commission;
+pub mod order;
+pub mod paper;
+pub mod config;
+pub mod data_loading;
+pub mod error;
+pub mod execution;
+pub mod export;
+pub mod fix;
+pub mod fixtures;
+pub mod frames;
+pub mod backtest;
+pub mod batch;
+pub mod strategy;
+pub mod target;
+pub mod metrics;
+pub mod optimize;
+pub mod portfolio;
+pub mod python;
+pub mod ratelimit;
+pub mod rebalance;
+pub mod report;
+pub mod risk;
+pub mod sizing;
+pub mod slippage;
+pub mod store;
+
+use crate::data_loading::Metadata;
+use crate::batch::{default_workers, run_parallel};
+use crate::config::{BacktestConfig, BrokerConfig, DataConfig, OutputConfig, RunOutputs, StrategyConfig};
+use crate::execution::ExecutionConfig;
+use crate::export::{export_run, ExportFormat, RunManifest};
+use crate::paper::PaperConfig;
+use crate::report::{write_tearsheet, ReportData};
+use crate::python::{
+    symbol_bars, without_gil, PyBacktest, PyBacktestConfig, PyBacktestResult, PyCancelToken,
+};
+use crate::ratelimit::RateLimiter;
+use crate::risk::RiskLimits;
+use crate::store::{parameter_differences, RunFilter, RunStore, RunSummary};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::time::Duration;
 
 #[pyfunction]
+#[pyo3(signature = (strategy_type, ticker, window, capital, long_qty, short_qty, commission=None, slippage=None, max_participation=None, lot_method=None, sizing=None, export_dir=None, export_format="json", report_path=None, store_path=None))]
+#[allow(clippy::too_many_arguments)]
 fn run_backtest(
     py: Python,
     strategy_type: &str,
@@ -34,47 +65,276 @@ fn run_backtest(
     capital: i64,
     long_qty: i64,
     short_qty: i64,
+    commission: Option<&str>,
+    slippage: Option<&str>,
+    max_participation: Option<f64>,
+    lot_method: Option<&str>,
+    sizing: Option<&str>,
+    export_dir: Option<&str>,
+    export_format: &str,
+    report_path: Option<&str>,
+    store_path: Option<&str>,
 ) -> PyResult<Py<PyDict>> {
-    let metadata = Metadata::new(ticker.to_string());
-    let loader = AlphaVantage;
+    let config = BacktestConfig {
+        data: DataConfig::default(),
+        universe: vec![ticker.to_string()],
+        start_date: None,
+        end_date: None,
+        capital,
+        strategy: StrategyConfig {
+            name: strategy_type.to_string(),
+            window,
+            long_quantity: long_qty,
+            short_quantity: short_qty,
+            sizing: sizing.map(str::to_string),
+            max_position_fraction: 1.0,
+        },
+        broker: BrokerConfig {
+            commission: commission.map(str::to_string),
+            slippage: slippage.map(str::to_string),
+            max_participation,
+            lot_method: lot_method.map(str::to_string),
+        },
+        risk: RiskLimits::default(),
+        output: OutputConfig::default(),
+        execution: ExecutionConfig::default(),
+        paper: PaperConfig::default(),
+    };
+    config.validate()?;
+    let format = ExportFormat::from_str(export_format)?;
+
+    let result = without_gil(py, || {
+        let metadata = Metadata::new(ticker.to_string());
+        let data = config.load_data(ticker)?;
+        let strategy = config.strategy()?;
+        let mut backtest = config.backtest()?;
+        let result = backtest.run(&*strategy, &data, &metadata)?;
 
-    let data = match loader.get_timeseries(&metadata.symbol, &Interval::Day) {
-        Ok(data) => data,
-        Err(e) => return Err(PyValueError::new_err(format!("Data loading error: {}", e))),
+        let mut parameters = config.parameters();
+        parameters.insert("ticker".to_string(), ticker.to_string());
+        let manifest = RunManifest::for_run(strategy_type, parameters, vec![ticker.to_string()], &data)
+            .with_api_usage(config.data.api_usage());
+
+        let mut outputs = RunOutputs::default();
+        if let Some(dir) = export_dir {
+            outputs.exported_files = export_run(&result, &manifest, Path::new(dir), format)?;
+        }
+        if let Some(path) = report_path {
+            write_tearsheet(&ReportData::from_result(&result, &manifest), Path::new(path))?;
+            outputs.report = Some(PathBuf::from(path));
+        }
+        if let Some(path) = store_path {
+            let run_id = RunStore::open(Path::new(path))
+                .and_then(|mut store| store.record(&result, &manifest))?;
+            outputs.run_id = Some(run_id);
+        }
+        Ok(PyBacktestResult::from_result(ticker, &result, outputs))
+    })?;
+
+    Ok(result.to_dict(py)?.into())
+}
+
+/// Validated configuration from a YAML or TOML file.
+#[pyfunction]
+fn load_config(path: PathBuf) -> PyResult<PyBacktestConfig> {
+    Ok(BacktestConfig::from_file(&path).map(PyBacktestConfig)?)
+}
+
+/// Runs the configured strategy over every symbol in the universe and
+/// returns the results keyed by symbol.
+#[pyfunction]
+fn run_config(py: Python, path: PathBuf) -> PyResult<BTreeMap<String, PyBacktestResult>> {
+    let config = BacktestConfig::from_file(&path)?;
+    without_gil(py, || {
+        config.universe.iter()
+            .map(|symbol| Ok((symbol.clone(), PyBacktest::run_symbol(&config, symbol)?)))
+            .collect()
+    })
+}
+
+/// Attaches `note` to the exception's traceback on Python 3.11 and later.
+fn with_note(py: Python, error: PyErr, note: &str) -> PyErr {
+    let _ = error.value(py).call_method1("add_note", (note,));
+    error
+}
+
+/// How often `run_many` wakes to check for Ctrl-C while it waits.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+fn cancelled_error(py: Python, completed: usize, total: usize) -> PyErr {
+    let message = format!("Cancelled after {} of {} backtests", completed, total);
+    match py.import("concurrent.futures").and_then(|m| m.getattr("CancelledError")) {
+        Ok(error) => match error.downcast_into::<PyType>() {
+            Ok(error) => PyErr::from_type(error, message),
+            Err(_) => PyRuntimeError::new_err(message),
+        },
+        Err(_) => PyRuntimeError::new_err(message),
+    }
+}
+
+/// Backtests every symbol of every configuration on a pool of
+/// `max_workers` threads (default: one per core) without holding the
+/// GIL. Results come back in order: each symbol of the first
+/// configuration, then of the second, and so on. `data` maps symbols to
+/// bars shared by all configurations; other symbols use each
+/// configuration's data source. `progress(completed, total)` is called
+/// after each backtest and cancels the batch by returning `False`.
+/// Cancellation raises `concurrent.futures.CancelledError`; a backtest
+/// that panics raises `EngineError`.
+#[pyfunction]
+#[pyo3(signature = (configs, data=None, max_workers=None, progress=None, cancel=None))]
+fn run_many(
+    py: Python,
+    configs: Vec<PyBacktestConfig>,
+    data: Option<&Bound<'_, PyMapping>>,
+    max_workers: Option<usize>,
+    progress: Option<Bound<'_, PyAny>>,
+    cancel: Option<PyCancelToken>,
+) -> PyResult<Vec<PyBacktestResult>> {
+    let jobs: Vec<(BacktestConfig, String)> = configs.into_iter()
+        .flat_map(|config| {
+            let symbols = config.0.universe.clone();
+            symbols.into_iter().map(move |symbol| (config.0.clone(), symbol))
+        })
+        .collect();
+    let symbols: Vec<String> = jobs.iter().map(|(_, symbol)| symbol.clone()).collect();
+    let bars = Arc::new(symbol_bars(data, &symbols)?);
+
+    let total = jobs.len();
+    let cancel = cancel.unwrap_or_default().0;
+    let workers = max_workers.unwrap_or_else(default_workers);
+    let mut receiver = run_parallel(jobs, workers, cancel.clone(), move |(config, symbol)| {
+        match bars.get(symbol) {
+            Some(bars) => PyBacktest::run_bars(config, symbol, bars.clone()),
+            None => PyBacktest::run_symbol(config, symbol),
+        }.map_err(|e| (format!("in backtest of {} ({})", symbol, config.strategy.name), e))
+    });
+
+    let stop = |error: PyErr| {
+        cancel.store(true, Ordering::Relaxed);
+        Err(error)
     };
+    let mut results: Vec<Option<PyBacktestResult>> = (0..total).map(|_| None).collect();
+    let mut completed = 0;
+    while completed < total {
+        let (returned, received) = py.allow_threads(move || {
+            let received = receiver.recv_timeout(POLL_INTERVAL);
+            (receiver, received)
+        });
+        receiver = returned;
+        if let Err(e) = py.check_signals() {
+            return stop(e);
+        }
+        match received {
+            Ok((i, Ok(Ok(result)))) => {
+                results[i] = Some(result);
+                completed += 1;
+                if let Some(progress) = &progress {
+                    match progress.call1((completed, total)) {
+                        Ok(keep) if keep.extract::<bool>().ok() == Some(false) => cancel.store(true, Ordering::Relaxed),
+                        Ok(_) => {},
+                        Err(e) => return stop(e),
+                    }
+                }
+            },
+            Ok((_, Ok(Err((context, e))))) => return stop(with_note(py, e.into(), &context)),
+            Ok((i, Err(e))) => return stop(with_note(py, e.into(), &format!("in backtest of {}", symbols[i]))),
+            Err(RecvTimeoutError::Timeout) => {},
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if cancel.load(Ordering::Relaxed) && completed < total {
+            return Err(cancelled_error(py, completed, total));
+        }
+    }
 
-    let portfolio = Portfolio::new(capital as isize);
+    results.into_iter().collect::<Option<Vec<_>>>()
+        .ok_or_else(|| cancelled_error(py, completed, total))
+}
 
-    let strategy_factory: &mut StrategyFactory = get_strategy_factory();
-    let strategy = strategy_factory.create(strategy_type, window, long_qty, short_qty)
-        .expect("Unable to generate strategy.");
+fn summary_dict<'py>(py: Python<'py>, run: &RunSummary) -> PyResult<Bound<'py, PyDict>> {
+    let run_dict = PyDict::new(py);
+    run_dict.set_item("run_id", &run.run_id)?;
+    run_dict.set_item("created_at", run.created_at.to_rfc3339())?;
+    run_dict.set_item("strategy", &run.strategy)?;
+    run_dict.set_item("symbols", &run.symbols)?;
+    run_dict.set_item("parameters", run.parameters.clone())?;
+    run_dict.set_item("total_return", run.metrics.total_return)?;
+    run_dict.set_item("sharpe_ratio", run.metrics.sharpe_ratio)?;
+    run_dict.set_item("max_drawdown", run.metrics.max_drawdown)?;
+    run_dict.set_item("n_trades", run.metrics.n_trades)?;
+    Ok(run_dict)
+}
 
-    let mut backtest = Backtest::new(window, portfolio);
-    let result = match backtest.run(&*strategy, &data, &metadata) {
-        Ok(r) => r,
-        Err(e) => return Err(PyValueError::new_err(format!("Backtest error: {}", e))),
+#[pyfunction]
+#[pyo3(signature = (store_path, strategy=None, symbol=None, parameters=None, limit=None))]
+fn list_runs(
+    py: Python,
+    store_path: &str,
+    strategy: Option<String>,
+    symbol: Option<String>,
+    parameters: Option<BTreeMap<String, String>>,
+    limit: Option<usize>,
+) -> PyResult<Py<PyList>> {
+    let filter = RunFilter {
+        strategy,
+        symbol,
+        parameters: parameters.unwrap_or_default(),
+        limit,
+        ..Default::default()
     };
+    let runs = RunStore::open(Path::new(store_path))
+        .and_then(|store| store.list_runs(&filter))?;
 
-    let result_dict = PyDict::new(py);
-    result_dict.set_item("n_trades", result.n_trades)?;
-
-    let trades = PyList::empty(py);
-    for trade in &result.portfolio.trades {
-        let trade_dict = PyDict::new(py);
-        trade_dict.set_item("timestamp", trade.timestamp.to_string())?;
-        trade_dict.set_item("ticker", &trade.ticker)?;
-        trade_dict.set_item("quantity", trade.quantity)?;
-        trade_dict.set_item("price", trade.price)?;
-        trades.append(trade_dict)?;
+    let run_list = PyList::empty(py);
+    for run in &runs {
+        run_list.append(summary_dict(py, run)?)?;
     }
-    result_dict.set_item("trades", trades)?;
+    Ok(run_list.into())
+}
+
+#[pyfunction]
+fn compare_runs(py: Python, store_path: &str, run_ids: Vec<String>) -> PyResult<Py<PyDict>> {
+    let run_ids: Vec<&str> = run_ids.iter().map(String::as_str).collect();
+    let runs = RunStore::open(Path::new(store_path))
+        .and_then(|store| store.compare(&run_ids))?;
 
-    Ok(result_dict.into())
+    let comparison = PyDict::new(py);
+    let run_list = PyList::empty(py);
+    for run in &runs {
+        run_list.append(summary_dict(py, run)?)?;
+    }
+    comparison.set_item("runs", run_list)?;
+    comparison.set_item("parameter_differences", parameter_differences(&runs))?;
+    Ok(comparison.into())
+}
+
+/// Alpha Vantage calls made by this process and the call budget left,
+/// summed over the API keys used.
+#[pyfunction]
+fn api_usage(py: Python) -> PyResult<Py<PyDict>> {
+    let usage = RateLimiter::total_usage();
+    let usage_dict = PyDict::new(py);
+    usage_dict.set_item("calls", usage.calls)?;
+    usage_dict.set_item("retries", usage.retries)?;
+    usage_dict.set_item("rate_limited", usage.rate_limited)?;
+    usage_dict.set_item("failures", usage.failures)?;
+    usage_dict.set_item("waited_secs", usage.waited_secs)?;
+    usage_dict.set_item("minute_remaining", usage.minute_remaining)?;
+    usage_dict.set_item("day_remaining", usage.day_remaining)?;
+    Ok(usage_dict.into())
 }
 
 
+/// Backtesting engine. The typed API is described in `trading_engine.pyi`.
 #[pymodule]
 fn trading_engine(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    python::register(m)?;
     m.add_function(wrap_pyfunction!(run_backtest, m)?)?;
+    m.add_function(wrap_pyfunction!(load_config, m)?)?;
+    m.add_function(wrap_pyfunction!(run_config, m)?)?;
+    m.add_function(wrap_pyfunction!(run_many, m)?)?;
+    m.add_function(wrap_pyfunction!(list_runs, m)?)?;
+    m.add_function(wrap_pyfunction!(compare_runs, m)?)?;
+    m.add_function(wrap_pyfunction!(api_usage, m)?)?;
     Ok(())
 }
@@ -1,59 +1,543 @@
-///
-/// Basic trading infrastructure.
-/// May 2023
-/// Jack Tobin
-///
-
-mod broker;
-mod order;
-mod config;
-mod data_loading;
-mod backtest;
-mod strategy;
-mod portfolio;
-
-use crate::data_loading::{AlphaVantage, Interval, Metadata};
-use crate::portfolio::*;
-use crate::strategy::*;
-use crate::backtest::*;
+//!
+//! Basic trading infrastructure.
+//! May 2023
+//! Jack Tobin
+//!
 
+use trading_engine::alpaca::MockAlpaca;
+use trading_engine::fix::MockAcceptor;
+use trading_engine::config::{BacktestConfig, DataConfig};
+use trading_engine::data_loading::{validate_bars, CsvCache, DataIssue, Interval, IssueSeverity, Metadata};
+use trading_engine::error::{EngineError, Result};
+use trading_engine::execution::{average_shortfall_bps, ParentOrder};
+use trading_engine::fixtures::{FixtureServer, Fixtures};
+use trading_engine::metrics::Metrics;
+use trading_engine::order::OrderStatus;
+use trading_engine::paper::{run_live, status, LiveLimits, PaperSummary, PaperTrader};
+use trading_engine::optimize::{rank, sweep, ParameterGrid, SweepResult};
+use trading_engine::ratelimit::ApiUsage;
+use trading_engine::report::write_tearsheet;
+use trading_engine::store::{RunFilter, RunStore};
+use trading_engine::strategy::{get_strategy_factory, StrategyInfo};
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use env_logger::Builder;
-use log::{info, error, LevelFilter};
+use log::LevelFilter;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::AtomicBool;
 
 
-fn main() {
-    Builder::new()
-        .filter_level(LevelFilter::Info)
-        .init();
+#[derive(Debug, Parser)]
+#[command(name = "trading_engine", about = "Backtest, optimise and report on trading strategies")]
+struct Cli {
+    /// Output format; json prints a single JSON document on stdout.
+    #[arg(long, value_enum, global = true, default_value = "text")]
+    format: OutputFormat,
+    /// Log engine activity to stderr.
+    #[arg(short, long, global = true)]
+    verbose: bool,
+    #[command(subcommand)]
+    command: Command,
+}
 
-    // Stuff to refactor into Python bindings.
-    let ticker: &str = "AAPL";
-    let window: u32 = 90;
-    let capital: isize = 1_000_000;
-    let long_qty: i64 = 100;
-    let short_qty: i64 = -100;
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
 
-    let metadata = Metadata::new(ticker.to_string());
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Download daily bars into the local cache.
+    Fetch(SymbolArgs),
+    /// Run the backtests described by a config file.
+    Backtest {
+        #[arg(short, long, default_value = "backtest.yaml")]
+        config: PathBuf,
+    },
+    /// Sweep strategy parameters and rank the results.
+    Optimize {
+        #[arg(short, long, default_value = "backtest.yaml")]
+        config: PathBuf,
+        /// Values to try, e.g. `strategy.window=20,50,90`. Repeatable.
+        #[arg(short, long = "param", required = true)]
+        params: Vec<String>,
+        /// Metric to rank by.
+        #[arg(short, long, default_value = "sharpe_ratio")]
+        metric: String,
+        /// Only show the best N results.
+        #[arg(short, long)]
+        top: Option<usize>,
+    },
+    /// Render a stored run as an HTML tearsheet.
+    Report {
+        /// SQLite run store.
+        #[arg(short, long)]
+        store: PathBuf,
+        /// Run to render; defaults to the most recent.
+        #[arg(short, long)]
+        run_id: Option<String>,
+        #[arg(short, long, default_value = "report.html")]
+        out: PathBuf,
+    },
+    /// List registered strategies and their parameters.
+    Strategies,
+    /// Check cached bars for gaps, duplicates and bad prices.
+    ValidateData(SymbolArgs),
+    /// Paper trade the configured strategy on live bars, resuming saved
+    /// accounts.
+    Paper {
+        #[arg(short, long, default_value = "backtest.yaml")]
+        config: PathBuf,
+        /// Stop after this many market sessions.
+        #[arg(long)]
+        sessions: Option<u32>,
+        /// Stop after this many polls of the feeds.
+        #[arg(long)]
+        polls: Option<u64>,
+        /// Show the saved accounts without trading.
+        #[arg(long)]
+        status: bool,
+    },
+    /// Serve an in-memory Alpaca-style order API locally; point
+    /// `paper.alpaca.base_url` at the printed URL.
+    MockBroker {
+        #[arg(short, long, default_value_t = 8766)]
+        port: u16,
+        #[arg(long, default_value_t = 100_000.0)]
+        cash: f64,
+        /// Fill price for a symbol, e.g. `AAPL=190.5`. Repeatable.
+        #[arg(long = "price")]
+        prices: Vec<String>,
+        /// Fill at most this many shares of an order per request.
+        #[arg(long)]
+        max_fill: Option<i64>,
+    },
+    /// Run a local FIX 4.4 acceptor that fills market orders; point
+    /// `paper.fix` at the printed address.
+    MockAcceptor {
+        #[arg(short, long, default_value_t = 9878)]
+        port: u16,
+        /// The acceptor's CompID, the initiator's `target_comp_id`.
+        #[arg(long, default_value = "DESK")]
+        comp_id: String,
+        /// Fill price for a symbol, e.g. `AAPL=190.5`. Repeatable.
+        #[arg(long = "price")]
+        prices: Vec<String>,
+        /// Fill at most this many shares of an order at a time.
+        #[arg(long)]
+        max_fill: Option<i64>,
+        /// Sequence but do not send this many execution reports, so the
+        /// initiator has to ask for them again.
+        #[arg(long, default_value_t = 0)]
+        lose_reports: u32,
+    },
+    /// Serve recorded Alpha Vantage responses locally; point
+    /// `data.base_url` at the printed URL.
+    MockServer {
+        /// Directory of fixtures; defaults to the bundled set.
+        #[arg(long)]
+        fixtures: Option<PathBuf>,
+        #[arg(short, long, default_value_t = 8765)]
+        port: u16,
+    },
+}
 
-    let loader = AlphaVantage;
-    let data = match loader.get_timeseries(&metadata.symbol, &Interval::Day) {
-        Ok(data) => data,
-        Err(e) => {
-            error!("{}", e);
-            return;
+/// Symbols and cache location, given directly or taken from a config.
+#[derive(Debug, Args)]
+struct SymbolArgs {
+    /// Comma-separated symbols.
+    #[arg(short, long, value_delimiter = ',')]
+    symbols: Vec<String>,
+    #[arg(long)]
+    cache_dir: Option<PathBuf>,
+    /// Take the universe and cache directory from this config.
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+}
+impl SymbolArgs {
+    fn resolve(&self) -> Result<(Vec<String>, CsvCache, DataConfig)> {
+        let config = self.config.as_deref().map(BacktestConfig::from_file).transpose()?;
+        let symbols = match (&config, self.symbols.is_empty()) {
+            (Some(config), true) => config.universe.clone(),
+            _ => self.symbols.clone(),
+        };
+        if symbols.is_empty() {
+            return Err(EngineError::Config("No symbols given; use --symbols or --config".to_string()));
         }
+        let data = config.map(|c| c.data).unwrap_or_default();
+        let cache_dir = self.cache_dir.clone()
+            .or_else(|| data.cache_dir.clone())
+            .unwrap_or(PathBuf::from("data"));
+        Ok((symbols, CsvCache::new(cache_dir), data))
+    }
+}
+
+
+#[derive(Debug, Serialize)]
+struct FetchResult {
+    symbol: String,
+    path: Option<PathBuf>,
+    bars: usize,
+    first: Option<String>,
+    last: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct FetchReport<'a> {
+    results: &'a [FetchResult],
+    api_usage: &'a ApiUsage,
+}
+
+#[derive(Debug, Serialize)]
+struct BacktestSummary {
+    symbol: String,
+    run_id: Option<String>,
+    metrics: Option<Metrics>,
+    exported_files: Vec<PathBuf>,
+    report: Option<PathBuf>,
+    stored: bool,
+    executions: Vec<ParentOrder>,
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ReportResult {
+    run_id: String,
+    path: PathBuf,
+}
+
+#[derive(Debug, Serialize)]
+struct DataValidation {
+    symbol: String,
+    bars: usize,
+    issues: Vec<DataIssue>,
+    error: Option<String>,
+}
+
+
+/// Prints `value` as JSON, or `text` for humans.
+fn emit<T: Serialize>(format: OutputFormat, value: &T, text: impl FnOnce() -> String) -> Result<()> {
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string(value)?),
+        OutputFormat::Text => println!("{}", text()),
+    }
+    Ok(())
+}
+
+fn fetch(args: &SymbolArgs, format: OutputFormat) -> Result<bool> {
+    let (symbols, cache, data) = args.resolve()?;
+    let client = data.alpha_vantage();
+    let results: Vec<FetchResult> = symbols.iter()
+        .map(|symbol| {
+            let fetched = client.get_timeseries(symbol, &Interval::Day)
+                .and_then(|rows| Ok((cache.store(symbol, &rows)?, rows)));
+            match fetched {
+                Ok((path, rows)) => FetchResult {
+                    symbol: symbol.clone(),
+                    path: Some(path),
+                    bars: rows.len(),
+                    first: rows.first().map(|r| r.date.clone()),
+                    last: rows.last().map(|r| r.date.clone()),
+                    error: None,
+                },
+                Err(e) => FetchResult {
+                    symbol: symbol.clone(), path: None, bars: 0, first: None, last: None, error: Some(e.to_string()),
+                },
+            }
+        })
+        .collect();
+
+    let usage = client.usage();
+    emit(format, &FetchReport { results: &results, api_usage: &usage }, || {
+        let mut lines: Vec<String> = results.iter()
+            .map(|r| match &r.error {
+                None => format!(
+                    "{}: {} bars {} to {} -> {}",
+                    r.symbol, r.bars, r.first.as_deref().unwrap_or("-"), r.last.as_deref().unwrap_or("-"),
+                    r.path.as_ref().map(|p| p.display().to_string()).unwrap_or_default(),
+                ),
+                Some(e) => format!("{}: failed: {}", r.symbol, e),
+            })
+            .collect();
+        lines.push(format!(
+            "API calls: {} ({} retries, {} rate limited), waited {:.1}s; {} left this minute, {} today",
+            usage.calls, usage.retries, usage.rate_limited, usage.waited_secs, usage.minute_remaining,
+            usage.day_remaining.map_or("unlimited".to_string(), |n| n.to_string()),
+        ));
+        lines.join("\n")
+    })?;
+    Ok(results.iter().all(|r| r.error.is_none()))
+}
+
+fn backtest(config: &Path, format: OutputFormat) -> Result<bool> {
+    let config = BacktestConfig::from_file(config)?;
+    let mut summaries: Vec<BacktestSummary> = vec![];
+    for symbol in &config.universe {
+        let mut summary = BacktestSummary {
+            symbol: symbol.clone(),
+            run_id: None,
+            metrics: None,
+            exported_files: vec![],
+            report: None,
+            stored: false,
+            executions: vec![],
+            error: None,
+        };
+        let outcome = (|| -> Result<()> {
+            let data = config.load_data(symbol)?;
+            let strategy = config.strategy()?;
+            let mut backtest = config.backtest()?;
+            let result = backtest.run(&*strategy, &data, &Metadata::new(symbol.clone()))?;
+            let manifest = config.manifest(symbol, &data);
+            summary.run_id = Some(manifest.run_id.clone());
+            summary.metrics = Some(result.metrics());
+            summary.executions = result.portfolio.blotter.parents().to_vec();
+            let outputs = config.write_outputs(&result, &manifest)?;
+            summary.exported_files = outputs.exported_files;
+            summary.report = outputs.report;
+            summary.stored = outputs.run_id.is_some();
+            if let Some(run_id) = outputs.run_id {
+                summary.run_id = Some(run_id);
+            }
+            Ok(())
+        })();
+        summary.error = outcome.err().map(|e| e.to_string());
+        summaries.push(summary);
+    }
+
+    emit(format, &summaries, || {
+        summaries.iter()
+            .map(|s| match (&s.metrics, &s.error) {
+                (_, Some(e)) => format!("{}: failed: {}", s.symbol, e),
+                (Some(m), None) => {
+                    let mut line = format!(
+                        "{}: return {:.2}%  sharpe {:.2}  max drawdown {:.2}%  trades {}  [{}]",
+                        s.symbol, m.total_return * 100.0, m.sharpe_ratio, m.max_drawdown * 100.0, m.n_trades,
+                        s.run_id.as_deref().unwrap_or(""),
+                    );
+                    if let Some(shortfall) = average_shortfall_bps(&s.executions) {
+                        let filled = s.executions.iter().filter(|p| p.status == OrderStatus::Filled).count();
+                        line.push_str(&format!(
+                            "\n  executions: {} worked, {} filled, shortfall {:.1}bps vs arrival",
+                            s.executions.len(), filled, shortfall,
+                        ));
+                    }
+                    line
+                },
+                (None, None) => format!("{}: no result", s.symbol),
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    })?;
+    Ok(summaries.iter().all(|s| s.error.is_none()))
+}
+
+fn optimize(
+    config: &Path,
+    params: &[String],
+    metric: &str,
+    top: Option<usize>,
+    format: OutputFormat,
+) -> Result<bool> {
+    if !Metrics::NAMES.contains(&metric) {
+        return Err(EngineError::Config(format!("Unknown metric {:?}, expected one of {:?}", metric, Metrics::NAMES)));
+    }
+    let config = BacktestConfig::from_file(config)?;
+    let grid = ParameterGrid::parse(params)?;
+    let mut data: HashMap<String, Vec<_>> = HashMap::new();
+    for symbol in &config.universe {
+        data.insert(symbol.clone(), config.load_data(symbol)?);
+    }
+
+    let mut results: Vec<SweepResult> = sweep(&config, &grid, &data)?;
+    rank(&mut results, metric)?;
+    if let Some(top) = top {
+        results.truncate(top);
+    }
+
+    emit(format, &results, || {
+        results.iter()
+            .map(|r| {
+                let parameters: Vec<String> = r.parameters.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+                format!(
+                    "{:<8} {}  {}={:.4}",
+                    r.symbol, parameters.join(" "), metric, r.metrics.value(metric).unwrap_or(f64::NAN),
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    })?;
+    Ok(true)
+}
+
+fn report(store: &Path, run_id: Option<&str>, out: &Path, format: OutputFormat) -> Result<bool> {
+    let store = RunStore::open(store)?;
+    let run_id = match run_id {
+        Some(run_id) => run_id.to_string(),
+        None => store.list_runs(&RunFilter { limit: Some(1), ..Default::default() })?
+            .pop()
+            .ok_or_else(|| EngineError::Store("The run store is empty".to_string()))?
+            .run_id,
     };
+    write_tearsheet(&store.load_run(&run_id)?, out)?;
+
+    let result = ReportResult { run_id, path: out.to_path_buf() };
+    emit(format, &result, || format!("{} -> {}", result.run_id, result.path.display()))?;
+    Ok(true)
+}
+
+fn strategies(format: OutputFormat) -> Result<bool> {
+    let factory = get_strategy_factory();
+    let infos: Vec<StrategyInfo> = factory.names().into_iter().filter_map(|name| factory.info(name)).collect();
+    emit(format, &infos, || {
+        infos.iter()
+            .map(|info| {
+                let parameters: Vec<String> = info.parameters.iter()
+                    .map(|p| format!(
+                        "    {:<16}{:<12}{:<10}{}",
+                        p.name, p.kind, p.default.as_deref().unwrap_or(if p.required { "required" } else { "-" }), p.description,
+                    ))
+                    .collect();
+                format!("{}: {}\n{}", info.name, info.description, parameters.join("\n"))
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n")
+    })?;
+    Ok(true)
+}
+
+fn validate_data(args: &SymbolArgs, format: OutputFormat) -> Result<bool> {
+    let (symbols, cache, _) = args.resolve()?;
+    let results: Vec<DataValidation> = symbols.iter()
+        .map(|symbol| match cache.load(symbol) {
+            Ok(rows) => DataValidation { symbol: symbol.clone(), bars: rows.len(), issues: validate_bars(&rows), error: None },
+            Err(e) => DataValidation { symbol: symbol.clone(), bars: 0, issues: vec![], error: Some(e.to_string()) },
+        })
+        .collect();
+
+    emit(format, &results, || {
+        let mut lines: Vec<String> = vec![];
+        for result in &results {
+            match &result.error {
+                Some(e) => lines.push(format!("{}: failed: {}", result.symbol, e)),
+                None => {
+                    lines.push(format!("{}: {} bars, {} issues", result.symbol, result.bars, result.issues.len()));
+                    lines.extend(result.issues.iter().map(|i| format!("    {} {:?}: {}", i.date, i.severity, i.message)));
+                },
+            }
+        }
+        lines.join("\n")
+    })?;
+    Ok(results.iter().all(|r| r.error.is_none() && r.issues.iter().all(|i| i.severity != IssueSeverity::Error)))
+}
 
-    let portfolio = Portfolio::new(capital);
+fn paper(config: &Path, limits: LiveLimits, status_only: bool, format: OutputFormat) -> Result<bool> {
+    let config = BacktestConfig::from_file(config)?;
+    if !status_only {
+        let mut traders = config.universe.iter()
+            .map(|symbol| PaperTrader::open(&config, symbol))
+            .collect::<Result<Vec<_>>>()?;
+        run_live(&mut traders, &config.paper, &AtomicBool::new(false), limits)?;
+    }
 
-    let strategy_factory: &mut StrategyFactory = get_strategy_factory();
+    let summaries: Vec<PaperSummary> = status(&config)?;
+    emit(format, &summaries, || {
+        summaries.iter()
+            .map(|s| format!(
+                "{}: position {}  equity {:.2}  cash {:.2}  pnl {:.2}  open orders {}  fills {}  last bar {}",
+                s.symbol, s.position, s.equity, s.cash, s.pnl, s.open_orders, s.fills, s.last_bar.as_deref().unwrap_or("-"),
+            ))
+            .collect::<Vec<_>>()
+            .join("\n")
+    })?;
+    Ok(true)
+}
+
+fn mock_broker(port: u16, cash: f64, prices: &[String], max_fill: Option<i64>, format: OutputFormat) -> Result<bool> {
+    let broker = MockAlpaca::start(port, cash)?;
+    for (symbol, price) in parse_prices(prices)? {
+        broker.set_price(symbol, price);
+    }
+    broker.set_max_fill(max_fill);
+    let url = broker.url();
+    emit(format, &serde_json::json!({ "url": url }), || format!("Serving a mock Alpaca account on {}", url))?;
+    broker.wait();
+    Ok(true)
+}
+
+fn mock_acceptor(port: u16, comp_id: &str, prices: &[String], max_fill: Option<i64>, lose_reports: u32, format: OutputFormat) -> Result<bool> {
+    let acceptor = MockAcceptor::start(port, comp_id)?;
+    for (symbol, price) in parse_prices(prices)? {
+        acceptor.set_price(symbol, price);
+    }
+    acceptor.set_max_fill(max_fill);
+    acceptor.lose_reports(lose_reports);
+    let addr = acceptor.addr();
+    emit(format, &serde_json::json!({ "addr": addr.to_string(), "comp_id": comp_id }), || {
+        format!("Accepting FIX 4.4 sessions to {} on {}", comp_id, addr)
+    })?;
+    acceptor.wait();
+    Ok(true)
+}
+
+/// `SYMBOL=PRICE` pairs.
+fn parse_prices(specs: &[String]) -> Result<Vec<(&str, f64)>> {
+    let mut prices = vec![];
+    for spec in specs {
+        let (symbol, price) = spec.split_once('=').ok_or_else(|| EngineError::Config(format!("Expected SYMBOL=PRICE, got {:?}", spec)))?;
+        let price = price.parse()
+            .map_err(|e| EngineError::Config(format!("Invalid price in {:?}: {}", spec, e)))?;
+        prices.push((symbol, price));
+    }
+    Ok(prices)
+}
+
+fn mock_server(fixtures: Option<PathBuf>, port: u16, format: OutputFormat) -> Result<bool> {
+    let server = FixtureServer::start(fixtures.map_or(Fixtures::Bundled, Fixtures::Dir), port)?;
+    let url = server.url();
+    emit(format, &serde_json::json!({ "url": url }), || format!("Serving Alpha Vantage fixtures on {}", url))?;
+    server.wait();
+    Ok(true)
+}
 
-    let strategy: Box<dyn Strategy> = strategy_factory.create("ma_crossover", window, long_qty, short_qty)
-        .expect("Unable to generate strategy.");
-    let mut backtest = Backtest::new(window, portfolio);
 
-    let result = backtest.run(&*strategy, &data, &metadata)
-        .expect("Backtesting error.");
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    Builder::new()
+        .filter_level(if cli.verbose { LevelFilter::Info } else { LevelFilter::Warn })
+        .init();
 
-    info!("{:?}", result);
+    let outcome = match &cli.command {
+        Command::Fetch(args) => fetch(args, cli.format),
+        Command::Backtest { config } => backtest(config, cli.format),
+        Command::Optimize { config, params, metric, top } => optimize(config, params, metric, *top, cli.format),
+        Command::Report { store, run_id, out } => report(store, run_id.as_deref(), out, cli.format),
+        Command::Strategies => strategies(cli.format),
+        Command::ValidateData(args) => validate_data(args, cli.format),
+        Command::Paper { config, sessions, polls, status } => {
+            paper(config, LiveLimits { sessions: *sessions, polls: *polls }, *status, cli.format)
+        },
+        Command::MockBroker { port, cash, prices, max_fill } => mock_broker(*port, *cash, prices, *max_fill, cli.format),
+        Command::MockAcceptor { port, comp_id, prices, max_fill, lose_reports } => {
+            mock_acceptor(*port, comp_id, prices, *max_fill, *lose_reports, cli.format)
+        },
+        Command::MockServer { fixtures, port } => mock_server(fixtures.clone(), *port, cli.format),
+    };
+
+    match outcome {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            match cli.format {
+                OutputFormat::Json => println!("{}", serde_json::json!({ "error": e.to_string() })),
+                OutputFormat::Text => eprintln!("error: {}", e),
+            }
+            ExitCode::from(2)
+        },
+    }
 }
@@ -0,0 +1,120 @@
+//!
+//! Performance metrics.
+//!
+//! The backtest records an `EquityPoint` per bar; `Metrics` summarises the
+//! resulting curve. Annualisation assumes daily bars.
+//!
+
+use chrono::{DateTime, Utc};
+use derive_new::new;
+use serde::Serialize;
+
+
+const PERIODS_PER_YEAR: f64 = 252.0;
+
+#[derive(Debug, Clone, new, Serialize)]
+pub struct EquityPoint {
+    pub timestamp: DateTime<Utc>,
+    pub equity: f64,
+    pub cash: f64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Metrics {
+    pub initial_equity: f64,
+    pub final_equity: f64,
+    pub total_return: f64,
+    pub annualized_return: f64,
+    pub annualized_volatility: f64,
+    pub sharpe_ratio: f64,
+    pub max_drawdown: f64,
+    pub n_trades: usize,
+}
+impl Metrics {
+    pub fn from_equity_curve(curve: &[EquityPoint], n_trades: usize) -> Self {
+        let (first, last) = match (curve.first(), curve.last()) {
+            (Some(first), Some(last)) if first.equity > 0.0 => (first, last),
+            _ => return Metrics { n_trades, ..Default::default() },
+        };
+
+        let returns = period_returns(curve);
+        let total_return = last.equity / first.equity - 1.0;
+        let years = returns.len() as f64 / PERIODS_PER_YEAR;
+        let annualized_return = if years > 0.0 && total_return > -1.0 {
+            (1.0 + total_return).powf(1.0 / years) - 1.0
+        } else {
+            0.0
+        };
+        let (mean, std_dev) = mean_std(&returns);
+        let annualized_volatility = std_dev * PERIODS_PER_YEAR.sqrt();
+        let sharpe_ratio = if std_dev > 0.0 {
+            mean / std_dev * PERIODS_PER_YEAR.sqrt()
+        } else {
+            0.0
+        };
+        let max_drawdown = drawdowns(curve).into_iter().fold(0.0, f64::min);
+
+        Metrics {
+            initial_equity: first.equity,
+            final_equity: last.equity,
+            total_return,
+            annualized_return,
+            annualized_volatility,
+            sharpe_ratio,
+            max_drawdown,
+            n_trades,
+        }
+    }
+}
+
+impl Metrics {
+    pub const NAMES: [&'static str; 8] = [
+        "initial_equity", "final_equity", "total_return", "annualized_return",
+        "annualized_volatility", "sharpe_ratio", "max_drawdown", "n_trades",
+    ];
+
+    /// A metric by field name.
+    pub fn value(&self, name: &str) -> Option<f64> {
+        match name {
+            "initial_equity" => Some(self.initial_equity),
+            "final_equity" => Some(self.final_equity),
+            "total_return" => Some(self.total_return),
+            "annualized_return" => Some(self.annualized_return),
+            "annualized_volatility" => Some(self.annualized_volatility),
+            "sharpe_ratio" => Some(self.sharpe_ratio),
+            "max_drawdown" => Some(self.max_drawdown),
+            "n_trades" => Some(self.n_trades as f64),
+            _ => None,
+        }
+    }
+}
+
+/// Simple returns between consecutive equity points.
+pub fn period_returns(curve: &[EquityPoint]) -> Vec<f64> {
+    curve.windows(2)
+        .filter(|w| w[0].equity != 0.0)
+        .map(|w| w[1].equity / w[0].equity - 1.0)
+        .collect()
+}
+
+/// Drawdown from the running peak at each point, as a non-positive
+/// fraction.
+pub fn drawdowns(curve: &[EquityPoint]) -> Vec<f64> {
+    let mut peak = f64::MIN;
+    curve.iter()
+        .map(|point| {
+            peak = peak.max(point.equity);
+            if peak > 0.0 { point.equity / peak - 1.0 } else { 0.0 }
+        })
+        .collect()
+}
+
+/// Sample mean and standard deviation.
+pub fn mean_std(values: &[f64]) -> (f64, f64) {
+    if values.len() < 2 {
+        return (values.first().copied().unwrap_or(0.0), 0.0);
+    }
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (values.len() - 1) as f64;
+    (mean, variance.sqrt())
+}
@@ -0,0 +1,112 @@
+//!
+//! Parameter sweeps.
+//!
+//! A `ParameterGrid` lists candidate values for configuration fields,
+//! e.g. `strategy.window=20,50,90`. `sweep` backtests every combination
+//! on every symbol of the universe and `rank` orders the results by a
+//! chosen metric.
+//!
+
+use crate::config::BacktestConfig;
+use crate::data_loading::{DatedStockData, Metadata};
+use crate::error::{EngineError, Result};
+use crate::metrics::Metrics;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+
+#[derive(Debug, Clone, Default)]
+pub struct ParameterGrid {
+    axes: Vec<(String, Vec<String>)>,
+}
+impl ParameterGrid {
+    /// Parses `path=value1,value2,...` specs, one per axis.
+    pub fn parse(specs: &[String]) -> Result<Self> {
+        let mut axes: Vec<(String, Vec<String>)> = vec![];
+        for spec in specs {
+            let (path, values) = spec.split_once('=')
+                .ok_or_else(|| EngineError::Config(format!("Invalid parameter spec {:?}, expected path=v1,v2", spec)))?;
+            let values: Vec<String> = values.split(',')
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+                .collect();
+            if values.is_empty() {
+                return Err(EngineError::Config(format!("No values given for {}", path)));
+            }
+            axes.push((path.trim().to_string(), values));
+        }
+        Ok(ParameterGrid { axes })
+    }
+
+    pub fn len(&self) -> usize {
+        if self.axes.is_empty() { 0 } else { self.axes.iter().map(|(_, v)| v.len()).product() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Every combination of values, as configuration overrides.
+    pub fn combinations(&self) -> Vec<Vec<(String, String)>> {
+        let mut combinations: Vec<Vec<(String, String)>> = vec![vec![]];
+        for (path, values) in &self.axes {
+            combinations = combinations.into_iter()
+                .flat_map(|prefix| {
+                    values.iter().map(move |value| {
+                        let mut combination = prefix.clone();
+                        combination.push((path.clone(), value.clone()));
+                        combination
+                    })
+                })
+                .collect();
+        }
+        combinations
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SweepResult {
+    pub symbol: String,
+    pub parameters: BTreeMap<String, String>,
+    pub metrics: Metrics,
+}
+
+/// Backtests every grid combination on the preloaded `data` for each
+/// symbol of the configuration's universe.
+pub fn sweep(
+    config: &BacktestConfig,
+    grid: &ParameterGrid,
+    data: &HashMap<String, Vec<DatedStockData>>,
+) -> Result<Vec<SweepResult>> {
+    let mut results: Vec<SweepResult> = vec![];
+    for overrides in grid.combinations() {
+        let candidate = config.with_overrides(&overrides)?;
+        for symbol in &candidate.universe {
+            let bars = data.get(symbol).ok_or_else(|| EngineError::DataFetch(format!("No data loaded for {}", symbol)))?;
+            let strategy = candidate.strategy()?;
+            let mut backtest = candidate.backtest()?;
+            let result = backtest.run(&*strategy, bars, &Metadata::new(symbol.clone()))?;
+            results.push(SweepResult {
+                symbol: symbol.clone(),
+                parameters: overrides.iter().cloned().collect(),
+                metrics: result.metrics(),
+            });
+        }
+    }
+    Ok(results)
+}
+
+/// Sorts best first by `metric`. Volatility ranks lower-is-better, every
+/// other metric higher-is-better.
+pub fn rank(results: &mut [SweepResult], metric: &str) -> Result<()> {
+    if Metrics::default().value(metric).is_none() {
+        return Err(EngineError::Config(format!("Unknown metric {:?}, expected one of {:?}", metric, Metrics::NAMES)));
+    }
+    let ascending = metric == "annualized_volatility";
+    results.sort_by(|a, b| {
+        let (a, b) = (a.metrics.value(metric).unwrap_or(f64::NAN), b.metrics.value(metric).unwrap_or(f64::NAN));
+        let order = a.partial_cmp(&b).unwrap_or(std::cmp::Ordering::Equal);
+        if ascending { order } else { order.reverse() }
+    });
+    Ok(())
+}
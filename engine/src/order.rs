@@ -1,21 +1,248 @@
+//!
+//! Order objects.
+//!
+//! Every order carries a unique identifier and an explicit lifecycle
+//! status. Fills are recorded against the order they belong to, and the
+//! `OrderBlotter` keeps the full history of orders submitted during a run.
+//!
 
 use chrono::{DateTime, Utc};
+use crate::clock;
+use crate::error::{EngineError, Result};
+use crate::execution::ParentOrder;
 use derive_new::new;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-#[allow(dead_code)]
-#[derive(Debug, new)]
+static NEXT_ORDER_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct OrderId(pub u64);
+impl OrderId {
+    pub fn next() -> Self {
+        OrderId(NEXT_ORDER_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Keeps ids issued from now on clear of `id`, for orders restored
+    /// from an earlier process.
+    pub fn reserve(id: OrderId) {
+        NEXT_ORDER_ID.fetch_max(id.0 + 1, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Display for OrderId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderStatus {
+    New,
+    /// Held back by the risk manager; not yet sent to the broker.
+    Held,
+    Accepted,
+    PartiallyFilled,
+    Filled,
+    Cancelled,
+    Rejected,
+    Expired,
+}
+impl OrderStatus {
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            OrderStatus::Filled | OrderStatus::Cancelled | OrderStatus::Rejected | OrderStatus::Expired
+        )
+    }
+
+    pub fn can_transition_to(&self, next: OrderStatus) -> bool {
+        use OrderStatus::*;
+        matches!(
+            (self, next),
+            (New, Accepted)
+                | (New, Held)
+                | (New, Rejected)
+                | (New, Cancelled)
+                | (Held, Accepted)
+                | (Held, Rejected)
+                | (Held, Cancelled)
+                | (Accepted, PartiallyFilled)
+                | (Accepted, Filled)
+                | (Accepted, Cancelled)
+                | (Accepted, Rejected)
+                | (Accepted, Expired)
+                | (PartiallyFilled, PartiallyFilled)
+                | (PartiallyFilled, Filled)
+                | (PartiallyFilled, Cancelled)
+                | (PartiallyFilled, Expired)
+        )
+    }
+}
+
+impl fmt::Display for OrderStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            OrderStatus::New => "new",
+            OrderStatus::Held => "held",
+            OrderStatus::Accepted => "accepted",
+            OrderStatus::PartiallyFilled => "partially_filled",
+            OrderStatus::Filled => "filled",
+            OrderStatus::Cancelled => "cancelled",
+            OrderStatus::Rejected => "rejected",
+            OrderStatus::Expired => "expired",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// How long an order keeps working before its remainder expires.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeInForce {
+    /// Works on every bar until filled or cancelled.
+    GoodTilCancelled,
+    /// Fills what it can on the submission bar; the rest expires.
+    ImmediateOrCancel,
+    /// Works for at most this many bars.
+    GoodForBars(u32),
+}
+
+impl fmt::Display for TimeInForce {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimeInForce::GoodTilCancelled => write!(f, "gtc"),
+            TimeInForce::ImmediateOrCancel => write!(f, "ioc"),
+            TimeInForce::GoodForBars(bars) => write!(f, "good_for_bars:{}", bars),
+        }
+    }
+}
+
+#[derive(Debug, Clone, new, Serialize, Deserialize)]
+pub struct Fill {
+    pub order_id: OrderId,
+    pub ticker: String,
+    pub timestamp: DateTime<Utc>,
+    pub quantity: i64,
+    pub price: f64,
+    pub trading_costs: f64,
+}
+
+#[derive(Debug, Clone, new, Serialize, Deserialize)]
 pub struct Order {
-    #[new(value = "Utc::now()")]
+    #[new(value = "OrderId::next()")]
+    pub id: OrderId,
+    #[new(value = "clock::now()")]
     pub timestamp: DateTime<Utc>,
     pub ticker: String,
     pub quantity: i64,
+    #[new(value = "OrderStatus::New")]
+    pub status: OrderStatus,
+    #[new(value = "0")]
+    pub filled_quantity: i64,
+    #[new(value = "vec![]")]
+    pub fills: Vec<Fill>,
+    #[new(value = "TimeInForce::GoodTilCancelled")]
+    pub time_in_force: TimeInForce,
+    #[new(value = "0")]
+    pub bars_worked: u32,
+}
+impl Order {
+    pub fn with_time_in_force(mut self, time_in_force: TimeInForce) -> Self {
+        self.time_in_force = time_in_force;
+        self
+    }
+
+    pub fn remaining_quantity(&self) -> i64 {
+        self.quantity - self.filled_quantity
+    }
+
+    pub fn is_open(&self) -> bool {
+        !self.status.is_terminal()
+    }
+
+    /// Whether the order has been sent to the broker.
+    pub fn is_sent(&self) -> bool {
+        !matches!(self.status, OrderStatus::New | OrderStatus::Held)
+    }
+
+    pub fn transition(&mut self, next: OrderStatus) -> Result<()> {
+        if !self.status.can_transition_to(next) {
+            return Err(EngineError::Accounting(format!(
+                "Order {}: invalid status transition {} -> {}",
+                self.id, self.status, next,
+            )));
+        }
+        self.status = next;
+        Ok(())
+    }
+
+    pub fn apply_fill(&mut self, fill: Fill) -> Result<OrderStatus> {
+        if fill.order_id != self.id {
+            return Err(EngineError::Accounting(format!(
+                "Fill for order {} applied to order {}", fill.order_id, self.id,
+            )));
+        }
+        if fill.quantity.signum() * self.quantity.signum() < 0
+            || fill.quantity.abs() > self.remaining_quantity().abs()
+        {
+            return Err(EngineError::Accounting(format!(
+                "Order {}: fill of {} exceeds remaining quantity {}",
+                self.id, fill.quantity, self.remaining_quantity(),
+            )));
+        }
+
+        let next = if fill.quantity == self.remaining_quantity() {
+            OrderStatus::Filled
+        } else {
+            OrderStatus::PartiallyFilled
+        };
+        self.transition(next)?;
+        self.filled_quantity += fill.quantity;
+        self.fills.push(fill);
+
+        Ok(next)
+    }
+
+    /// Records that the order was worked on another bar, expiring it if
+    /// its time in force has run out.
+    pub fn end_bar(&mut self) -> Result<()> {
+        if !self.is_open() {
+            return Ok(());
+        }
+        self.bars_worked += 1;
+        let expired = match self.time_in_force {
+            TimeInForce::GoodTilCancelled => false,
+            TimeInForce::ImmediateOrCancel => true,
+            TimeInForce::GoodForBars(n) => self.bars_worked >= n,
+        };
+        if expired {
+            self.transition(OrderStatus::Expired)?;
+        }
+        Ok(())
+    }
+
+    pub fn average_fill_price(&self) -> Option<f64> {
+        if self.filled_quantity == 0 {
+            return None;
+        }
+        let notional: f64 = self.fills.iter()
+            .map(|f| f.price * f.quantity as f64)
+            .sum();
+        Some(notional / self.filled_quantity as f64)
+    }
 }
 
 #[allow(dead_code)]
 #[derive(Debug, new)]
 pub struct OrderResult {
+    pub order_id: OrderId,
     pub ticker: String,
-    #[new(value = "Utc::now()")]
+    #[new(value = "clock::now()")]
     pub timestamp: DateTime<Utc>,
     pub filled_quantity: i64,
     pub filled_price: f64,
@@ -24,9 +251,214 @@ pub struct OrderResult {
 #[allow(dead_code)]
 #[derive(Debug, new)]
 pub struct Confirm {
+    pub order_id: OrderId,
     pub ticker: String,
     pub executed_timestamp: DateTime<Utc>,
     pub quantity_filled: i64,
     pub executed_price: f64,
     pub trading_costs: f64,
 }
+impl Confirm {
+    pub fn to_fill(&self) -> Fill {
+        Fill::new(
+            self.order_id,
+            self.ticker.clone(),
+            self.executed_timestamp,
+            self.quantity_filled,
+            self.executed_price,
+            self.trading_costs,
+        )
+    }
+}
+
+/// Chronological record of every order submitted, queryable by id,
+/// status or ticker, and of the parent orders execution algos work
+/// through some of them.
+#[derive(Debug, Clone, Default)]
+pub struct OrderBlotter {
+    orders: Vec<Order>,
+    index: HashMap<OrderId, usize>,
+    parents: Vec<ParentOrder>,
+}
+impl OrderBlotter {
+    pub fn submit(&mut self, order: Order) -> OrderId {
+        let id = order.id;
+        self.index.insert(id, self.orders.len());
+        self.orders.push(order);
+        id
+    }
+
+    pub fn get(&self, id: OrderId) -> Option<&Order> {
+        self.index.get(&id).map(|&i| &self.orders[i])
+    }
+
+    fn get_mut(&mut self, id: OrderId) -> Result<&mut Order> {
+        let i = *self.index.get(&id).ok_or_else(|| EngineError::Accounting(format!("Unknown order {}", id)))?;
+        Ok(&mut self.orders[i])
+    }
+
+    pub fn transition(&mut self, id: OrderId, next: OrderStatus) -> Result<()> {
+        self.get_mut(id)?.transition(next)
+    }
+
+    pub fn record_fill(&mut self, fill: Fill) -> Result<OrderStatus> {
+        self.get_mut(fill.order_id)?.apply_fill(fill)
+    }
+
+    /// Changes the quantity of an order the broker has not seen yet.
+    pub fn resize(&mut self, id: OrderId, quantity: i64) -> Result<()> {
+        let order = self.get_mut(id)?;
+        if order.is_sent() {
+            return Err(EngineError::Accounting(format!("Order {}: cannot resize once {}", id, order.status)));
+        }
+        order.quantity = quantity;
+        Ok(())
+    }
+
+    pub fn cancel(&mut self, id: OrderId) -> Result<()> {
+        self.transition(id, OrderStatus::Cancelled)
+    }
+
+    pub fn end_bar(&mut self, id: OrderId) -> Result<()> {
+        self.get_mut(id)?.end_bar()
+    }
+
+    pub fn orders(&self) -> &[Order] {
+        &self.orders
+    }
+
+    pub fn len(&self) -> usize {
+        self.orders.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orders.is_empty()
+    }
+
+    pub fn with_status(&self, status: OrderStatus) -> impl Iterator<Item = &Order> {
+        self.orders.iter().filter(move |o| o.status == status)
+    }
+
+    pub fn open_orders(&self) -> impl Iterator<Item = &Order> {
+        self.orders.iter().filter(|o| o.is_open())
+    }
+
+    pub fn for_ticker<'a>(&'a self, ticker: &'a str) -> impl Iterator<Item = &'a Order> {
+        self.orders.iter().filter(move |o| o.ticker == ticker)
+    }
+
+    pub fn fills(&self) -> impl Iterator<Item = &Fill> {
+        self.orders.iter().flat_map(|o| o.fills.iter())
+    }
+
+    pub fn work(&mut self, parent: ParentOrder) {
+        self.parents.push(parent);
+    }
+
+    pub fn parents(&self) -> &[ParentOrder] {
+        &self.parents
+    }
+
+    pub fn parents_mut(&mut self) -> &mut [ParentOrder] {
+        &mut self.parents
+    }
+
+    /// Brings open parents up to date with their children's fills.
+    pub fn refresh_parents(&mut self) {
+        let (orders, index) = (&self.orders, &self.index);
+        for parent in self.parents.iter_mut().filter(|p| p.is_open()) {
+            let children: Vec<&Order> = parent.children.iter().filter_map(|id| index.get(id).map(|&i| &orders[i])).collect();
+            parent.refresh(children.into_iter());
+        }
+    }
+
+    /// Unfilled quantity still to trade for `ticker`: open orders,
+    /// including those the risk manager is holding, plus whatever open
+    /// parents have yet to send.
+    pub fn pending_quantity(&self, ticker: &str) -> i64 {
+        let parents: Vec<&ParentOrder> = self.parents.iter().filter(|p| p.ticker == ticker && p.is_open()).collect();
+        let unsent: i64 = parents.iter()
+            .map(|p| p.quantity - p.children.iter().filter_map(|id| self.get(*id)).map(|o| o.filled_quantity).sum::<i64>())
+            .sum();
+        let open: i64 = self.for_ticker(ticker)
+            .filter(|o| o.is_open() && !parents.iter().any(|p| p.children.contains(&o.id)))
+            .map(|o| o.remaining_quantity())
+            .sum();
+        open + unsent
+    }
+
+    /// Whether an order or parent order for `ticker` is still working.
+    pub fn is_working(&self, ticker: &str) -> bool {
+        self.for_ticker(ticker).any(|o| o.is_open()) || self.parents.iter().any(|p| p.ticker == ticker && p.is_open())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accepted(quantity: i64) -> Order {
+        let mut order = Order::new("TEST".to_string(), quantity);
+        order.transition(OrderStatus::Accepted).unwrap();
+        order
+    }
+
+    fn fill(order: &Order, quantity: i64) -> Fill {
+        Fill::new(order.id, "TEST".to_string(), Utc::now(), quantity, 100.0, 0.0)
+    }
+
+    #[test]
+    fn transitions_follow_the_lifecycle() {
+        use OrderStatus::*;
+        assert!(New.can_transition_to(Held) && Held.can_transition_to(Accepted));
+        assert!(Accepted.can_transition_to(PartiallyFilled) && PartiallyFilled.can_transition_to(Filled));
+        assert!(!New.can_transition_to(Filled));
+        assert!(!Held.can_transition_to(PartiallyFilled));
+        assert!(!PartiallyFilled.can_transition_to(Rejected));
+        for terminal in [Filled, Cancelled, Rejected, Expired] {
+            assert!(terminal.is_terminal());
+            assert!([New, Held, Accepted, PartiallyFilled, Filled, Cancelled].iter().all(|n| !terminal.can_transition_to(*n)));
+        }
+
+        let mut order = accepted(10);
+        order.transition(OrderStatus::Cancelled).unwrap();
+        let error = order.transition(OrderStatus::Accepted).unwrap_err();
+        assert!(matches!(&error, EngineError::Accounting(m) if m.contains("cancelled -> accepted")), "{:?}", error);
+        assert_eq!(order.status, OrderStatus::Cancelled);
+    }
+
+    #[test]
+    fn partial_fills_leave_the_remainder_working() {
+        let mut order = accepted(-10);
+        assert_eq!(order.apply_fill(fill(&order, -4)).unwrap(), OrderStatus::PartiallyFilled);
+        assert_eq!((order.filled_quantity, order.remaining_quantity()), (-4, -6));
+        assert!(order.is_open());
+
+        assert_eq!(order.apply_fill(fill(&order, -6)).unwrap(), OrderStatus::Filled);
+        assert_eq!((order.remaining_quantity(), order.fills.len()), (0, 2));
+        assert!(!order.is_open());
+    }
+
+    #[test]
+    fn overfills_and_wrong_side_fills_are_refused() {
+        let mut order = accepted(10);
+        order.apply_fill(fill(&order, 6)).unwrap();
+        for quantity in [5, -1] {
+            let error = order.apply_fill(fill(&order, quantity)).unwrap_err();
+            assert!(matches!(error, EngineError::Accounting(_)), "{:?}", error);
+        }
+        let other = accepted(10);
+        assert!(order.apply_fill(fill(&other, 1)).is_err());
+        assert_eq!((order.filled_quantity, order.status, order.fills.len()), (6, OrderStatus::PartiallyFilled, 1));
+    }
+
+    #[test]
+    fn good_for_bars_orders_expire() {
+        let mut order = accepted(10).with_time_in_force(TimeInForce::GoodForBars(2));
+        order.end_bar().unwrap();
+        assert_eq!(order.status, OrderStatus::Accepted);
+        order.end_bar().unwrap();
+        assert_eq!(order.status, OrderStatus::Expired);
+    }
+}
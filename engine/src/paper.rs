@@ -0,0 +1,794 @@
+//!
+//! Paper trading.
+//!
+//! A `PaperTrader` runs a configured strategy forward on bars as they
+//! arrive, through the same `Backtest::step` as a historical run, so the
+//! strategy, cost models and risk limits behave exactly as they did in
+//! testing. Bars come from a `BarFeed`: `QuoteFeed` polls Alpha Vantage
+//! quotes and emits each trading day once it closes, `FileFeed` tails a
+//! bar file that another process appends to. Orders go to the `venue`:
+//! a `PaperBroker`, the fill simulator with its ledger saved to
+//! `<paper.dir>/<SYMBOL>.broker.json`, Alpaca's API, or a desk over FIX
+//! 4.4. The engine's
+//! book is saved to `<paper.dir>/<SYMBOL>.json` after every bar and
+//! restored on start, so a restart picks up the open position, working
+//! orders and the history the strategy needs. Fills are acknowledged to
+//! the venue only once the book holding them is saved; the paper broker
+//! replays any it delivered that a crash kept out of the book. `run_live` polls every
+//! trader while the market is open and sleeps through the rest of the
+//! week.
+//!
+//! ```yaml
+//! paper:
+//!   dir: paper
+//!   feed: file
+//!   feed_dir: live
+//!   poll_secs: 60
+//!   holidays: [2024-12-25]
+//! ```
+//!
+
+use crate::alpaca::AlpacaConfig;
+use crate::backtest::{Backtest, VOLATILITY_LOOKBACK};
+use crate::broker::{Account, Broker, Ledger, SimulatedBroker};
+use crate::clock;
+use crate::config::BacktestConfig;
+use crate::data_loading::{AlphaVantage, CsvCache, DatedStockData, Metadata};
+use crate::error::{EngineError, Result};
+use crate::execution::ParentOrder;
+use crate::fix::FixConfig;
+use crate::order::{Fill, Order, OrderId};
+use crate::slippage::BarContext;
+use crate::strategy::Strategy;
+use chrono::{DateTime, Datelike, Duration as ChronoDuration, NaiveDate, NaiveDateTime, NaiveTime, Utc, Weekday};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+
+/// Bars kept in the saved state for the strategy's lookback.
+pub const MAX_HISTORY: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FeedKind {
+    /// Polls Alpha Vantage quotes through `data`'s client.
+    #[default]
+    Quote,
+    /// Tails `<feed_dir>/<SYMBOL>.csv` in the bar cache format.
+    File,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Venue {
+    /// A `PaperBroker` using the `broker` cost models.
+    #[default]
+    Simulated,
+    Alpaca,
+    Fix,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PaperConfig {
+    /// Directory holding one saved account per symbol.
+    pub dir: PathBuf,
+    pub feed: FeedKind,
+    pub feed_dir: Option<PathBuf>,
+    pub poll_secs: f64,
+    /// Only poll while NYSE is open. Off, feeds are polled around the
+    /// clock, e.g. to replay a file feed.
+    pub market_hours: bool,
+    /// Exchange holidays on top of weekends.
+    pub holidays: Vec<NaiveDate>,
+    /// Seed a new account's history from `data`, so the strategy can
+    /// trade from the first live bar.
+    pub warm_up: bool,
+    pub venue: Venue,
+    pub alpaca: AlpacaConfig,
+    pub fix: FixConfig,
+}
+impl Default for PaperConfig {
+    fn default() -> Self {
+        PaperConfig {
+            dir: PathBuf::from("paper"),
+            feed: FeedKind::Quote,
+            feed_dir: None,
+            poll_secs: 60.0,
+            market_hours: true,
+            holidays: vec![],
+            warm_up: true,
+            venue: Venue::Simulated,
+            alpaca: AlpacaConfig::default(),
+            fix: FixConfig::default(),
+        }
+    }
+}
+impl PaperConfig {
+    pub fn schedule(&self) -> MarketHours {
+        MarketHours::nyse(self.holidays.clone())
+    }
+
+    pub fn state_path(&self, symbol: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", symbol))
+    }
+
+    pub fn broker_path(&self, symbol: &str) -> PathBuf {
+        self.dir.join(format!("{}.broker.json", symbol))
+    }
+
+    /// Problems with the settings, for config validation.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.feed == FeedKind::File && self.feed_dir.is_none() {
+            problems.push("feed_dir: required when feed is file".to_string());
+        }
+        if !(self.poll_secs > 0.0 && self.poll_secs.is_finite()) {
+            problems.push(format!("poll_secs: must be positive, got {}", self.poll_secs));
+        }
+        if self.venue == Venue::Alpaca {
+            problems.extend(self.alpaca.problems().into_iter().map(|p| format!("alpaca.{}", p)));
+        }
+        if self.venue == Venue::Fix {
+            problems.extend(self.fix.problems().into_iter().map(|p| format!("fix.{}", p)));
+        }
+        problems
+    }
+}
+
+
+/// Regular trading hours of a US exchange in US/Eastern time, with
+/// daylight saving from the second Sunday of March to the first Sunday
+/// of November.
+#[derive(Debug, Clone)]
+pub struct MarketHours {
+    pub open: NaiveTime,
+    pub close: NaiveTime,
+    pub holidays: Vec<NaiveDate>,
+}
+impl MarketHours {
+    /// 09:30 to 16:00.
+    pub fn nyse(holidays: Vec<NaiveDate>) -> Self {
+        MarketHours {
+            open: NaiveTime::from_hms_opt(9, 30, 0).unwrap_or(NaiveTime::MIN),
+            close: NaiveTime::from_hms_opt(16, 0, 0).unwrap_or(NaiveTime::MIN),
+            holidays,
+        }
+    }
+
+    pub fn is_trading_day(&self, day: NaiveDate) -> bool {
+        !matches!(day.weekday(), Weekday::Sat | Weekday::Sun) && !self.holidays.contains(&day)
+    }
+
+    pub fn is_open(&self, now: DateTime<Utc>) -> bool {
+        let local = eastern(now);
+        self.is_trading_day(local.date()) && local.time() >= self.open && local.time() < self.close
+    }
+
+    /// Whether trading on `day` has finished.
+    pub fn is_closed_for(&self, day: NaiveDate, now: DateTime<Utc>) -> bool {
+        now >= utc(day.and_time(self.close))
+    }
+
+    /// The next opening bell after `now`, or `now` if the market is open.
+    pub fn next_open(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        if self.is_open(now) {
+            return now;
+        }
+        let local = eastern(now);
+        let mut day = local.date();
+        if local.time() >= self.open {
+            day += ChronoDuration::days(1);
+        }
+        while !self.is_trading_day(day) {
+            day += ChronoDuration::days(1);
+        }
+        utc(day.and_time(self.open))
+    }
+}
+
+fn is_daylight_saving(day: NaiveDate) -> bool {
+    let sunday = |month: u32, nth: u32| -> Option<NaiveDate> {
+        NaiveDate::from_weekday_of_month_opt(day.year(), month, Weekday::Sun, nth as u8)
+    };
+    match (sunday(3, 2), sunday(11, 1)) {
+        (Some(start), Some(end)) => day >= start && day < end,
+        _ => false,
+    }
+}
+
+fn utc_offset_hours(day: NaiveDate) -> i64 {
+    if is_daylight_saving(day) { 4 } else { 5 }
+}
+
+fn eastern(now: DateTime<Utc>) -> NaiveDateTime {
+    let standard = now.naive_utc() - ChronoDuration::hours(5);
+    now.naive_utc() - ChronoDuration::hours(utc_offset_hours(standard.date()))
+}
+
+fn utc(local: NaiveDateTime) -> DateTime<Utc> {
+    (local + ChronoDuration::hours(utc_offset_hours(local.date()))).and_utc()
+}
+
+
+/// A source of new bars for one symbol.
+pub trait BarFeed {
+    /// Bars that arrived since the last poll, oldest first.
+    fn poll(&mut self) -> Result<Vec<DatedStockData>>;
+}
+
+/// Daily bars built from `AlphaVantage::get_quote_bar`. A day's bar is
+/// emitted once the market has closed for it, or once a quote for a
+/// later day shows it missed the close.
+#[derive(Debug)]
+pub struct QuoteFeed {
+    client: AlphaVantage,
+    symbol: String,
+    schedule: MarketHours,
+    pending: Option<DatedStockData>,
+    emitted: Option<String>,
+}
+impl QuoteFeed {
+    pub fn new(client: AlphaVantage, symbol: &str, schedule: MarketHours) -> Self {
+        QuoteFeed { client, symbol: symbol.to_string(), schedule, pending: None, emitted: None }
+    }
+}
+impl BarFeed for QuoteFeed {
+    fn poll(&mut self) -> Result<Vec<DatedStockData>> {
+        let bar = self.client.get_quote_bar(&self.symbol)?;
+        let mut bars = vec![];
+        if let Some(pending) = self.pending.take().filter(|p| p.date < bar.date) {
+            bars.push(pending);
+        }
+        let day = NaiveDate::parse_from_str(&bar.date, "%Y-%m-%d")
+            .map_err(|e| EngineError::Parse(format!("Invalid quote date {:?}: {}", bar.date, e)))?;
+        if self.schedule.is_closed_for(day, clock::now()) {
+            bars.push(bar);
+        } else {
+            self.pending = Some(bar);
+        }
+        bars.retain(|b| self.emitted.as_ref().is_none_or(|e| b.date > *e));
+        if let Some(last) = bars.last() {
+            self.emitted = Some(last.date.clone());
+        }
+        Ok(bars)
+    }
+}
+
+/// Rows appended to a bar file in the `CsvCache` format. A line is only
+/// read once it ends in a newline; a file that shrinks is read again
+/// from the start.
+#[derive(Debug)]
+pub struct FileFeed {
+    path: PathBuf,
+    offset: u64,
+}
+impl FileFeed {
+    pub fn new(path: PathBuf) -> Self {
+        FileFeed { path, offset: 0 }
+    }
+}
+impl BarFeed for FileFeed {
+    fn poll(&mut self) -> Result<Vec<DatedStockData>> {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) => return Err(EngineError::DataFetch(format!("Unable to read {}: {}", self.path.display(), e))),
+        };
+        if file.metadata()?.len() < self.offset {
+            warn!("{} was truncated; reading from the start", self.path.display());
+            self.offset = 0;
+        }
+        file.seek(SeekFrom::Start(self.offset))?;
+        let mut text = String::new();
+        file.read_to_string(&mut text)?;
+        let complete = match text.rfind('\n') {
+            Some(end) => &text[..=end],
+            None => return Ok(vec![]),
+        };
+        self.offset += complete.len() as u64;
+
+        complete.lines()
+            .filter(|line| !line.trim().is_empty() && !CsvCache::is_header(line))
+            .map(|line| CsvCache::parse_row(line)
+                .map_err(|e| EngineError::Parse(format!("{}: {}", self.path.display(), e))))
+            .collect()
+    }
+}
+
+
+/// The saved form of a paper account.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PaperState {
+    pub symbol: String,
+    pub capital: isize,
+    pub cash: f64,
+    pub pnl: f64,
+    pub marks: HashMap<String, f64>,
+    pub orders: Vec<Order>,
+    /// Orders an execution algo is working or has worked.
+    #[serde(default)]
+    pub parents: Vec<ParentOrder>,
+    pub history: Vec<DatedStockData>,
+    pub updated_at: DateTime<Utc>,
+}
+impl PaperState {
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        match fs::read_to_string(path) {
+            Ok(text) => serde_json::from_str(&text)
+                .map(Some)
+                .map_err(|e| EngineError::Parse(format!("{}: {}", path.display(), e))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes through a temporary file so a crash never leaves a torn
+    /// state behind.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        write_atomic(path, &serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn summary(&self) -> PaperSummary {
+        let fills = self.orders.iter().flat_map(|o| o.fills.iter());
+        PaperSummary {
+            symbol: self.symbol.clone(),
+            last_bar: self.history.last().map(|b| b.date.clone()),
+            position: fills.clone().filter(|f| f.ticker == self.symbol).map(|f| f.quantity).sum(),
+            cash: self.cash,
+            equity: self.capital as f64 + self.pnl,
+            pnl: self.pnl,
+            open_orders: self.orders.iter().filter(|o| o.is_open()).count(),
+            fills: fills.count(),
+            updated_at: self.updated_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PaperSummary {
+    pub symbol: String,
+    pub last_bar: Option<String>,
+    pub position: i64,
+    pub cash: f64,
+    pub equity: f64,
+    pub pnl: f64,
+    pub open_orders: usize,
+    pub fills: usize,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Summaries of the saved accounts for `config`'s universe.
+pub fn status(config: &BacktestConfig) -> Result<Vec<PaperSummary>> {
+    let mut summaries = vec![];
+    for symbol in &config.universe {
+        if let Some(state) = PaperState::load(&config.paper.state_path(symbol))? {
+            summaries.push(state.summary());
+        }
+    }
+    Ok(summaries)
+}
+
+
+pub(crate) fn write_atomic(path: &Path, text: &str) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, text)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+
+/// A saved `PaperBroker`: the simulator's ledger plus the fills handed
+/// to the engine but not yet acknowledged.
+#[derive(Debug, Serialize, Deserialize)]
+struct PaperLedger {
+    #[serde(flatten)]
+    ledger: Ledger,
+    #[serde(default)]
+    delivered: Vec<Fill>,
+}
+
+/// The fill simulator as a standing venue: its ledger of cash,
+/// positions, working orders and unreported fills is saved after every
+/// change and reloaded on open. Delivered fills are kept until
+/// acknowledged.
+#[derive(Debug)]
+pub struct PaperBroker {
+    simulator: SimulatedBroker,
+    path: PathBuf,
+    delivered: Vec<Fill>,
+}
+impl PaperBroker {
+    /// Resumes the ledger at `path` if there is one, queueing again any
+    /// delivered fill missing from `orders`, the orders the saved book
+    /// sent. A new ledger starts from the simulator's cash and works the
+    /// open ones among `orders`.
+    pub fn open(path: PathBuf, simulator: SimulatedBroker, orders: &[Order]) -> Result<Self> {
+        let simulator = match fs::read_to_string(&path) {
+            Ok(text) => {
+                let saved: PaperLedger = serde_json::from_str(&text)
+                    .map_err(|e| EngineError::Parse(format!("{}: {}", path.display(), e)))?;
+                let mut ledger = saved.ledger;
+                let lost: Vec<Fill> = saved.delivered.into_iter().filter(|fill| !is_booked(fill, orders)).collect();
+                if !lost.is_empty() {
+                    warn!("Replaying {} fills delivered but not saved before {} was closed", lost.len(), path.display());
+                }
+                ledger.unreported.splice(0..0, lost);
+                simulator.with_ledger(ledger)
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let mut ledger = simulator.ledger().clone();
+                ledger.orders = orders.iter().filter(|o| o.is_open()).cloned().collect();
+                simulator.with_ledger(ledger)
+            },
+            Err(e) => return Err(e.into()),
+        };
+        let broker = PaperBroker { simulator, path, delivered: vec![] };
+        broker.save()?;
+        Ok(broker)
+    }
+
+    pub fn ledger(&self) -> &Ledger {
+        self.simulator.ledger()
+    }
+
+    fn save(&self) -> Result<()> {
+        let saved = PaperLedger { ledger: self.simulator.ledger().clone(), delivered: self.delivered.clone() };
+        write_atomic(&self.path, &serde_json::to_string_pretty(&saved)?)
+    }
+}
+
+fn is_booked(fill: &Fill, orders: &[Order]) -> bool {
+    orders.iter()
+        .filter(|o| o.id == fill.order_id)
+        .flat_map(|o| o.fills.iter())
+        .any(|f| f.timestamp == fill.timestamp && f.quantity == fill.quantity && f.price == fill.price)
+}
+impl Broker for PaperBroker {
+    fn submit(&mut self, order: &Order) -> Result<()> {
+        self.simulator.submit(order)?;
+        self.save()
+    }
+
+    fn cancel(&mut self, id: OrderId) -> Result<()> {
+        self.simulator.cancel(id)?;
+        self.save()
+    }
+
+    fn positions(&self) -> Result<BTreeMap<String, i64>> {
+        self.simulator.positions()
+    }
+
+    fn account(&self) -> Result<Account> {
+        self.simulator.account()
+    }
+
+    fn fills(&mut self) -> Result<Vec<Fill>> {
+        let fills = self.simulator.fills()?;
+        if !fills.is_empty() {
+            self.delivered.extend(fills.iter().cloned());
+            self.save()?;
+        }
+        Ok(fills)
+    }
+
+    fn acknowledge(&mut self) -> Result<()> {
+        if self.delivered.is_empty() {
+            return Ok(());
+        }
+        self.delivered.clear();
+        self.save()
+    }
+
+    fn on_bar(&mut self, ticker: &str, bar: &BarContext) -> Result<()> {
+        self.simulator.on_bar(ticker, bar)?;
+        self.save()
+    }
+}
+
+
+/// One symbol's strategy trading a paper account.
+pub struct PaperTrader {
+    metadata: Metadata,
+    strategy: Box<dyn Strategy>,
+    backtest: Backtest,
+    feed: Box<dyn BarFeed>,
+    history: Vec<DatedStockData>,
+    window: usize,
+    path: PathBuf,
+}
+impl PaperTrader {
+    /// Restores the saved account for `symbol`, or opens a new one with
+    /// the configured capital.
+    pub fn open(config: &BacktestConfig, symbol: &str) -> Result<Self> {
+        let path = config.paper.state_path(symbol);
+        let mut portfolio = config.portfolio()?;
+        let saved = PaperState::load(&path)?;
+        let is_new = saved.is_none();
+        let history = match saved {
+            Some(state) => {
+                info!("Restoring paper account {} from {}", symbol, path.display());
+                let mut fills: Vec<_> = state.orders.iter().flat_map(|o| o.fills.iter().cloned()).collect();
+                fills.sort_by_key(|f| f.timestamp);
+                for fill in &fills {
+                    portfolio.apply_fill(fill);
+                }
+                for order in state.orders {
+                    OrderId::reserve(order.id);
+                    portfolio.blotter.submit(order);
+                }
+                for parent in state.parents {
+                    OrderId::reserve(parent.id);
+                    portfolio.blotter.work(parent);
+                }
+                portfolio.blotter.refresh_parents();
+                for (ticker, price) in &state.marks {
+                    portfolio.mark_to_market(ticker, *price);
+                }
+                portfolio.capital = state.capital;
+                portfolio.cash = state.cash;
+                portfolio.pnl = state.pnl;
+                state.history
+            },
+            None if config.paper.warm_up => config.load_data(symbol)?,
+            None => vec![],
+        };
+
+        let sent: Vec<Order> = portfolio.blotter.orders().iter().filter(|o| o.is_sent()).cloned().collect();
+        let broker: Box<dyn Broker> = match config.paper.venue {
+            Venue::Simulated => Box::new(PaperBroker::open(
+                config.paper.broker_path(symbol),
+                config.broker.simulator()?.with_cash(portfolio.cash),
+                &sent,
+            )?),
+            Venue::Alpaca => Box::new(config.paper.alpaca.broker()?.resume(&sent)),
+            Venue::Fix => Box::new(config.paper.fix.broker()?.with_cash(portfolio.cash).resume(&sent)),
+        };
+
+        let feed: Box<dyn BarFeed> = match config.paper.feed {
+            FeedKind::Quote => Box::new(QuoteFeed::new(config.data.alpha_vantage(), symbol, config.paper.schedule())),
+            FeedKind::File => {
+                let dir = config.paper.feed_dir.clone()
+                    .ok_or_else(|| EngineError::Config("paper.feed_dir is not set".to_string()))?;
+                Box::new(FileFeed::new(CsvCache::new(dir).path(symbol)))
+            },
+        };
+        let mut trader = PaperTrader {
+            metadata: Metadata::new(symbol.to_string()),
+            strategy: config.strategy()?,
+            backtest: config.backtest_from(portfolio)?.with_broker(broker),
+            feed,
+            history,
+            window: config.strategy.window as usize,
+            path,
+        };
+        trader.trim_history();
+        if is_new {
+            trader.save()?;
+        }
+        trader.reconcile()?;
+        Ok(trader)
+    }
+
+    pub fn with_feed(mut self, feed: Box<dyn BarFeed>) -> Self {
+        self.feed = feed;
+        self
+    }
+
+    pub fn symbol(&self) -> &str {
+        &self.metadata.symbol
+    }
+
+    pub fn backtest(&self) -> &Backtest {
+        &self.backtest
+    }
+
+    /// Books fills the venue reported since the last bar, then trades
+    /// every new bar from the feed and returns how many there were.
+    pub fn poll(&mut self) -> Result<usize> {
+        if self.backtest.sync(&*self.strategy)? > 0 {
+            self.save()?;
+        }
+        let mut traded = 0;
+        for bar in self.feed.poll()? {
+            if self.on_bar(bar)? {
+                traded += 1;
+            }
+        }
+        Ok(traded)
+    }
+
+    /// Trades `bar` and saves the account. Bars no later than the last
+    /// one seen are ignored, so feeds may repeat themselves.
+    pub fn on_bar(&mut self, bar: DatedStockData) -> Result<bool> {
+        if self.history.last().is_some_and(|last| bar.date <= last.date) {
+            return Ok(false);
+        }
+        info!("{} bar {}: close {}", self.metadata.symbol, bar.date, bar.close);
+        self.history.push(bar);
+        self.trim_history();
+        if self.history.len() >= self.window {
+            self.backtest.step(&*self.strategy, &self.history, &self.metadata)?;
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Saves the account, then acknowledges the fills it now holds.
+    fn save(&mut self) -> Result<()> {
+        self.state().save(&self.path)?;
+        self.backtest.acknowledge_fills()
+    }
+
+    /// Warns if the venue holds a different position from the book, e.g.
+    /// after a fill was lost in a crash or a manual trade.
+    pub fn reconcile(&self) -> Result<bool> {
+        let symbol = &self.metadata.symbol;
+        let booked = self.backtest.portfolio().position_in(symbol);
+        let held = self.backtest.broker().positions()?.get(symbol).copied().unwrap_or(0);
+        if booked != held {
+            warn!("{}: the book holds {} shares but the broker holds {}", symbol, booked, held);
+        }
+        Ok(booked == held)
+    }
+
+    pub fn state(&self) -> PaperState {
+        let portfolio = self.backtest.portfolio();
+        PaperState {
+            symbol: self.metadata.symbol.clone(),
+            capital: portfolio.capital,
+            cash: portfolio.cash,
+            pnl: portfolio.pnl,
+            marks: portfolio.marks().clone(),
+            orders: portfolio.blotter.orders().to_vec(),
+            parents: portfolio.blotter.parents().to_vec(),
+            history: self.history.clone(),
+            updated_at: clock::now(),
+        }
+    }
+
+    fn trim_history(&mut self) {
+        let keep = MAX_HISTORY.max(self.window + VOLATILITY_LOOKBACK + 1);
+        if self.history.len() > keep {
+            self.history.drain(..self.history.len() - keep);
+        }
+    }
+}
+
+
+/// When `run_live` should return, besides `stop` being set.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LiveLimits {
+    /// Market sessions to trade through.
+    pub sessions: Option<u32>,
+    pub polls: Option<u64>,
+}
+
+/// Polls every trader until `stop` is set or a limit is reached. With
+/// market hours on, traders are polled once on start, throughout each
+/// session and once more after the close for the day's final bar; the
+/// loop sleeps in between. Data errors are logged and retried on the
+/// next poll.
+pub fn run_live(traders: &mut [PaperTrader], config: &PaperConfig, stop: &AtomicBool, limits: LiveLimits) -> Result<()> {
+    let schedule = config.market_hours.then(|| config.schedule());
+    let interval = Duration::from_secs_f64(config.poll_secs);
+    let mut polls = 0;
+    let mut sessions = 0;
+    let mut in_session = false;
+    loop {
+        poll_all(traders)?;
+        polls += 1;
+        if limits.polls.is_some_and(|n| polls >= n) {
+            return Ok(());
+        }
+
+        let now = clock::now();
+        let pause = match &schedule {
+            Some(schedule) if schedule.is_open(now) => {
+                in_session = true;
+                interval
+            },
+            Some(schedule) if in_session => {
+                // One more poll picks up the closing bar.
+                in_session = false;
+                sessions += 1;
+                info!("Session {} closed", sessions);
+                if limits.sessions.is_some_and(|n| sessions >= n) {
+                    poll_all(traders)?;
+                    return Ok(());
+                }
+                interval.min((schedule.next_open(now) - now).to_std().unwrap_or_default())
+            },
+            Some(schedule) => {
+                let next = schedule.next_open(now);
+                info!("Market closed until {}", next);
+                (next - now).to_std().unwrap_or_default()
+            },
+            None => interval,
+        };
+        if sleep(pause, stop) {
+            return Ok(());
+        }
+    }
+}
+
+fn poll_all(traders: &mut [PaperTrader]) -> Result<()> {
+    for trader in traders.iter_mut() {
+        match trader.poll() {
+            Ok(_) => {},
+            Err(e @ (EngineError::DataFetch(_) | EngineError::RateLimit(_) | EngineError::Broker(_))) => {
+                warn!("{}: {}", trader.symbol(), e);
+            },
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Sleeps in short steps so `stop` is noticed; returns whether it was set.
+fn sleep(duration: Duration, stop: &AtomicBool) -> bool {
+    let step = Duration::from_millis(250);
+    let mut slept = Duration::ZERO;
+    while slept < duration {
+        if stop.load(Ordering::Relaxed) {
+            return true;
+        }
+        let chunk = step.min(duration - slept);
+        thread::sleep(chunk);
+        slept += chunk;
+    }
+    stop.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::order::OrderStatus;
+
+    fn filled_broker(path: &Path) -> (PaperBroker, Order) {
+        let bars: Vec<DatedStockData> = ["2024-01-02", "2024-01-03"].iter()
+            .map(|date| DatedStockData::new(date.to_string(), 100.0, 101.0, 99.0, 100.0, 1_000))
+            .collect();
+        let mut broker = PaperBroker::open(path.to_path_buf(), SimulatedBroker::default().with_cash(10_000.0), &[]).unwrap();
+        broker.on_bar("TEST", &BarContext::from_history(&bars, VOLATILITY_LOOKBACK).unwrap()).unwrap();
+        let mut order = Order::new("TEST".to_string(), 10);
+        order.transition(OrderStatus::Accepted).unwrap();
+        broker.submit(&order).unwrap();
+        (broker, order)
+    }
+
+    #[test]
+    fn unacknowledged_fills_are_replayed_on_open() {
+        let path = std::env::temp_dir().join(format!("paper-broker-{}.json", rand::random::<u64>()));
+        let (mut broker, mut order) = filled_broker(&path);
+        let fills = broker.fills().unwrap();
+        assert_eq!(fills.len(), 1);
+        drop(broker);
+
+        // The book was never saved, so the fill comes back.
+        let mut broker = PaperBroker::open(path.clone(), SimulatedBroker::default(), &[]).unwrap();
+        let replayed = broker.fills().unwrap();
+        assert_eq!(replayed.len(), 1);
+        assert_eq!((replayed[0].order_id, replayed[0].quantity), (order.id, 10));
+        drop(broker);
+
+        // Once the book holds it, it does not.
+        order.apply_fill(replayed[0].clone()).unwrap();
+        let mut broker = PaperBroker::open(path.clone(), SimulatedBroker::default(), &[order]).unwrap();
+        assert!(broker.fills().unwrap().is_empty());
+        broker.acknowledge().unwrap();
+        drop(broker);
+        let mut broker = PaperBroker::open(path.clone(), SimulatedBroker::default(), &[]).unwrap();
+        assert!(broker.fills().unwrap().is_empty());
+        fs::remove_file(&path).ok();
+    }
+}
@@ -1,9 +1,12 @@
 
 use chrono::{DateTime, Utc};
 use derive_new::new;
+use crate::accounting::{LotBook, LotMethod, TaxReport};
+use crate::order::{Fill, OrderBlotter};
+use std::collections::HashMap;
 
 #[allow(dead_code)]
-#[derive(Debug, new)]
+#[derive(Debug, Clone, new)]
 pub struct Trade {
     pub ticker: String,
     pub timestamp: DateTime<Utc>,
@@ -12,17 +15,111 @@ pub struct Trade {
 }
 
 #[allow(dead_code)]
-#[derive(Debug, new)]
+#[derive(Debug, Clone, new)]
 pub struct Portfolio {
     pub capital: isize,
+    #[new(value = "capital as f64")]
+    pub cash: f64,
+    /// Net shares across all symbols; the position of single-symbol runs.
     #[new(value = "0")]
     pub position: i64,
     #[new(value = "0.0")]
     pub pnl: f64,
     #[new(value = "vec![]")]
     pub trades: Vec<Trade>,
+    #[new(default)]
+    pub blotter: OrderBlotter,
+    #[new(value = "LotBook::new(LotMethod::Fifo)")]
+    pub lots: LotBook,
+    #[new(default)]
+    last_prices: HashMap<String, f64>,
 }
 impl Portfolio {
+    pub fn with_lot_method(mut self, method: LotMethod) -> Self {
+        self.lots = LotBook::new(method);
+        self
+    }
+
+    /// Revalues the position at `price` and records it as the latest mark.
+    pub fn mark_to_market(&mut self, ticker: &str, price: f64) {
+        if let Some(last) = self.last_prices.insert(ticker.to_string(), price) {
+            self.pnl += self.position_in(ticker) as f64 * (price - last);
+        }
+    }
+
+    pub fn last_price(&self, ticker: &str) -> Option<f64> {
+        self.last_prices.get(ticker).copied()
+    }
+
+    /// Latest mark for every symbol traded.
+    pub fn marks(&self) -> &HashMap<String, f64> {
+        &self.last_prices
+    }
+
+    /// Books a fill against the position, the tax lots and the running P&L.
+    pub fn apply_fill(&mut self, fill: &Fill) {
+        self.trades.push(Trade::new(
+            fill.ticker.clone(),
+            fill.timestamp,
+            fill.price,
+            fill.quantity,
+        ));
+        self.position += fill.quantity;
+        self.cash -= fill.price * fill.quantity as f64 + fill.trading_costs;
+        self.lots.apply(&fill.ticker, fill.timestamp, fill.quantity, fill.price, fill.trading_costs);
+
+        let mark = self.last_price(&fill.ticker).unwrap_or(fill.price);
+        self.pnl += (mark - fill.price) * fill.quantity as f64;
+        self.pnl -= fill.trading_costs;
+    }
+
+    pub fn equity(&self) -> f64 {
+        self.capital as f64 + self.pnl
+    }
+
+    pub fn position_in(&self, ticker: &str) -> i64 {
+        self.lots.open_lots(ticker).iter().map(|l| l.quantity).sum()
+    }
+
+    /// Symbols with an open position.
+    pub fn tickers(&self) -> Vec<String> {
+        self.last_prices.keys()
+            .filter(|t| self.position_in(t) != 0)
+            .cloned()
+            .collect()
+    }
+
+    /// Unfilled quantity of orders still working for `ticker`.
+    pub fn pending_quantity(&self, ticker: &str) -> i64 {
+        self.blotter.pending_quantity(ticker)
+    }
+
+    pub fn gross_exposure(&self) -> f64 {
+        self.last_prices.iter()
+            .map(|(ticker, price)| (self.position_in(ticker) as f64 * price).abs())
+            .sum()
+    }
+
+    pub fn net_exposure(&self) -> f64 {
+        self.last_prices.iter()
+            .map(|(ticker, price)| self.position_in(ticker) as f64 * price)
+            .sum()
+    }
+
+    pub fn realized_pnl(&self) -> f64 {
+        self.lots.realized_pnl()
+    }
+
+    pub fn unrealized_pnl(&self) -> f64 {
+        self.last_prices.iter()
+            .map(|(ticker, price)| self.lots.unrealized_pnl(ticker, *price))
+            .sum()
+    }
+
+    pub fn tax_report(&self, detect_wash_sales: bool) -> TaxReport {
+        self.lots.tax_report(detect_wash_sales)
+    }
+
     pub fn is_long(&self) -> bool {
         self.position > 0
     }
@@ -0,0 +1,1523 @@
+//!
+//! Python classes.
+//!
+//! The extension module's object API. Each class wraps an owned copy of
+//! the engine's state, so Python code can hold results, portfolios and
+//! configurations after the backtest that produced them has finished.
+//! The public surface is described in `trading_engine.pyi`; keep the two
+//! in step.
+//!
+
+use crate::accounting::TaxReport;
+use crate::backtest::BacktestResult;
+use crate::config::{
+    BacktestConfig, BrokerConfig, DataConfig, DataSource, OutputConfig, RunOutputs, StrategyConfig,
+};
+use crate::data_loading::{AlphaVantage, DatedStockData, Metadata};
+use crate::error::EngineError as Error;
+use crate::execution::{ExecutionConfig, ParentOrder};
+use crate::export::{equity_frame, executions_frame, orders_frame, trades_frame};
+use crate::fixtures::{FixtureServer, Fixtures};
+use crate::frames::{bars_from_python, PyTable};
+use crate::metrics::{EquityPoint, Metrics};
+use crate::order::Order;
+use crate::paper::PaperConfig;
+use crate::portfolio::{Portfolio, Trade};
+use crate::ratelimit::RateLimits;
+use crate::risk::{RiskEvent, RiskLimits};
+use chrono::{DateTime, NaiveDate, Utc};
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyList, PyMapping, PyTuple};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+
+create_exception!(trading_engine, EngineError, PyException, "Base class of the engine's errors.");
+create_exception!(trading_engine, DataFetchError, EngineError, "A market data request failed.");
+create_exception!(trading_engine, RateLimitError, DataFetchError, "The data provider's rate limit was hit.");
+create_exception!(trading_engine, MissingApiKeyError, EngineError, "A required API key is not set.");
+create_exception!(trading_engine, ParseError, EngineError, "Bars, dates or numbers could not be read.");
+create_exception!(trading_engine, ConfigError, EngineError, "The configuration is invalid.");
+create_exception!(trading_engine, UnknownStrategyError, ConfigError, "No strategy is registered under the name.");
+create_exception!(trading_engine, OrderRejectedError, EngineError, "The broker rejected an order.");
+create_exception!(trading_engine, BrokerError, EngineError, "A broker could not be reached or returned an error.");
+create_exception!(trading_engine, AccountingError, EngineError, "Orders or positions would become inconsistent.");
+create_exception!(trading_engine, StorageError, EngineError, "Writing or reading run outputs failed.");
+
+impl From<Error> for PyErr {
+    fn from(e: Error) -> Self {
+        let message = e.to_string();
+        match e {
+            Error::DataFetch(_) => DataFetchError::new_err(message),
+            Error::RateLimit(_) => RateLimitError::new_err(message),
+            Error::MissingApiKey(_) => MissingApiKeyError::new_err(message),
+            Error::Parse(_) => ParseError::new_err(message),
+            Error::Config(_) => ConfigError::new_err(message),
+            Error::UnknownStrategy(_) => UnknownStrategyError::new_err(message),
+            Error::OrderRejected(_) => OrderRejectedError::new_err(message),
+            Error::Broker(_) => BrokerError::new_err(message),
+            Error::Accounting(_) => AccountingError::new_err(message),
+            Error::Store(_) | Error::Export(_) | Error::Io(_) => StorageError::new_err(message),
+            Error::Panic(_) => EngineError::new_err(message),
+        }
+    }
+}
+
+/// For errors in the arguments themselves rather than in the engine.
+pub(crate) fn value_error(e: impl Display) -> PyErr {
+    PyValueError::new_err(e.to_string())
+}
+
+/// Runs `f` with the GIL released, so other Python threads (a web
+/// server, say) keep running while the engine computes.
+pub(crate) fn without_gil<T, F>(py: Python<'_>, f: F) -> PyResult<T>
+where
+    T: Send,
+    F: FnOnce() -> Result<T, Error> + Send,
+{
+    Ok(py.allow_threads(f)?)
+}
+
+/// Bars for each symbol of `data` that is in `symbols`, converted while
+/// the GIL is still held.
+pub(crate) fn symbol_bars(
+    data: Option<&Bound<'_, PyMapping>>,
+    symbols: &[String],
+) -> PyResult<HashMap<String, Vec<DatedStockData>>> {
+    let mut bars = HashMap::new();
+    if let Some(data) = data {
+        for symbol in symbols {
+            if data.contains(symbol)? {
+                bars.insert(symbol.clone(), bars_from_python(&data.get_item(symbol)?)?);
+            }
+        }
+    }
+    Ok(bars)
+}
+
+fn repr_option<T: std::fmt::Debug>(value: &Option<T>) -> String {
+    match value {
+        Some(value) => format!("{:?}", value),
+        None => "None".to_string(),
+    }
+}
+
+
+/// Where bars come from: `alpha_vantage`, or `cache` for CSV files
+/// written by `trading_engine fetch`.
+#[pyclass(name = "DataConfig", module = "trading_engine", frozen)]
+#[derive(Debug, Clone)]
+pub struct PyDataConfig(pub DataConfig);
+
+#[pymethods]
+impl PyDataConfig {
+    #[new]
+    #[pyo3(signature = (
+        source="alpha_vantage", interval="day", cache_dir=None, base_url=None, api_key_env=None,
+        requests_per_minute=5, requests_per_day=Some(25), max_retries=3,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        source: &str,
+        interval: &str,
+        cache_dir: Option<PathBuf>,
+        base_url: Option<String>,
+        api_key_env: Option<String>,
+        requests_per_minute: u32,
+        requests_per_day: Option<u32>,
+        max_retries: u32,
+    ) -> PyResult<Self> {
+        let rate_limit = RateLimits {
+            per_minute: requests_per_minute,
+            per_day: requests_per_day,
+            max_retries,
+            ..RateLimits::default()
+        };
+        Ok(PyDataConfig(DataConfig {
+            source: DataSource::from_str(source).map_err(PyErr::from)?,
+            interval: interval.to_string(),
+            cache_dir,
+            base_url,
+            api_key_env,
+            rate_limit,
+        }))
+    }
+
+    #[getter]
+    fn source(&self) -> &'static str {
+        self.0.source.as_str()
+    }
+
+    #[getter]
+    fn interval(&self) -> &str {
+        &self.0.interval
+    }
+
+    #[getter]
+    fn cache_dir(&self) -> Option<PathBuf> {
+        self.0.cache_dir.clone()
+    }
+
+    #[getter]
+    fn base_url(&self) -> &str {
+        self.0.base_url.as_deref().unwrap_or(AlphaVantage::BASE_URL)
+    }
+
+    #[getter]
+    fn api_key_env(&self) -> &str {
+        self.0.api_key_env.as_deref().unwrap_or(AlphaVantage::CONFIG_KEY)
+    }
+
+    #[getter]
+    fn requests_per_minute(&self) -> u32 {
+        self.0.rate_limit.per_minute
+    }
+
+    #[getter]
+    fn requests_per_day(&self) -> Option<u32> {
+        self.0.rate_limit.per_day
+    }
+
+    #[getter]
+    fn max_retries(&self) -> u32 {
+        self.0.rate_limit.max_retries
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "DataConfig(source={:?}, interval={:?}, cache_dir={})",
+            self.0.source.as_str(), self.0.interval, repr_option(&self.0.cache_dir),
+        )
+    }
+}
+
+
+/// A registered strategy and its parameters. `sizing` takes a position
+/// sizer spec such as `percent_equity:0.1` and replaces the fixed
+/// quantities; the sizer is capped at `max_position_fraction` of equity.
+#[pyclass(name = "StrategyConfig", module = "trading_engine", frozen)]
+#[derive(Debug, Clone)]
+pub struct PyStrategyConfig(pub StrategyConfig);
+
+#[pymethods]
+impl PyStrategyConfig {
+    #[new]
+    #[pyo3(signature = (name, window, long_quantity=100, short_quantity=-100, sizing=None, max_position_fraction=1.0))]
+    fn new(name: &str, window: u32, long_quantity: i64, short_quantity: i64, sizing: Option<String>, max_position_fraction: f64) -> Self {
+        PyStrategyConfig(StrategyConfig { name: name.to_string(), window, long_quantity, short_quantity, sizing, max_position_fraction })
+    }
+
+    #[getter]
+    fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    #[getter]
+    fn window(&self) -> u32 {
+        self.0.window
+    }
+
+    #[getter]
+    fn long_quantity(&self) -> i64 {
+        self.0.long_quantity
+    }
+
+    #[getter]
+    fn short_quantity(&self) -> i64 {
+        self.0.short_quantity
+    }
+
+    #[getter]
+    fn sizing(&self) -> Option<String> {
+        self.0.sizing.clone()
+    }
+
+    #[getter]
+    fn max_position_fraction(&self) -> f64 {
+        self.0.max_position_fraction
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "StrategyConfig(name={:?}, window={}, long_quantity={}, short_quantity={}, sizing={}, max_position_fraction={})",
+            self.0.name, self.0.window, self.0.long_quantity, self.0.short_quantity, repr_option(&self.0.sizing),
+            self.0.max_position_fraction,
+        )
+    }
+}
+
+
+/// Broker cost models by preset name. Unset fields keep the engine
+/// defaults.
+#[pyclass(name = "BrokerConfig", module = "trading_engine", frozen)]
+#[derive(Debug, Clone)]
+pub struct PyBrokerConfig(pub BrokerConfig);
+
+#[pymethods]
+impl PyBrokerConfig {
+    #[new]
+    #[pyo3(signature = (commission=None, slippage=None, max_participation=None, lot_method=None))]
+    fn new(
+        commission: Option<String>,
+        slippage: Option<String>,
+        max_participation: Option<f64>,
+        lot_method: Option<String>,
+    ) -> Self {
+        PyBrokerConfig(BrokerConfig { commission, slippage, max_participation, lot_method })
+    }
+
+    #[getter]
+    fn commission(&self) -> Option<String> {
+        self.0.commission.clone()
+    }
+
+    #[getter]
+    fn slippage(&self) -> Option<String> {
+        self.0.slippage.clone()
+    }
+
+    #[getter]
+    fn max_participation(&self) -> Option<f64> {
+        self.0.max_participation
+    }
+
+    #[getter]
+    fn lot_method(&self) -> Option<String> {
+        self.0.lot_method.clone()
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "BrokerConfig(commission={}, slippage={}, max_participation={}, lot_method={})",
+            repr_option(&self.0.commission), repr_option(&self.0.slippage),
+            repr_option(&self.0.max_participation), repr_option(&self.0.lot_method),
+        )
+    }
+}
+
+
+/// Pre-trade risk limits; `None` disables a limit.
+#[pyclass(name = "RiskLimits", module = "trading_engine", frozen)]
+#[derive(Debug, Clone)]
+pub struct PyRiskLimits(pub RiskLimits);
+
+#[pymethods]
+impl PyRiskLimits {
+    #[new]
+    #[pyo3(signature = (
+        max_position=None, max_order_notional=None, max_gross_exposure=None, max_net_exposure=None,
+        max_leverage=None, max_concentration=None, daily_loss_limit=None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        max_position: Option<i64>,
+        max_order_notional: Option<f64>,
+        max_gross_exposure: Option<f64>,
+        max_net_exposure: Option<f64>,
+        max_leverage: Option<f64>,
+        max_concentration: Option<f64>,
+        daily_loss_limit: Option<f64>,
+    ) -> Self {
+        PyRiskLimits(RiskLimits {
+            max_position,
+            max_order_notional,
+            max_gross_exposure,
+            max_net_exposure,
+            max_leverage,
+            max_concentration,
+            daily_loss_limit,
+        })
+    }
+
+    #[getter]
+    fn max_position(&self) -> Option<i64> {
+        self.0.max_position
+    }
+
+    #[getter]
+    fn max_order_notional(&self) -> Option<f64> {
+        self.0.max_order_notional
+    }
+
+    #[getter]
+    fn max_gross_exposure(&self) -> Option<f64> {
+        self.0.max_gross_exposure
+    }
+
+    #[getter]
+    fn max_net_exposure(&self) -> Option<f64> {
+        self.0.max_net_exposure
+    }
+
+    #[getter]
+    fn max_leverage(&self) -> Option<f64> {
+        self.0.max_leverage
+    }
+
+    #[getter]
+    fn max_concentration(&self) -> Option<f64> {
+        self.0.max_concentration
+    }
+
+    #[getter]
+    fn daily_loss_limit(&self) -> Option<f64> {
+        self.0.daily_loss_limit
+    }
+
+    fn __repr__(&self) -> String {
+        let limits = &self.0;
+        format!(
+            "RiskLimits(max_position={}, max_order_notional={}, max_gross_exposure={}, max_net_exposure={}, \
+             max_leverage={}, max_concentration={}, daily_loss_limit={})",
+            repr_option(&limits.max_position), repr_option(&limits.max_order_notional),
+            repr_option(&limits.max_gross_exposure), repr_option(&limits.max_net_exposure),
+            repr_option(&limits.max_leverage), repr_option(&limits.max_concentration),
+            repr_option(&limits.daily_loss_limit),
+        )
+    }
+}
+
+
+/// Where results are written. With `dir` set each run is exported to
+/// `<dir>/<run_id>/`; `store` records runs in a SQLite database.
+#[pyclass(name = "OutputConfig", module = "trading_engine", frozen)]
+#[derive(Debug, Clone)]
+pub struct PyOutputConfig(pub OutputConfig);
+
+#[pymethods]
+impl PyOutputConfig {
+    #[new]
+    #[pyo3(signature = (dir=None, format="json", report=false, store=None))]
+    fn new(dir: Option<PathBuf>, format: &str, report: bool, store: Option<PathBuf>) -> Self {
+        PyOutputConfig(OutputConfig { dir, format: format.to_string(), report, store })
+    }
+
+    #[getter]
+    fn dir(&self) -> Option<PathBuf> {
+        self.0.dir.clone()
+    }
+
+    #[getter]
+    fn format(&self) -> &str {
+        &self.0.format
+    }
+
+    #[getter]
+    fn report(&self) -> bool {
+        self.0.report
+    }
+
+    #[getter]
+    fn store(&self) -> Option<PathBuf> {
+        self.0.store.clone()
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "OutputConfig(dir={}, format={:?}, report={}, store={})",
+            repr_option(&self.0.dir), self.0.format, if self.0.report { "True" } else { "False" },
+            repr_option(&self.0.store),
+        )
+    }
+}
+
+
+/// How approved orders are executed. With `algo` set to `twap`, `vwap`,
+/// `pov` or `is` they are worked as parent orders in child orders over
+/// the following bars; otherwise they go to the broker whole.
+#[pyclass(name = "ExecutionConfig", module = "trading_engine", frozen)]
+#[derive(Debug, Clone)]
+pub struct PyExecutionConfig(pub ExecutionConfig);
+
+#[pymethods]
+impl PyExecutionConfig {
+    #[new]
+    #[pyo3(signature = (
+        algo=None, horizon=5, urgency=0.5, participation=0.1, max_participation=None, limit_bps=None,
+        max_bars=None, volume_lookback=20,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        algo: Option<String>,
+        horizon: u32,
+        urgency: f64,
+        participation: f64,
+        max_participation: Option<f64>,
+        limit_bps: Option<f64>,
+        max_bars: Option<u32>,
+        volume_lookback: usize,
+    ) -> Self {
+        PyExecutionConfig(ExecutionConfig {
+            algo,
+            horizon,
+            urgency,
+            participation,
+            max_participation,
+            limit_bps,
+            max_bars,
+            volume_lookback,
+        })
+    }
+
+    #[getter]
+    fn algo(&self) -> Option<String> {
+        self.0.algo.clone()
+    }
+
+    #[getter]
+    fn horizon(&self) -> u32 {
+        self.0.horizon
+    }
+
+    #[getter]
+    fn urgency(&self) -> f64 {
+        self.0.urgency
+    }
+
+    #[getter]
+    fn participation(&self) -> f64 {
+        self.0.participation
+    }
+
+    #[getter]
+    fn max_participation(&self) -> Option<f64> {
+        self.0.max_participation
+    }
+
+    #[getter]
+    fn limit_bps(&self) -> Option<f64> {
+        self.0.limit_bps
+    }
+
+    #[getter]
+    fn max_bars(&self) -> Option<u32> {
+        self.0.max_bars
+    }
+
+    #[getter]
+    fn volume_lookback(&self) -> usize {
+        self.0.volume_lookback
+    }
+
+    fn __repr__(&self) -> String {
+        let config = &self.0;
+        format!(
+            "ExecutionConfig(algo={}, horizon={}, urgency={}, participation={}, max_participation={}, \
+             limit_bps={}, max_bars={}, volume_lookback={})",
+            repr_option(&config.algo), config.horizon, config.urgency, config.participation,
+            repr_option(&config.max_participation), repr_option(&config.limit_bps),
+            repr_option(&config.max_bars), config.volume_lookback,
+        )
+    }
+}
+
+
+/// A complete, validated backtest description. Construct directly or load
+/// from YAML/TOML; environment variables `TRADING__<SECTION>__<KEY>`
+/// override file values.
+#[pyclass(name = "BacktestConfig", module = "trading_engine", frozen)]
+#[derive(Debug, Clone)]
+pub struct PyBacktestConfig(pub BacktestConfig);
+
+#[pymethods]
+impl PyBacktestConfig {
+    #[new]
+    #[pyo3(signature = (
+        universe, strategy, capital=1_000_000, data=None, broker=None, risk=None, output=None,
+        start_date=None, end_date=None, execution=None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        universe: Vec<String>,
+        strategy: PyStrategyConfig,
+        capital: i64,
+        data: Option<PyDataConfig>,
+        broker: Option<PyBrokerConfig>,
+        risk: Option<PyRiskLimits>,
+        output: Option<PyOutputConfig>,
+        start_date: Option<NaiveDate>,
+        end_date: Option<NaiveDate>,
+        execution: Option<PyExecutionConfig>,
+    ) -> PyResult<Self> {
+        let config = BacktestConfig {
+            data: data.map(|d| d.0).unwrap_or_default(),
+            universe,
+            start_date,
+            end_date,
+            capital,
+            strategy: strategy.0,
+            broker: broker.map(|b| b.0).unwrap_or_default(),
+            risk: risk.map(|r| r.0).unwrap_or_default(),
+            output: output.map(|o| o.0).unwrap_or_default(),
+            execution: execution.map(|e| e.0).unwrap_or_default(),
+            paper: PaperConfig::default(),
+        };
+        config.validate().map_err(PyErr::from)?;
+        Ok(PyBacktestConfig(config))
+    }
+
+    #[staticmethod]
+    fn from_file(path: PathBuf) -> PyResult<Self> {
+        BacktestConfig::from_file(&path).map(PyBacktestConfig).map_err(PyErr::from)
+    }
+
+    #[staticmethod]
+    fn from_yaml(text: &str) -> PyResult<Self> {
+        BacktestConfig::from_yaml_str(text).map(PyBacktestConfig).map_err(PyErr::from)
+    }
+
+    #[staticmethod]
+    fn from_toml(text: &str) -> PyResult<Self> {
+        BacktestConfig::from_toml_str(text).map(PyBacktestConfig).map_err(PyErr::from)
+    }
+
+    /// A copy with fields replaced by dotted path, e.g.
+    /// `{"strategy.window": 50, "universe": ["AAPL", "MSFT"]}`.
+    fn with_overrides(&self, py: Python, overrides: HashMap<String, PyObject>) -> PyResult<Self> {
+        let json = py.import("json")?;
+        let overrides = overrides.into_iter()
+            .map(|(path, value)| Ok((path, json.call_method1("dumps", (value,))?.extract::<String>()?)))
+            .collect::<PyResult<Vec<_>>>()?;
+        self.0.with_overrides(&overrides).map(PyBacktestConfig).map_err(PyErr::from)
+    }
+
+    /// The configuration as plain dicts and lists.
+    fn to_dict(&self, py: Python) -> PyResult<PyObject> {
+        let json = serde_json::to_string(&self.0).map_err(value_error)?;
+        Ok(py.import("json")?.call_method1("loads", (json,))?.unbind())
+    }
+
+    #[getter]
+    fn universe(&self) -> Vec<String> {
+        self.0.universe.clone()
+    }
+
+    #[getter]
+    fn strategy(&self) -> PyStrategyConfig {
+        PyStrategyConfig(self.0.strategy.clone())
+    }
+
+    #[getter]
+    fn capital(&self) -> i64 {
+        self.0.capital
+    }
+
+    #[getter]
+    fn data(&self) -> PyDataConfig {
+        PyDataConfig(self.0.data.clone())
+    }
+
+    #[getter]
+    fn broker(&self) -> PyBrokerConfig {
+        PyBrokerConfig(self.0.broker.clone())
+    }
+
+    #[getter]
+    fn risk(&self) -> PyRiskLimits {
+        PyRiskLimits(self.0.risk.clone())
+    }
+
+    #[getter]
+    fn output(&self) -> PyOutputConfig {
+        PyOutputConfig(self.0.output.clone())
+    }
+
+    #[getter]
+    fn start_date(&self) -> Option<NaiveDate> {
+        self.0.start_date
+    }
+
+    #[getter]
+    fn end_date(&self) -> Option<NaiveDate> {
+        self.0.end_date
+    }
+
+    #[getter]
+    fn execution(&self) -> PyExecutionConfig {
+        PyExecutionConfig(self.0.execution.clone())
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "BacktestConfig(universe={:?}, strategy={:?}, window={}, capital={})",
+            self.0.universe, self.0.strategy.name, self.0.strategy.window, self.0.capital,
+        )
+    }
+}
+
+
+/// An executed fill as recorded on the portfolio.
+#[pyclass(name = "Trade", module = "trading_engine", frozen)]
+#[derive(Debug, Clone)]
+pub struct PyTrade(pub Trade);
+
+#[pymethods]
+impl PyTrade {
+    #[getter]
+    fn ticker(&self) -> &str {
+        &self.0.ticker
+    }
+
+    #[getter]
+    fn timestamp(&self) -> DateTime<Utc> {
+        self.0.timestamp
+    }
+
+    #[getter]
+    fn price(&self) -> f64 {
+        self.0.price
+    }
+
+    /// Signed: positive for buys, negative for sells.
+    #[getter]
+    fn quantity(&self) -> i64 {
+        self.0.quantity
+    }
+
+    #[getter]
+    fn value(&self) -> f64 {
+        self.0.price * self.0.quantity as f64
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "Trade(ticker={:?}, timestamp='{}', quantity={}, price={:.4})",
+            self.0.ticker, self.0.timestamp.to_rfc3339(), self.0.quantity, self.0.price,
+        )
+    }
+}
+
+
+#[pyclass(name = "Order", module = "trading_engine", frozen)]
+#[derive(Debug, Clone)]
+pub struct PyOrder(pub Order);
+
+#[pymethods]
+impl PyOrder {
+    #[getter]
+    fn id(&self) -> u64 {
+        self.0.id.0
+    }
+
+    #[getter]
+    fn ticker(&self) -> &str {
+        &self.0.ticker
+    }
+
+    #[getter]
+    fn timestamp(&self) -> DateTime<Utc> {
+        self.0.timestamp
+    }
+
+    #[getter]
+    fn quantity(&self) -> i64 {
+        self.0.quantity
+    }
+
+    #[getter]
+    fn filled_quantity(&self) -> i64 {
+        self.0.filled_quantity
+    }
+
+    #[getter]
+    fn remaining_quantity(&self) -> i64 {
+        self.0.remaining_quantity()
+    }
+
+    /// One of `new`, `held`, `accepted`, `partially_filled`, `filled`,
+    /// `cancelled`, `rejected` or `expired`.
+    #[getter]
+    fn status(&self) -> String {
+        self.0.status.to_string()
+    }
+
+    #[getter]
+    fn time_in_force(&self) -> String {
+        self.0.time_in_force.to_string()
+    }
+
+    #[getter]
+    fn average_fill_price(&self) -> Option<f64> {
+        self.0.average_fill_price()
+    }
+
+    #[getter]
+    fn is_open(&self) -> bool {
+        self.0.is_open()
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "Order(id={}, ticker={:?}, quantity={}, filled_quantity={}, status={:?})",
+            self.0.id.0, self.0.ticker, self.0.quantity, self.0.filled_quantity, self.0.status.to_string(),
+        )
+    }
+}
+
+
+/// An order the risk manager resized, held or rejected.
+#[pyclass(name = "RiskEvent", module = "trading_engine", frozen)]
+#[derive(Debug, Clone)]
+pub struct PyRiskEvent(pub RiskEvent);
+
+#[pymethods]
+impl PyRiskEvent {
+    #[getter]
+    fn order_id(&self) -> u64 {
+        self.0.order_id.0
+    }
+
+    #[getter]
+    fn date(&self) -> &str {
+        &self.0.date
+    }
+
+    #[getter]
+    fn ticker(&self) -> &str {
+        &self.0.ticker
+    }
+
+    #[getter]
+    fn requested_quantity(&self) -> i64 {
+        self.0.requested_quantity
+    }
+
+    #[getter]
+    fn approved_quantity(&self) -> i64 {
+        self.0.approved_quantity
+    }
+
+    /// One of `resized`, `held` or `rejected`.
+    #[getter]
+    fn action(&self) -> String {
+        format!("{:?}", self.0.action).to_lowercase()
+    }
+
+    #[getter]
+    fn reason(&self) -> &str {
+        &self.0.reason
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "RiskEvent(order_id={}, date={:?}, ticker={:?}, action={:?}, reason={:?})",
+            self.0.order_id.0, self.0.date, self.0.ticker, self.action(), self.0.reason,
+        )
+    }
+}
+
+
+/// An order an execution algo worked through child orders, with its
+/// average price against the arrival price.
+#[pyclass(name = "ParentOrder", module = "trading_engine", frozen)]
+#[derive(Debug, Clone)]
+pub struct PyParentOrder(pub ParentOrder);
+
+#[pymethods]
+impl PyParentOrder {
+    #[getter]
+    fn id(&self) -> u64 {
+        self.0.id.0
+    }
+
+    #[getter]
+    fn ticker(&self) -> &str {
+        &self.0.ticker
+    }
+
+    /// One of `twap`, `vwap`, `pov` or `is`.
+    #[getter]
+    fn algo(&self) -> String {
+        self.0.algo.to_string()
+    }
+
+    #[getter]
+    fn quantity(&self) -> i64 {
+        self.0.quantity
+    }
+
+    #[getter]
+    fn filled_quantity(&self) -> i64 {
+        self.0.filled_quantity
+    }
+
+    #[getter]
+    fn remaining_quantity(&self) -> i64 {
+        self.0.remaining_quantity()
+    }
+
+    #[getter]
+    fn arrival_time(&self) -> DateTime<Utc> {
+        self.0.arrival_time
+    }
+
+    #[getter]
+    fn arrival_price(&self) -> f64 {
+        self.0.arrival_price
+    }
+
+    #[getter]
+    fn average_price(&self) -> Option<f64> {
+        self.0.average_price
+    }
+
+    /// Average price against arrival in basis points, positive when
+    /// execution cost money.
+    #[getter]
+    fn shortfall_bps(&self) -> Option<f64> {
+        self.0.shortfall_bps
+    }
+
+    #[getter]
+    fn trading_costs(&self) -> f64 {
+        self.0.trading_costs
+    }
+
+    #[getter]
+    fn bars(&self) -> u32 {
+        self.0.bars
+    }
+
+    /// Ids of the child orders, which appear among the portfolio's orders.
+    #[getter]
+    fn children(&self) -> Vec<u64> {
+        self.0.children.iter().map(|id| id.0).collect()
+    }
+
+    #[getter]
+    fn status(&self) -> String {
+        self.0.status.to_string()
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "ParentOrder(id={}, ticker={:?}, algo={:?}, quantity={}, filled_quantity={}, arrival_price={:.2}, \
+             average_price={}, status={:?})",
+            self.0.id.0, self.0.ticker, self.algo(), self.0.quantity, self.0.filled_quantity, self.0.arrival_price,
+            repr_option(&self.0.average_price), self.status(),
+        )
+    }
+}
+
+
+#[pyclass(name = "Metrics", module = "trading_engine", frozen)]
+#[derive(Debug, Clone)]
+pub struct PyMetrics(pub Metrics);
+
+#[pymethods]
+impl PyMetrics {
+    #[getter]
+    fn initial_equity(&self) -> f64 {
+        self.0.initial_equity
+    }
+
+    #[getter]
+    fn final_equity(&self) -> f64 {
+        self.0.final_equity
+    }
+
+    #[getter]
+    fn total_return(&self) -> f64 {
+        self.0.total_return
+    }
+
+    #[getter]
+    fn annualized_return(&self) -> f64 {
+        self.0.annualized_return
+    }
+
+    #[getter]
+    fn annualized_volatility(&self) -> f64 {
+        self.0.annualized_volatility
+    }
+
+    #[getter]
+    fn sharpe_ratio(&self) -> f64 {
+        self.0.sharpe_ratio
+    }
+
+    #[getter]
+    fn max_drawdown(&self) -> f64 {
+        self.0.max_drawdown
+    }
+
+    #[getter]
+    fn n_trades(&self) -> usize {
+        self.0.n_trades
+    }
+
+    fn to_dict<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let metrics = PyDict::new(py);
+        for name in Metrics::NAMES {
+            metrics.set_item(name, self.0.value(name))?;
+        }
+        metrics.set_item("n_trades", self.0.n_trades)?;
+        Ok(metrics)
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "Metrics(total_return={:.4}, sharpe_ratio={:.4}, max_drawdown={:.4}, n_trades={})",
+            self.0.total_return, self.0.sharpe_ratio, self.0.max_drawdown, self.0.n_trades,
+        )
+    }
+}
+
+
+/// Snapshot of a portfolio at the end of a backtest.
+#[pyclass(name = "Portfolio", module = "trading_engine", frozen)]
+#[derive(Debug, Clone)]
+pub struct PyPortfolio(pub Portfolio);
+
+#[pymethods]
+impl PyPortfolio {
+    #[getter]
+    fn capital(&self) -> isize {
+        self.0.capital
+    }
+
+    #[getter]
+    fn cash(&self) -> f64 {
+        self.0.cash
+    }
+
+    #[getter]
+    fn equity(&self) -> f64 {
+        self.0.equity()
+    }
+
+    #[getter]
+    fn pnl(&self) -> f64 {
+        self.0.pnl
+    }
+
+    #[getter]
+    fn realized_pnl(&self) -> f64 {
+        self.0.realized_pnl()
+    }
+
+    #[getter]
+    fn unrealized_pnl(&self) -> f64 {
+        self.0.unrealized_pnl()
+    }
+
+    /// Net shares held per symbol.
+    #[getter]
+    fn positions(&self) -> HashMap<String, i64> {
+        self.0.tickers().into_iter()
+            .map(|ticker| {
+                let position = self.0.position_in(&ticker);
+                (ticker, position)
+            })
+            .collect()
+    }
+
+    #[getter]
+    fn gross_exposure(&self) -> f64 {
+        self.0.gross_exposure()
+    }
+
+    #[getter]
+    fn net_exposure(&self) -> f64 {
+        self.0.net_exposure()
+    }
+
+    #[getter]
+    fn trades(&self) -> Vec<PyTrade> {
+        self.0.trades.iter().cloned().map(PyTrade).collect()
+    }
+
+    #[getter]
+    fn orders(&self) -> Vec<PyOrder> {
+        self.0.blotter.orders().iter().cloned().map(PyOrder).collect()
+    }
+
+    #[getter]
+    fn parent_orders(&self) -> Vec<PyParentOrder> {
+        self.0.blotter.parents().iter().cloned().map(PyParentOrder).collect()
+    }
+
+    /// Realised gains split by holding period, with wash-sale
+    /// adjustments when `detect_wash_sales` is set.
+    #[pyo3(signature = (detect_wash_sales=true))]
+    fn tax_report<'py>(&self, py: Python<'py>, detect_wash_sales: bool) -> PyResult<Bound<'py, PyDict>> {
+        let TaxReport { short_term_gain, long_term_gain, wash_sale_disallowed, .. } =
+            self.0.tax_report(detect_wash_sales);
+        let report = PyDict::new(py);
+        report.set_item("short_term_gain", short_term_gain)?;
+        report.set_item("long_term_gain", long_term_gain)?;
+        report.set_item("wash_sale_disallowed", wash_sale_disallowed)?;
+        Ok(report)
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "Portfolio(equity={:.2}, cash={:.2}, positions={:?}, n_trades={})",
+            self.0.equity(), self.0.cash, self.positions(), self.0.trades.len(),
+        )
+    }
+}
+
+
+/// Everything a finished backtest produced.
+#[pyclass(name = "BacktestResult", module = "trading_engine", frozen)]
+#[derive(Debug, Clone)]
+pub struct PyBacktestResult {
+    symbol: String,
+    n_trades: isize,
+    metrics: Metrics,
+    portfolio: Portfolio,
+    equity_curve: Vec<EquityPoint>,
+    risk_events: Vec<RiskEvent>,
+    outputs: RunOutputs,
+}
+impl PyBacktestResult {
+    pub fn from_result(symbol: &str, result: &BacktestResult, outputs: RunOutputs) -> Self {
+        PyBacktestResult {
+            symbol: symbol.to_string(),
+            n_trades: result.n_trades,
+            metrics: result.metrics(),
+            portfolio: result.portfolio.clone(),
+            equity_curve: result.equity_curve.to_vec(),
+            risk_events: result.risk_events.to_vec(),
+            outputs,
+        }
+    }
+
+    pub fn outputs(&self) -> &RunOutputs {
+        &self.outputs
+    }
+
+    pub(crate) fn view(&self) -> BacktestResult<'_> {
+        BacktestResult {
+            n_trades: self.n_trades,
+            portfolio: &self.portfolio,
+            risk_events: &self.risk_events,
+            rebalances: &[],
+            equity_curve: &self.equity_curve,
+        }
+    }
+}
+
+#[pymethods]
+impl PyBacktestResult {
+    #[getter]
+    fn symbol(&self) -> &str {
+        &self.symbol
+    }
+
+    #[getter]
+    fn n_trades(&self) -> isize {
+        self.n_trades
+    }
+
+    #[getter]
+    fn metrics(&self) -> PyMetrics {
+        PyMetrics(self.metrics.clone())
+    }
+
+    #[getter]
+    fn portfolio(&self) -> PyPortfolio {
+        PyPortfolio(self.portfolio.clone())
+    }
+
+    #[getter]
+    fn trades(&self) -> Vec<PyTrade> {
+        self.portfolio.trades.iter().cloned().map(PyTrade).collect()
+    }
+
+    #[getter]
+    fn orders(&self) -> Vec<PyOrder> {
+        self.portfolio.blotter.orders().iter().cloned().map(PyOrder).collect()
+    }
+
+    /// `(timestamp, equity, cash)` per bar.
+    #[getter]
+    fn equity_curve(&self) -> Vec<(DateTime<Utc>, f64, f64)> {
+        self.equity_curve.iter().map(|p| (p.timestamp, p.equity, p.cash)).collect()
+    }
+
+    /// Columns timestamp, equity, cash and drawdown.
+    fn equity_table(&self) -> PyResult<PyTable> {
+        PyTable::from_export(equity_frame(&self.view()))
+    }
+
+    /// Columns timestamp, ticker, quantity and price.
+    fn trades_table(&self) -> PyResult<PyTable> {
+        PyTable::from_export(trades_frame(&self.view()))
+    }
+
+    /// Columns order_id, timestamp, ticker, quantity, filled_quantity,
+    /// average_price and status.
+    fn orders_table(&self) -> PyResult<PyTable> {
+        PyTable::from_export(orders_frame(&self.view()))
+    }
+
+    /// Columns order_id, arrival_time, ticker, algo, quantity,
+    /// filled_quantity, arrival_price, average_price, shortfall_bps,
+    /// trading_costs, bars, n_children and status.
+    fn executions_table(&self) -> PyResult<PyTable> {
+        PyTable::from_export(executions_frame(&self.view()))
+    }
+
+    #[getter]
+    fn risk_events(&self) -> Vec<PyRiskEvent> {
+        self.risk_events.iter().cloned().map(PyRiskEvent).collect()
+    }
+
+    /// Orders worked by the execution algo, if one was configured.
+    #[getter]
+    fn executions(&self) -> Vec<PyParentOrder> {
+        self.portfolio.blotter.parents().iter().cloned().map(PyParentOrder).collect()
+    }
+
+    /// Run id in the run store, when the configuration records runs.
+    #[getter]
+    fn run_id(&self) -> Option<String> {
+        self.outputs.run_id.clone()
+    }
+
+    #[getter]
+    fn exported_files(&self) -> Vec<PathBuf> {
+        self.outputs.exported_files.clone()
+    }
+
+    #[getter]
+    fn report_path(&self) -> Option<PathBuf> {
+        self.outputs.report.clone()
+    }
+
+    /// Everything above as plain dicts and lists, the shape
+    /// `run_backtest` returns.
+    pub(crate) fn to_dict<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let result = self.view();
+        let result_dict = PyDict::new(py);
+        result_dict.set_item("n_trades", result.n_trades)?;
+
+        let metrics = &self.metrics;
+        let metrics_dict = PyDict::new(py);
+        metrics_dict.set_item("initial_equity", metrics.initial_equity)?;
+        metrics_dict.set_item("final_equity", metrics.final_equity)?;
+        metrics_dict.set_item("total_return", metrics.total_return)?;
+        metrics_dict.set_item("annualized_return", metrics.annualized_return)?;
+        metrics_dict.set_item("annualized_volatility", metrics.annualized_volatility)?;
+        metrics_dict.set_item("sharpe_ratio", metrics.sharpe_ratio)?;
+        metrics_dict.set_item("max_drawdown", metrics.max_drawdown)?;
+        result_dict.set_item("metrics", metrics_dict)?;
+
+        result_dict.set_item("realized_pnl", result.portfolio.realized_pnl())?;
+        result_dict.set_item("unrealized_pnl", result.portfolio.unrealized_pnl())?;
+
+        let tax_report = result.portfolio.tax_report(true);
+        result_dict.set_item("short_term_gain", tax_report.short_term_gain)?;
+        result_dict.set_item("long_term_gain", tax_report.long_term_gain)?;
+        result_dict.set_item("wash_sale_disallowed", tax_report.wash_sale_disallowed)?;
+
+        let trades = PyList::empty(py);
+        for trade in &result.portfolio.trades {
+            let trade_dict = PyDict::new(py);
+            trade_dict.set_item("timestamp", trade.timestamp.to_string())?;
+            trade_dict.set_item("ticker", &trade.ticker)?;
+            trade_dict.set_item("quantity", trade.quantity)?;
+            trade_dict.set_item("price", trade.price)?;
+            trades.append(trade_dict)?;
+        }
+        result_dict.set_item("trades", trades)?;
+
+        let orders = PyList::empty(py);
+        for order in result.portfolio.blotter.orders() {
+            let order_dict = PyDict::new(py);
+            order_dict.set_item("id", order.id.0)?;
+            order_dict.set_item("timestamp", order.timestamp.to_string())?;
+            order_dict.set_item("ticker", &order.ticker)?;
+            order_dict.set_item("quantity", order.quantity)?;
+            order_dict.set_item("filled_quantity", order.filled_quantity)?;
+            order_dict.set_item("status", order.status.to_string())?;
+            orders.append(order_dict)?;
+        }
+        result_dict.set_item("orders", orders)?;
+
+        let risk_events = PyList::empty(py);
+        for event in result.risk_events {
+            let event_dict = PyDict::new(py);
+            event_dict.set_item("order_id", event.order_id.0)?;
+            event_dict.set_item("date", &event.date)?;
+            event_dict.set_item("ticker", &event.ticker)?;
+            event_dict.set_item("requested_quantity", event.requested_quantity)?;
+            event_dict.set_item("approved_quantity", event.approved_quantity)?;
+            event_dict.set_item("action", format!("{:?}", event.action).to_lowercase())?;
+            event_dict.set_item("reason", &event.reason)?;
+            risk_events.append(event_dict)?;
+        }
+        result_dict.set_item("risk_events", risk_events)?;
+
+        let executions = PyList::empty(py);
+        for parent in result.portfolio.blotter.parents() {
+            let parent_dict = PyDict::new(py);
+            parent_dict.set_item("order_id", parent.id.0)?;
+            parent_dict.set_item("ticker", &parent.ticker)?;
+            parent_dict.set_item("algo", parent.algo.to_string())?;
+            parent_dict.set_item("quantity", parent.quantity)?;
+            parent_dict.set_item("filled_quantity", parent.filled_quantity)?;
+            parent_dict.set_item("arrival_price", parent.arrival_price)?;
+            parent_dict.set_item("average_price", parent.average_price)?;
+            parent_dict.set_item("shortfall_bps", parent.shortfall_bps)?;
+            parent_dict.set_item("status", parent.status.to_string())?;
+            executions.append(parent_dict)?;
+        }
+        result_dict.set_item("executions", executions)?;
+
+        if !self.outputs.exported_files.is_empty() {
+            let files: Vec<String> = self.outputs.exported_files.iter().map(|p| p.display().to_string()).collect();
+            result_dict.set_item("exported_files", files)?;
+        }
+        if let Some(path) = &self.outputs.report {
+            result_dict.set_item("report_path", path.display().to_string())?;
+        }
+        if let Some(run_id) = &self.outputs.run_id {
+            result_dict.set_item("run_id", run_id)?;
+        }
+
+        Ok(result_dict)
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "BacktestResult(symbol={:?}, n_trades={}, total_return={:.4}, sharpe_ratio={:.4})",
+            self.symbol, self.n_trades, self.metrics.total_return, self.metrics.sharpe_ratio,
+        )
+    }
+}
+
+
+/// Runs a `BacktestConfig`. Outputs configured in `config.output` are
+/// written after each run.
+#[pyclass(name = "Backtest", module = "trading_engine", frozen)]
+#[derive(Debug, Clone)]
+pub struct PyBacktest {
+    config: BacktestConfig,
+}
+impl PyBacktest {
+    pub fn run_symbol(config: &BacktestConfig, symbol: &str) -> Result<PyBacktestResult, Error> {
+        Self::run_bars(config, symbol, config.load_data(symbol)?)
+    }
+
+    /// Backtests bars supplied by the caller rather than the configured
+    /// data source. The configured date range still applies.
+    pub fn run_bars(
+        config: &BacktestConfig,
+        symbol: &str,
+        data: Vec<DatedStockData>,
+    ) -> Result<PyBacktestResult, Error> {
+        let data = config.select_range(data);
+        let strategy = config.strategy()?;
+        let mut backtest = config.backtest()?;
+        let result = backtest.run(&*strategy, &data, &Metadata::new(symbol.to_string()))?;
+        let outputs = config.write_outputs(&result, &config.manifest(symbol, &data))?;
+        Ok(PyBacktestResult::from_result(symbol, &result, outputs))
+    }
+}
+
+#[pymethods]
+impl PyBacktest {
+    #[new]
+    fn new(config: PyBacktestConfig) -> Self {
+        PyBacktest { config: config.0 }
+    }
+
+    #[staticmethod]
+    fn from_file(path: PathBuf) -> PyResult<Self> {
+        BacktestConfig::from_file(Path::new(&path)).map(|config| PyBacktest { config }).map_err(PyErr::from)
+    }
+
+    #[getter]
+    fn config(&self) -> PyBacktestConfig {
+        PyBacktestConfig(self.config.clone())
+    }
+
+    /// Backtests one symbol, by default the first of the universe. Bars
+    /// are read from `data` when given, otherwise from the configured
+    /// source.
+    #[pyo3(signature = (symbol=None, data=None))]
+    fn run(&self, py: Python<'_>, symbol: Option<&str>, data: Option<&Bound<'_, PyAny>>) -> PyResult<PyBacktestResult> {
+        let symbol = symbol
+            .or(self.config.universe.first().map(String::as_str))
+            .ok_or_else(|| value_error("The universe is empty"))?;
+        let bars = data.map(bars_from_python).transpose()?;
+        without_gil(py, || match bars {
+            Some(bars) => Self::run_bars(&self.config, symbol, bars),
+            None => Self::run_symbol(&self.config, symbol),
+        })
+    }
+
+    /// Backtests every symbol of the universe, in order. `data` maps
+    /// symbols to bars; symbols missing from it use the configured source.
+    #[pyo3(signature = (data=None))]
+    fn run_all(&self, py: Python<'_>, data: Option<&Bound<'_, PyMapping>>) -> PyResult<Vec<PyBacktestResult>> {
+        let mut bars = symbol_bars(data, &self.config.universe)?;
+        without_gil(py, || {
+            self.config.universe.iter()
+                .map(|symbol| match bars.remove(symbol) {
+                    Some(bars) => Self::run_bars(&self.config, symbol, bars),
+                    None => Self::run_symbol(&self.config, symbol),
+                })
+                .collect()
+        })
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Backtest({})", PyBacktestConfig(self.config.clone()).__repr__())
+    }
+}
+
+
+/// Cancels a `run_many` batch, from a callback or another thread.
+/// Backtests already running finish; no new ones start.
+#[pyclass(name = "CancelToken", module = "trading_engine", frozen)]
+#[derive(Debug, Clone, Default)]
+pub struct PyCancelToken(pub Arc<AtomicBool>);
+
+#[pymethods]
+impl PyCancelToken {
+    #[new]
+    fn new() -> Self {
+        PyCancelToken::default()
+    }
+
+    fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    #[getter]
+    fn cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn __repr__(&self) -> String {
+        format!("CancelToken(cancelled={})", if self.cancelled() { "True" } else { "False" })
+    }
+}
+
+
+/// Serves recorded Alpha Vantage responses on a local port, from
+/// `fixtures` or the bundled set, until closed. Pass `url` as a
+/// `DataConfig` base URL to load bars offline.
+#[pyclass(name = "FixtureServer", module = "trading_engine", frozen)]
+#[derive(Debug)]
+pub struct PyFixtureServer {
+    url: String,
+    server: Mutex<Option<FixtureServer>>,
+}
+
+#[pymethods]
+impl PyFixtureServer {
+    #[new]
+    #[pyo3(signature = (fixtures=None, port=0))]
+    fn new(fixtures: Option<PathBuf>, port: u16) -> PyResult<Self> {
+        let server = FixtureServer::start(fixtures.map_or(Fixtures::Bundled, Fixtures::Dir), port)?;
+        Ok(PyFixtureServer { url: server.url(), server: Mutex::new(Some(server)) })
+    }
+
+    #[getter]
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn close(&self, py: Python<'_>) {
+        let server = self.server.lock().ok().and_then(|mut s| s.take());
+        py.allow_threads(|| drop(server));
+    }
+
+    fn __enter__(slf: Py<Self>) -> Py<Self> {
+        slf
+    }
+
+    #[pyo3(signature = (*_args))]
+    fn __exit__(&self, py: Python<'_>, _args: &Bound<'_, PyTuple>) {
+        self.close(py);
+    }
+
+    fn __repr__(&self) -> String {
+        format!("FixtureServer(url={:?})", self.url)
+    }
+}
+
+
+pub fn register(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyDataConfig>()?;
+    m.add_class::<PyStrategyConfig>()?;
+    m.add_class::<PyBrokerConfig>()?;
+    m.add_class::<PyRiskLimits>()?;
+    m.add_class::<PyOutputConfig>()?;
+    m.add_class::<PyExecutionConfig>()?;
+    m.add_class::<PyBacktestConfig>()?;
+    m.add_class::<PyTrade>()?;
+    m.add_class::<PyOrder>()?;
+    m.add_class::<PyRiskEvent>()?;
+    m.add_class::<PyParentOrder>()?;
+    m.add_class::<PyMetrics>()?;
+    m.add_class::<PyPortfolio>()?;
+    m.add_class::<PyBacktestResult>()?;
+    m.add_class::<PyBacktest>()?;
+    m.add_class::<PyTable>()?;
+    m.add_class::<PyCancelToken>()?;
+    m.add_class::<PyFixtureServer>()?;
+    let py = m.py();
+    m.add("EngineError", py.get_type::<EngineError>())?;
+    m.add("DataFetchError", py.get_type::<DataFetchError>())?;
+    m.add("RateLimitError", py.get_type::<RateLimitError>())?;
+    m.add("MissingApiKeyError", py.get_type::<MissingApiKeyError>())?;
+    m.add("ParseError", py.get_type::<ParseError>())?;
+    m.add("ConfigError", py.get_type::<ConfigError>())?;
+    m.add("UnknownStrategyError", py.get_type::<UnknownStrategyError>())?;
+    m.add("OrderRejectedError", py.get_type::<OrderRejectedError>())?;
+    m.add("BrokerError", py.get_type::<BrokerError>())?;
+    m.add("AccountingError", py.get_type::<AccountingError>())?;
+    m.add("StorageError", py.get_type::<StorageError>())?;
+    Ok(())
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pyo3::PyTypeInfo;
+
+    fn raises<T: PyTypeInfo>(py: Python<'_>, e: Error) -> bool {
+        let message = e.to_string();
+        let raised = PyErr::from(e);
+        raised.is_instance_of::<T>(py) && raised.is_instance_of::<EngineError>(py)
+            && raised.value(py).to_string() == message
+    }
+
+    #[test]
+    fn engine_errors_raise_their_exception_class() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let text = || "x".to_string();
+            assert!(raises::<DataFetchError>(py, Error::DataFetch(text())));
+            assert!(raises::<RateLimitError>(py, Error::RateLimit(text())));
+            assert!(raises::<DataFetchError>(py, Error::RateLimit(text())));
+            assert!(raises::<MissingApiKeyError>(py, Error::MissingApiKey(text())));
+            assert!(raises::<ParseError>(py, Error::Parse(text())));
+            assert!(raises::<ConfigError>(py, Error::Config(text())));
+            assert!(raises::<UnknownStrategyError>(py, Error::UnknownStrategy(text())));
+            assert!(raises::<ConfigError>(py, Error::UnknownStrategy(text())));
+            assert!(raises::<OrderRejectedError>(py, Error::OrderRejected(text())));
+            assert!(raises::<BrokerError>(py, Error::Broker(text())));
+            assert!(raises::<AccountingError>(py, Error::Accounting(text())));
+            assert!(raises::<StorageError>(py, Error::Store(text())));
+            assert!(raises::<StorageError>(py, Error::Export(text())));
+            assert!(raises::<StorageError>(py, Error::Io(std::io::Error::other("x"))));
+            assert!(raises::<EngineError>(py, Error::Panic(text())));
+            assert!(!raises::<ConfigError>(py, Error::Parse(text())));
+        });
+    }
+}
@@ -0,0 +1,363 @@
+//!
+//! API rate limiting.
+//!
+//! Data providers cap calls per minute and per day. `RateLimiter` keeps a
+//! token bucket for each window and `acquire` blocks until both hold a
+//! token, so a bulk download paces itself rather than tripping the limit.
+//! `call` adds retries: transient failures and rate limit responses are
+//! retried with exponential backoff. Limiters are shared process-wide,
+//! one per API key, since the budget belongs to the key and not to a
+//! client, and each one's `ApiUsage` counts calls, retries and time spent
+//! waiting. A call that would wait past `max_wait_secs` fails with a rate
+//! limit error that says whether the minute or the day ran out.
+//!
+
+use crate::error::{EngineError, Result};
+use log::warn;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+
+const MINUTE: Duration = Duration::from_secs(60);
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A provider plan's call budget and retry settings. The defaults match
+/// the Alpha Vantage free plan.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimits {
+    pub per_minute: u32,
+    /// `None` for plans without a daily cap.
+    pub per_day: Option<u32>,
+    /// Retries of a transient failure before giving up.
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for each one after.
+    pub backoff_secs: f64,
+    /// Longest wait for budget before a call fails with a rate limit
+    /// error instead.
+    pub max_wait_secs: f64,
+    pub timeout_secs: f64,
+}
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            per_minute: 5,
+            per_day: Some(25),
+            max_retries: 3,
+            backoff_secs: 2.0,
+            max_wait_secs: 300.0,
+            timeout_secs: 30.0,
+        }
+    }
+}
+impl RateLimits {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs_f64(self.timeout_secs)
+    }
+
+    /// Delay before retry `attempt` (from 0), with jitter so that
+    /// threads which failed together do not retry together.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self.backoff_secs * 2f64.powi(attempt.min(16) as i32);
+        Duration::from_secs_f64(delay * rand::thread_rng().gen_range(0.5..=1.0))
+    }
+
+    /// Problems with the settings, for config validation.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.per_minute == 0 {
+            problems.push("per_minute: must be at least 1".to_string());
+        }
+        if self.per_day == Some(0) {
+            problems.push("per_day: must be at least 1".to_string());
+        }
+        for (name, value) in [("backoff_secs", self.backoff_secs), ("max_wait_secs", self.max_wait_secs)] {
+            if !(value >= 0.0 && value.is_finite()) {
+                problems.push(format!("{}: must be a non-negative number of seconds, got {}", name, value));
+            }
+        }
+        if !(self.timeout_secs > 0.0 && self.timeout_secs.is_finite()) {
+            problems.push(format!("timeout_secs: must be positive, got {}", self.timeout_secs));
+        }
+        problems
+    }
+}
+
+
+/// Counters since the process started, plus the budget left now.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ApiUsage {
+    pub calls: u64,
+    pub retries: u64,
+    pub rate_limited: u64,
+    pub failures: u64,
+    pub waited_secs: f64,
+    pub minute_remaining: u32,
+    pub day_remaining: Option<u32>,
+}
+impl ApiUsage {
+    fn add(self, other: ApiUsage) -> ApiUsage {
+        ApiUsage {
+            calls: self.calls + other.calls,
+            retries: self.retries + other.retries,
+            rate_limited: self.rate_limited + other.rate_limited,
+            failures: self.failures + other.failures,
+            waited_secs: self.waited_secs + other.waited_secs,
+            minute_remaining: self.minute_remaining + other.minute_remaining,
+            // A key without a daily cap leaves the total uncapped.
+            day_remaining: self.day_remaining.zip(other.day_remaining).map(|(a, b)| a + b),
+        }
+    }
+}
+
+
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    per_second: f64,
+    updated: Instant,
+}
+impl TokenBucket {
+    fn new(capacity: u32, period: Duration) -> Self {
+        let capacity = capacity as f64;
+        TokenBucket { capacity, tokens: capacity, per_second: capacity / period.as_secs_f64(), updated: Instant::now() }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Time until a token is available.
+    fn wait(&self) -> Duration {
+        match self.tokens >= 1.0 {
+            true => Duration::ZERO,
+            false => Duration::from_secs_f64((1.0 - self.tokens) / self.per_second),
+        }
+    }
+
+    /// Keeps the tokens already spent when the plan changes.
+    fn resize(&self, capacity: u32, period: Duration) -> Self {
+        let mut bucket = TokenBucket::new(capacity, period);
+        bucket.tokens = (bucket.capacity - (self.capacity - self.tokens)).max(0.0);
+        bucket
+    }
+}
+
+
+/// How an attempt passed to `RateLimiter::call` failed.
+#[derive(Debug)]
+pub enum Failure {
+    /// Timeouts, dropped connections and server errors.
+    Transient(EngineError),
+    /// The provider refused the call for exceeding its limit.
+    RateLimited(EngineError),
+    /// Not worth retrying.
+    Fatal(EngineError),
+}
+
+#[derive(Debug)]
+struct LimiterState {
+    limits: RateLimits,
+    minute: TokenBucket,
+    day: Option<TokenBucket>,
+    usage: ApiUsage,
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    state: Mutex<LimiterState>,
+}
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        RateLimiter {
+            state: Mutex::new(LimiterState {
+                limits,
+                minute: TokenBucket::new(limits.per_minute, MINUTE),
+                day: limits.per_day.map(|n| TokenBucket::new(n, DAY)),
+                usage: ApiUsage::default(),
+            }),
+        }
+    }
+
+    fn registry() -> &'static Mutex<HashMap<String, Arc<RateLimiter>>> {
+        static LIMITERS: OnceLock<Mutex<HashMap<String, Arc<RateLimiter>>>> = OnceLock::new();
+        LIMITERS.get_or_init(Default::default)
+    }
+
+    /// The process-wide limiter for `api_key`, created with `limits` on
+    /// first use and switched to them if they differ from the current ones.
+    pub fn for_key(api_key: &str, limits: &RateLimits) -> Arc<RateLimiter> {
+        let limiter = Self::registry().lock().unwrap_or_else(|e| e.into_inner())
+            .entry(api_key.to_string())
+            .or_insert_with(|| Arc::new(RateLimiter::new(*limits)))
+            .clone();
+        limiter.configure(limits);
+        limiter
+    }
+
+    /// Usage summed over the limiters of every key used so far.
+    pub fn total_usage() -> ApiUsage {
+        let limiters: Vec<Arc<RateLimiter>> = Self::registry().lock().unwrap_or_else(|e| e.into_inner())
+            .values()
+            .cloned()
+            .collect();
+        limiters.iter().map(|l| l.usage()).reduce(ApiUsage::add).unwrap_or_default()
+    }
+
+    pub fn configure(&self, limits: &RateLimits) {
+        let mut state = self.lock();
+        if state.limits == *limits {
+            return;
+        }
+        let now = Instant::now();
+        state.minute.refill(now);
+        state.minute = state.minute.resize(limits.per_minute, MINUTE);
+        state.day = match (&mut state.day, limits.per_day) {
+            (Some(day), Some(n)) => {
+                day.refill(now);
+                Some(day.resize(n, DAY))
+            },
+            (_, per_day) => per_day.map(|n| TokenBucket::new(n, DAY)),
+        };
+        state.limits = *limits;
+    }
+
+    pub fn limits(&self) -> RateLimits {
+        self.lock().limits
+    }
+
+    pub fn usage(&self) -> ApiUsage {
+        let mut state = self.lock();
+        state.refill(Instant::now());
+        let mut usage = state.usage.clone();
+        usage.minute_remaining = state.minute.tokens.floor() as u32;
+        usage.day_remaining = state.day.as_ref().map(|d| d.tokens.floor() as u32);
+        usage
+    }
+
+    /// Blocks until the budget allows a call, then spends it. Fails with
+    /// a rate limit error rather than wait longer than `max_wait_secs`.
+    pub fn acquire(&self) -> Result<()> {
+        let mut waited = Duration::ZERO;
+        loop {
+            let wait = {
+                let mut state = self.lock();
+                state.refill(Instant::now());
+                let wait = state.minute.wait().max(state.day.as_ref().map_or(Duration::ZERO, TokenBucket::wait));
+                if wait.is_zero() {
+                    state.minute.tokens -= 1.0;
+                    if let Some(day) = &mut state.day {
+                        day.tokens -= 1.0;
+                    }
+                    state.usage.calls += 1;
+                    state.usage.waited_secs += waited.as_secs_f64();
+                    return Ok(());
+                }
+                if (waited + wait).as_secs_f64() > state.limits.max_wait_secs {
+                    state.usage.waited_secs += waited.as_secs_f64();
+                    let (budget, window) = match (state.limits.per_day, &state.day) {
+                        (Some(n), Some(day)) if day.wait() >= state.minute.wait() => (n, "day"),
+                        _ => (state.limits.per_minute, "minute"),
+                    };
+                    return Err(EngineError::RateLimit(format!(
+                        "call budget of {} per {} used up; next call allowed in {:.0}s, past max_wait_secs of {:.0}",
+                        budget, window, wait.as_secs_f64(), state.limits.max_wait_secs,
+                    )));
+                }
+                wait
+            };
+            thread::sleep(wait);
+            waited += wait;
+        }
+    }
+
+    /// Marks the rest of the current window as spent after the provider
+    /// refused a call, so later calls wait instead of being refused too.
+    pub fn exhaust(&self, daily: bool) {
+        let mut state = self.lock();
+        state.usage.rate_limited += 1;
+        state.minute.tokens = 0.0;
+        if daily {
+            if let Some(day) = &mut state.day {
+                day.tokens = 0.0;
+            }
+        }
+    }
+
+    /// Runs `attempt` within the budget, retrying transient failures and
+    /// rate limit responses up to `max_retries` times.
+    pub fn call<T>(&self, mut attempt: impl FnMut() -> std::result::Result<T, Failure>) -> Result<T> {
+        let limits = self.limits();
+        let mut retry = 0;
+        loop {
+            self.acquire()?;
+            let (error, retryable) = match attempt() {
+                Ok(value) => return Ok(value),
+                Err(Failure::Fatal(e)) => (e, false),
+                Err(Failure::Transient(e)) => (e, true),
+                Err(Failure::RateLimited(e)) => {
+                    self.exhaust(false);
+                    (e, true)
+                },
+            };
+            if !retryable || retry >= limits.max_retries {
+                self.lock().usage.failures += 1;
+                return Err(error);
+            }
+            let delay = limits.backoff(retry);
+            warn!("{}; retry {} of {} in {:.1}s", error, retry + 1, limits.max_retries, delay.as_secs_f64());
+            self.lock().usage.retries += 1;
+            thread::sleep(delay);
+            retry += 1;
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, LimiterState> {
+        // The state stays consistent even if a holder panicked.
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl LimiterState {
+    fn refill(&mut self, now: Instant) {
+        self.minute.refill(now);
+        if let Some(day) = &mut self.day {
+            day.refill(now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(per_minute: u32, per_day: Option<u32>) -> RateLimits {
+        RateLimits { per_minute, per_day, max_wait_secs: 0.0, ..RateLimits::default() }
+    }
+
+    #[test]
+    fn each_key_has_its_own_budget() {
+        let first = RateLimiter::for_key("test-key-a", &limits(1, None));
+        assert!(first.acquire().is_ok());
+        assert!(matches!(first.acquire(), Err(EngineError::RateLimit(_))));
+        assert!(RateLimiter::for_key("test-key-b", &limits(1, None)).acquire().is_ok());
+        assert!(Arc::ptr_eq(&first, &RateLimiter::for_key("test-key-a", &limits(1, None))));
+    }
+
+    #[test]
+    fn daily_exhaustion_names_the_daily_budget() {
+        let limiter = RateLimiter::new(limits(5, Some(1)));
+        assert!(limiter.acquire().is_ok());
+        match limiter.acquire() {
+            Err(EngineError::RateLimit(message)) => assert!(message.contains("1 per day"), "{}", message),
+            other => panic!("expected a rate limit error, got {:?}", other),
+        }
+    }
+}
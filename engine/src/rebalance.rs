@@ -0,0 +1,228 @@
+//!
+//! Target-weight rebalancing.
+//!
+//! Allocation strategies supply target portfolio weights. The `Rebalancer`
+//! decides when to act, on a calendar schedule or when holdings drift too
+//! far from target, and turns the weights into orders that respect lot
+//! sizes, a minimum trade size and a cash buffer. Each rebalance is
+//! recorded with the turnover of the orders actually sent and a count of
+//! those dropped along the way.
+//!
+
+use crate::data_loading::DatedStockData;
+use crate::error::{EngineError, Result};
+use crate::order::{Fill, Order};
+use crate::portfolio::Portfolio;
+use chrono::{Datelike, NaiveDate};
+use derive_new::new;
+use log::warn;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+
+pub trait AllocationStrategy {
+    /// Desired weight per symbol as a fraction of investable equity.
+    fn target_weights(
+        &self,
+        date: &str,
+        history: &HashMap<String, &[DatedStockData]>,
+        portfolio: &Portfolio,
+    ) -> HashMap<String, f64>;
+
+    fn on_fill(&self, _fill: &Fill, _order: &Order) {}
+}
+
+
+/// Constant weights, e.g. a 60/40 portfolio.
+#[derive(Debug, Clone, new)]
+pub struct FixedWeights {
+    weights: HashMap<String, f64>,
+}
+impl AllocationStrategy for FixedWeights {
+    fn target_weights(
+        &self,
+        _date: &str,
+        _history: &HashMap<String, &[DatedStockData]>,
+        _portfolio: &Portfolio,
+    ) -> HashMap<String, f64> {
+        self.weights.clone()
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RebalanceSchedule {
+    #[default]
+    Monthly,
+    Quarterly,
+    /// Only rebalance on drift.
+    Never,
+}
+
+impl FromStr for RebalanceSchedule {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "monthly" => Ok(RebalanceSchedule::Monthly),
+            "quarterly" => Ok(RebalanceSchedule::Quarterly),
+            "never" => Ok(RebalanceSchedule::Never),
+            _ => Err(EngineError::Config(format!("Unknown rebalance schedule: {}", s))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, new)]
+pub struct RebalanceConfig {
+    pub schedule: RebalanceSchedule,
+    /// Rebalance whenever any weight is further than this from target.
+    pub drift_tolerance: Option<f64>,
+    /// Orders are rounded down to a multiple of this many shares.
+    pub lot_size: i64,
+    /// Trades below this notional are skipped.
+    pub min_trade_notional: f64,
+    /// Fraction of equity held back as cash.
+    pub cash_buffer: f64,
+}
+impl Default for RebalanceConfig {
+    fn default() -> Self {
+        RebalanceConfig::new(RebalanceSchedule::Monthly, None, 1, 0.0, 0.0)
+    }
+}
+
+/// Statistics for a single rebalance.
+#[derive(Debug, Clone, new)]
+pub struct RebalanceRecord {
+    pub date: String,
+    pub equity: f64,
+    /// Orders sent on to execution.
+    #[new(value = "0")]
+    pub n_orders: usize,
+    /// Notional of the orders sent.
+    #[new(value = "0.0")]
+    pub traded_notional: f64,
+    /// Traded notional as a fraction of equity.
+    #[new(value = "0.0")]
+    pub turnover: f64,
+    /// Orders held or rejected by risk, or left untraded for want of a bar.
+    #[new(value = "0")]
+    pub n_dropped: usize,
+}
+
+
+#[derive(Debug, new)]
+pub struct Rebalancer {
+    config: RebalanceConfig,
+    #[new(default)]
+    last_period: Option<(i32, u32)>,
+    #[new(default)]
+    history: Vec<RebalanceRecord>,
+}
+impl Rebalancer {
+    pub fn records(&self) -> &[RebalanceRecord] {
+        &self.history
+    }
+
+    fn period(&self, date: NaiveDate) -> (i32, u32) {
+        match self.config.schedule {
+            RebalanceSchedule::Monthly => (date.year(), date.month()),
+            RebalanceSchedule::Quarterly => (date.year(), (date.month() - 1) / 3),
+            RebalanceSchedule::Never => (0, 0),
+        }
+    }
+
+    /// Weight of each symbol the rebalance aims for, after the cash buffer.
+    fn investable_weight(&self, weight: f64) -> f64 {
+        weight * (1.0 - self.config.cash_buffer)
+    }
+
+    fn max_drift(&self, weights: &HashMap<String, f64>, portfolio: &Portfolio) -> f64 {
+        let equity = portfolio.equity();
+        if equity <= 0.0 {
+            return 0.0;
+        }
+        let mut tickers: Vec<&String> = weights.keys().collect();
+        let held = portfolio.tickers();
+        tickers.extend(held.iter().filter(|t| !weights.contains_key(*t)));
+
+        tickers.into_iter()
+            .map(|ticker| {
+                let price = portfolio.last_price(ticker).unwrap_or(0.0);
+                let current = portfolio.position_in(ticker) as f64 * price / equity;
+                let target = self.investable_weight(*weights.get(ticker).unwrap_or(&0.0));
+                (current - target).abs()
+            })
+            .fold(0.0, f64::max)
+    }
+
+    pub fn is_due(&self, date: &str, weights: &HashMap<String, f64>, portfolio: &Portfolio) -> Result<bool> {
+        let day = NaiveDate::parse_from_str(date.get(..10).unwrap_or(date), "%Y-%m-%d")?;
+        let calendar_due = match self.last_period {
+            None => true,
+            Some(last) => self.config.schedule != RebalanceSchedule::Never && self.period(day) != last,
+        };
+        let drift_due = self.config.drift_tolerance
+            .map(|tolerance| self.max_drift(weights, portfolio) > tolerance)
+            .unwrap_or(false);
+        Ok(calendar_due || drift_due)
+    }
+
+    /// Orders that move the portfolio to `weights`, sells first so that
+    /// buys can use the freed cash. The caller reports what became of
+    /// each through `record_sent` or `record_dropped`.
+    pub fn rebalance(
+        &mut self,
+        date: &str,
+        weights: &HashMap<String, f64>,
+        portfolio: &Portfolio,
+    ) -> Result<Vec<Order>> {
+        let day = NaiveDate::parse_from_str(date.get(..10).unwrap_or(date), "%Y-%m-%d")?;
+        let equity = portfolio.equity();
+        let lot_size = self.config.lot_size.max(1);
+
+        let mut tickers: Vec<String> = weights.keys().cloned().collect();
+        tickers.extend(portfolio.tickers().into_iter().filter(|t| !weights.contains_key(t)));
+        tickers.sort();
+
+        let mut orders: Vec<Order> = vec![];
+        for ticker in tickers {
+            let price = match portfolio.last_price(&ticker) {
+                Some(price) if price > 0.0 => price,
+                _ => continue,
+            };
+            let weight = self.investable_weight(*weights.get(&ticker).unwrap_or(&0.0));
+            let target = (weight * equity / price).trunc() as i64;
+            let current = portfolio.position_in(&ticker) + portfolio.pending_quantity(&ticker);
+            let delta = (target - current) / lot_size * lot_size;
+            let notional = (delta as f64 * price).abs();
+            if delta == 0 || notional < self.config.min_trade_notional {
+                continue;
+            }
+            orders.push(Order::new(ticker, delta));
+        }
+        orders.sort_by_key(|o| o.quantity > 0);
+
+        self.last_period = Some(self.period(day));
+        self.history.push(RebalanceRecord::new(date.to_string(), equity));
+
+        Ok(orders)
+    }
+
+    /// Counts an order from the latest rebalance that went on to
+    /// execution with `quantity` shares at `price`.
+    pub fn record_sent(&mut self, quantity: i64, price: f64) {
+        if let Some(record) = self.history.last_mut() {
+            record.n_orders += 1;
+            record.traded_notional += (quantity as f64 * price).abs();
+            record.turnover = if record.equity > 0.0 { record.traded_notional / record.equity } else { 0.0 };
+        }
+    }
+
+    /// Counts an order from the latest rebalance that was not sent.
+    pub fn record_dropped(&mut self, order: &Order, reason: &str) {
+        warn!("Rebalance order {} ({} {}) dropped: {}", order.id, order.ticker, order.quantity, reason);
+        if let Some(record) = self.history.last_mut() {
+            record.n_dropped += 1;
+        }
+    }
+}
@@ -0,0 +1,392 @@
+//!
+//! HTML tearsheet reports.
+//!
+//! Renders a backtest into a single static HTML page: metrics summary,
+//! parameters, equity and drawdown charts, a monthly returns heatmap, a
+//! rolling Sharpe chart and the trade list. Charts are inline SVG and
+//! styles are embedded, so the file has no external assets and can be
+//! attached to an email.
+//!
+
+use crate::backtest::BacktestResult;
+use crate::error::Result;
+use crate::export::RunManifest;
+use crate::metrics::{drawdowns, mean_std, period_returns, EquityPoint, Metrics};
+use crate::portfolio::Trade;
+use chrono::Datelike;
+use derive_new::new;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+
+/// Bars in the rolling Sharpe window, roughly one quarter of daily bars.
+const ROLLING_SHARPE_WINDOW: usize = 63;
+const PERIODS_PER_YEAR: f64 = 252.0;
+const CHART_WIDTH: f64 = 860.0;
+const CHART_HEIGHT: f64 = 220.0;
+const CHART_MARGIN: f64 = 48.0;
+
+const STYLE: &str = "
+body { font-family: -apple-system, 'Segoe UI', Helvetica, Arial, sans-serif; margin: 32px; color: #222; }
+h1 { margin-bottom: 4px; }
+h2 { margin-top: 32px; border-bottom: 1px solid #ddd; padding-bottom: 4px; }
+.subtitle { color: #666; }
+table { border-collapse: collapse; font-size: 13px; }
+th, td { padding: 4px 10px; border-bottom: 1px solid #eee; text-align: right; }
+th { background: #f6f6f6; }
+td.label, th.label { text-align: left; }
+.metrics td { font-size: 15px; }
+.heatmap td { width: 52px; text-align: center; }
+svg text { font-size: 11px; fill: #555; }
+";
+
+/// Everything the tearsheet shows, independent of where the run came from.
+#[derive(Debug, Clone, new)]
+pub struct ReportData {
+    pub manifest: RunManifest,
+    pub metrics: Metrics,
+    pub equity_curve: Vec<EquityPoint>,
+    pub trades: Vec<Trade>,
+}
+impl ReportData {
+    pub fn from_result(result: &BacktestResult, manifest: &RunManifest) -> Self {
+        ReportData::new(
+            manifest.clone(),
+            result.metrics(),
+            result.equity_curve.to_vec(),
+            result.portfolio.trades.clone(),
+        )
+    }
+}
+
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn pct(value: f64) -> String {
+    format!("{:.2}%", value * 100.0)
+}
+
+/// Line chart of `values` against their index, optionally filled down
+/// to the zero line.
+fn line_chart(values: &[f64], labels: (&str, &str), color: &str, fill: bool, as_percent: bool) -> String {
+    let mut svg = String::new();
+    let _ = write!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}">"#,
+        w = CHART_WIDTH, h = CHART_HEIGHT,
+    );
+    if values.len() < 2 {
+        svg.push_str(r#"<text x="10" y="20">Not enough data</text></svg>"#);
+        return svg;
+    }
+
+    let mut min = values.iter().cloned().fold(f64::INFINITY, f64::min);
+    let mut max = values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    if fill {
+        min = min.min(0.0);
+        max = max.max(0.0);
+    }
+    if (max - min).abs() < f64::EPSILON {
+        max = min + 1.0;
+    }
+
+    let plot_w = CHART_WIDTH - 2.0 * CHART_MARGIN;
+    let plot_h = CHART_HEIGHT - CHART_MARGIN;
+    let top = CHART_MARGIN / 2.0;
+    let x = |i: usize| CHART_MARGIN + plot_w * i as f64 / (values.len() - 1) as f64;
+    let y = |v: f64| top + plot_h * (max - v) / (max - min);
+
+    let points: Vec<String> = values.iter().enumerate()
+        .map(|(i, v)| format!("{:.1},{:.1}", x(i), y(*v)))
+        .collect();
+    let fmt = |v: f64| if as_percent { pct(v) } else { format!("{:.0}", v) };
+
+    let _ = write!(
+        svg,
+        r##"<line x1="{l}" y1="{z:.1}" x2="{r}" y2="{z:.1}" stroke="#ccc"/>"##,
+        l = CHART_MARGIN, r = CHART_MARGIN + plot_w, z = y(0.0_f64.clamp(min, max)),
+    );
+    if fill {
+        let _ = write!(
+            svg,
+            r#"<polygon points="{:.1},{:.1} {} {:.1},{:.1}" fill="{}" fill-opacity="0.25"/>"#,
+            x(0), y(0.0), points.join(" "), x(values.len() - 1), y(0.0), color,
+        );
+    }
+    let _ = write!(
+        svg,
+        r#"<polyline points="{}" fill="none" stroke="{}" stroke-width="1.5"/>"#,
+        points.join(" "), color,
+    );
+    let _ = write!(
+        svg,
+        r#"<text x="2" y="{:.1}">{}</text><text x="2" y="{:.1}">{}</text>"#,
+        top + 4.0, fmt(max), top + plot_h, fmt(min),
+    );
+    let _ = write!(
+        svg,
+        r#"<text x="{:.1}" y="{:.1}">{}</text><text x="{:.1}" y="{:.1}" text-anchor="end">{}</text>"#,
+        CHART_MARGIN, CHART_HEIGHT - 4.0, escape(labels.0),
+        CHART_MARGIN + plot_w, CHART_HEIGHT - 4.0, escape(labels.1),
+    );
+    svg.push_str("</svg>");
+    svg
+}
+
+/// Monthly returns keyed by (year, month), chaining each month from the
+/// previous month's final equity.
+pub fn monthly_returns(curve: &[EquityPoint]) -> BTreeMap<(i32, u32), f64> {
+    let mut month_end: BTreeMap<(i32, u32), f64> = BTreeMap::new();
+    for point in curve {
+        month_end.insert((point.timestamp.year(), point.timestamp.month()), point.equity);
+    }
+    let mut previous = curve.first().map(|p| p.equity).unwrap_or(0.0);
+    month_end.into_iter()
+        .map(|(month, equity)| {
+            let ret = if previous != 0.0 { equity / previous - 1.0 } else { 0.0 };
+            previous = equity;
+            (month, ret)
+        })
+        .collect()
+}
+
+/// Annualised Sharpe ratio over a trailing window of returns.
+pub fn rolling_sharpe(curve: &[EquityPoint], window: usize) -> Vec<f64> {
+    period_returns(curve).windows(window)
+        .map(|w| {
+            let (mean, std_dev) = mean_std(w);
+            if std_dev > 0.0 { mean / std_dev * PERIODS_PER_YEAR.sqrt() } else { 0.0 }
+        })
+        .collect()
+}
+
+fn heatmap_color(ret: f64) -> String {
+    let intensity = (ret.abs() / 0.10).min(1.0);
+    let fade = (255.0 - 155.0 * intensity) as u8;
+    if ret >= 0.0 {
+        format!("rgb({},{},{})", fade, 255, fade)
+    } else {
+        format!("rgb({},{},{})", 255, fade, fade)
+    }
+}
+
+fn heatmap(curve: &[EquityPoint]) -> String {
+    let returns = monthly_returns(curve);
+    let mut years: Vec<i32> = returns.keys().map(|(y, _)| *y).collect();
+    years.dedup();
+
+    let mut html = String::from(r#"<table class="heatmap"><tr><th class="label">Year</th>"#);
+    for month in ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"] {
+        let _ = write!(html, "<th>{}</th>", month);
+    }
+    html.push_str("</tr>");
+    for year in years {
+        let _ = write!(html, r#"<tr><td class="label">{}</td>"#, year);
+        for month in 1..=12 {
+            match returns.get(&(year, month)) {
+                Some(ret) => {
+                    let _ = write!(html, r#"<td style="background:{}">{:.1}</td>"#, heatmap_color(*ret), ret * 100.0);
+                },
+                None => html.push_str("<td></td>"),
+            }
+        }
+        html.push_str("</tr>");
+    }
+    html.push_str("</table>");
+    html
+}
+
+fn metrics_table(metrics: &Metrics) -> String {
+    let rows = [
+        ("Initial equity", format!("{:.2}", metrics.initial_equity)),
+        ("Final equity", format!("{:.2}", metrics.final_equity)),
+        ("Total return", pct(metrics.total_return)),
+        ("Annualized return", pct(metrics.annualized_return)),
+        ("Annualized volatility", pct(metrics.annualized_volatility)),
+        ("Sharpe ratio", format!("{:.2}", metrics.sharpe_ratio)),
+        ("Max drawdown", pct(metrics.max_drawdown)),
+        ("Trades", metrics.n_trades.to_string()),
+    ];
+    let mut html = String::from(r#"<table class="metrics">"#);
+    for (label, value) in rows {
+        let _ = write!(html, r#"<tr><td class="label">{}</td><td>{}</td></tr>"#, label, value);
+    }
+    html.push_str("</table>");
+    html
+}
+
+fn parameters_table(manifest: &RunManifest) -> String {
+    let mut html = String::from("<table>");
+    let mut rows: Vec<(String, String)> = vec![
+        ("strategy".to_string(), manifest.strategy.clone()),
+        ("symbols".to_string(), manifest.symbols.join(", ")),
+    ];
+    rows.extend(manifest.parameters.iter().map(|(k, v)| (k.clone(), v.clone())));
+    for (key, value) in rows {
+        let _ = write!(html, r#"<tr><td class="label">{}</td><td class="label">{}</td></tr>"#, escape(&key), escape(&value));
+    }
+    html.push_str("</table>");
+    html
+}
+
+fn trades_table(trades: &[Trade]) -> String {
+    if trades.is_empty() {
+        return "<p>No trades were executed in this backtest.</p>".to_string();
+    }
+    let mut html = String::from(
+        r#"<table><tr><th class="label">Date</th><th class="label">Symbol</th><th>Quantity</th><th>Price</th><th>Value</th></tr>"#,
+    );
+    for trade in trades {
+        let _ = write!(
+            html,
+            r#"<tr><td class="label">{}</td><td class="label">{}</td><td>{}</td><td>{:.2}</td><td>{:.2}</td></tr>"#,
+            trade.timestamp.format("%Y-%m-%d %H:%M"),
+            escape(&trade.ticker),
+            trade.quantity,
+            trade.price,
+            trade.price * trade.quantity as f64,
+        );
+    }
+    html.push_str("</table>");
+    html
+}
+
+pub fn render_tearsheet(data: &ReportData) -> String {
+    let curve = &data.equity_curve;
+    let labels = (
+        curve.first().map(|p| p.timestamp.format("%Y-%m-%d").to_string()).unwrap_or_default(),
+        curve.last().map(|p| p.timestamp.format("%Y-%m-%d").to_string()).unwrap_or_default(),
+    );
+    let labels = (labels.0.as_str(), labels.1.as_str());
+    let equity: Vec<f64> = curve.iter().map(|p| p.equity).collect();
+    let sharpe = rolling_sharpe(curve, ROLLING_SHARPE_WINDOW);
+
+    let mut html = String::new();
+    let _ = write!(
+        html,
+        r#"<!DOCTYPE html><html><head><meta charset="utf-8"><title>{title}</title><style>{style}</style></head><body>"#,
+        title = escape(&data.manifest.run_id), style = STYLE,
+    );
+    let _ = write!(
+        html,
+        r#"<h1>{}</h1><div class="subtitle">Run {} &middot; {} to {} &middot; engine {}</div>"#,
+        escape(&data.manifest.strategy), escape(&data.manifest.run_id), labels.0, labels.1,
+        escape(&data.manifest.engine_version),
+    );
+    let _ = write!(html, "<h2>Summary</h2>{}", metrics_table(&data.metrics));
+    let _ = write!(html, "<h2>Parameters</h2>{}", parameters_table(&data.manifest));
+    let _ = write!(html, "<h2>Equity</h2>{}", line_chart(&equity, labels, "#1f77b4", false, false));
+    let _ = write!(html, "<h2>Drawdown</h2>{}", line_chart(&drawdowns(curve), labels, "#d62728", true, true));
+    let _ = write!(html, "<h2>Monthly returns (%)</h2>{}", heatmap(curve));
+    let _ = write!(
+        html,
+        "<h2>Rolling Sharpe ({} bars)</h2>{}",
+        ROLLING_SHARPE_WINDOW, line_chart(&sharpe, labels, "#2ca02c", true, false),
+    );
+    let _ = write!(html, "<h2>Trades</h2>{}", trades_table(&data.trades));
+    html.push_str("</body></html>\n");
+    html
+}
+
+pub fn write_tearsheet(data: &ReportData, path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, render_tearsheet(data))?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_loading::DatedStockData;
+    use chrono::{TimeZone, Utc};
+
+    fn point(year: i32, month: u32, day: u32, equity: f64) -> EquityPoint {
+        EquityPoint::new(Utc.with_ymd_and_hms(year, month, day, 0, 0, 0).unwrap(), equity, equity)
+    }
+
+    fn manifest(parameters: &[(&str, &str)]) -> RunManifest {
+        let parameters = parameters.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        let data = vec![DatedStockData::new("2024-01-02".to_string(), 100.0, 101.0, 99.0, 100.0, 1_000)];
+        RunManifest::for_run("sma_cross", parameters, vec!["AAPL".to_string()], &data)
+    }
+
+    #[test]
+    fn monthly_returns_chain_from_the_previous_month_end() {
+        let curve = [
+            point(2024, 1, 2, 100.0),
+            point(2024, 1, 31, 110.0),
+            point(2024, 2, 15, 120.0),
+            point(2024, 2, 29, 99.0),
+            point(2024, 3, 28, 99.0),
+        ];
+        let returns: Vec<f64> = monthly_returns(&curve).into_values().collect();
+        assert_eq!(returns.len(), 3);
+        assert!((returns[0] - 0.10).abs() < 1e-12);
+        assert!((returns[1] - (99.0 / 110.0 - 1.0)).abs() < 1e-12);
+        assert_eq!(returns[2], 0.0);
+        assert!(monthly_returns(&[]).is_empty());
+    }
+
+    #[test]
+    fn rolling_sharpe_is_annualised_per_window() {
+        let curve = [
+            point(2024, 1, 2, 100.0),
+            point(2024, 1, 3, 102.0),
+            point(2024, 1, 4, 102.0),
+            point(2024, 1, 5, 104.04),
+        ];
+        let sharpe = rolling_sharpe(&curve, 2);
+        // Returns of 2% and 0% have mean 1% and standard deviation 1.414%.
+        let expected = 0.01 / 0.0002_f64.sqrt() * 252.0_f64.sqrt();
+        assert_eq!(sharpe.len(), 2);
+        assert!(sharpe.iter().all(|s| (s - expected).abs() < 1e-9), "{:?}", sharpe);
+
+        let flat = [point(2024, 1, 2, 100.0), point(2024, 1, 3, 100.0), point(2024, 1, 4, 100.0)];
+        assert_eq!(rolling_sharpe(&flat, 2), vec![0.0]);
+        assert!(rolling_sharpe(&curve, 10).is_empty());
+    }
+
+    #[test]
+    fn charts_need_two_points() {
+        for values in [&[][..], &[1.0][..]] {
+            let svg = line_chart(values, ("a", "b"), "#000", false, false);
+            assert!(svg.contains("Not enough data") && svg.ends_with("</svg>"));
+            assert!(!svg.contains("<polyline"));
+        }
+        assert!(line_chart(&[1.0, 2.0], ("a", "b"), "#000", false, false).contains("<polyline"));
+    }
+
+    #[test]
+    fn tables_escape_their_text() {
+        let trades = [Trade::new("<b>&Co".to_string(), Utc::now(), 10.0, 5)];
+        let html = trades_table(&trades);
+        assert!(html.contains("&lt;b&gt;&amp;Co") && !html.contains("<b>"));
+
+        let html = parameters_table(&manifest(&[("note", "\"quoted\" <i>")]));
+        assert!(html.contains("&quot;quoted&quot; &lt;i&gt;") && !html.contains("<i>"));
+    }
+
+    #[test]
+    fn tearsheets_have_no_external_assets() {
+        let curve = [point(2024, 1, 2, 100_000.0), point(2024, 1, 3, 101_000.0), point(2024, 2, 1, 100_500.0)];
+        let trades = vec![Trade::new("AAPL".to_string(), curve[1].timestamp, 100.0, 10)];
+        let data = ReportData::new(manifest(&[]), Metrics::from_equity_curve(&curve, 1), curve.to_vec(), trades);
+
+        let html = render_tearsheet(&data);
+        assert!(html.starts_with("<!DOCTYPE html>") && html.trim_end().ends_with("</html>"));
+        assert!(html.contains("<svg") && html.contains(r#"<table class="heatmap">"#));
+        for asset in ["src=", "href=", "<link", "<script"] {
+            assert!(!html.contains(asset), "{}", asset);
+        }
+    }
+}
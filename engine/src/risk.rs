@@ -0,0 +1,321 @@
+//!
+//! Pre-trade risk management.
+//!
+//! The `RiskManager` sits between the strategy and the order processor.
+//! Each order is approved, resized to fit within the configured limits,
+//! held until a later bar, or rejected. Held orders wait in the blotter
+//! with a `held` status and are checked again on each bar. Every
+//! intervention is recorded as a `RiskEvent` with its reason, a hold once
+//! however many bars it lasts.
+//!
+
+use crate::order::{Order, OrderId};
+use crate::portfolio::Portfolio;
+use derive_new::new;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RiskLimits {
+    /// Maximum absolute shares held per symbol.
+    pub max_position: Option<i64>,
+    /// Maximum notional of a single order.
+    pub max_order_notional: Option<f64>,
+    /// Maximum sum of absolute position values.
+    pub max_gross_exposure: Option<f64>,
+    /// Maximum absolute sum of signed position values.
+    pub max_net_exposure: Option<f64>,
+    /// Maximum gross exposure as a multiple of equity.
+    pub max_leverage: Option<f64>,
+    /// Maximum absolute position value per symbol as a fraction of equity.
+    pub max_concentration: Option<f64>,
+    /// Loss from the start-of-day equity beyond which risk-increasing
+    /// orders are held until the next day.
+    pub daily_loss_limit: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RiskAction {
+    Resized,
+    Held,
+    Rejected,
+}
+
+#[derive(Debug, Clone, new)]
+pub struct RiskEvent {
+    pub order_id: OrderId,
+    pub date: String,
+    pub ticker: String,
+    pub requested_quantity: i64,
+    pub approved_quantity: i64,
+    pub action: RiskAction,
+    pub reason: String,
+}
+
+#[derive(Debug)]
+pub enum RiskDecision {
+    Approve(Order),
+    Resize(Order),
+    Hold(Order),
+    Reject(Order),
+}
+
+
+#[derive(Debug, new)]
+pub struct RiskManager {
+    limits: RiskLimits,
+    #[new(default)]
+    date: String,
+    #[new(value = "0.0")]
+    day_start_equity: f64,
+    #[new(default)]
+    events: Vec<RiskEvent>,
+    /// Orders a hold has been recorded for.
+    #[new(default)]
+    held: HashSet<OrderId>,
+}
+impl RiskManager {
+    /// Starts a new bar, resetting the daily loss baseline when the date
+    /// changes.
+    pub fn begin_bar(&mut self, date: &str, portfolio: &Portfolio) {
+        if self.date != date {
+            self.date = date.to_string();
+            self.day_start_equity = portfolio.equity();
+        }
+    }
+
+    pub fn events(&self) -> &[RiskEvent] {
+        &self.events
+    }
+
+    pub fn check(&mut self, mut order: Order, portfolio: &Portfolio) -> RiskDecision {
+        let requested = order.quantity;
+        let price = match portfolio.last_price(&order.ticker) {
+            Some(price) if price > 0.0 => price,
+            _ => {
+                self.record(&order, 0, RiskAction::Rejected, "No market price to assess order risk".to_string());
+                return RiskDecision::Reject(order);
+            }
+        };
+
+        let current = portfolio.position_in(&order.ticker);
+        let increases_risk = (current + requested).abs() > current.abs();
+
+        if let Some(limit) = self.limits.daily_loss_limit {
+            let loss = self.day_start_equity - portfolio.equity();
+            if increases_risk && loss >= limit {
+                let reason = format!("Daily loss {:.2} breaches limit {:.2}", loss, limit);
+                if self.held.insert(order.id) {
+                    self.record(&order, 0, RiskAction::Held, reason);
+                } else {
+                    debug!("Order {} still held: {}", order.id, reason);
+                }
+                return RiskDecision::Hold(order);
+            }
+        }
+
+        let mut quantity = requested;
+        let mut reasons: Vec<String> = vec![];
+        let equity = portfolio.equity();
+        let symbol_value = current.abs() as f64 * price;
+
+        if let Some(max) = self.limits.max_position {
+            quantity = constrain(
+                quantity,
+                cap_abs_target(current, quantity, max),
+                &mut reasons,
+                format!("max position {}", max),
+            );
+        }
+        if let Some(max) = self.limits.max_order_notional {
+            let max_shares = (max / price).floor() as i64;
+            quantity = constrain(
+                quantity,
+                quantity.signum() * quantity.abs().min(max_shares),
+                &mut reasons,
+                format!("max order notional {:.2}", max),
+            );
+        }
+        if let Some(max) = self.limits.max_gross_exposure {
+            let other = portfolio.gross_exposure() - symbol_value;
+            let max_shares = ((max - other) / price).floor() as i64;
+            quantity = constrain(
+                quantity,
+                cap_abs_target(current, quantity, max_shares),
+                &mut reasons,
+                format!("max gross exposure {:.2}", max),
+            );
+        }
+        if let Some(max) = self.limits.max_net_exposure {
+            let net = portfolio.net_exposure();
+            let after = net + quantity as f64 * price;
+            let capped = if after.abs() > max && after.abs() > net.abs() {
+                let shares = ((after.signum() * max - net) / price).trunc() as i64;
+                if shares.signum() == quantity.signum() { shares } else { 0 }
+            } else {
+                quantity
+            };
+            quantity = constrain(quantity, capped, &mut reasons, format!("max net exposure {:.2}", max));
+        }
+        if let Some(max) = self.limits.max_leverage {
+            let other = portfolio.gross_exposure() - symbol_value;
+            let max_shares = ((max * equity - other) / price).floor() as i64;
+            quantity = constrain(
+                quantity,
+                cap_abs_target(current, quantity, max_shares),
+                &mut reasons,
+                format!("max leverage {:.2}", max),
+            );
+        }
+        if let Some(max) = self.limits.max_concentration {
+            let max_shares = (max * equity / price).floor() as i64;
+            quantity = constrain(
+                quantity,
+                cap_abs_target(current, quantity, max_shares),
+                &mut reasons,
+                format!("max concentration {:.2}", max),
+            );
+        }
+
+        if quantity == requested {
+            return RiskDecision::Approve(order);
+        }
+        let reason = format!("Breaches {}", reasons.join(", "));
+        if quantity == 0 {
+            self.record(&order, 0, RiskAction::Rejected, reason);
+            return RiskDecision::Reject(order);
+        }
+        self.record(&order, quantity, RiskAction::Resized, reason);
+        order.quantity = quantity;
+        RiskDecision::Resize(order)
+    }
+
+    fn record(&mut self, order: &Order, approved: i64, action: RiskAction, reason: String) {
+        warn!("Risk {:?} order {} ({} {}): {}", action, order.id, order.ticker, order.quantity, reason);
+        self.events.push(RiskEvent::new(
+            order.id,
+            self.date.clone(),
+            order.ticker.clone(),
+            order.quantity,
+            approved,
+            action,
+            reason,
+        ));
+    }
+}
+
+fn constrain(quantity: i64, capped: i64, reasons: &mut Vec<String>, reason: String) -> i64 {
+    if capped != quantity {
+        reasons.push(reason);
+    }
+    capped
+}
+
+/// Shrinks `quantity` so the resulting position stays within `max_abs`
+/// shares. Orders that reduce the position are never shrunk.
+fn cap_abs_target(current: i64, quantity: i64, max_abs: i64) -> i64 {
+    let target = current + quantity;
+    if target.abs() <= max_abs || target.abs() <= current.abs() {
+        return quantity;
+    }
+    let capped = target.signum() * max_abs.max(0) - current;
+    if capped.signum() == quantity.signum() { capped } else { 0 }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::order::Fill;
+    use chrono::Utc;
+
+    /// A portfolio of 100,000 holding `position` shares of TEST at 100.
+    fn portfolio(position: i64) -> Portfolio {
+        let mut portfolio = Portfolio::new(100_000);
+        portfolio.mark_to_market("TEST", 100.0);
+        if position != 0 {
+            portfolio.apply_fill(&Fill::new(OrderId(0), "TEST".to_string(), Utc::now(), position, 100.0, 0.0));
+        }
+        portfolio
+    }
+
+    fn manager(limits: RiskLimits) -> RiskManager {
+        let mut manager = RiskManager::new(limits);
+        manager.begin_bar("2024-01-02", &portfolio(0));
+        manager
+    }
+
+    fn quantity(decision: &RiskDecision) -> (&'static str, i64) {
+        match decision {
+            RiskDecision::Approve(o) => ("approve", o.quantity),
+            RiskDecision::Resize(o) => ("resize", o.quantity),
+            RiskDecision::Hold(o) => ("hold", o.quantity),
+            RiskDecision::Reject(o) => ("reject", o.quantity),
+        }
+    }
+
+    fn order(quantity: i64) -> Order {
+        Order::new("TEST".to_string(), quantity)
+    }
+
+    #[test]
+    fn max_position_shrinks_only_growing_orders() {
+        let mut manager = manager(RiskLimits { max_position: Some(50), ..Default::default() });
+        assert_eq!(quantity(&manager.check(order(80), &portfolio(0))), ("resize", 50));
+        assert_eq!(quantity(&manager.check(order(20), &portfolio(40))), ("resize", 10));
+        assert_eq!(quantity(&manager.check(order(-100), &portfolio(40))), ("resize", -90));
+        assert_eq!(quantity(&manager.check(order(-30), &portfolio(40))), ("approve", -30));
+        assert_eq!(quantity(&manager.check(order(5), &portfolio(50))), ("reject", 5));
+
+        let actions: Vec<(RiskAction, i64, i64)> = manager.events().iter()
+            .map(|e| (e.action, e.requested_quantity, e.approved_quantity))
+            .collect();
+        assert_eq!(actions, vec![
+            (RiskAction::Resized, 80, 50),
+            (RiskAction::Resized, 20, 10),
+            (RiskAction::Resized, -100, -90),
+            (RiskAction::Rejected, 5, 0),
+        ]);
+        assert!(manager.events()[0].reason.contains("max position 50"));
+    }
+
+    #[test]
+    fn max_order_notional_resizes_either_side() {
+        let mut manager = manager(RiskLimits { max_order_notional: Some(1_050.0), ..Default::default() });
+        assert_eq!(quantity(&manager.check(order(25), &portfolio(0))), ("resize", 10));
+        assert_eq!(quantity(&manager.check(order(-25), &portfolio(0))), ("resize", -10));
+        assert_eq!(quantity(&manager.check(order(10), &portfolio(0))), ("approve", 10));
+    }
+
+    #[test]
+    fn orders_without_a_price_are_rejected() {
+        let mut manager = manager(RiskLimits::default());
+        let unpriced = Order::new("OTHER".to_string(), 10);
+        assert_eq!(quantity(&manager.check(unpriced, &portfolio(0))), ("reject", 10));
+        assert_eq!(manager.events().len(), 1);
+        assert_eq!(manager.events()[0].action, RiskAction::Rejected);
+        assert!(manager.events()[0].reason.contains("No market price"));
+    }
+
+    #[test]
+    fn holds_are_recorded_once_per_order() {
+        let mut manager = RiskManager::new(RiskLimits { daily_loss_limit: Some(500.0), ..Default::default() });
+        let mut losing = portfolio(100);
+        manager.begin_bar("2024-01-02", &losing);
+        losing.mark_to_market("TEST", 90.0);
+
+        let held = order(10);
+        for _ in 0..3 {
+            assert_eq!(quantity(&manager.check(held.clone(), &losing)), ("hold", 10));
+        }
+        assert_eq!(quantity(&manager.check(order(10), &losing)), ("hold", 10));
+        assert_eq!(quantity(&manager.check(order(-10), &losing)), ("approve", -10));
+
+        let held_ids: Vec<OrderId> = manager.events().iter().map(|e| e.order_id).collect();
+        assert_eq!(held_ids.len(), 2);
+        assert_eq!(held_ids[0], held.id);
+    }
+}
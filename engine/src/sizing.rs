@@ -0,0 +1,318 @@
+//!
+//! Position sizing.
+//!
+//! A `PositionSizer` turns a directional `Signal` into a number of shares,
+//! taking price, capital and recent market behaviour into account. Sizers
+//! built from a spec are `Capped` at a fraction of equity, so a quiet
+//! market cannot size a position at many times the account.
+//!
+
+use crate::data_loading::DatedStockData;
+use crate::error::{EngineError, Result};
+use crate::portfolio::Portfolio;
+use crate::slippage::BarContext;
+use derive_new::new;
+use std::fmt::Debug;
+
+
+/// Trading days per year, used to annualise daily volatility.
+const TRADING_DAYS: f64 = 252.0;
+
+/// Direction and conviction for a symbol. `strength` runs from -1.0
+/// (full short) to 1.0 (full long).
+#[derive(Debug, Clone, new)]
+pub struct Signal {
+    pub ticker: String,
+    pub strength: f64,
+}
+
+
+pub trait PositionSizer: Debug + Send + Sync {
+    /// Signed number of shares for `signal` given the price history of
+    /// its symbol.
+    fn size(&self, signal: &Signal, data: &[DatedStockData], portfolio: &Portfolio) -> i64;
+}
+
+fn last_price(data: &[DatedStockData]) -> Option<f64> {
+    data.last().map(|bar| bar.close).filter(|p| *p > 0.0)
+}
+
+fn shares_for_value(value: f64, price: f64) -> i64 {
+    (value / price).trunc() as i64
+}
+
+
+#[derive(Debug, Clone, new)]
+pub struct FixedShares {
+    shares: i64,
+}
+impl PositionSizer for FixedShares {
+    fn size(&self, signal: &Signal, _data: &[DatedStockData], _portfolio: &Portfolio) -> i64 {
+        (self.shares as f64 * signal.strength).round() as i64
+    }
+}
+
+
+#[derive(Debug, Clone, new)]
+pub struct FixedNotional {
+    notional: f64,
+}
+impl PositionSizer for FixedNotional {
+    fn size(&self, signal: &Signal, data: &[DatedStockData], _portfolio: &Portfolio) -> i64 {
+        match last_price(data) {
+            Some(price) => shares_for_value(self.notional * signal.strength, price),
+            None => 0,
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, new)]
+pub struct PercentOfEquity {
+    fraction: f64,
+}
+impl PositionSizer for PercentOfEquity {
+    fn size(&self, signal: &Signal, data: &[DatedStockData], portfolio: &Portfolio) -> i64 {
+        match last_price(data) {
+            Some(price) => shares_for_value(self.fraction * portfolio.equity() * signal.strength, price),
+            None => 0,
+        }
+    }
+}
+
+
+/// Scales exposure so the position's annualised volatility matches
+/// `target_volatility`.
+#[derive(Debug, Clone, new)]
+pub struct VolatilityTarget {
+    target_volatility: f64,
+    lookback: usize,
+}
+impl PositionSizer for VolatilityTarget {
+    fn size(&self, signal: &Signal, data: &[DatedStockData], portfolio: &Portfolio) -> i64 {
+        let (price, bar) = match (last_price(data), BarContext::from_history(data, self.lookback)) {
+            (Some(price), Some(bar)) => (price, bar),
+            _ => return 0,
+        };
+        let annual_volatility = bar.volatility * TRADING_DAYS.sqrt();
+        if annual_volatility <= 0.0 {
+            return 0;
+        }
+        let value = portfolio.equity() * self.target_volatility / annual_volatility * signal.strength;
+        shares_for_value(value, price)
+    }
+}
+
+
+/// Risks `risk_fraction` of equity per trade, with the stop placed
+/// `atr_multiple` average true ranges away.
+#[derive(Debug, Clone, new)]
+pub struct AtrRiskPerTrade {
+    risk_fraction: f64,
+    atr_period: usize,
+    atr_multiple: f64,
+}
+impl AtrRiskPerTrade {
+    fn average_true_range(&self, data: &[DatedStockData]) -> Option<f64> {
+        let start = data.len().saturating_sub(self.atr_period + 1);
+        let ranges: Vec<f64> = data[start..].windows(2)
+            .map(|w| {
+                let (prev, bar) = (&w[0], &w[1]);
+                (bar.high - bar.low)
+                    .max((bar.high - prev.close).abs())
+                    .max((bar.low - prev.close).abs())
+            })
+            .collect();
+        if ranges.is_empty() {
+            return None;
+        }
+        Some(ranges.iter().sum::<f64>() / ranges.len() as f64)
+    }
+}
+impl PositionSizer for AtrRiskPerTrade {
+    fn size(&self, signal: &Signal, data: &[DatedStockData], portfolio: &Portfolio) -> i64 {
+        let stop_distance = match self.average_true_range(data) {
+            Some(atr) if atr > 0.0 => atr * self.atr_multiple,
+            _ => return 0,
+        };
+        let risk = self.risk_fraction * portfolio.equity() * signal.strength;
+        (risk / stop_distance).trunc() as i64
+    }
+}
+
+
+/// A fraction of the Kelly-optimal weight `mean / variance` estimated from
+/// recent returns, capped at full equity. No position is taken when the
+/// estimated edge disagrees with the signal.
+#[derive(Debug, Clone, new)]
+pub struct FractionalKelly {
+    fraction: f64,
+    lookback: usize,
+}
+impl PositionSizer for FractionalKelly {
+    fn size(&self, signal: &Signal, data: &[DatedStockData], portfolio: &Portfolio) -> i64 {
+        let price = match last_price(data) {
+            Some(price) => price,
+            None => return 0,
+        };
+        let start = data.len().saturating_sub(self.lookback + 1);
+        let returns: Vec<f64> = data[start..].windows(2)
+            .filter(|w| w[0].close > 0.0)
+            .map(|w| w[1].close / w[0].close - 1.0)
+            .collect();
+        if returns.len() < 2 {
+            return 0;
+        }
+        let mean = returns.iter().sum::<f64>() / returns.len() as f64;
+        let variance = returns.iter().map(|r| (r - mean).powi(2)).sum::<f64>()
+            / (returns.len() - 1) as f64;
+        if variance <= 0.0 {
+            return 0;
+        }
+
+        let kelly = (mean * signal.strength.signum() / variance).max(0.0);
+        let weight = (self.fraction * kelly * signal.strength.abs()).min(1.0);
+        shares_for_value(weight * portfolio.equity() * signal.strength.signum(), price)
+    }
+}
+
+
+/// Limits the position `sizer` asks for to `max_fraction` of equity at
+/// the latest price.
+#[derive(Debug, new)]
+pub struct Capped {
+    sizer: Box<dyn PositionSizer>,
+    max_fraction: f64,
+}
+impl PositionSizer for Capped {
+    fn size(&self, signal: &Signal, data: &[DatedStockData], portfolio: &Portfolio) -> i64 {
+        let shares = self.sizer.size(signal, data, portfolio);
+        match last_price(data) {
+            Some(price) => {
+                let max = shares_for_value(self.max_fraction * portfolio.equity(), price).max(0);
+                shares.clamp(-max, max)
+            },
+            None => shares,
+        }
+    }
+}
+
+
+/// Builds a sizer from a `name:parameter` specification, e.g.
+/// `percent_equity:0.1` or `kelly:0.5`, capped at `max_fraction` of
+/// equity.
+pub fn sizer_from_spec(spec: &str, max_fraction: f64) -> Result<Box<dyn PositionSizer>> {
+    let (name, param) = match spec.split_once(':') {
+        Some((name, param)) => {
+            let param = param.parse::<f64>()
+                .map_err(|e| EngineError::Config(format!("Invalid parameter {:?} for sizer {}: {}", param, name, e)))?;
+            (name, Some(param))
+        },
+        None => (spec, None),
+    };
+
+    let sizer: Box<dyn PositionSizer> = match name {
+        "fixed_shares" => Box::new(FixedShares::new(param.unwrap_or(100.0) as i64)),
+        "fixed_notional" => Box::new(FixedNotional::new(param.unwrap_or(10_000.0))),
+        "percent_equity" => Box::new(PercentOfEquity::new(param.unwrap_or(0.1))),
+        "vol_target" => Box::new(VolatilityTarget::new(param.unwrap_or(0.15), 20)),
+        "atr_risk" => Box::new(AtrRiskPerTrade::new(param.unwrap_or(0.01), 14, 2.0)),
+        "kelly" => Box::new(FractionalKelly::new(param.unwrap_or(0.5), 60)),
+        _ => return Err(EngineError::Config(format!("Unknown position sizer: {}", name))),
+    };
+    Ok(Box::new(Capped::new(sizer, max_fraction)))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn portfolio() -> Portfolio {
+        Portfolio::new(100_000)
+    }
+
+    /// Bars at `closes`, each with a high-low range of `range`.
+    fn bars(closes: &[f64], range: f64) -> Vec<DatedStockData> {
+        closes.iter().enumerate()
+            .map(|(i, close)| DatedStockData::new(format!("2024-01-{:02}", i + 1), *close, close + range / 2.0, close - range / 2.0, *close, 1_000))
+            .collect()
+    }
+
+    fn long() -> Signal {
+        Signal::new("TEST".to_string(), 1.0)
+    }
+
+    #[test]
+    fn fixed_sizers_scale_with_the_signal() {
+        let data = bars(&[50.0], 0.0);
+        let half_short = Signal::new("TEST".to_string(), -0.5);
+        assert_eq!(FixedShares::new(100).size(&half_short, &data, &portfolio()), -50);
+        assert_eq!(FixedNotional::new(10_000.0).size(&half_short, &data, &portfolio()), -100);
+        assert_eq!(PercentOfEquity::new(0.1).size(&long(), &data, &portfolio()), 200);
+    }
+
+    #[test]
+    fn volatility_target_scales_inversely_with_volatility() {
+        // Returns alternate +1% and -1%, a daily volatility of about 1.05%.
+        let closes: Vec<f64> = (0..21).map(|i| if i % 2 == 0 { 100.0 } else { 101.0 }).collect();
+        let data = bars(&closes, 0.0);
+        let volatility = BarContext::from_history(&data, 20).unwrap().volatility * TRADING_DAYS.sqrt();
+        let expected = (100_000.0 * 0.15 / volatility / 100.0).trunc() as i64;
+        assert_eq!(VolatilityTarget::new(0.15, 20).size(&long(), &data, &portfolio()), expected);
+        let doubled = VolatilityTarget::new(0.30, 20).size(&long(), &data, &portfolio());
+        assert!((doubled - 2 * expected).abs() <= 1, "{} vs {}", doubled, expected);
+    }
+
+    #[test]
+    fn atr_risk_stops_out_at_the_risk_budget() {
+        // A constant range of 2 gives an ATR of 2 and a stop 4 away.
+        let data = bars(&[100.0; 15], 2.0);
+        assert_eq!(AtrRiskPerTrade::new(0.01, 14, 2.0).size(&long(), &data, &portfolio()), 250);
+        let short = Signal::new("TEST".to_string(), -1.0);
+        assert_eq!(AtrRiskPerTrade::new(0.01, 14, 2.0).size(&short, &data, &portfolio()), -250);
+    }
+
+    #[test]
+    fn kelly_follows_only_an_agreeing_edge() {
+        let closes: Vec<f64> = (0..30).map(|i| 100.0 * 1.01f64.powi(i) * if i % 2 == 0 { 1.0 } else { 1.005 }).collect();
+        let data = bars(&closes, 0.0);
+        let sizer = FractionalKelly::new(0.5, 60);
+        let shares = sizer.size(&long(), &data, &portfolio());
+        assert!(shares > 0);
+        assert!(shares as f64 * closes[29] <= 100_000.0);
+        assert_eq!(sizer.size(&Signal::new("TEST".to_string(), -1.0), &data, &portfolio()), 0);
+    }
+
+    #[test]
+    fn empty_history_sizes_nothing() {
+        let sizers: Vec<Box<dyn PositionSizer>> = vec![
+            Box::new(FixedNotional::new(10_000.0)),
+            Box::new(PercentOfEquity::new(0.1)),
+            Box::new(VolatilityTarget::new(0.15, 20)),
+            Box::new(AtrRiskPerTrade::new(0.01, 14, 2.0)),
+            Box::new(FractionalKelly::new(0.5, 60)),
+            sizer_from_spec("vol_target", 1.0).unwrap(),
+        ];
+        for sizer in sizers {
+            assert_eq!(sizer.size(&long(), &[], &portfolio()), 0, "{:?}", sizer);
+        }
+    }
+
+    #[test]
+    fn quiet_markets_are_capped_at_equity() {
+        // Almost no volatility or range asks for many times equity.
+        let closes: Vec<f64> = (0..21).map(|i| if i % 2 == 0 { 100.0 } else { 100.001 }).collect();
+        let data = bars(&closes, 0.001);
+        assert!(VolatilityTarget::new(0.15, 20).size(&long(), &data, &portfolio()) > 100_000);
+        assert!(AtrRiskPerTrade::new(0.01, 14, 2.0).size(&long(), &data, &portfolio()) > 100_000);
+
+        let price = closes[20];
+        for spec in ["vol_target", "atr_risk"] {
+            assert_eq!(sizer_from_spec(spec, 1.0).unwrap().size(&long(), &data, &portfolio()), (100_000.0 / price) as i64);
+            let short = Signal::new("TEST".to_string(), -1.0);
+            assert_eq!(sizer_from_spec(spec, 0.5).unwrap().size(&short, &data, &portfolio()), -(50_000.0 / price) as i64);
+        }
+        assert_eq!(sizer_from_spec("percent_equity:0.1", 1.0).unwrap().size(&long(), &data, &portfolio()), (10_000.0 / price) as i64);
+    }
+}
@@ -0,0 +1,223 @@
+//!
+//! Slippage and market impact models.
+//!
+//! A `SlippageModel` moves the reference price against the order: buys
+//! execute higher and sells lower. Models that depend on liquidity use the
+//! current bar's volume and the recent volatility carried in `BarContext`.
+//!
+
+use crate::data_loading::DatedStockData;
+use crate::error::EngineError;
+use derive_new::new;
+use std::fmt::Debug;
+use std::str::FromStr;
+
+
+/// Market conditions at the time of execution.
+#[derive(Debug, Clone, new)]
+pub struct BarContext {
+    pub close: f64,
+    pub high: f64,
+    pub low: f64,
+    pub volume: u64,
+    /// Standard deviation of close-to-close returns over the lookback.
+    pub volatility: f64,
+}
+impl BarContext {
+    /// Builds the context from the last bar of `history`, estimating
+    /// volatility over at most `lookback` returns.
+    pub fn from_history(history: &[DatedStockData], lookback: usize) -> Option<Self> {
+        let last = history.last()?;
+        let start = history.len().saturating_sub(lookback + 1);
+        let returns: Vec<f64> = history[start..].windows(2)
+            .filter(|w| w[0].close > 0.0)
+            .map(|w| w[1].close / w[0].close - 1.0)
+            .collect();
+
+        let volatility = if returns.len() > 1 {
+            let mean = returns.iter().sum::<f64>() / returns.len() as f64;
+            let var = returns.iter().map(|r| (r - mean).powi(2)).sum::<f64>()
+                / (returns.len() - 1) as f64;
+            var.sqrt()
+        } else {
+            0.0
+        };
+
+        Some(BarContext::new(last.close, last.high, last.low, last.volume, volatility))
+    }
+
+    /// Fraction of the bar's volume an order of `quantity` shares represents.
+    pub fn participation(&self, quantity: i64) -> f64 {
+        if self.volume == 0 {
+            return 0.0;
+        }
+        quantity.unsigned_abs() as f64 / self.volume as f64
+    }
+}
+
+
+pub trait SlippageModel: Debug + Send + Sync {
+    /// Price adjustment as a fraction of the reference price, always
+    /// non-negative. The sign is applied by `execution_price`.
+    fn slippage_fraction(&self, quantity: i64, bar: &BarContext) -> f64;
+
+    fn execution_price(&self, quantity: i64, reference_price: f64, bar: &BarContext) -> f64 {
+        let side = quantity.signum() as f64;
+        reference_price * (1.0 + side * self.slippage_fraction(quantity, bar))
+    }
+}
+
+
+/// No slippage; executes at the reference price.
+#[derive(Debug, Clone, new)]
+pub struct NoSlippage;
+impl SlippageModel for NoSlippage {
+    fn slippage_fraction(&self, _quantity: i64, _bar: &BarContext) -> f64 {
+        0.0
+    }
+}
+
+
+/// Constant cost in basis points of the reference price.
+#[derive(Debug, Clone, new)]
+pub struct FixedBasisPoints {
+    bps: f64,
+}
+impl SlippageModel for FixedBasisPoints {
+    fn slippage_fraction(&self, _quantity: i64, _bar: &BarContext) -> f64 {
+        self.bps / 10_000.0
+    }
+}
+
+
+/// Pays half the estimated bid-ask spread.
+///
+/// The spread is estimated as `range_fraction` of the bar's high-low range,
+/// floored at `min_spread_bps`.
+#[derive(Debug, Clone, new)]
+pub struct SpreadBased {
+    min_spread_bps: f64,
+    range_fraction: f64,
+}
+impl SlippageModel for SpreadBased {
+    fn slippage_fraction(&self, _quantity: i64, bar: &BarContext) -> f64 {
+        let range_spread = if bar.close > 0.0 {
+            self.range_fraction * (bar.high - bar.low) / bar.close
+        } else {
+            0.0
+        };
+        let spread = range_spread.max(self.min_spread_bps / 10_000.0);
+        spread / 2.0
+    }
+}
+
+
+/// Impact that grows linearly with the order's share of bar volume.
+#[derive(Debug, Clone, new)]
+pub struct VolumeParticipation {
+    impact_coefficient: f64,
+}
+impl SlippageModel for VolumeParticipation {
+    fn slippage_fraction(&self, quantity: i64, bar: &BarContext) -> f64 {
+        self.impact_coefficient * bar.participation(quantity)
+    }
+}
+
+
+/// Square-root market impact in the style of Almgren et al.:
+/// `eta * volatility * sqrt(quantity / volume)`.
+#[derive(Debug, Clone, new)]
+pub struct SquareRootImpact {
+    eta: f64,
+}
+impl SlippageModel for SquareRootImpact {
+    fn slippage_fraction(&self, quantity: i64, bar: &BarContext) -> f64 {
+        self.eta * bar.volatility * bar.participation(quantity).sqrt()
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlippagePreset {
+    None,
+    FixedBps,
+    Spread,
+    VolumeParticipation,
+    SquareRootImpact,
+}
+impl SlippagePreset {
+    pub fn model(&self) -> Box<dyn SlippageModel> {
+        match self {
+            SlippagePreset::None => Box::new(NoSlippage::new()),
+            SlippagePreset::FixedBps => Box::new(FixedBasisPoints::new(5.0)),
+            SlippagePreset::Spread => Box::new(SpreadBased::new(2.0, 0.1)),
+            SlippagePreset::VolumeParticipation => Box::new(VolumeParticipation::new(0.1)),
+            SlippagePreset::SquareRootImpact => Box::new(SquareRootImpact::new(1.0)),
+        }
+    }
+}
+
+impl FromStr for SlippagePreset {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(SlippagePreset::None),
+            "fixed_bps" => Ok(SlippagePreset::FixedBps),
+            "spread" => Ok(SlippagePreset::Spread),
+            "volume_participation" => Ok(SlippagePreset::VolumeParticipation),
+            "sqrt_impact" => Ok(SlippagePreset::SquareRootImpact),
+            _ => Err(EngineError::Config(format!("Unknown slippage model: {}", s))),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bar(high: f64, low: f64, volume: u64, volatility: f64) -> BarContext {
+        BarContext::new(100.0, high, low, volume, volatility)
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn participation_is_zero_without_volume() {
+        assert_eq!(bar(100.0, 100.0, 0, 0.02).participation(500), 0.0);
+        assert_eq!(bar(100.0, 100.0, 1_000, 0.02).participation(-250), 0.25);
+        assert_eq!(SquareRootImpact::new(1.0).slippage_fraction(500, &bar(100.0, 100.0, 0, 0.02)), 0.0);
+    }
+
+    #[test]
+    fn buys_pay_up_and_sells_give_way() {
+        let model = FixedBasisPoints::new(10.0);
+        let bar = bar(100.0, 100.0, 1_000, 0.02);
+        assert!(close(model.execution_price(100, 50.0, &bar), 50.05));
+        assert!(close(model.execution_price(-100, 50.0, &bar), 49.95));
+        assert_eq!(NoSlippage::new().execution_price(-100, 50.0, &bar), 50.0);
+    }
+
+    #[test]
+    fn spread_is_floored_at_the_minimum() {
+        let model = SpreadBased::new(2.0, 0.1);
+        // A 2% range gives a 20bps spread, half of it paid.
+        assert!(close(model.slippage_fraction(100, &bar(101.0, 99.0, 1_000, 0.02)), 0.001));
+        assert!(close(model.slippage_fraction(100, &bar(100.0, 100.0, 1_000, 0.02)), 0.0001));
+    }
+
+    #[test]
+    fn impact_grows_with_participation() {
+        let bar = bar(100.0, 100.0, 10_000, 0.02);
+        let linear = VolumeParticipation::new(0.1);
+        assert!(close(linear.slippage_fraction(2_000, &bar), 2.0 * linear.slippage_fraction(1_000, &bar)));
+
+        let sqrt = SquareRootImpact::new(1.0);
+        assert!(close(sqrt.slippage_fraction(100, &bar), 0.02 * 0.1));
+        assert!(close(sqrt.slippage_fraction(400, &bar), 2.0 * sqrt.slippage_fraction(100, &bar)));
+        assert_eq!(sqrt.slippage_fraction(-400, &bar), sqrt.slippage_fraction(400, &bar));
+    }
+}
@@ -0,0 +1,417 @@
+//!
+//! SQLite run store.
+//!
+//! Records each backtest, its parameters, trades, equity curve and
+//! metrics in a local SQLite database so that runs outlive the process
+//! that produced them and can be listed, filtered, reloaded and compared.
+//!
+//! | table   | key                  | columns                                       |
+//! |---------|----------------------|-----------------------------------------------|
+//! | runs    | run_id               | created_at, strategy, engine/schema version, symbols, data range |
+//! | params  | run_id, key          | value                                         |
+//! | trades  | run_id, seq          | timestamp, ticker, quantity, price            |
+//! | equity  | run_id, seq          | timestamp, equity, cash                       |
+//! | metrics | run_id               | one column per `Metrics` field                |
+//!
+
+use crate::backtest::BacktestResult;
+use crate::error::{EngineError, Result};
+use crate::export::RunManifest;
+use crate::metrics::{EquityPoint, Metrics};
+use crate::portfolio::Trade;
+use crate::report::ReportData;
+use chrono::{DateTime, Utc};
+use rusqlite::{params, params_from_iter, Connection, ErrorCode, OptionalExtension, Row, TransactionBehavior};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+
+
+/// Suffixes tried on a run id that is already taken.
+const MAX_ID_ATTEMPTS: u32 = 100;
+/// How long a write waits for another connection, e.g. a parallel run,
+/// to release the database.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS runs (
+    run_id TEXT PRIMARY KEY,
+    created_at TEXT NOT NULL,
+    strategy TEXT NOT NULL,
+    engine_version TEXT NOT NULL,
+    schema_version INTEGER NOT NULL,
+    symbols TEXT NOT NULL,
+    data_start TEXT,
+    data_end TEXT
+);
+CREATE TABLE IF NOT EXISTS params (
+    run_id TEXT NOT NULL REFERENCES runs(run_id) ON DELETE CASCADE,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (run_id, key)
+);
+CREATE TABLE IF NOT EXISTS trades (
+    run_id TEXT NOT NULL REFERENCES runs(run_id) ON DELETE CASCADE,
+    seq INTEGER NOT NULL,
+    timestamp TEXT NOT NULL,
+    ticker TEXT NOT NULL,
+    quantity INTEGER NOT NULL,
+    price REAL NOT NULL,
+    PRIMARY KEY (run_id, seq)
+);
+CREATE TABLE IF NOT EXISTS equity (
+    run_id TEXT NOT NULL REFERENCES runs(run_id) ON DELETE CASCADE,
+    seq INTEGER NOT NULL,
+    timestamp TEXT NOT NULL,
+    equity REAL NOT NULL,
+    cash REAL NOT NULL,
+    PRIMARY KEY (run_id, seq)
+);
+CREATE TABLE IF NOT EXISTS metrics (
+    run_id TEXT PRIMARY KEY REFERENCES runs(run_id) ON DELETE CASCADE,
+    initial_equity REAL NOT NULL,
+    final_equity REAL NOT NULL,
+    total_return REAL NOT NULL,
+    annualized_return REAL NOT NULL,
+    annualized_volatility REAL NOT NULL,
+    sharpe_ratio REAL NOT NULL,
+    max_drawdown REAL NOT NULL,
+    n_trades INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS runs_strategy ON runs(strategy);
+";
+
+/// Criteria for `RunStore::list_runs`. Unset fields match every run.
+#[derive(Debug, Clone, Default)]
+pub struct RunFilter {
+    pub strategy: Option<String>,
+    pub symbol: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// Parameters that must be recorded with exactly these values.
+    pub parameters: BTreeMap<String, String>,
+    /// Most recent runs first, at most this many.
+    pub limit: Option<usize>,
+}
+
+/// A run's identity, parameters and headline metrics, without its
+/// trades or equity curve.
+#[derive(Debug, Clone)]
+pub struct RunSummary {
+    pub run_id: String,
+    pub created_at: DateTime<Utc>,
+    pub strategy: String,
+    pub symbols: Vec<String>,
+    pub parameters: BTreeMap<String, String>,
+    pub metrics: Metrics,
+}
+
+
+fn parse_time(text: &str) -> rusqlite::Result<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(text)
+        .map(|t| t.with_timezone(&Utc))
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e)))
+}
+
+fn split_symbols(symbols: &str) -> Vec<String> {
+    symbols.split(',').filter(|s| !s.is_empty()).map(str::to_string).collect()
+}
+
+fn metrics_from_row(row: &Row, offset: usize) -> rusqlite::Result<Metrics> {
+    Ok(Metrics {
+        initial_equity: row.get(offset)?,
+        final_equity: row.get(offset + 1)?,
+        total_return: row.get(offset + 2)?,
+        annualized_return: row.get(offset + 3)?,
+        annualized_volatility: row.get(offset + 4)?,
+        sharpe_ratio: row.get(offset + 5)?,
+        max_drawdown: row.get(offset + 6)?,
+        n_trades: row.get::<_, i64>(offset + 7)? as usize,
+    })
+}
+
+
+#[derive(Debug)]
+pub struct RunStore {
+    conn: Connection,
+}
+impl RunStore {
+    /// Opens, creating if needed, the database at `path`.
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    pub fn in_memory() -> Result<Self> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(conn: Connection) -> Result<Self> {
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(RunStore { conn })
+    }
+
+    /// Records a finished backtest under the manifest's run id, or under
+    /// that id with a numeric suffix if another run already took it.
+    /// Returns the id used.
+    pub fn record(&mut self, result: &BacktestResult, manifest: &RunManifest) -> Result<String> {
+        let metrics = result.metrics();
+        // Take the write lock up front so concurrent writers wait out the
+        // busy timeout rather than failing mid-transaction.
+        let tx = self.conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let mut run_id = manifest.run_id.clone();
+        for attempt in 2.. {
+            let inserted = tx.execute(
+                "INSERT INTO runs (run_id, created_at, strategy, engine_version, schema_version, symbols, data_start, data_end)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    run_id,
+                    manifest.created_at.to_rfc3339(),
+                    manifest.strategy,
+                    manifest.engine_version,
+                    manifest.schema_version,
+                    manifest.symbols.join(","),
+                    manifest.data_start.map(|t| t.to_rfc3339()),
+                    manifest.data_end.map(|t| t.to_rfc3339()),
+                ],
+            );
+            match inserted {
+                Ok(_) => break,
+                Err(rusqlite::Error::SqliteFailure(e, _))
+                    if e.code == ErrorCode::ConstraintViolation && attempt <= MAX_ID_ATTEMPTS =>
+                {
+                    run_id = format!("{}-{}", manifest.run_id, attempt);
+                },
+                Err(e) => return Err(e.into()),
+            }
+        }
+        {
+            let mut insert = tx.prepare("INSERT INTO params (run_id, key, value) VALUES (?1, ?2, ?3)")?;
+            for (key, value) in &manifest.parameters {
+                insert.execute(params![run_id, key, value])?;
+            }
+            let mut insert = tx.prepare(
+                "INSERT INTO trades (run_id, seq, timestamp, ticker, quantity, price) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?;
+            for (seq, trade) in result.portfolio.trades.iter().enumerate() {
+                insert.execute(params![
+                    run_id, seq as i64, trade.timestamp.to_rfc3339(), trade.ticker, trade.quantity, trade.price,
+                ])?;
+            }
+            let mut insert = tx.prepare(
+                "INSERT INTO equity (run_id, seq, timestamp, equity, cash) VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for (seq, point) in result.equity_curve.iter().enumerate() {
+                insert.execute(params![run_id, seq as i64, point.timestamp.to_rfc3339(), point.equity, point.cash])?;
+            }
+        }
+        tx.execute(
+            "INSERT INTO metrics VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                run_id,
+                metrics.initial_equity,
+                metrics.final_equity,
+                metrics.total_return,
+                metrics.annualized_return,
+                metrics.annualized_volatility,
+                metrics.sharpe_ratio,
+                metrics.max_drawdown,
+                metrics.n_trades as i64,
+            ],
+        )?;
+        tx.commit()?;
+        Ok(run_id)
+    }
+
+    fn parameters(&self, run_id: &str) -> Result<BTreeMap<String, String>> {
+        let mut query = self.conn.prepare("SELECT key, value FROM params WHERE run_id = ?1")?;
+        let rows = query.query_map([run_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Runs matching `filter`, most recent first.
+    pub fn list_runs(&self, filter: &RunFilter) -> Result<Vec<RunSummary>> {
+        let mut sql = String::new();
+        let mut args: Vec<String> = vec![];
+        if let Some(strategy) = &filter.strategy {
+            sql.push_str(" AND r.strategy = ?");
+            args.push(strategy.clone());
+        }
+        if let Some(symbol) = &filter.symbol {
+            sql.push_str(" AND ',' || r.symbols || ',' LIKE ?");
+            args.push(format!("%,{},%", symbol));
+        }
+        // RFC 3339 strings in UTC sort chronologically.
+        if let Some(since) = filter.since {
+            sql.push_str(" AND r.created_at >= ?");
+            args.push(since.to_rfc3339());
+        }
+        if let Some(until) = filter.until {
+            sql.push_str(" AND r.created_at <= ?");
+            args.push(until.to_rfc3339());
+        }
+        for (key, value) in &filter.parameters {
+            sql.push_str(" AND EXISTS (SELECT 1 FROM params p WHERE p.run_id = r.run_id AND p.key = ? AND p.value = ?)");
+            args.push(key.clone());
+            args.push(value.clone());
+        }
+        sql.push_str(" ORDER BY r.created_at DESC");
+        if let Some(limit) = filter.limit {
+            sql.push_str(&format!(" LIMIT {}", limit));
+        }
+        self.summaries(&sql, &args)
+    }
+
+    /// Summaries of runs matching `conditions`, which continue a `WHERE`
+    /// clause over `runs r` and `metrics m`.
+    fn summaries(&self, conditions: &str, args: &[String]) -> Result<Vec<RunSummary>> {
+        let sql = format!(
+            "SELECT r.run_id, r.created_at, r.strategy, r.symbols, m.initial_equity, m.final_equity, m.total_return,
+                    m.annualized_return, m.annualized_volatility, m.sharpe_ratio, m.max_drawdown, m.n_trades
+             FROM runs r JOIN metrics m ON m.run_id = r.run_id WHERE 1 = 1{}",
+            conditions,
+        );
+        let mut query = self.conn.prepare(&sql)?;
+        let rows = query.query_map(params_from_iter(args.iter()), |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                parse_time(&row.get::<_, String>(1)?)?,
+                row.get::<_, String>(2)?,
+                split_symbols(&row.get::<_, String>(3)?),
+                metrics_from_row(row, 4)?,
+            ))
+        })?;
+
+        let mut runs: Vec<RunSummary> = vec![];
+        for row in rows {
+            let (run_id, created_at, strategy, symbols, metrics) = row?;
+            let parameters = self.parameters(&run_id)?;
+            runs.push(RunSummary { run_id, created_at, strategy, symbols, parameters, metrics });
+        }
+        Ok(runs)
+    }
+
+    /// Everything recorded for a run, ready to render or analyse.
+    pub fn load_run(&self, run_id: &str) -> Result<ReportData> {
+        let manifest = self.conn.query_row(
+            "SELECT run_id, created_at, strategy, engine_version, schema_version, symbols, data_start, data_end
+             FROM runs WHERE run_id = ?1",
+            [run_id],
+            |row| {
+                let data_start: Option<String> = row.get(6)?;
+                let data_end: Option<String> = row.get(7)?;
+                Ok(RunManifest {
+                    run_id: row.get(0)?,
+                    schema_version: row.get(4)?,
+                    engine_version: row.get(3)?,
+                    created_at: parse_time(&row.get::<_, String>(1)?)?,
+                    strategy: row.get(2)?,
+                    parameters: BTreeMap::new(),
+                    symbols: split_symbols(&row.get::<_, String>(5)?),
+                    data_start: data_start.as_deref().map(parse_time).transpose()?,
+                    data_end: data_end.as_deref().map(parse_time).transpose()?,
+                    api_usage: None,
+                    files: vec![],
+                })
+            },
+        ).optional()?;
+        let mut manifest = manifest.ok_or_else(|| EngineError::Store(format!("Unknown run: {}", run_id)))?;
+        manifest.parameters = self.parameters(run_id)?;
+
+        let metrics = self.conn.query_row(
+            "SELECT initial_equity, final_equity, total_return, annualized_return, annualized_volatility,
+                    sharpe_ratio, max_drawdown, n_trades FROM metrics WHERE run_id = ?1",
+            [run_id],
+            |row| metrics_from_row(row, 0),
+        )?;
+
+        let mut query = self.conn.prepare("SELECT timestamp, equity, cash FROM equity WHERE run_id = ?1 ORDER BY seq")?;
+        let equity_curve = query.query_map([run_id], |row| {
+            Ok(EquityPoint::new(parse_time(&row.get::<_, String>(0)?)?, row.get(1)?, row.get(2)?))
+        })?.collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let mut query = self.conn.prepare(
+            "SELECT ticker, timestamp, price, quantity FROM trades WHERE run_id = ?1 ORDER BY seq",
+        )?;
+        let trades = query.query_map([run_id], |row| {
+            Ok(Trade::new(row.get(0)?, parse_time(&row.get::<_, String>(1)?)?, row.get(2)?, row.get(3)?))
+        })?.collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(ReportData::new(manifest, metrics, equity_curve, trades))
+    }
+
+    /// Summaries of the given runs, in the order requested.
+    pub fn compare(&self, run_ids: &[&str]) -> Result<Vec<RunSummary>> {
+        run_ids.iter()
+            .map(|id| {
+                self.summaries(" AND r.run_id = ?", &[id.to_string()])?
+                    .pop()
+                    .ok_or_else(|| EngineError::Store(format!("Unknown run: {}", id)))
+            })
+            .collect()
+    }
+
+    pub fn delete_run(&self, run_id: &str) -> Result<bool> {
+        Ok(self.conn.execute("DELETE FROM runs WHERE run_id = ?1", [run_id])? > 0)
+    }
+}
+
+/// Parameters whose values differ between `runs`, with each run's value
+/// in order (`None` where a run did not record it).
+pub fn parameter_differences(runs: &[RunSummary]) -> BTreeMap<String, Vec<Option<String>>> {
+    let mut keys: Vec<&String> = runs.iter().flat_map(|r| r.parameters.keys()).collect();
+    keys.sort();
+    keys.dedup();
+
+    keys.into_iter()
+        .map(|key| (key.clone(), runs.iter().map(|r| r.parameters.get(key).cloned()).collect::<Vec<_>>()))
+        .filter(|(_, values)| values.windows(2).any(|w| w[0] != w[1]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::portfolio::Portfolio;
+    use std::thread;
+
+    fn manifest(run_id: &str) -> RunManifest {
+        let mut manifest = RunManifest::for_run("ma_crossover", BTreeMap::new(), vec!["AAPL".to_string()], &[]);
+        manifest.run_id = run_id.to_string();
+        manifest
+    }
+
+    #[test]
+    fn taken_run_ids_get_a_suffix() {
+        let portfolio = Portfolio::new(100_000);
+        let result = BacktestResult::new(0, &portfolio, &[], &[], &[]);
+        let mut store = RunStore::in_memory().unwrap();
+        assert_eq!(store.record(&result, &manifest("run")).unwrap(), "run");
+        assert_eq!(store.record(&result, &manifest("run")).unwrap(), "run-2");
+        assert_eq!(store.record(&result, &manifest("run")).unwrap(), "run-3");
+    }
+
+    #[test]
+    fn parallel_runs_share_a_store() {
+        let path = std::env::temp_dir().join(format!("trading-engine-store-{}.db", rand::random::<u64>()));
+        let ids: Vec<String> = thread::scope(|scope| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| scope.spawn(|| {
+                    let portfolio = Portfolio::new(100_000);
+                    let result = BacktestResult::new(0, &portfolio, &[], &[], &[]);
+                    RunStore::open(&path).and_then(|mut store| store.record(&result, &manifest("run")))
+                }))
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap().unwrap()).collect()
+        });
+        let mut unique = ids.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), 8);
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -1,15 +1,54 @@
-/// Trading strategies.
+//! Trading strategies.
 
 use derive_new::new;
-use crate::order::Order;
+use crate::order::{Fill, Order};
 use crate::portfolio::Portfolio;
 use crate::data_loading::{DatedStockData, Metadata};
+use crate::sizing::{PositionSizer, Signal};
+use crate::target::{order_for_target, Target, TargetStrategy};
+use serde::Serialize;
 use std::collections::HashMap;
-use std::sync::Once;
+use std::sync::OnceLock;
+
+
+pub const DEFAULT_LONG_QUANTITY: i64 = 100;
+pub const DEFAULT_SHORT_QUANTITY: i64 = -100;
 
 
 pub trait Strategy {
+    /// Called once per bar. During a backtest `clock::now()` returns the
+    /// time of the latest bar in `data`; in paper trading, real time.
     fn on_data(&self, data: Vec<DatedStockData>, metadata: &Metadata, portfolio: &Portfolio) -> Option<Order>;
+
+    /// Called for every fill, including each partial fill of a working order.
+    fn on_fill(&self, _fill: &Fill, _order: &Order) {}
+}
+
+
+/// A strategy that only decides direction and conviction, leaving the
+/// size of the resulting position to a `PositionSizer`.
+pub trait SignalStrategy {
+    fn signal(&self, data: &[DatedStockData], metadata: &Metadata, portfolio: &Portfolio) -> Option<Signal>;
+}
+
+
+#[derive(Debug, new)]
+pub struct SizedStrategy<S: SignalStrategy> {
+    signals: S,
+    sizer: Box<dyn PositionSizer>,
+}
+
+impl<S: SignalStrategy> Strategy for SizedStrategy<S> {
+    fn on_data(
+        &self,
+        data: Vec<DatedStockData>,
+        metadata: &Metadata,
+        portfolio: &Portfolio,
+    ) -> Option<Order> {
+        let signal = self.signals.signal(&data, metadata, portfolio)?;
+        let shares = self.sizer.size(&signal, &data, portfolio);
+        order_for_target(&Target::shares(&signal.ticker, shares), &data, portfolio)
+    }
 }
 
 
@@ -20,13 +59,13 @@ pub struct MACrossoverStrategy {
     short_quantity: i64,
 }
 
-impl Strategy for MACrossoverStrategy {
-    fn on_data(
+impl SignalStrategy for MACrossoverStrategy {
+    fn signal(
         &self,
-        data: Vec<DatedStockData>,
+        data: &[DatedStockData],
         metadata: &Metadata,
         portfolio: &Portfolio,
-    ) -> Option<Order> {
+    ) -> Option<Signal> {
         // MA crossover strategy strategy
         // If price is greater than avg price over a window, buy or maintain
         // If price is lower than avg price over a window, sell or maintain.
@@ -34,9 +73,14 @@ impl Strategy for MACrossoverStrategy {
 
         let ticker = metadata.symbol.clone();
 
+        // Let a working order finish before trading again.
+        if portfolio.blotter.is_working(&ticker) {
+            return None;
+        }
+
         let n = data.len();
         let data_subset = if n >= self.window as usize {
-            data[(n - self.window as usize)..].to_vec()
+            &data[(n - self.window as usize)..]
         } else {
             return None;
         };
@@ -49,10 +93,10 @@ impl Strategy for MACrossoverStrategy {
         let last_price = prices.last()?;
 
         if (*last_price > subset_mean) & portfolio.is_not_long() {
-            Some(Order::new(ticker, self.long_quantity))
+            Some(Signal::new(ticker, 1.0))
         }
         else if (*last_price < subset_mean) & portfolio.is_not_short() {
-            Some(Order::new(ticker, self.short_quantity))
+            Some(Signal::new(ticker, -1.0))
         }
         else {
             None
@@ -60,11 +104,75 @@ impl Strategy for MACrossoverStrategy {
     }
 }
 
+impl TargetStrategy for MACrossoverStrategy {
+    fn target(
+        &self,
+        data: &[DatedStockData],
+        metadata: &Metadata,
+        _portfolio: &Portfolio,
+    ) -> Option<Target> {
+        // Hold the long quantity above the moving average and the short
+        // quantity below it, whatever the current position.
+        let n = data.len();
+        if n < self.window as usize {
+            return None;
+        }
+        let prices = &data[(n - self.window as usize)..];
+        let mean = prices.iter().map(|x| x.close).sum::<f64>() / prices.len() as f64;
+        let last_price = prices.last()?.close;
 
-type StrategyConstructor = Box<dyn Fn(u32, i64, i64) -> Box<dyn Strategy>>;
+        if last_price > mean {
+            Some(Target::shares(&metadata.symbol, self.long_quantity.abs()))
+        } else if last_price < mean {
+            Some(Target::shares(&metadata.symbol, -self.short_quantity.abs()))
+        } else {
+            None
+        }
+    }
+}
+
+impl Strategy for MACrossoverStrategy {
+    fn on_data(
+        &self,
+        data: Vec<DatedStockData>,
+        metadata: &Metadata,
+        portfolio: &Portfolio,
+    ) -> Option<Order> {
+        // No working-order guard is needed here: the target delta nets
+        // off whatever is still working, so a pending order that already
+        // reaches the target produces no new order.
+        let target = self.target(&data, metadata, portfolio)?;
+        order_for_target(&target, &data, portfolio)
+    }
+}
+
+
+type StrategyConstructor = Box<dyn Fn(u32, i64, i64) -> Box<dyn Strategy> + Send + Sync>;
+type SizedStrategyConstructor = Box<dyn Fn(u32, Box<dyn PositionSizer>) -> Box<dyn Strategy> + Send + Sync>;
+
+/// A constructor parameter as exposed to configuration files.
+#[derive(Debug, Clone, Serialize)]
+pub struct ParameterInfo {
+    pub name: &'static str,
+    pub kind: &'static str,
+    pub required: bool,
+    pub default: Option<String>,
+    pub description: &'static str,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StrategyInfo {
+    pub name: String,
+    pub description: String,
+    /// Whether a position sizer can be supplied via `sizing`.
+    pub sizable: bool,
+    pub parameters: Vec<ParameterInfo>,
+}
 
 pub struct StrategyFactory {
-    strategies: HashMap<String, StrategyConstructor>
+    strategies: HashMap<String, StrategyConstructor>,
+    sized_strategies: HashMap<String, SizedStrategyConstructor>,
+    descriptions: HashMap<String, (String, Vec<ParameterInfo>)>,
 }
 
 impl StrategyFactory {
@@ -72,29 +180,119 @@ impl StrategyFactory {
         self.strategies.insert(name.to_string(), constructor);
     }
 
+    pub fn register_sized(&mut self, name: &str, constructor: SizedStrategyConstructor) {
+        self.sized_strategies.insert(name.to_string(), constructor);
+    }
+
+    /// Documents a strategy and the parameters its constructors read.
+    /// `sizing` is added for strategies registered with `register_sized`.
+    pub fn describe(&mut self, name: &str, description: &str, parameters: Vec<ParameterInfo>) {
+        self.descriptions.insert(name.to_string(), (description.to_string(), parameters));
+    }
+
+    pub fn info(&self, name: &str) -> Option<StrategyInfo> {
+        let fixed = self.strategies.contains_key(name);
+        let sizable = self.sized_strategies.contains_key(name);
+        if !fixed && !sizable {
+            return None;
+        }
+
+        let (description, mut parameters) = self.descriptions.get(name).cloned().unwrap_or_default();
+        if sizable {
+            parameters.push(ParameterInfo {
+                name: "sizing",
+                kind: "sizer spec",
+                required: false,
+                default: None,
+                description: "Position sizer such as percent_equity:0.1, replacing the fixed quantities",
+            });
+        }
+
+        Some(StrategyInfo {
+            name: name.to_string(),
+            description,
+            sizable,
+            parameters,
+        })
+    }
+
+    /// Registered strategy names, sorted.
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.strategies.keys()
+            .chain(self.sized_strategies.keys())
+            .map(String::as_str)
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
     pub fn create(&self, name: &str, window: u32, long_qty: i64, short_qty: i64) -> Option<Box<dyn Strategy>> {
         self.strategies.get(name)
             .map(|constructor| constructor(window, long_qty, short_qty))
     }
+
+    /// Creates a signal strategy whose trade sizes come from `sizer`.
+    pub fn create_sized(&self, name: &str, window: u32, sizer: Box<dyn PositionSizer>) -> Option<Box<dyn Strategy>> {
+        self.sized_strategies.get(name)
+            .map(|constructor| constructor(window, sizer))
+    }
 }
 
-static mut STRATEGY_FACTORY: Option<StrategyFactory> = None;
-static INIT: Once = Once::new();
+static STRATEGY_FACTORY: OnceLock<StrategyFactory> = OnceLock::new();
+
+pub fn get_strategy_factory() -> &'static StrategyFactory {
+    STRATEGY_FACTORY.get_or_init(|| {
+        let mut factory = StrategyFactory {
+            strategies: HashMap::new(),
+            sized_strategies: HashMap::new(),
+            descriptions: HashMap::new(),
+        };
 
-pub fn get_strategy_factory() -> &'static mut StrategyFactory {
-    unsafe {
-        INIT.call_once(|| {
-            let mut factory = StrategyFactory {
-                strategies: HashMap::new()
-            };
+        factory.register("ma_crossover", Box::new(|w, l, s| {
+            Box::new(MACrossoverStrategy::new(w, l, s))
+        }));
+        factory.register_sized("ma_crossover", Box::new(|w, sizer| {
+            Box::new(SizedStrategy::new(MACrossoverStrategy::new(w, 0, 0), sizer))
+        }));
+        factory.describe("ma_crossover", "Long above the moving average of closes, short below it", vec![
+            ParameterInfo {
+                name: "window",
+                kind: "u32",
+                required: true,
+                default: None,
+                description: "Lookback in bars; also the warm-up period",
+            },
+            ParameterInfo {
+                name: "long_quantity",
+                kind: "i64",
+                required: false,
+                default: Some(DEFAULT_LONG_QUANTITY.to_string()),
+                description: "Target shares when long",
+            },
+            ParameterInfo {
+                name: "short_quantity",
+                kind: "i64",
+                required: false,
+                default: Some(DEFAULT_SHORT_QUANTITY.to_string()),
+                description: "Target shares when short",
+            },
+        ]);
 
-            factory.register("ma_crossover", Box::new(|w, l, s| {
-                Box::new(MACrossoverStrategy::new(w, l, s))
-            }));
+        factory
+    })
+}
 
-            STRATEGY_FACTORY = Some(factory);
-        });
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        STRATEGY_FACTORY.as_mut().unwrap()
+    #[test]
+    fn info_lists_the_registered_parameters() {
+        let info = get_strategy_factory().info("ma_crossover").unwrap();
+        let names: Vec<&str> = info.parameters.iter().map(|p| p.name).collect();
+        assert_eq!(names, ["window", "long_quantity", "short_quantity", "sizing"]);
+        assert!(info.sizable);
+        assert!(get_strategy_factory().info("unknown").is_none());
     }
 }
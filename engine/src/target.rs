@@ -0,0 +1,105 @@
+//!
+//! Target positions.
+//!
+//! Strategies can describe where they want to be rather than how to get
+//! there. A `Target` names a desired share count or portfolio weight for a
+//! symbol, and the engine works out the order needed to reach it from the
+//! current position and any orders still working.
+//!
+
+use crate::data_loading::{DatedStockData, Metadata};
+use crate::order::Order;
+use crate::portfolio::Portfolio;
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    /// Hold exactly this many shares; negative for a short position.
+    Shares { ticker: String, shares: i64 },
+    /// Hold this fraction of equity; negative for a short position.
+    Weight { ticker: String, weight: f64 },
+}
+impl Target {
+    pub fn shares(ticker: &str, shares: i64) -> Self {
+        Target::Shares { ticker: ticker.to_string(), shares }
+    }
+
+    pub fn weight(ticker: &str, weight: f64) -> Self {
+        Target::Weight { ticker: ticker.to_string(), weight }
+    }
+
+    pub fn ticker(&self) -> &str {
+        match self {
+            Target::Shares { ticker, .. } | Target::Weight { ticker, .. } => ticker,
+        }
+    }
+
+    /// Desired position in shares at `price`.
+    pub fn target_shares(&self, price: f64, portfolio: &Portfolio) -> i64 {
+        match self {
+            Target::Shares { shares, .. } => *shares,
+            Target::Weight { weight, .. } => {
+                if price <= 0.0 {
+                    return 0;
+                }
+                (weight * portfolio.equity() / price).trunc() as i64
+            },
+        }
+    }
+
+    /// Shares to trade to move from the current position, including
+    /// quantities still working, to the target.
+    pub fn delta(&self, price: f64, portfolio: &Portfolio) -> i64 {
+        let ticker = self.ticker();
+        let expected = portfolio.position_in(ticker) + portfolio.pending_quantity(ticker);
+        self.target_shares(price, portfolio) - expected
+    }
+
+    /// The order that closes the gap to the target, if any.
+    pub fn order(&self, price: f64, portfolio: &Portfolio) -> Option<Order> {
+        let delta = self.delta(price, portfolio);
+        if delta == 0 {
+            return None;
+        }
+        Some(Order::new(self.ticker().to_string(), delta))
+    }
+}
+
+
+/// A strategy that expresses a desired position instead of order sizes.
+pub trait TargetStrategy {
+    fn target(&self, data: &[DatedStockData], metadata: &Metadata, portfolio: &Portfolio) -> Option<Target>;
+}
+
+/// Converts a target into an order priced off the last bar of `data`.
+pub fn order_for_target(target: &Target, data: &[DatedStockData], portfolio: &Portfolio) -> Option<Order> {
+    let price = data.last()?.close;
+    target.order(price, portfolio)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::order::OrderStatus;
+
+    fn with_working_order(quantity: i64, status: OrderStatus) -> Portfolio {
+        let mut portfolio = Portfolio::new(100_000);
+        let id = portfolio.blotter.submit(Order::new("TEST".to_string(), quantity));
+        portfolio.blotter.transition(id, status).unwrap();
+        portfolio
+    }
+
+    #[test]
+    fn delta_nets_against_working_orders() {
+        let portfolio = with_working_order(10, OrderStatus::Accepted);
+        assert_eq!(Target::shares("TEST", 10).order(100.0, &portfolio).map(|o| o.quantity), None);
+        assert_eq!(Target::shares("TEST", 25).delta(100.0, &portfolio), 15);
+        assert_eq!(Target::shares("TEST", -5).delta(100.0, &portfolio), -15);
+    }
+
+    #[test]
+    fn delta_nets_against_held_orders() {
+        let portfolio = with_working_order(10, OrderStatus::Held);
+        assert!(Target::shares("TEST", 10).order(100.0, &portfolio).is_none());
+    }
+}
@@ -0,0 +1,112 @@
+use trading_engine::alpaca::{AlpacaBroker, MockAlpaca};
+use trading_engine::broker::Broker;
+use trading_engine::error::EngineError;
+use trading_engine::order::{Fill, Order, OrderStatus};
+
+
+fn broker(mock: &MockAlpaca) -> AlpacaBroker {
+    AlpacaBroker::new(&mock.url(), "test-key", "test-secret")
+}
+
+fn order(ticker: &str, quantity: i64) -> Order {
+    let mut order = Order::new(ticker.to_string(), quantity);
+    order.transition(OrderStatus::Accepted).unwrap();
+    order
+}
+
+fn average_price(fills: &[Fill]) -> f64 {
+    let notional: f64 = fills.iter().map(|f| f.price * f.quantity as f64).sum();
+    notional / fills.iter().map(|f| f.quantity).sum::<i64>() as f64
+}
+
+#[test]
+fn submitted_orders_fill_at_the_mock_price() {
+    let mock = MockAlpaca::start(0, 10_000.0).unwrap();
+    mock.set_price("AAPL", 150.0);
+    let mut broker = broker(&mock);
+    let order = order("AAPL", 10);
+    broker.submit(&order).unwrap();
+
+    let fills = broker.fills().unwrap();
+    assert_eq!(fills.len(), 1);
+    assert_eq!((fills[0].order_id, fills[0].ticker.as_str()), (order.id, "AAPL"));
+    assert_eq!((fills[0].quantity, fills[0].price), (10, 150.0));
+    assert!(broker.fills().unwrap().is_empty());
+}
+
+#[test]
+fn partial_fills_sum_to_the_average_price() {
+    let mock = MockAlpaca::start(0, 10_000.0).unwrap();
+    mock.set_price("AAPL", 100.0);
+    mock.set_max_fill(Some(4));
+    let mut broker = broker(&mock);
+    broker.submit(&order("AAPL", 10)).unwrap();
+
+    // Every request works the order by another 4 shares.
+    let mut fills = vec![];
+    for price in [110.0, 120.0] {
+        mock.set_price("AAPL", price);
+        fills.extend(broker.fills().unwrap());
+    }
+    assert_eq!(fills.iter().map(|f| f.quantity).collect::<Vec<_>>(), vec![8, 2]);
+    assert!((average_price(&fills) - 108.0).abs() < 1e-9);
+    assert!(broker.fills().unwrap().is_empty());
+}
+
+#[test]
+fn cancelling_a_filled_order_is_rejected() {
+    let mock = MockAlpaca::start(0, 10_000.0).unwrap();
+    mock.set_price("AAPL", 150.0);
+    let mut broker = broker(&mock);
+    let order = order("AAPL", 10);
+    broker.submit(&order).unwrap();
+
+    let error = broker.cancel(order.id).unwrap_err();
+    assert!(matches!(&error, EngineError::OrderRejected(m) if m.contains("filled")), "{:?}", error);
+}
+
+#[test]
+fn resumed_orders_report_only_new_fills() {
+    let mock = MockAlpaca::start(0, 10_000.0).unwrap();
+    mock.set_price("AAPL", 100.0);
+    mock.set_max_fill(Some(4));
+    let mut first = broker(&mock);
+    let mut order = order("AAPL", 10);
+    first.submit(&order).unwrap();
+    for fill in first.fills().unwrap() {
+        order.apply_fill(fill).unwrap();
+    }
+    assert_eq!(order.filled_quantity, 8);
+
+    // A restarted broker knows of the 8 shares booked before.
+    mock.set_price("AAPL", 120.0);
+    let mut resumed = broker(&mock).resume(&[order.clone()]);
+    let fills = resumed.fills().unwrap();
+    assert_eq!((fills.len(), fills[0].quantity, fills[0].price), (1, 2, 120.0));
+    for fill in fills {
+        order.apply_fill(fill).unwrap();
+    }
+    assert_eq!(order.status, OrderStatus::Filled);
+
+    let mut restarted = broker(&mock).resume(&[order]);
+    assert!(restarted.fills().unwrap().is_empty());
+}
+
+#[test]
+fn positions_and_account_match_the_mock() {
+    let mock = MockAlpaca::start(0, 10_000.0).unwrap();
+    mock.set_price("AAPL", 100.0);
+    mock.set_price("MSFT", 50.0);
+    let mut broker = broker(&mock);
+    broker.submit(&order("AAPL", 10)).unwrap();
+    broker.submit(&order("MSFT", 20)).unwrap();
+    broker.submit(&order("MSFT", -5)).unwrap();
+
+    let positions = broker.positions().unwrap();
+    assert_eq!(positions.into_iter().collect::<Vec<_>>(), vec![("AAPL".to_string(), 10), ("MSFT".to_string(), 15)]);
+
+    mock.set_price("AAPL", 110.0);
+    let account = broker.account().unwrap();
+    assert_eq!(account.cash, 10_000.0 - 1_000.0 - 1_000.0 + 250.0);
+    assert_eq!(account.equity, account.cash + 10.0 * 110.0 + 15.0 * 50.0);
+}
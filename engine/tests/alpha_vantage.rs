@@ -0,0 +1,91 @@
+use trading_engine::data_loading::{AlphaVantage, Interval};
+use trading_engine::error::EngineError;
+use trading_engine::fixtures::{FixtureServer, Fixtures};
+use trading_engine::ratelimit::RateLimits;
+
+
+/// A generous budget with short waits, so retries finish quickly.
+fn limits() -> RateLimits {
+    RateLimits {
+        per_minute: 600,
+        per_day: None,
+        max_retries: 2,
+        backoff_secs: 0.01,
+        max_wait_secs: 1.0,
+        timeout_secs: 5.0,
+    }
+}
+
+/// A client against a fresh fixture server. Limiters are shared per API
+/// key, so each test uses its own key.
+fn client(key: &str) -> (FixtureServer, AlphaVantage) {
+    let server = FixtureServer::start(Fixtures::Bundled, 0).unwrap();
+    let client = AlphaVantage::new(&limits()).with_base_url(&server.url()).with_api_key(key);
+    (server, client)
+}
+
+#[test]
+fn daily_bars_parse() {
+    let (_server, client) = client("test-daily");
+    let bars = client.get_timeseries("IBM", &Interval::Day).unwrap();
+
+    assert_eq!(bars.len(), 60);
+    let last = bars.last().unwrap();
+    assert_eq!(last.date, "2024-03-25");
+    assert_eq!((last.open, last.close, last.volume), (164.3629, 164.9853, 2_233_754));
+    assert!(bars.windows(2).all(|w| w[0].date < w[1].date));
+    assert_eq!(client.usage().calls, 1);
+}
+
+#[test]
+fn flaky_responses_are_retried() {
+    let (_server, client) = client("test-flaky");
+    let bars = client.get_timeseries("FLAKY", &Interval::Day).unwrap();
+
+    assert!(!bars.is_empty());
+    let usage = client.usage();
+    assert_eq!((usage.calls, usage.retries, usage.rate_limited), (2, 1, 1));
+}
+
+#[test]
+fn persistent_rate_limits_give_up_after_retries() {
+    let (_server, client) = client("test-ratelimit");
+    let error = client.get_timeseries("RATELIMIT", &Interval::Day).unwrap_err();
+
+    assert!(matches!(error, EngineError::RateLimit(_)), "{:?}", error);
+    let usage = client.usage();
+    assert_eq!((usage.calls, usage.retries, usage.failures), (3, 2, 1));
+}
+
+#[test]
+fn daily_limit_is_not_retried() {
+    let (_server, client) = client("test-dailylimit");
+    let error = client.get_timeseries("DAILYLIMIT", &Interval::Day).unwrap_err();
+
+    assert!(matches!(&error, EngineError::RateLimit(m) if m.contains("per day")), "{:?}", error);
+    let usage = client.usage();
+    assert_eq!((usage.calls, usage.retries, usage.rate_limited), (1, 0, 1));
+}
+
+#[test]
+fn missing_api_key_is_a_data_fetch_error() {
+    let (_server, client) = client("");
+    let error = client.get_timeseries("IBM", &Interval::Day).unwrap_err();
+
+    assert!(matches!(&error, EngineError::DataFetch(m) if m.contains("apikey")), "{:?}", error);
+    assert_eq!(client.usage().retries, 0);
+}
+
+#[test]
+fn failed_requests_do_not_show_the_api_key() {
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let client = AlphaVantage::new(&limits())
+        .with_base_url(&format!("http://127.0.0.1:{}/query", port))
+        .with_api_key("test-secret-key");
+    let error = client.get_timeseries("IBM", &Interval::Day).unwrap_err();
+
+    assert!(matches!(error, EngineError::DataFetch(_)), "{:?}", error);
+    assert!(!error.to_string().contains("test-secret-key"), "{}", error);
+    assert!(!format!("{:?}", error).contains("test-secret-key"), "{:?}", error);
+    assert_eq!(client.usage().retries, 2);
+}
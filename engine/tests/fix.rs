@@ -0,0 +1,140 @@
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+use trading_engine::broker::Broker;
+use trading_engine::clock::{self, parse_bar_time, ReplayClock};
+use trading_engine::error::EngineError;
+use trading_engine::fix::{FixBroker, FixConfig, MockAcceptor};
+use trading_engine::order::{Fill, Order, OrderStatus};
+
+
+fn store_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("fix-store-{}", rand::random::<u64>()))
+}
+
+fn config(acceptor: &MockAcceptor, store_dir: PathBuf) -> FixConfig {
+    FixConfig { port: acceptor.addr().port(), store_dir, timeout_secs: 5.0, ..Default::default() }
+}
+
+fn order(ticker: &str, quantity: i64) -> Order {
+    let mut order = Order::new(ticker.to_string(), quantity);
+    order.transition(OrderStatus::Accepted).unwrap();
+    order
+}
+
+/// Polls for fills until `quantity` shares have been reported.
+fn fills_for(broker: &mut FixBroker, quantity: i64) -> Vec<Fill> {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut fills = vec![];
+    while fills.iter().map(|f: &Fill| f.quantity).sum::<i64>() != quantity && Instant::now() < deadline {
+        fills.extend(broker.fills().unwrap());
+        thread::sleep(Duration::from_millis(20));
+    }
+    fills
+}
+
+#[test]
+fn logs_on_to_the_desk() {
+    let acceptor = MockAcceptor::start(0, "DESK").unwrap();
+    let session = config(&acceptor, store_dir()).session().unwrap();
+    assert!(!session.is_closed());
+}
+
+#[test]
+fn new_orders_fill_from_execution_reports() {
+    let acceptor = MockAcceptor::start(0, "DESK").unwrap();
+    acceptor.set_price("AAPL", 150.0);
+    acceptor.set_max_fill(Some(4));
+    let mut broker = config(&acceptor, store_dir()).broker().unwrap();
+    let order = order("AAPL", 10);
+    broker.submit(&order).unwrap();
+
+    let fills = fills_for(&mut broker, 10);
+    assert_eq!(fills.iter().map(|f| f.quantity).collect::<Vec<_>>(), vec![4, 4, 2]);
+    assert!(fills.iter().all(|f| f.order_id == order.id && f.price == 150.0));
+    assert_eq!(broker.positions().unwrap().get("AAPL"), Some(&10));
+    assert_eq!(broker.account().unwrap().cash, -1_500.0);
+}
+
+#[test]
+fn fills_carry_real_time_during_a_replay() {
+    let acceptor = MockAcceptor::start(0, "DESK").unwrap();
+    acceptor.set_price("AAPL", 150.0);
+    let mut broker = config(&acceptor, store_dir()).broker().unwrap();
+    let replay = ReplayClock::start();
+    replay.set(parse_bar_time("2020-01-02").unwrap());
+    // Execution reports carry milliseconds.
+    let before = clock::wall_time() - chrono::Duration::milliseconds(1);
+    broker.submit(&order("AAPL", 10)).unwrap();
+
+    let fills = fills_for(&mut broker, 10);
+    assert!(fills.iter().all(|f| f.timestamp >= before), "{:?}", fills);
+}
+
+#[test]
+fn working_orders_cancel() {
+    let acceptor = MockAcceptor::start(0, "DESK").unwrap();
+    let mut broker = config(&acceptor, store_dir()).broker().unwrap();
+    let order = order("AAPL", 10);
+    broker.submit(&order).unwrap();
+
+    broker.cancel(order.id).unwrap();
+    let error = broker.cancel(order.id).unwrap_err();
+    assert!(matches!(&error, EngineError::OrderRejected(m) if m.contains("not working")), "{:?}", error);
+    assert!(broker.fills().unwrap().is_empty());
+}
+
+#[test]
+fn desk_rejects_cancels_of_unknown_orders() {
+    let acceptor = MockAcceptor::start(0, "DESK").unwrap();
+    let order = order("AAPL", 10);
+    let mut broker = config(&acceptor, store_dir()).broker().unwrap().resume(std::slice::from_ref(&order));
+
+    let error = broker.cancel(order.id).unwrap_err();
+    assert!(matches!(&error, EngineError::OrderRejected(m) if m == "Unknown order"), "{:?}", error);
+}
+
+#[test]
+fn lost_reports_are_resent() {
+    let acceptor = MockAcceptor::start(0, "DESK").unwrap();
+    acceptor.set_price("AAPL", 150.0);
+    // The acknowledgement goes missing; the fill after it shows the gap.
+    acceptor.lose_reports(1);
+    let mut broker = config(&acceptor, store_dir()).broker().unwrap();
+    let order = order("AAPL", 10);
+    broker.submit(&order).unwrap();
+
+    let fills = fills_for(&mut broker, 10);
+    assert_eq!(fills.len(), 1);
+    assert_eq!((fills[0].quantity, fills[0].price), (10, 150.0));
+    thread::sleep(Duration::from_millis(200));
+    assert!(broker.fills().unwrap().is_empty());
+    assert_eq!(broker.positions().unwrap().get("AAPL"), Some(&10));
+}
+
+#[test]
+fn sequence_numbers_survive_reconnects() {
+    let acceptor = MockAcceptor::start(0, "DESK").unwrap();
+    acceptor.set_price("AAPL", 150.0);
+    let dir = store_dir();
+    let config = config(&acceptor, dir.clone());
+    {
+        let mut broker = config.broker().unwrap();
+        broker.submit(&order("AAPL", 10)).unwrap();
+        fills_for(&mut broker, 10);
+    }
+    let stored = std::fs::read_to_string(dir.join("ENGINE-DESK.json")).unwrap();
+    let seqnums: serde_json::Value = serde_json::from_str(&stored).unwrap();
+    assert!(seqnums["next_out"].as_u64().unwrap() > 2, "{}", stored);
+    assert!(seqnums["next_in"].as_u64().unwrap() > 2, "{}", stored);
+
+    let mut broker = config.broker().unwrap();
+    let order = order("AAPL", 5);
+    broker.submit(&order).unwrap();
+    assert_eq!(fills_for(&mut broker, 5).len(), 1);
+    drop(broker);
+
+    // Starting again from 1 is refused by the desk.
+    let error = FixConfig { store_dir: store_dir(), ..config }.session().unwrap_err();
+    assert!(matches!(&error, EngineError::Broker(m) if m.contains("too low")), "{:?}", error);
+}
@@ -0,0 +1,580 @@
+"""Type stubs for the trading_engine extension module.
+
+The classes below are the supported Python API. Results and portfolios
+are snapshots: they own their data and stay valid after the run.
+Backtests run with the GIL released, so other Python threads keep
+running meanwhile. Engine failures raise a subclass of `EngineError`;
+invalid arguments raise `ValueError`.
+"""
+
+from datetime import date, datetime
+from os import PathLike
+from pathlib import Path
+from typing import Any, Callable, Literal, Mapping, Optional, Protocol, Sequence
+
+StrPath = str | PathLike[str]
+
+class EngineError(Exception):
+    """Base class of the engine's errors."""
+
+class DataFetchError(EngineError):
+    """A market data request failed, or bars could not be read from the
+    cache."""
+
+class RateLimitError(DataFetchError):
+    """The data provider's rate limit was hit."""
+
+class MissingApiKeyError(EngineError):
+    """A required API key, such as `AV_KEY`, is not set."""
+
+class ParseError(EngineError):
+    """Bars, dates or numbers could not be read."""
+
+class ConfigError(EngineError):
+    """The configuration is invalid."""
+
+class UnknownStrategyError(ConfigError):
+    """No strategy is registered under the name."""
+
+class OrderRejectedError(EngineError):
+    """The broker rejected an order."""
+
+class BrokerError(EngineError):
+    """A broker could not be reached or returned an error."""
+
+class AccountingError(EngineError):
+    """Orders or positions would become inconsistent."""
+
+class StorageError(EngineError):
+    """Writing exports, reports or the run store failed."""
+
+class ArrowStreamExportable(Protocol):
+    """Anything implementing the Arrow PyCapsule stream interface, such as
+    a pyarrow table or a pandas (2.2+) or polars frame."""
+
+    def __arrow_c_stream__(self, requested_schema: Optional[object] = None) -> object: ...
+
+#: Bars with a date column (`date`, `timestamp`, `datetime` or `time`,
+#: any case) and `open`, `high`, `low`, `close` and `volume` columns. A
+#: pandas frame may keep its dates in the index. Dates may be strings,
+#: dates or datetimes; aware datetimes are converted to UTC.
+Bars = ArrowStreamExportable | Mapping[str, Sequence[Any]] | Any
+
+class DataConfig:
+    """Where bars come from: `alpha_vantage`, or `cache` for CSV files
+    written by `trading_engine fetch`. Alpha Vantage calls are paced to
+    the plan's budget (`requests_per_day=None` for no daily cap) and
+    transient failures are retried up to `max_retries` times. The API key
+    is read from the `api_key_env` environment variable; `base_url` may
+    point at a `FixtureServer` instead of the public endpoint."""
+
+    def __init__(
+        self,
+        source: Literal["alpha_vantage", "cache"] = "alpha_vantage",
+        interval: str = "day",
+        cache_dir: Optional[StrPath] = None,
+        base_url: Optional[str] = None,
+        api_key_env: Optional[str] = None,
+        requests_per_minute: int = 5,
+        requests_per_day: Optional[int] = 25,
+        max_retries: int = 3,
+    ) -> None: ...
+    @property
+    def source(self) -> str: ...
+    @property
+    def interval(self) -> str: ...
+    @property
+    def cache_dir(self) -> Optional[Path]: ...
+    @property
+    def base_url(self) -> str: ...
+    @property
+    def api_key_env(self) -> str: ...
+    @property
+    def requests_per_minute(self) -> int: ...
+    @property
+    def requests_per_day(self) -> Optional[int]: ...
+    @property
+    def max_retries(self) -> int: ...
+
+class StrategyConfig:
+    """A registered strategy and its parameters. `sizing` takes a position
+    sizer spec such as `percent_equity:0.1` and replaces the fixed
+    quantities; the sizer is capped at `max_position_fraction` of equity."""
+
+    def __init__(
+        self,
+        name: str,
+        window: int,
+        long_quantity: int = 100,
+        short_quantity: int = -100,
+        sizing: Optional[str] = None,
+        max_position_fraction: float = 1.0,
+    ) -> None: ...
+    @property
+    def name(self) -> str: ...
+    @property
+    def window(self) -> int: ...
+    @property
+    def long_quantity(self) -> int: ...
+    @property
+    def short_quantity(self) -> int: ...
+    @property
+    def sizing(self) -> Optional[str]: ...
+    @property
+    def max_position_fraction(self) -> float: ...
+
+class BrokerConfig:
+    """Broker cost models by preset name. Unset fields keep the engine
+    defaults."""
+
+    def __init__(
+        self,
+        commission: Optional[
+            Literal["zero", "retail_flat", "ibkr_fixed", "ibkr_tiered", "institutional"]
+        ] = None,
+        slippage: Optional[
+            Literal["none", "fixed_bps", "spread", "volume_participation", "sqrt_impact"]
+        ] = None,
+        max_participation: Optional[float] = None,
+        lot_method: Optional[Literal["fifo", "lifo", "highest_cost", "average_cost"]] = None,
+    ) -> None: ...
+    @property
+    def commission(self) -> Optional[str]: ...
+    @property
+    def slippage(self) -> Optional[str]: ...
+    @property
+    def max_participation(self) -> Optional[float]: ...
+    @property
+    def lot_method(self) -> Optional[str]: ...
+
+class RiskLimits:
+    """Pre-trade risk limits; `None` disables a limit."""
+
+    def __init__(
+        self,
+        max_position: Optional[int] = None,
+        max_order_notional: Optional[float] = None,
+        max_gross_exposure: Optional[float] = None,
+        max_net_exposure: Optional[float] = None,
+        max_leverage: Optional[float] = None,
+        max_concentration: Optional[float] = None,
+        daily_loss_limit: Optional[float] = None,
+    ) -> None: ...
+    @property
+    def max_position(self) -> Optional[int]: ...
+    @property
+    def max_order_notional(self) -> Optional[float]: ...
+    @property
+    def max_gross_exposure(self) -> Optional[float]: ...
+    @property
+    def max_net_exposure(self) -> Optional[float]: ...
+    @property
+    def max_leverage(self) -> Optional[float]: ...
+    @property
+    def max_concentration(self) -> Optional[float]: ...
+    @property
+    def daily_loss_limit(self) -> Optional[float]: ...
+
+class OutputConfig:
+    """Where results are written. With `dir` set each run is exported to
+    `<dir>/<run_id>/`; `store` records runs in a SQLite database."""
+
+    def __init__(
+        self,
+        dir: Optional[StrPath] = None,
+        format: Literal["csv", "json", "parquet"] = "json",
+        report: bool = False,
+        store: Optional[StrPath] = None,
+    ) -> None: ...
+    @property
+    def dir(self) -> Optional[Path]: ...
+    @property
+    def format(self) -> str: ...
+    @property
+    def report(self) -> bool: ...
+    @property
+    def store(self) -> Optional[Path]: ...
+
+class ExecutionConfig:
+    """How approved orders are executed. With `algo` set they are worked as
+    parent orders in child orders over the following bars; otherwise they
+    go to the broker whole."""
+
+    def __init__(
+        self,
+        algo: Optional[Literal["twap", "vwap", "pov", "is"]] = None,
+        horizon: int = 5,
+        urgency: float = 0.5,
+        participation: float = 0.1,
+        max_participation: Optional[float] = None,
+        limit_bps: Optional[float] = None,
+        max_bars: Optional[int] = None,
+        volume_lookback: int = 20,
+    ) -> None: ...
+    @property
+    def algo(self) -> Optional[str]: ...
+    @property
+    def horizon(self) -> int: ...
+    @property
+    def urgency(self) -> float: ...
+    @property
+    def participation(self) -> float: ...
+    @property
+    def max_participation(self) -> Optional[float]: ...
+    @property
+    def limit_bps(self) -> Optional[float]: ...
+    @property
+    def max_bars(self) -> Optional[int]: ...
+    @property
+    def volume_lookback(self) -> int: ...
+
+class BacktestConfig:
+    """A complete, validated backtest description. Raises `ValueError`
+    listing every problem if validation fails."""
+
+    def __init__(
+        self,
+        universe: Sequence[str],
+        strategy: StrategyConfig,
+        capital: int = 1_000_000,
+        data: Optional[DataConfig] = None,
+        broker: Optional[BrokerConfig] = None,
+        risk: Optional[RiskLimits] = None,
+        output: Optional[OutputConfig] = None,
+        start_date: Optional[date] = None,
+        end_date: Optional[date] = None,
+        execution: Optional[ExecutionConfig] = None,
+    ) -> None: ...
+    @staticmethod
+    def from_file(path: StrPath) -> BacktestConfig:
+        """Loads `.yaml`, `.yml` or `.toml`, applying `TRADING__<SECTION>__<KEY>`
+        environment overrides."""
+    @staticmethod
+    def from_yaml(text: str) -> BacktestConfig: ...
+    @staticmethod
+    def from_toml(text: str) -> BacktestConfig: ...
+    def with_overrides(self, overrides: dict[str, Any]) -> BacktestConfig:
+        """A copy with fields replaced by dotted path, e.g.
+        `{"strategy.window": 50}`."""
+    def to_dict(self) -> dict[str, Any]: ...
+    @property
+    def universe(self) -> list[str]: ...
+    @property
+    def strategy(self) -> StrategyConfig: ...
+    @property
+    def capital(self) -> int: ...
+    @property
+    def data(self) -> DataConfig: ...
+    @property
+    def broker(self) -> BrokerConfig: ...
+    @property
+    def risk(self) -> RiskLimits: ...
+    @property
+    def output(self) -> OutputConfig: ...
+    @property
+    def start_date(self) -> Optional[date]: ...
+    @property
+    def end_date(self) -> Optional[date]: ...
+    @property
+    def execution(self) -> ExecutionConfig: ...
+
+class Trade:
+    """An executed fill as recorded on the portfolio."""
+
+    @property
+    def ticker(self) -> str: ...
+    @property
+    def timestamp(self) -> datetime: ...
+    @property
+    def price(self) -> float: ...
+    @property
+    def quantity(self) -> int:
+        """Signed: positive for buys, negative for sells."""
+    @property
+    def value(self) -> float: ...
+
+class Order:
+    @property
+    def id(self) -> int: ...
+    @property
+    def ticker(self) -> str: ...
+    @property
+    def timestamp(self) -> datetime: ...
+    @property
+    def quantity(self) -> int: ...
+    @property
+    def filled_quantity(self) -> int: ...
+    @property
+    def remaining_quantity(self) -> int: ...
+    @property
+    def status(
+        self,
+    ) -> Literal[
+        "new", "held", "accepted", "partially_filled", "filled", "cancelled", "rejected", "expired"
+    ]: ...
+    @property
+    def time_in_force(self) -> str: ...
+    @property
+    def average_fill_price(self) -> Optional[float]: ...
+    @property
+    def is_open(self) -> bool: ...
+
+class RiskEvent:
+    """An order the risk manager resized, held or rejected."""
+
+    @property
+    def order_id(self) -> int: ...
+    @property
+    def date(self) -> str: ...
+    @property
+    def ticker(self) -> str: ...
+    @property
+    def requested_quantity(self) -> int: ...
+    @property
+    def approved_quantity(self) -> int: ...
+    @property
+    def action(self) -> Literal["resized", "held", "rejected"]: ...
+    @property
+    def reason(self) -> str: ...
+
+class ParentOrder:
+    """An order an execution algo worked through child orders, with its
+    average price against the arrival price."""
+
+    @property
+    def id(self) -> int: ...
+    @property
+    def ticker(self) -> str: ...
+    @property
+    def algo(self) -> Literal["twap", "vwap", "pov", "is"]: ...
+    @property
+    def quantity(self) -> int: ...
+    @property
+    def filled_quantity(self) -> int: ...
+    @property
+    def remaining_quantity(self) -> int: ...
+    @property
+    def arrival_time(self) -> datetime: ...
+    @property
+    def arrival_price(self) -> float: ...
+    @property
+    def average_price(self) -> Optional[float]: ...
+    @property
+    def shortfall_bps(self) -> Optional[float]:
+        """Average price against arrival in basis points, positive when
+        execution cost money."""
+    @property
+    def trading_costs(self) -> float: ...
+    @property
+    def bars(self) -> int: ...
+    @property
+    def children(self) -> list[int]:
+        """Ids of the child orders, which appear among the portfolio's orders."""
+    @property
+    def status(self) -> str: ...
+
+class Metrics:
+    @property
+    def initial_equity(self) -> float: ...
+    @property
+    def final_equity(self) -> float: ...
+    @property
+    def total_return(self) -> float: ...
+    @property
+    def annualized_return(self) -> float: ...
+    @property
+    def annualized_volatility(self) -> float: ...
+    @property
+    def sharpe_ratio(self) -> float: ...
+    @property
+    def max_drawdown(self) -> float: ...
+    @property
+    def n_trades(self) -> int: ...
+    def to_dict(self) -> dict[str, float]: ...
+
+class Portfolio:
+    """Snapshot of a portfolio at the end of a backtest."""
+
+    @property
+    def capital(self) -> int: ...
+    @property
+    def cash(self) -> float: ...
+    @property
+    def equity(self) -> float: ...
+    @property
+    def pnl(self) -> float: ...
+    @property
+    def realized_pnl(self) -> float: ...
+    @property
+    def unrealized_pnl(self) -> float: ...
+    @property
+    def positions(self) -> dict[str, int]: ...
+    @property
+    def gross_exposure(self) -> float: ...
+    @property
+    def net_exposure(self) -> float: ...
+    @property
+    def trades(self) -> list[Trade]: ...
+    @property
+    def orders(self) -> list[Order]: ...
+    @property
+    def parent_orders(self) -> list[ParentOrder]: ...
+    def tax_report(self, detect_wash_sales: bool = True) -> dict[str, float]: ...
+
+class Table:
+    """A result table. Implements the Arrow PyCapsule interface, so it can
+    be passed straight to `pyarrow.table`, `polars.DataFrame` or DuckDB.
+    Timestamps are UTC datetimes."""
+
+    def __arrow_c_stream__(self, requested_schema: Optional[object] = None) -> object: ...
+    @property
+    def columns(self) -> list[str]: ...
+    def __len__(self) -> int: ...
+    def to_dict(self) -> dict[str, list[Any]]: ...
+    def to_arrow(self) -> Any:
+        """A `pyarrow.Table`; requires pyarrow."""
+    def to_pandas(self) -> Any:
+        """A `pandas.DataFrame`, converted through pyarrow when installed."""
+    def to_polars(self) -> Any:
+        """A `polars.DataFrame`, converted through pyarrow when installed."""
+
+class BacktestResult:
+    """Everything a finished backtest produced."""
+
+    @property
+    def symbol(self) -> str: ...
+    @property
+    def n_trades(self) -> int: ...
+    @property
+    def metrics(self) -> Metrics: ...
+    @property
+    def portfolio(self) -> Portfolio: ...
+    @property
+    def trades(self) -> list[Trade]: ...
+    @property
+    def orders(self) -> list[Order]: ...
+    @property
+    def equity_curve(self) -> list[tuple[datetime, float, float]]:
+        """`(timestamp, equity, cash)` per bar."""
+    @property
+    def risk_events(self) -> list[RiskEvent]: ...
+    @property
+    def executions(self) -> list[ParentOrder]:
+        """Orders worked by the execution algo, if one was configured."""
+    @property
+    def run_id(self) -> Optional[str]: ...
+    @property
+    def exported_files(self) -> list[Path]: ...
+    @property
+    def report_path(self) -> Optional[Path]: ...
+    def to_dict(self) -> dict[str, Any]:
+        """Everything above as plain dicts and lists, the shape
+        `run_backtest` returns."""
+    def equity_table(self) -> Table:
+        """Columns timestamp, equity, cash and drawdown."""
+    def trades_table(self) -> Table:
+        """Columns timestamp, ticker, quantity and price."""
+    def orders_table(self) -> Table:
+        """Columns order_id, timestamp, ticker, quantity, filled_quantity,
+        average_price and status."""
+    def executions_table(self) -> Table:
+        """Columns order_id, arrival_time, ticker, algo, quantity,
+        filled_quantity, arrival_price, average_price, shortfall_bps,
+        trading_costs, bars, n_children and status."""
+
+class Backtest:
+    """Runs a `BacktestConfig`. Outputs configured in `config.output` are
+    written after each run."""
+
+    def __init__(self, config: BacktestConfig) -> None: ...
+    @staticmethod
+    def from_file(path: StrPath) -> Backtest: ...
+    @property
+    def config(self) -> BacktestConfig: ...
+    def run(self, symbol: Optional[str] = None, data: Optional[Bars] = None) -> BacktestResult:
+        """Backtests one symbol, by default the first of the universe. Bars
+        are read from `data` when given, otherwise from the configured
+        source. The configured date range applies either way."""
+    def run_all(self, data: Optional[Mapping[str, Bars]] = None) -> list[BacktestResult]:
+        """Backtests every symbol of the universe, in order. Symbols
+        missing from `data` use the configured source."""
+
+def load_config(path: StrPath) -> BacktestConfig: ...
+def run_config(path: StrPath) -> dict[str, BacktestResult]: ...
+
+class CancelToken:
+    """Cancels a `run_many` batch, from a callback or another thread.
+    Backtests already running finish; no new ones start."""
+
+    def __init__(self) -> None: ...
+    def cancel(self) -> None: ...
+    @property
+    def cancelled(self) -> bool: ...
+
+def run_many(
+    configs: Sequence[BacktestConfig],
+    data: Optional[Mapping[str, Bars]] = None,
+    max_workers: Optional[int] = None,
+    progress: Optional[Callable[[int, int], Optional[bool]]] = None,
+    cancel: Optional[CancelToken] = None,
+) -> list[BacktestResult]:
+    """Backtests every symbol of every configuration on a pool of
+    `max_workers` threads (default: one per core). Results come back in
+    order: each symbol of the first configuration, then of the second,
+    and so on. `data` maps symbols to bars shared by all configurations.
+    `progress(completed, total)` is called after each backtest and cancels
+    the batch by returning `False`. Cancellation, by callback or token,
+    raises `concurrent.futures.CancelledError`; the first failing backtest
+    raises its `EngineError`, with a note naming the symbol and strategy.
+    A backtest that panics raises the base `EngineError`."""
+def run_backtest(
+    strategy_type: str,
+    ticker: str,
+    window: int,
+    capital: int,
+    long_qty: int,
+    short_qty: int,
+    commission: Optional[str] = None,
+    slippage: Optional[str] = None,
+    max_participation: Optional[float] = None,
+    lot_method: Optional[str] = None,
+    sizing: Optional[str] = None,
+    export_dir: Optional[str] = None,
+    export_format: str = "json",
+    report_path: Optional[str] = None,
+    store_path: Optional[str] = None,
+) -> dict[str, Any]:
+    """Single-symbol convenience wrapper returning plain dicts. Prefer
+    `Backtest` for new code."""
+def list_runs(
+    store_path: str,
+    strategy: Optional[str] = None,
+    symbol: Optional[str] = None,
+    parameters: Optional[dict[str, str]] = None,
+    limit: Optional[int] = None,
+) -> list[dict[str, Any]]: ...
+def compare_runs(store_path: str, run_ids: Sequence[str]) -> dict[str, Any]: ...
+class FixtureServer:
+    """Serves recorded Alpha Vantage responses on a local port until
+    closed, from `fixtures` or the bundled set: daily bars and quotes for
+    `IBM` and `AAPL`, rate limit notices for `RATELIMIT` and `DAILYLIMIT`,
+    and `FLAKY`, rate limited once and then served. A query for function
+    `F` and symbol `S` is answered from `S.F.<n>.json` (the n-th call),
+    `S.F.json` or `S.json`. Unknown symbols and a missing API key get
+    Alpha Vantage's error payloads.
+
+        with FixtureServer() as server:
+            data = DataConfig(base_url=server.url)
+    """
+
+    def __init__(self, fixtures: Optional[StrPath] = None, port: int = 0) -> None: ...
+    @property
+    def url(self) -> str: ...
+    def close(self) -> None: ...
+    def __enter__(self) -> FixtureServer: ...
+    def __exit__(self, *args: object) -> None: ...
+
+def api_usage() -> dict[str, Any]:
+    """Alpha Vantage calls made by this process (`calls`, `retries`,
+    `rate_limited`, `failures`, `waited_secs`) and the budget left
+    (`minute_remaining`, `day_remaining`), summed over the API keys used."""